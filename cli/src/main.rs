@@ -1,5 +1,4 @@
 use osai_core::OSAI;
-use std::io::{Write};
 use std::io;
 
 #[tokio::main]
@@ -14,11 +13,14 @@ async fn main() {
     if cmd == "server"{
         let _ = osai.run().await;
     }else if cmd == "http_server"{
-        OSAI::http_server().await;
+        let _ = osai.http_server().await;
     }else if cmd == "text" {
-        OSAI::send_text_cli().await;
+        match osai.send_text_cli("").await {
+            Ok(msg) => println!("{}", msg),
+            Err(e) => eprintln!("Error: {}", e),
+        }
     }else if cmd == "r_file" {
-            OSAI::request_http("172.20.10.2");
+            osai.request_http("172.20.10.2");
     }else{
         println!("no cmd"); 
     }
@@ -0,0 +1,632 @@
+// iCalendar (RFC 5545) との変換。`task import` / `task export` と HTTP サーバーの /tasks.ics で使う。
+//
+// タスク1つが VEVENT 1つ:
+//   UID                  `<タスクの ID>@osai`。よそのカレンダーから読んだタスクはその UID のまま (Task::ical_uid)
+//   DTSTART              次に通知する時刻。タスクのタイムゾーンの TZID 付き (UTC なら Z)
+//   SUMMARY / CATEGORIES 名前 / タグ
+//   RRULE                繰り返し。cron は RRULE にできないので X-OSAI-RECURRENCE だけ
+//   VALARM               通知の段階 (TRIGGER:-PT30M)。設定の通知を使うタスクは X-OSAI-REMINDERS:DEFAULT
+//   X-OSAI-STATE         pending 以外の状態
+//
+// 読むときの RRULE は DAILY / WEEKLY (BYDAY) / HOURLY / MINUTELY と、INTERVAL の無い MONTHLY / YEARLY。
+// COUNT・UNTIL などタスクで表せないものは無視して警告に出す。
+// 終日の予定は ALL_DAY_HOUR 時に通知し、STATUS:CANCELLED は done にする。
+// TZID は IANA 名だけ読む (VTIMEZONE は書かない。Google・Apple・Outlook は IANA 名で読める)
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use uuid::Uuid;
+
+use crate::IOT::recurrence::Recurrence;
+use crate::IOT::task::{Task, TaskState};
+use crate::IOT::when::resolve_local;
+
+/// 自分が書いた UID の後ろ
+const UID_SUFFIX: &str = "@osai";
+/// 終日の予定を通知する時刻
+const ALL_DAY_HOUR: u32 = 9;
+/// RFC 5545 の1行の長さ (オクテット)。長い行は折り返す
+const MAX_LINE_LEN: usize = 75;
+
+/// `task import` で読んだタスク。読めなかった VEVENT は飛ばして warnings に理由を入れる
+#[derive(Debug, Default)]
+pub struct IcsImport {
+    pub tasks: Vec<Task>,
+    pub warnings: Vec<String>,
+}
+
+/// タスクを VCALENDAR にする。`default_reminders` は設定の通知 (VALARM に書く)
+pub fn to_ics(tasks: &[Task], default_reminders: &[u32], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//OSAI//Tasks//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:OSAI tasks".to_string(),
+    ];
+    for task in tasks {
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}", escape(&uid(task))));
+        lines.push(format!("DTSTAMP:{}", now.format("%Y%m%dT%H%M%SZ")));
+        if task.timezone == Tz::UTC {
+            lines.push(format!("DTSTART:{}", task.datetime.format("%Y%m%dT%H%M%SZ")));
+        } else {
+            lines.push(format!("DTSTART;TZID={}:{}", task.timezone, task.local_time().format("%Y%m%dT%H%M%S")));
+        }
+        lines.push(format!("SUMMARY:{}", escape(&task.name)));
+        if !task.tags.is_empty() {
+            let tags: Vec<String> = task.tags.iter().map(|t| escape(t)).collect();
+            lines.push(format!("CATEGORIES:{}", tags.join(",")));
+        }
+        if let Some(rule) = &task.recurrence {
+            match rrule(rule) {
+                Some(rrule) => lines.push(format!("RRULE:{}", rrule)),
+                None => lines.push(format!("X-OSAI-RECURRENCE:{}", escape(&rule.to_string()))),
+            }
+        }
+        if task.state != TaskState::Pending {
+            lines.push(format!("X-OSAI-STATE:{}", task.state.to_string().to_lowercase()));
+        }
+        if task.reminders.is_empty() {
+            lines.push("X-OSAI-REMINDERS:DEFAULT".to_string());
+        }
+        for minutes in task.stages(default_reminders) {
+            lines.push("BEGIN:VALARM".to_string());
+            lines.push("ACTION:DISPLAY".to_string());
+            lines.push(format!("DESCRIPTION:{}", escape(&task.name)));
+            lines.push(format!("TRIGGER:{}", trigger(minutes)));
+            lines.push("END:VALARM".to_string());
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut out = String::new();
+    for line in lines {
+        fold(&line, &mut out);
+    }
+    out
+}
+
+/// VCALENDAR を読む。オフセットも TZID も無い時刻は `zone` で読む
+pub fn from_ics(text: &str, zone: Tz) -> Result<IcsImport, String> {
+    let events = parse_events(text)?;
+    let mut import = IcsImport::default();
+    // 同じ UID が2回あれば後の方
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        let label = event.get("SUMMARY").map_or_else(|| format!("event {}", i + 1), |p| format!("'{}'", unescape(&p.value)));
+        match event_to_task(event, zone) {
+            Ok((task, warnings)) => {
+                import.warnings.extend(warnings.into_iter().map(|w| format!("{}: {}", label, w)));
+                let key = uid(&task);
+                match seen.get(&key) {
+                    Some(&index) => {
+                        import.warnings.push(format!("{}: duplicate UID {} in the file, using the last one", label, key));
+                        import.tasks[index] = task;
+                    }
+                    None => {
+                        seen.insert(key, import.tasks.len());
+                        import.tasks.push(task);
+                    }
+                }
+            }
+            Err(e) => import.warnings.push(format!("{}: skipped, {}", label, e)),
+        }
+    }
+    Ok(import)
+}
+
+/// VEVENT の UID
+fn uid(task: &Task) -> String {
+    task.ical_uid.clone().unwrap_or_else(|| format!("{}{}", task.id, UID_SUFFIX))
+}
+
+/// cron は RRULE にできない
+fn rrule(rule: &Recurrence) -> Option<String> {
+    let rule = match rule {
+        Recurrence::Daily => "FREQ=DAILY".to_string(),
+        Recurrence::Weekdays => "FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR".to_string(),
+        Recurrence::Weekly(days) if days.is_empty() => "FREQ=WEEKLY".to_string(),
+        Recurrence::Weekly(days) => {
+            let days: Vec<String> = days.iter().map(|d| d.to_string()[..2].to_uppercase()).collect();
+            format!("FREQ=WEEKLY;BYDAY={}", days.join(","))
+        }
+        Recurrence::EveryMinutes(minutes) if minutes % 1440 == 0 => format!("FREQ=DAILY;INTERVAL={}", minutes / 1440),
+        Recurrence::EveryMinutes(minutes) if minutes % 60 == 0 => format!("FREQ=HOURLY;INTERVAL={}", minutes / 60),
+        Recurrence::EveryMinutes(minutes) => format!("FREQ=MINUTELY;INTERVAL={}", minutes),
+        Recurrence::Cron(_) => return None,
+    };
+    Some(rule)
+}
+
+/// 30 -> -PT30M、0 -> PT0S
+fn trigger(minutes: u32) -> String {
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => "PT0S".to_string(),
+        (0, m) => format!("-PT{}M", m),
+        (h, 0) => format!("-PT{}H", h),
+        (h, m) => format!("-PT{}H{}M", h, m),
+    }
+}
+
+/// TEXT の値のエスケープ
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// 75オクテットごとに CRLF + 空白で折り返す (UTF-8 の途中では切らない)
+fn fold(line: &str, out: &mut String) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > MAX_LINE_LEN {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// `NAME;PARAM=VALUE:value`
+#[derive(Debug, Clone)]
+struct Property {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl Property {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Event {
+    props: Vec<Property>,
+    alarms: Vec<Vec<Property>>,
+}
+
+impl Event {
+    fn get(&self, name: &str) -> Option<&Property> {
+        self.props.iter().find(|p| p.name == name)
+    }
+
+    fn all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Property> + 'a {
+        self.props.iter().filter(move |p| p.name == name)
+    }
+}
+
+/// 折り返しを戻して VEVENT (と中の VALARM) を集める。VTIMEZONE などほかの部品は読み飛ばす
+fn parse_events(text: &str) -> Result<Vec<Event>, String> {
+    let mut lines: Vec<String> = Vec::new();
+    for raw in text.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l)) {
+        match (raw.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ if raw.trim().is_empty() => {}
+            _ => lines.push(raw.to_string()),
+        }
+    }
+    if !lines.first().is_some_and(|l| l.eq_ignore_ascii_case("BEGIN:VCALENDAR")) {
+        return Err("Not an iCalendar file (it must start with BEGIN:VCALENDAR)".to_string());
+    }
+
+    let mut events = Vec::new();
+    let mut event: Option<Event> = None;
+    let mut alarm: Option<Vec<Property>> = None;
+    // VEVENT の中の VALARM 以外の部品と、VCALENDAR 直下の VTIMEZONE など
+    let mut skipping = 0usize;
+    for (number, line) in lines.iter().enumerate() {
+        let prop = parse_line(line).ok_or_else(|| format!("Invalid iCalendar line {}: '{}'", number + 1, line))?;
+        let component = prop.value.to_ascii_uppercase();
+        match prop.name.as_str() {
+            "BEGIN" if skipping > 0 => skipping += 1,
+            "END" if skipping > 0 => skipping -= 1,
+            _ if skipping > 0 => {}
+            "BEGIN" if component == "VEVENT" && event.is_none() => event = Some(Event::default()),
+            "BEGIN" if component == "VALARM" && event.is_some() && alarm.is_none() => alarm = Some(Vec::new()),
+            "BEGIN" if component == "VCALENDAR" && number == 0 => {}
+            "BEGIN" => skipping = 1,
+            "END" if component == "VALARM" => {
+                if let (Some(props), Some(event)) = (alarm.take(), event.as_mut()) {
+                    event.alarms.push(props);
+                }
+            }
+            "END" if component == "VEVENT" => events.extend(event.take()),
+            "END" => {}
+            _ => match (alarm.as_mut(), event.as_mut()) {
+                (Some(alarm), _) => alarm.push(prop),
+                (None, Some(event)) => event.props.push(prop),
+                (None, None) => {}
+            },
+        }
+    }
+    Ok(events)
+}
+
+/// 名前とパラメーターは大文字にする。値の中の ':' と、"" で囲んだパラメーターの ':' ';' はそのまま
+fn parse_line(line: &str) -> Option<Property> {
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let mut parts = head.split(';');
+    let name = parts.next()?.trim().to_ascii_uppercase();
+    if name.is_empty() {
+        return None;
+    }
+    let params = parts
+        .filter_map(|p| p.split_once('='))
+        .map(|(k, v)| (k.trim().to_ascii_uppercase(), v.trim_matches('"').to_string()))
+        .collect();
+    Some(Property { name, params, value: value.to_string() })
+}
+
+/// VEVENT 1つをタスクにする。タスクにできない部分は警告で返す
+fn event_to_task(event: &Event, zone: Tz) -> Result<(Task, Vec<String>), String> {
+    let mut warnings = Vec::new();
+    if event.get("RECURRENCE-ID").is_some() {
+        return Err("changes to a single occurrence (RECURRENCE-ID) are not supported".to_string());
+    }
+    let name = event.get("SUMMARY").map(|p| unescape(&p.value)).unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("no SUMMARY".to_string());
+    }
+    let start = event.get("DTSTART").ok_or("no DTSTART")?;
+    let (datetime, timezone, local) = parse_start(start, zone)?;
+
+    let mut recurrence = match event.get("RRULE") {
+        Some(rule) => {
+            let (rule, ignored) = parse_rrule(&rule.value, local)?;
+            if !ignored.is_empty() {
+                warnings.push(format!("ignored {} in RRULE (tasks repeat forever)", ignored.join(", ")));
+            }
+            Some(rule)
+        }
+        None => None,
+    };
+    if let Some(rule) = event.get("X-OSAI-RECURRENCE") {
+        recurrence = Some(unescape(&rule.value).parse()?);
+    }
+    for ignored in ["RDATE", "EXDATE"] {
+        if event.get(ignored).is_some() {
+            warnings.push(format!("ignored {}", ignored));
+        }
+    }
+
+    let mut reminders = Vec::new();
+    for alarm in &event.alarms {
+        let Some(trigger) = alarm.iter().find(|p| p.name == "TRIGGER") else { continue };
+        match parse_trigger(trigger, datetime) {
+            Ok(minutes) if !reminders.contains(&minutes) => reminders.push(minutes),
+            Ok(_) => {}
+            Err(e) => warnings.push(e),
+        }
+    }
+    // 設定の通知を使うタスクを書き出したもの
+    if event.get("X-OSAI-REMINDERS").is_some_and(|p| p.value.eq_ignore_ascii_case("DEFAULT")) {
+        reminders.clear();
+    }
+
+    let tags = event
+        .all("CATEGORIES")
+        .flat_map(|p| split_list(&p.value))
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let cancelled = event.get("STATUS").is_some_and(|p| p.value.eq_ignore_ascii_case("CANCELLED"));
+    let state = match event.get("X-OSAI-STATE").and_then(|p| p.value.parse::<TaskState>().ok()) {
+        _ if cancelled => TaskState::Done,
+        // 鳴っている途中の状態は持ち込まない
+        Some(TaskState::Fired) | None => TaskState::Pending,
+        Some(state) => state,
+    };
+
+    let (id, ical_uid) = match event.get("UID").map(|p| unescape(&p.value)) {
+        Some(uid) => match uid.strip_suffix(UID_SUFFIX).and_then(|id| Uuid::parse_str(id).ok()) {
+            Some(id) => (id, None),
+            None => (Uuid::new_v4(), Some(uid)),
+        },
+        None => {
+            warnings.push("no UID, importing it again will add it again".to_string());
+            (Uuid::new_v4(), None)
+        }
+    };
+
+    let task = Task {
+        id,
+        datetime,
+        timezone,
+        name: name.to_string(),
+        state,
+        recurrence,
+        reminders,
+        fired: Vec::new(),
+        alert: None,
+        tags,
+        ical_uid,
+    };
+    Ok((task, warnings))
+}
+
+/// DTSTART を (UTC の時刻, タスクのタイムゾーン, そのゾーンの壁時計) にする
+fn parse_start(prop: &Property, zone: Tz) -> Result<(DateTime<Utc>, Tz, NaiveDateTime), String> {
+    let value = prop.value.trim();
+    let zone = match prop.param("TZID") {
+        Some(tzid) => tzid.trim_start_matches('/').parse::<Tz>().map_err(|_| {
+            format!("unknown TZID '{}' (only IANA names such as Asia/Tokyo are supported)", tzid)
+        })?,
+        None => zone,
+    };
+    if prop.param("VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE")) || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").map_err(|_| format!("invalid DTSTART date '{}'", value))?;
+        let local = date.and_hms_opt(ALL_DAY_HOUR, 0, 0).ok_or("invalid DTSTART")?;
+        let at = resolve_local(zone, local).ok_or_else(|| format!("DTSTART '{}' does not exist in {}", value, zone))?;
+        return Ok((at, zone, local));
+    }
+    if let Some(utc) = value.strip_suffix(['Z', 'z']) {
+        let at = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+            .map_err(|_| format!("invalid DTSTART '{}'", value))?
+            .and_utc();
+        return Ok((at, zone, at.with_timezone(&zone).naive_local()));
+    }
+    let local = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").map_err(|_| format!("invalid DTSTART '{}'", value))?;
+    let at = resolve_local(zone, local).ok_or_else(|| format!("DTSTART '{}' does not exist in {}", value, zone))?;
+    Ok((at, zone, local))
+}
+
+/// RRULE を繰り返しルールにする。表せないが無視してよいもの (COUNT・UNTIL など) の名前も返す
+fn parse_rrule(rule: &str, start: NaiveDateTime) -> Result<(Recurrence, Vec<String>), String> {
+    let unsupported = || format!("RRULE '{}' is not supported", rule);
+    let mut freq = String::new();
+    let mut interval = 1u32;
+    let mut days: Vec<Weekday> = Vec::new();
+    let mut ignored = Vec::new();
+    for part in rule.split(';').filter(|p| !p.is_empty()) {
+        let (key, value) = part.split_once('=').ok_or_else(unsupported)?;
+        match key.to_ascii_uppercase().as_str() {
+            "FREQ" => freq = value.to_ascii_uppercase(),
+            "INTERVAL" => interval = value.parse().ok().filter(|n| *n > 0).ok_or_else(unsupported)?,
+            "BYDAY" => {
+                for day in value.split(',') {
+                    // "1MO" (第1月曜) などは表せない
+                    let weekday = match day.to_ascii_uppercase().as_str() {
+                        "MO" => Weekday::Mon,
+                        "TU" => Weekday::Tue,
+                        "WE" => Weekday::Wed,
+                        "TH" => Weekday::Thu,
+                        "FR" => Weekday::Fri,
+                        "SA" => Weekday::Sat,
+                        "SU" => Weekday::Sun,
+                        _ => return Err(unsupported()),
+                    };
+                    if !days.contains(&weekday) {
+                        days.push(weekday);
+                    }
+                }
+            }
+            "WKST" => {}
+            other => ignored.push(other.to_string()),
+        }
+    }
+    days.sort_by_key(|d| d.num_days_from_monday());
+    let weekdays = [Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri];
+    let minutes = |unit: u32| interval.checked_mul(unit).map(Recurrence::EveryMinutes).ok_or_else(unsupported);
+    let rule = match (freq.as_str(), interval, days.is_empty()) {
+        ("DAILY" | "WEEKLY", 1, false) if days == weekdays => Recurrence::Weekdays,
+        ("DAILY" | "WEEKLY", 1, false) => Recurrence::Weekly(days),
+        ("DAILY", 1, true) => Recurrence::Daily,
+        ("DAILY", _, true) => minutes(1440)?,
+        ("WEEKLY", 1, true) => Recurrence::Weekly(Vec::new()),
+        ("WEEKLY", _, true) => minutes(7 * 1440)?,
+        ("HOURLY", _, true) => minutes(60)?,
+        ("MINUTELY", _, true) => minutes(1)?,
+        ("MONTHLY", 1, true) => format!("cron {} {} {} * *", start.minute(), start.hour(), start.day()).parse()?,
+        ("YEARLY", 1, true) => {
+            format!("cron {} {} {} {} *", start.minute(), start.hour(), start.day(), start.month()).parse()?
+        }
+        _ => return Err(unsupported()),
+    };
+    Ok((rule, ignored))
+}
+
+/// VALARM の TRIGGER を何分前かにする。予定より後の通知は読めない
+fn parse_trigger(prop: &Property, start: DateTime<Utc>) -> Result<u32, String> {
+    let value = prop.value.trim();
+    let before = if prop.param("VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE-TIME")) {
+        let at = NaiveDateTime::parse_from_str(value.trim_end_matches(['Z', 'z']), "%Y%m%dT%H%M%S")
+            .map_err(|_| format!("ignored alarm with invalid TRIGGER '{}'", value))?
+            .and_utc();
+        start - at
+    } else {
+        // DTEND は読まないので RELATED=END も始まりからとして扱う
+        -parse_duration(value).ok_or_else(|| format!("ignored alarm with invalid TRIGGER '{}'", value))?
+    };
+    if before < Duration::zero() {
+        return Err(format!("ignored alarm after the start (TRIGGER {})", value));
+    }
+    u32::try_from(before.num_minutes()).map_err(|_| format!("ignored alarm too long before the start (TRIGGER {})", value))
+}
+
+/// -PT30M / PT0S / -P1D / -P1DT2H / -P1W。表せないほど長ければ None
+fn parse_duration(value: &str) -> Option<Duration> {
+    let (sign, rest) = match value.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let rest = rest.strip_prefix(['P', 'p'])?;
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    let mut time = false;
+    for c in rest.chars() {
+        match c.to_ascii_uppercase() {
+            '0'..='9' => number.push(c),
+            'T' => time = true,
+            unit => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                let part = match (unit, time) {
+                    ('W', false) => Duration::try_weeks(n),
+                    ('D', false) => Duration::try_days(n),
+                    ('H', true) => Duration::try_hours(n),
+                    ('M', true) => Duration::try_minutes(n),
+                    ('S', true) => Duration::try_seconds(n),
+                    _ => None,
+                }?;
+                total = total.checked_add(&part)?;
+            }
+        }
+    }
+    number.is_empty().then(|| if sign < 0 { -total } else { total })
+}
+
+/// CATEGORIES などのカンマ区切り (\, はカンマのまま)
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                items.last_mut().unwrap().push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            ',' => items.push(String::new()),
+            _ => items.last_mut().unwrap().push(c),
+        }
+    }
+    items.into_iter().map(|t| t.trim().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IOT::task::add_new_task;
+    use chrono::TimeZone;
+    use chrono_tz::{America::New_York, Asia::Tokyo};
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap()
+    }
+
+    fn event(body: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nVERSION:2.0\r\nBEGIN:VEVENT\r\n{}END:VEVENT\r\nEND:VCALENDAR\r\n", body)
+    }
+
+    #[test]
+    fn daily_task_with_alarms_round_trips() {
+        let task = add_new_task("2030-01-01:08:00:薬を飲む @ daily @ remind 30,5,0 @ tags care,medicine", Tokyo).unwrap();
+        let ics = to_ics(std::slice::from_ref(&task), &[5], now());
+        assert!(ics.contains("RRULE:FREQ=DAILY\r\n"));
+        assert!(ics.contains("TRIGGER:-PT30M\r\n"));
+        assert!(ics.contains("DTSTART;TZID=Asia/Tokyo:20300101T080000\r\n"));
+
+        let import = from_ics(&ics, New_York).unwrap();
+        assert!(import.warnings.is_empty(), "{:?}", import.warnings);
+        let [imported] = import.tasks.as_slice() else { panic!("{:?}", import.tasks) };
+        assert_eq!(imported.id, task.id);
+        assert_eq!(imported.name, task.name);
+        assert_eq!(imported.datetime, task.datetime);
+        assert_eq!(imported.timezone, Tokyo);
+        assert_eq!(imported.recurrence, Some(Recurrence::Daily));
+        assert_eq!(imported.reminders, vec![30, 5, 0]);
+        assert_eq!(imported.tags, task.tags);
+        assert_eq!(imported.ical_uid, None);
+
+        // もう一度読み込んでも変わらない
+        let mut existing = task.clone();
+        assert!(!existing.update_from(imported.clone()));
+    }
+
+    #[test]
+    fn default_reminders_and_cron_round_trip() {
+        let task = add_new_task("2030-01-03:10:00:cron @ cron 0 8 * * 1-5", Tokyo).unwrap();
+        let ics = to_ics(std::slice::from_ref(&task), &[5], now());
+        assert!(ics.contains("X-OSAI-REMINDERS:DEFAULT\r\n"));
+        let import = from_ics(&ics, Tokyo).unwrap();
+        assert_eq!(import.tasks[0].reminders, Vec::<u32>::new());
+        assert_eq!(import.tasks[0].recurrence, task.recurrence);
+    }
+
+    #[test]
+    fn foreign_events() {
+        let text = event(concat!(
+            "UID:abc@example.com\r\n",
+            "DTSTART;TZID=America/New_York:20300105T073000\r\n",
+            "SUMMARY:Walk\\, dog\r\n",
+            "RRULE:FREQ=WEEKLY;BYDAY=TU,TH;COUNT=10\r\n",
+            "BEGIN:VALARM\r\nTRIGGER:-PT15M\r\nEND:VALARM\r\n",
+        ));
+        let import = from_ics(&text, Tokyo).unwrap();
+        let task = &import.tasks[0];
+        assert_eq!(task.name, "Walk, dog");
+        assert_eq!(task.ical_uid.as_deref(), Some("abc@example.com"));
+        assert_eq!(task.timezone, New_York);
+        assert_eq!(task.reminders, vec![15]);
+        assert_eq!(task.recurrence, Some(Recurrence::Weekly(vec![Weekday::Tue, Weekday::Thu])));
+        assert!(import.warnings.iter().any(|w| w.contains("COUNT")));
+    }
+
+    #[test]
+    fn duplicate_uids_keep_the_last_event() {
+        let one = "BEGIN:VEVENT\r\nUID:x\r\nDTSTART:20300101T080000Z\r\nSUMMARY:first\r\nEND:VEVENT\r\n";
+        let two = "BEGIN:VEVENT\r\nUID:x\r\nDTSTART:20300101T090000Z\r\nSUMMARY:second\r\nEND:VEVENT\r\n";
+        let text = format!("BEGIN:VCALENDAR\r\n{}{}END:VCALENDAR\r\n", one, two);
+        let import = from_ics(&text, Tokyo).unwrap();
+        assert_eq!(import.tasks.len(), 1);
+        assert_eq!(import.tasks[0].name, "second");
+        assert!(import.warnings.iter().any(|w| w.contains("duplicate UID")));
+    }
+
+    #[test]
+    fn huge_triggers_are_warnings() {
+        for trigger in ["-P99999999999999W", "-P99999999999999D", "-PT99999999999999H", "-P1W99999999999999D"] {
+            let text = event(&format!(
+                "UID:y\r\nDTSTART:20300101T080000Z\r\nSUMMARY:s\r\nBEGIN:VALARM\r\nTRIGGER:{}\r\nEND:VALARM\r\n",
+                trigger
+            ));
+            let import = from_ics(&text, Tokyo).unwrap();
+            assert_eq!(import.tasks.len(), 1);
+            assert!(import.tasks[0].reminders.is_empty());
+            assert!(import.warnings.iter().any(|w| w.contains(trigger)), "{:?}", import.warnings);
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("-PT30M"), Some(Duration::minutes(-30)));
+        assert_eq!(parse_duration("PT0S"), Some(Duration::zero()));
+        assert_eq!(parse_duration("-P1DT2H"), Some(-(Duration::days(1) + Duration::hours(2))));
+        assert_eq!(parse_duration("-P1W"), Some(Duration::weeks(-1)));
+        assert_eq!(parse_duration("P1H"), None);
+        assert_eq!(parse_duration("-PT30"), None);
+        assert_eq!(parse_duration("-P99999999999999W"), None);
+    }
+}
@@ -1,3 +1,8 @@
 pub mod task;
+pub mod task_store;
+pub mod recurrence;
 pub mod mem;
 
+pub mod when;
+pub mod ical;
+pub mod templates;
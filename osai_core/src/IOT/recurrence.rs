@@ -0,0 +1,384 @@
+// タスクの繰り返しルール。タスクファイルには文字列のまま保存する (`"recurrence": "weekdays"` など)。
+//
+// 書き方:
+//   daily / every day
+//   weekdays / every weekday           (月〜金)
+//   weekly                             (最初の日時と同じ曜日)
+//   weekly mon,wed,fri / every mon,wed,fri
+//   every 30 minutes / every 2 hours / every 45m
+//   cron 0 8 * * 1-5                   (分 時 日 月 曜日。曜日は 0,7 = 日曜)
+//
+// 時刻はタスクの datetime の時刻を使う (every N minutes と cron を除く)。
+use std::fmt;
+use std::str::FromStr;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// cron で次の日時を探す範囲 (2/29 だけの指定も見つかるように4年以上)
+const CRON_SEARCH_DAYS: i64 = 366 * 5;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Recurrence {
+    Daily,
+    /// 月〜金
+    Weekdays,
+    /// 指定した曜日。空なら最初の日時と同じ曜日
+    Weekly(Vec<Weekday>),
+    EveryMinutes(u32),
+    Cron(CronSchedule),
+}
+
+impl Recurrence {
+    /// `current` (今のタスクの日時) のあと、`after` より後で最初の日時
+    pub fn next_after(&self, current: NaiveDateTime, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        match self {
+            Recurrence::Daily => next_on_days(current.time(), after, |_| true),
+            Recurrence::Weekdays => next_on_days(current.time(), after, |d| {
+                !matches!(d.weekday(), Weekday::Sat | Weekday::Sun)
+            }),
+            Recurrence::Weekly(days) if days.is_empty() => {
+                let weekday = current.weekday();
+                next_on_days(current.time(), after, |d| d.weekday() == weekday)
+            }
+            Recurrence::Weekly(days) => next_on_days(current.time(), after, |d| days.contains(&d.weekday())),
+            Recurrence::EveryMinutes(minutes) => {
+                let step = i64::from((*minutes).max(1));
+                if current > after {
+                    return Some(current);
+                }
+                // current から step 分ずつ進めて after を越える最初の日時
+                let steps = (after - current).num_minutes() / step + 1;
+                current.checked_add_signed(Duration::minutes(step * steps))
+            }
+            Recurrence::Cron(schedule) => schedule.next_after(after),
+        }
+    }
+}
+
+/// `after` より後で、`matches` な日の `time`
+fn next_on_days(time: NaiveTime, after: NaiveDateTime, matches: impl Fn(NaiveDate) -> bool) -> Option<NaiveDateTime> {
+    let mut date = after.date();
+    for _ in 0..=7 {
+        let candidate = date.and_time(time);
+        if candidate > after && matches(date) {
+            return Some(candidate);
+        }
+        date = date.succ_opt()?;
+    }
+    None
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recurrence::Daily => write!(f, "daily"),
+            Recurrence::Weekdays => write!(f, "weekdays"),
+            Recurrence::Weekly(days) if days.is_empty() => write!(f, "weekly"),
+            Recurrence::Weekly(days) => {
+                let days: Vec<String> = days.iter().map(|d| d.to_string().to_lowercase()).collect();
+                write!(f, "weekly {}", days.join(","))
+            }
+            Recurrence::EveryMinutes(minutes) if minutes % 60 == 0 => write!(f, "every {} hours", minutes / 60),
+            Recurrence::EveryMinutes(minutes) => write!(f, "every {} minutes", minutes),
+            Recurrence::Cron(schedule) => write!(f, "cron {}", schedule.expr),
+        }
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rule = s.trim().to_ascii_lowercase();
+        let rule = rule.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let Some(expr) = rule.strip_prefix("cron ") {
+            return CronSchedule::parse(expr).map(Recurrence::Cron);
+        }
+        match rule.as_str() {
+            "daily" | "every day" => return Ok(Recurrence::Daily),
+            "weekdays" | "every weekday" => return Ok(Recurrence::Weekdays),
+            "weekly" | "every week" => return Ok(Recurrence::Weekly(Vec::new())),
+            _ => {}
+        }
+        if let Some(days) = rule.strip_prefix("weekly ") {
+            return parse_weekdays(days).map(Recurrence::Weekly);
+        }
+        if let Some(rest) = rule.strip_prefix("every ") {
+            if let Some(minutes) = parse_interval(rest) {
+                return Ok(Recurrence::EveryMinutes(minutes));
+            }
+            if let Ok(days) = parse_weekdays(rest) {
+                return Ok(Recurrence::Weekly(days));
+            }
+        }
+        Err(format!(
+            "Unknown repeat rule '{}'. Use daily, weekdays, weekly [mon,wed], every N minutes/hours or cron <min hour day month weekday>",
+            s.trim()
+        ))
+    }
+}
+
+impl From<Recurrence> for String {
+    fn from(recurrence: Recurrence) -> Self {
+        recurrence.to_string()
+    }
+}
+
+impl TryFrom<String> for Recurrence {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// "30 minutes" / "2 hours" / "45m" / "1h" を分にする
+fn parse_interval(s: &str) -> Option<u32> {
+    let s = s.replace(' ', "");
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = s.split_at(split);
+    let n: u32 = number.parse().ok().filter(|n| *n > 0)?;
+    match unit {
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(n),
+        "h" | "hour" | "hours" => n.checked_mul(60),
+        _ => None,
+    }
+}
+
+fn parse_weekdays(s: &str) -> Result<Vec<Weekday>, String> {
+    let mut days = Vec::new();
+    for day in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let day: Weekday = day.parse().map_err(|_| format!("Unknown weekday '{}'", day))?;
+        if !days.contains(&day) {
+            days.push(day);
+        }
+    }
+    if days.is_empty() {
+        return Err("No weekdays given".to_string());
+    }
+    days.sort_by_key(|d| d.num_days_from_monday());
+    Ok(days)
+}
+
+/// 5フィールドの cron 式。`*`、`a-b`、`*/n`、`a-b/n`、`a,b` が使える。
+/// 日と曜日の両方を絞ったときはどちらかに当てはまれば実行する (普通の cron と同じ)。
+/// 絞っていない (`*`、`*/1`、`1-31` のようにすべての値に当てはまる) 方は見ない。`*/2` は絞っている
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expr: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    /// 0 = 日曜
+    days_of_week: Vec<bool>,
+    /// 日がすべての値に当てはまる
+    dom_any: bool,
+    /// 曜日がすべての値に当てはまる
+    dow_any: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("cron expression needs 5 fields (min hour day month weekday): '{}'", expr));
+        }
+        let mut days_of_week = parse_field(fields[4], 0, 7, "weekday")?;
+        // 7 も日曜
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        days_of_week.truncate(7);
+        let days_of_month = parse_field(fields[2], 1, 31, "day")?;
+        let schedule = CronSchedule {
+            expr: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59, "minute")?,
+            hours: parse_field(fields[1], 0, 23, "hour")?,
+            dom_any: days_of_month[1..].iter().all(|day| *day),
+            days_of_month,
+            months: parse_field(fields[3], 1, 12, "month")?,
+            dow_any: days_of_week.iter().all(|day| *day),
+            days_of_week,
+        };
+        // "0 8 31 2 *" (2/31) のように一度も来ない式はタスクが止まったままになるので受け付けない
+        if schedule.next_after(Utc::now().naive_utc()).is_none() {
+            return Err(format!("cron expression '{}' never matches a date", schedule.expr));
+        }
+        Ok(schedule)
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let dom = self.days_of_month[date.day() as usize];
+        let dow = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        match (self.dom_any, self.dow_any) {
+            (true, true) => true,
+            (true, false) => dow,
+            (false, true) => dom,
+            (false, false) => dom || dow,
+        }
+    }
+
+    /// `after` より後で最初に当てはまる日時 (秒は0)
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut date = after.date();
+        for _ in 0..CRON_SEARCH_DAYS {
+            if self.matches_day(date) {
+                for hour in (0..24).filter(|h| self.hours[*h as usize]) {
+                    for minute in (0..60).filter(|m| self.minutes[*m as usize]) {
+                        let candidate = date.and_hms_opt(hour, minute, 0)?;
+                        if candidate > after {
+                            return Some(candidate);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+/// `min..=max` のどれに当てはまるかを index = 値 の表にする
+fn parse_field(field: &str, min: u32, max: u32, name: &str) -> Result<Vec<bool>, String> {
+    let mut allowed = vec![false; max as usize + 1];
+    let invalid = || format!("Invalid cron {} field '{}'", name, field);
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|s| *s > 0).ok_or_else(invalid)?),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (a.parse().map_err(|_| invalid())?, b.parse().map_err(|_| invalid())?)
+        } else {
+            let value: u32 = range.parse().map_err(|_| invalid())?;
+            // "5/15" は 5 から最後まで 15 おき
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            allowed[value as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn next(rule: &str, current: &str, after: &str) -> Option<NaiveDateTime> {
+        rule.parse::<Recurrence>().unwrap().next_after(at(current), at(after))
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for (rule, expected) in [
+            ("daily", Recurrence::Daily),
+            ("Every  Day", Recurrence::Daily),
+            ("every weekday", Recurrence::Weekdays),
+            ("weekly", Recurrence::Weekly(Vec::new())),
+            ("every fri,mon,mon", Recurrence::Weekly(vec![Weekday::Mon, Weekday::Fri])),
+            ("every 30 minutes", Recurrence::EveryMinutes(30)),
+            ("every 2h", Recurrence::EveryMinutes(120)),
+        ] {
+            let parsed: Recurrence = rule.parse().unwrap();
+            assert_eq!(parsed, expected, "{}", rule);
+            assert_eq!(parsed.to_string().parse::<Recurrence>().unwrap(), parsed);
+        }
+        let cron: Recurrence = "cron  0 8 * * 1-5".parse().unwrap();
+        assert_eq!(cron.to_string(), "cron 0 8 * * 1-5");
+        for rule in ["hourly", "weekly moonday", "every 0 minutes", "every 5 days", "cron 0 8 * *", "cron 60 8 * * *"] {
+            assert!(rule.parse::<Recurrence>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn daily_and_weekdays() {
+        // 2025-06-06 は金曜
+        assert_eq!(next("daily", "2025-06-01 08:00", "2025-06-06 07:59"), Some(at("2025-06-06 08:00")));
+        assert_eq!(next("daily", "2025-06-01 08:00", "2025-06-06 08:00"), Some(at("2025-06-07 08:00")));
+        assert_eq!(next("weekdays", "2025-06-01 08:00", "2025-06-06 09:00"), Some(at("2025-06-09 08:00")));
+        assert_eq!(next("weekdays", "2025-06-01 08:00", "2025-06-08 23:00"), Some(at("2025-06-09 08:00")));
+    }
+
+    #[test]
+    fn weekly() {
+        // 2025-06-03 は火曜
+        assert_eq!(next("weekly", "2025-06-03 19:30", "2025-06-03 19:30"), Some(at("2025-06-10 19:30")));
+        assert_eq!(next("weekly mon,wed", "2025-06-03 19:30", "2025-06-03 19:30"), Some(at("2025-06-04 19:30")));
+        assert_eq!(next("every wed", "2025-06-03 19:30", "2025-06-04 20:00"), Some(at("2025-06-11 19:30")));
+    }
+
+    #[test]
+    fn every_n_minutes() {
+        assert_eq!(next("every 45m", "2025-06-01 08:00", "2025-06-01 07:00"), Some(at("2025-06-01 08:00")));
+        assert_eq!(next("every 45m", "2025-06-01 08:00", "2025-06-01 08:00"), Some(at("2025-06-01 08:45")));
+        // 止まっていた間の分は飛ばして、after より後の刻みに合わせる
+        assert_eq!(next("every 45m", "2025-06-01 08:00", "2025-06-01 10:20"), Some(at("2025-06-01 11:00")));
+        assert_eq!(next("every 2 hours", "2025-06-01 08:00", "2025-06-02 08:00"), Some(at("2025-06-02 10:00")));
+    }
+
+    #[test]
+    fn cron() {
+        let weekday_mornings = CronSchedule::parse("0 8 * * 1-5").unwrap();
+        assert_eq!(weekday_mornings.next_after(at("2025-06-06 08:00")), Some(at("2025-06-09 08:00")));
+        let quarter_hours = CronSchedule::parse("*/15 9-10 * * *").unwrap();
+        assert_eq!(quarter_hours.next_after(at("2025-06-01 09:50")), Some(at("2025-06-01 10:00")));
+        assert_eq!(quarter_hours.next_after(at("2025-06-01 10:45")), Some(at("2025-06-02 09:00")));
+        // 日と曜日の両方を指定したらどちらか
+        let first_or_sunday = CronSchedule::parse("30 7 1 * 0").unwrap();
+        assert_eq!(first_or_sunday.next_after(at("2025-06-01 08:00")), Some(at("2025-06-08 07:30")));
+        assert_eq!(first_or_sunday.next_after(at("2025-06-29 08:00")), Some(at("2025-07-01 07:30")));
+        // 7 も日曜
+        assert_eq!(CronSchedule::parse("0 12 * * 7").unwrap().next_after(at("2025-06-02 00:00")), Some(at("2025-06-08 12:00")));
+        let leap_day = CronSchedule::parse("0 0 29 2 *").unwrap();
+        assert_eq!(leap_day.next_after(at("2025-01-01 00:00")), Some(at("2028-02-29 00:00")));
+    }
+
+    #[test]
+    fn cron_day_of_month_and_weekday() {
+        // 2025-06-02 は月曜
+        let after = at("2025-06-02 09:00");
+        let next = |expr: &str| CronSchedule::parse(expr).unwrap().next_after(after);
+        // 両方絞ったら 13日か金曜
+        assert_eq!(next("0 8 13 * 5"), Some(at("2025-06-06 08:00")));
+        assert_eq!(CronSchedule::parse("0 8 13 * 5").unwrap().next_after(at("2025-06-12 09:00")), Some(at("2025-06-13 08:00")));
+        // */2 は奇数日に絞っているので、奇数日か月曜
+        assert_eq!(next("0 8 */2 * 1"), Some(at("2025-06-03 08:00")));
+        assert_eq!(CronSchedule::parse("0 8 */2 * 1").unwrap().next_after(at("2025-06-07 09:00")), Some(at("2025-06-09 08:00")));
+        // */1 や 1-31 は絞っていないので月曜だけ
+        for expr in ["0 8 * * 1", "0 8 */1 * 1", "0 8 1-31 * 1"] {
+            assert_eq!(next(expr), Some(at("2025-06-09 08:00")), "{}", expr);
+        }
+        // 曜日を絞っていなければ日だけ
+        for expr in ["0 8 15 * *", "0 8 15 * */1", "0 8 15 * 0-6"] {
+            assert_eq!(next(expr), Some(at("2025-06-15 08:00")), "{}", expr);
+        }
+        // 曜日の */2 は日・火・木・土に絞っている
+        assert_eq!(next("0 8 15 * */2"), Some(at("2025-06-03 08:00")));
+    }
+
+    #[test]
+    fn cron_that_never_fires_is_rejected() {
+        for expr in ["0 8 31 2 *", "0 8 30 2 *", "0 8 31 4,6,9,11 *"] {
+            let error = CronSchedule::parse(expr).unwrap_err();
+            assert!(error.contains("never matches"), "{}", error);
+        }
+        assert!("cron 0 8 31 2 *".parse::<Recurrence>().is_err());
+        // 曜日も指定していればそちらで来る
+        assert!(CronSchedule::parse("0 8 31 2 1").is_ok());
+    }
+}
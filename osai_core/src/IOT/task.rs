@@ -1,110 +1,657 @@
 use crate::OSAI;
+use crate::config::{OsaiConfig, SchedulerConfig};
+use crate::llm::{self, LlmBackendKind, LlmRequest};
+use crate::IOT::templates::SpeechTemplates;
 use crate::IOT::mem::{FileIO, create_wav};
 use std::io;
 use std::path::Path;
 use std::fs;
 use std::error::Error;
-use chrono::{Local, NaiveDateTime, Duration};
+use crate::IOT::recurrence::Recurrence;
+use crate::IOT::task_store::TaskStore;
+use crate::IOT::when::{local_zone, parse_at, parse_when, resolve_local};
+use crate::client::client_task::send_task_alert;
+use crate::protocol::task_alert::{TaskAlert, TaskAlertKind};
+use chrono::{DateTime, NaiveDate, Duration, Utc};
+use chrono_tz::Tz;
+use uuid::Uuid;
 use serde::{Serialize, Deserialize};
-use reqwest::Client;
 use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
 use std::env::var;
 
-// 定数定義 (main.rsから移動)
-const TASK_FILE: &str = "scheduled_tasks.json";
-const LYRIC_FILE: &str = "lyric.txt";
-const API_URL: &str = "https://generativelanguage.googleapis.com/v1beta/models/gemini-2.5-flash-preview-09-2025:generateContent";
+// 定数定義 (main.rsから移動)。ファイルの場所と通知のタイミングは OsaiConfig の paths / scheduler
 const EMOTION_PARAMS: &str = "5,5,5,5,5,5,5,5,5,5,5,5,5,5"; 
 
+/// 一覧などに出す時刻の形式 (タスクのタイムゾーンで)。前のバージョンのタスクファイルの Task::datetime もこれ
+pub const TASK_DATETIME_FORMAT: &str = "%Y-%m-%d:%H:%M";
+/// 前のバージョンのタスクファイルの Alert::next_at (秒まで)
+const ALERT_DATETIME_FORMAT: &str = "%Y-%m-%d:%H:%M:%S";
+
+/// この回の通知の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    /// 次の通知を待っている
+    #[default]
+    Pending,
+    /// 通知して ack を待っている
+    Fired,
+    /// 最後の通知に ack があった
+    Acknowledged,
+    /// 鳴らし直しても応答が無かった
+    Missed,
+    /// `task done` で終わらせた
+    Done,
+}
+
+impl TaskState {
+    fn is_final(self) -> bool {
+        matches!(self, TaskState::Acknowledged | TaskState::Missed | TaskState::Done)
+    }
+}
+
+impl std::str::FromStr for TaskState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pending" => Ok(TaskState::Pending),
+            "fired" => Ok(TaskState::Fired),
+            "acknowledged" => Ok(TaskState::Acknowledged),
+            "missed" => Ok(TaskState::Missed),
+            "done" => Ok(TaskState::Done),
+            other => Err(format!("Unknown task state '{}'. Use pending, fired, acknowledged, missed or done", other)),
+        }
+    }
+}
+
+// 古いタスクファイルの "notified": true/false も読めるようにする
+impl<'de> Deserialize<'de> for TaskState {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Notified(bool),
+            State(String),
+        }
+        match Stored::deserialize(deserializer)? {
+            Stored::Notified(true) => Ok(TaskState::Acknowledged),
+            Stored::Notified(false) => Ok(TaskState::Pending),
+            Stored::State(state) => state.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl std::fmt::Display for TaskState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self {
+            TaskState::Pending => "PENDING",
+            TaskState::Fired => "WAITING FOR ACK",
+            TaskState::Acknowledged => "ACKNOWLEDGED",
+            TaskState::Missed => "MISSED",
+            TaskState::Done => "DONE",
+        };
+        write!(f, "{}", label)
+    }
+}
+
 // タスクを保存・ロードするための構造体
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Task {
-    pub datetime: String, 
+    /// 古いタスクファイルには無いので、TaskStore が最初に読んだときに振って保存する
+    #[serde(default = "Uuid::nil")]
+    pub id: Uuid,
+    /// 次に通知する時刻 (UTC)。繰り返すタスクは通知するたびに次の時刻へ進む
+    #[serde(with = "task_time")]
+    pub datetime: DateTime<Utc>,
+    /// 時刻を表示する・繰り返すときのタイムゾーン。古いタスクファイルには無いのでこのマシンのゾーン
+    #[serde(default = "local_zone")]
+    pub timezone: Tz,
     pub name: String,
-    pub notified: bool, // 通知済みフラグ
+    #[serde(default, alias = "notified")]
+    pub state: TaskState,
+    /// 無ければ1回だけ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<Recurrence>,
+    /// 何分前に通知するか (0 はその時刻)。空なら scheduler.reminders_mins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reminders: Vec<u32>,
+    /// この回で通知した (か飛ばした) 段階。再起動しても同じ段階を繰り返さないように保存する
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fired: Vec<u32>,
+    /// 鳴っていて ack を待っている通知
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert: Option<Alert>,
+    /// `task list tag=...` で絞り込む
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// よそのカレンダーから `task import` した VEVENT の UID。読み直したときに同じタスクを更新する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ical_uid: Option<String>,
 }
 
-// --- Gemini API Call ---
+/// 今出す通知の段階
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReminderStage {
+    pub minutes_before: u32,
+    /// 止まっていたなどで遅れて出す
+    pub late: bool,
+    /// 鳴らし直した回数 (最初の通知は 0)
+    #[serde(default)]
+    pub repeat: u32,
+}
 
-pub async fn gemini_call(query: &str) -> Result<String, Box<dyn Error>> {
-    
-    let api_key = match std::env::var("GEMINI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            // キーが設定されていない場合、エラーを返して処理を中断
-            return Err("Error: GEMINI_API_KEY environment variable not set. Please set your API key.".into());
+/// ack を待っている通知
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Alert {
+    pub stage: ReminderStage,
+    /// この時刻まで ack が無ければ鳴らし直す (snooze 中なら鳴らす)
+    #[serde(with = "task_time")]
+    pub next_at: DateTime<Utc>,
+    #[serde(default)]
+    pub snoozed: bool,
+}
+
+impl Alert {
+    fn new(stage: ReminderStage, next_at: DateTime<Utc>) -> Self {
+        Alert { stage, next_at, snoozed: false }
+    }
+}
+
+/// タスクファイルの時刻。RFC 3339 (UTC) で書く。
+/// 前のバージョンの "YYYY-MM-DD:HH:MM" (秒まであるものも) はこのマシンのローカル時刻として読む
+mod task_time {
+    use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::{ALERT_DATETIME_FORMAT, TASK_DATETIME_FORMAT};
+    use crate::IOT::when::{local_zone, resolve_local};
+
+    pub fn serialize<S: Serializer>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime<Utc>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if let Ok(time) = DateTime::parse_from_rfc3339(&text) {
+            return Ok(time.with_timezone(&Utc));
         }
-    };
+        [ALERT_DATETIME_FORMAT, TASK_DATETIME_FORMAT]
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(&text, format).ok())
+            .and_then(|local| resolve_local(local_zone(), local))
+            .ok_or_else(|| D::Error::custom(format!("invalid time '{}'", text)))
+    }
+}
 
-    let payload = serde_json::json!({
-        "contents": [{ "parts": [{ "text": query }] }],
-        "tools": [{ "google_search": {} }],
-        "systemInstruction": {
-            "parts": [{ "text": "Act as a helpful and friendly small AI assistant. Respond concisely and clearly in Japanese." }]
-        },
-    });
+/// 鳴っている通知への応答。CLI・ピアの TASK_ACK・音声 (OSAI::listen) から来る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AckAction {
+    Ack,
+    /// 分数
+    Snooze(u32),
+}
+
+impl std::fmt::Display for AckAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AckAction::Ack => write!(f, "ack"),
+            AckAction::Snooze(minutes) => write!(f, "snooze {} min", minutes),
+        }
+    }
+}
+
+/// スケジューラーが鳴らす・知らせるもの。タスクファイルの更新が済んでから外で実行する
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub task: Task,
+    pub stage: ReminderStage,
+    pub kind: NoticeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeKind {
+    /// 通知 (snooze 明けを含む)
+    Ring,
+    /// ack が無いので鳴らし直し、caregiver に知らせる
+    Escalate,
+    /// 鳴らし直しても応答が無かった。caregiver に知らせるだけ
+    GiveUp,
+}
+
+/// 予定の時刻からこれ以上遅れた通知は late として出す
+const LATE_THRESHOLD_MINS: i64 = 1;
 
-    let client = Client::new();
-    let url = format!("{}?key={}", API_URL, api_key);
-
-    for i in 0..3 {
-        match client.post(&url).json(&payload).send().await {
-            Ok(response) => {
-                if response.status().is_success() {
-                    let json_response: serde_json::Value = response.json().await?;
-                    if let Some(text) = json_response["candidates"][0]["content"]["parts"][0]["text"].as_str() {
-                        return Ok(text.to_string());
-                    } else {
-                        return Err("API response format error: missing text content.".into());
-                    }
-                } else {
-                    let status = response.status();
-                    let error_body = response.text().await.unwrap_or_else(|_| "No body".to_string());
-                    eprintln!("API Error (Attempt {}): Status: {}, Body: {}", i + 1, status, error_body);
-                    if i == 2 {
-                        return Err(format!("Gemini API failed after 3 attempts. Status: {}", status).into());
-                    }
-                }
+impl Task {
+    /// タスクのタイムゾーンでの時刻
+    pub fn local_time(&self) -> DateTime<Tz> {
+        self.datetime.with_timezone(&self.timezone)
+    }
+
+    /// 一覧や通知に出す時刻。"2025-12-01:08:00 JST"
+    pub fn display_time(&self) -> String {
+        let time = self.local_time();
+        format!("{} {}", time.format(TASK_DATETIME_FORMAT), time.format("%Z"))
+    }
+
+    /// 繰り返すタスクの、`after` より後の次の回。
+    /// タイムゾーンの壁時計で進めるので、夏時間が変わっても「毎朝8時」は8時のまま
+    fn next_occurrence(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let rule = self.recurrence.as_ref()?;
+        let current = self.local_time().naive_local();
+        let next = rule.next_after(current, after.with_timezone(&self.timezone).naive_local())?;
+        resolve_local(self.timezone, next)
+    }
+
+    /// `now` から予定の時刻まで何分か (分単位に丸める)。過ぎていれば 0
+    pub fn minutes_left(&self, now: DateTime<Utc>) -> u32 {
+        let seconds = (self.datetime - now).num_seconds().max(0);
+        u32::try_from((seconds + 30) / 60).unwrap_or(u32::MAX)
+    }
+
+    /// 一覧に出す短いID
+    pub fn short_id(&self) -> String {
+        self.id.simple().to_string()[..8].to_string()
+    }
+
+    /// IDの先頭 (ハイフンは有っても無くてもよい) か、名前 (大文字小文字は区別しない) が `query` に当てはまる
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.trim();
+        let id = query.replace('-', "").to_ascii_lowercase();
+        (id.len() >= 4 && self.id.simple().to_string().starts_with(&id)) || self.name.eq_ignore_ascii_case(query)
+    }
+
+    /// 1回だけのタスクで、最後まで通知が済んだ (`task done` で終わらせたものも)
+    pub fn is_done(&self, now: DateTime<Utc>) -> bool {
+        self.recurrence.is_none()
+            && self.alert.is_none()
+            && (self.state == TaskState::Done || (self.state.is_final() && self.datetime <= now))
+    }
+
+    /// 通知の段階 (何分前か)。大きい順
+    pub fn stages(&self, default: &[u32]) -> Vec<u32> {
+        let mut stages = if self.reminders.is_empty() { default.to_vec() } else { self.reminders.clone() };
+        stages.sort_unstable_by(|a, b| b.cmp(a));
+        stages.dedup();
+        stages
+    }
+
+    /// `now` に出す段階を選んで fired に入れる。
+    /// いくつも溜まっていれば一番新しい段階だけを出し、古いものと猶予を過ぎたものは飛ばす
+    pub fn take_due_stage(&mut self, now: DateTime<Utc>, default: &[u32], grace: Duration) -> Option<ReminderStage> {
+        let due = self.datetime;
+        let pending: Vec<u32> = self
+            .stages(default)
+            .into_iter()
+            .filter(|stage| !self.fired.contains(stage))
+            .filter(|stage| due - Duration::minutes(i64::from(*stage)) <= now)
+            .collect();
+        // 大きい順なので最後が一番新しい
+        let (&latest, older) = pending.split_last()?;
+        for stage in older {
+            println!("Skipped reminder '{}' {} min before {}", self.name, stage, self.display_time());
+        }
+        self.fired.extend(pending.iter().copied());
+
+        let late = now - (due - Duration::minutes(i64::from(latest)));
+        if late > grace {
+            println!("Skipped reminder '{}' {} min before {} (too late)", self.name, latest, self.display_time());
+            return None;
+        }
+        Some(ReminderStage {
+            minutes_before: latest,
+            late: late >= Duration::minutes(LATE_THRESHOLD_MINS),
+            repeat: 0,
+        })
+    }
+
+    /// 鳴っている通知の期限が来ていれば、鳴らし直す・諦める
+    fn check_alert(&mut self, now: DateTime<Utc>, ack_timeout: Duration, max_repeats: u32) -> Option<Notice> {
+        let alert = self.alert.as_mut()?;
+        if alert.next_at > now {
+            return None;
+        }
+        let kind = if alert.snoozed {
+            alert.snoozed = false;
+            NoticeKind::Ring
+        } else if alert.stage.repeat < max_repeats {
+            alert.stage.repeat += 1;
+            NoticeKind::Escalate
+        } else {
+            NoticeKind::GiveUp
+        };
+        alert.next_at = now + ack_timeout;
+        let stage = alert.stage;
+        if kind == NoticeKind::GiveUp {
+            println!("No response to reminder '{}' after {} repeats", self.name, stage.repeat);
+            self.alert = None;
+            self.state = TaskState::Missed;
+        }
+        Some(Notice { task: self.clone(), stage, kind })
+    }
+
+    /// この回の段階が全部済み、鳴っている通知も無ければ次の回へ進める。変えたら true
+    pub fn finish_occurrence(&mut self, now: DateTime<Utc>, default: &[u32]) -> bool {
+        let due = self.datetime;
+        let stages = self.stages(default);
+        if self.alert.is_some() || !stages.iter().all(|stage| self.fired.contains(stage)) {
+            return false;
+        }
+        match self.next_occurrence(due.max(now)) {
+            Some(next) => {
+                self.datetime = next;
+                self.state = TaskState::Pending;
+                // 前の回の時刻より前の段階は前の回と重なるので出さない (間隔が通知より短い繰り返し)
+                self.fired = stages
+                    .into_iter()
+                    .filter(|stage| next - Duration::minutes(i64::from(*stage)) <= due)
+                    .collect();
+                true
             }
-            Err(e) => {
-                eprintln!("Network Error (Attempt {}): {}", i + 1, e);
-                if i == 2 {
-                    return Err(e.into());
-                }
+            // 全部飛ばした (猶予切れ) 1回だけのタスク
+            None if !self.state.is_final() => {
+                self.state = TaskState::Missed;
+                true
             }
+            None => false,
         }
-        sleep(tokio::time::Duration::from_secs(1 << i)).await;
     }
-    
-    Err("Failed to get response from Gemini API after all retries.".into())
+
+    /// スケジューラーが次に起きる日時 (まだの通知の段階か、鳴っている通知の期限)
+    pub fn next_event(&self, now: DateTime<Utc>, default: &[u32]) -> Option<DateTime<Utc>> {
+        if self.is_done(now) {
+            return None;
+        }
+        let due = self.datetime;
+        let stages = self
+            .stages(default)
+            .into_iter()
+            .filter(|stage| !self.fired.contains(stage))
+            .map(|stage| due - Duration::minutes(i64::from(stage)));
+        stages.chain(self.alert.as_ref().map(|alert| alert.next_at)).min()
+    }
+
+    /// 鳴っている通知に応答する。鳴っていなければ false
+    pub fn respond(&mut self, action: AckAction, now: DateTime<Utc>) -> bool {
+        let Some(alert) = self.alert.as_mut() else { return false };
+        match action {
+            AckAction::Ack => {
+                self.alert = None;
+                self.state = TaskState::Acknowledged;
+            }
+            AckAction::Snooze(minutes) => {
+                *alert = Alert { snoozed: true, ..Alert::new(alert.stage, now + Duration::minutes(i64::from(minutes))) };
+            }
+        }
+        true
+    }
+
+    /// この回を終わらせる。繰り返すタスクは残りの通知を飛ばして次の回へ、1回だけなら Done
+    pub fn complete(&mut self, now: DateTime<Utc>) {
+        self.alert = None;
+        match self.next_occurrence(self.datetime.max(now)) {
+            Some(next) => {
+                self.datetime = next;
+                self.state = TaskState::Pending;
+                self.fired.clear();
+            }
+            None => self.state = TaskState::Done,
+        }
+    }
+
+    /// 日時や通知の段階を変えたら、この回の進み具合をやり直す
+    fn reset_occurrence(&mut self) {
+        self.state = TaskState::Pending;
+        self.fired.clear();
+        self.alert = None;
+    }
+
+    /// `task import` で読み直したタスクで置き換える。ID・UID とこの回の進み具合は残し、
+    /// 時刻・繰り返し・通知が変わっていればこの回をやり直す。変わったら true
+    pub fn update_from(&mut self, imported: Task) -> bool {
+        let rescheduled = self.datetime != imported.datetime
+            || self.timezone != imported.timezone
+            || self.recurrence != imported.recurrence
+            || self.reminders != imported.reminders;
+        let changed = rescheduled || self.name != imported.name || self.tags != imported.tags;
+        let finished = imported.state.is_final() && imported.state != self.state;
+        if rescheduled {
+            self.reset_occurrence();
+        }
+        if finished {
+            self.alert = None;
+            self.state = imported.state;
+        }
+        self.datetime = imported.datetime;
+        self.timezone = imported.timezone;
+        self.recurrence = imported.recurrence;
+        self.reminders = imported.reminders;
+        self.name = imported.name;
+        self.tags = imported.tags;
+        changed || finished
+    }
+
+    /// at= は `now` からの相対 (in 20m など) もタスクのタイムゾーンで読む
+    pub fn apply(&mut self, edit: &TaskEdit, now: DateTime<Utc>) -> Result<(), String> {
+        if let Some(name) = &edit.name {
+            if name.trim().is_empty() {
+                return Err("Task name must not be empty".to_string());
+            }
+            self.name = name.trim().to_string();
+        }
+        if let Some(zone) = edit.timezone {
+            // 時刻は変えずに新しいタイムゾーンの同じ壁時計の時刻にする (8:00 は 8:00 のまま)
+            let local = self.local_time().naive_local();
+            self.datetime = resolve_local(zone, local).ok_or_else(|| format!("{} has no time {}", zone, local))?;
+            self.timezone = zone;
+            self.reset_occurrence();
+        }
+        if let Some(datetime) = &edit.datetime {
+            let when = parse_at(datetime, now, self.timezone)?;
+            self.datetime = when.at;
+            if when.recurrence.is_some() && edit.recurrence.is_none() {
+                self.recurrence = when.recurrence;
+            }
+            self.reset_occurrence();
+        }
+        if let Some(recurrence) = &edit.recurrence {
+            self.recurrence = recurrence.clone();
+        }
+        if let Some(reminders) = &edit.reminders {
+            self.reminders = reminders.clone();
+            self.reset_occurrence();
+        }
+        if let Some(tags) = &edit.tags {
+            self.tags = tags.clone();
+        }
+        Ok(())
+    }
 }
 
+/// `task edit` で変えるところ。None は変えない
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskEdit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// 書いたままの日時 (IOT::when)。in 20m などは受けたノードの今からになる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datetime: Option<String>,
+    /// 同じ壁時計の時刻のまま、このタイムゾーンに移す
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<Tz>,
+    /// Some(None) で繰り返しをやめる
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recurrence: Option<Option<Recurrence>>,
+    /// 空なら scheduler.reminders_mins に戻す
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reminders: Option<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+}
 
-// Geminiからの応答とパラメータを結合し、lyric.txtに保存する
-pub async fn generate_and_save_lyric(task_name: &str, task_time_str: &str) -> Result<(), Box<dyn Error>> {
-    
-    // 1. Task Doc (Gemini呼び出し)
+impl TaskEdit {
+    /// `name=薬を飲む; at=tomorrow 09:00; repeat=daily; remind=30,0; tags=care; tz=Asia/Tokyo`。
+    /// repeat=none で繰り返しをやめ、remind=default で設定の通知に戻す
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut edit = TaskEdit::default();
+        for field in args.split(';').map(str::trim).filter(|f| !f.is_empty()) {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected field=value, got '{}'", field))?;
+            let value = value.trim();
+            match key.trim() {
+                "name" => edit.name = Some(value.to_string()),
+                "at" | "datetime" => edit.datetime = Some(value.to_string()),
+                "repeat" => edit.recurrence = Some(match value {
+                    "none" | "" => None,
+                    rule => Some(rule.parse::<Recurrence>()?),
+                }),
+                "remind" => edit.reminders = Some(match value {
+                    "default" | "" => Vec::new(),
+                    offsets => parse_reminders(offsets)?,
+                }),
+                "tags" | "tag" => edit.tags = Some(parse_tags(value)),
+                "tz" | "timezone" => edit.timezone = Some(parse_zone(value)?),
+                other => return Err(format!("Unknown field '{}'. Use name, at, repeat, remind, tags or tz", other)),
+            }
+        }
+        if edit.name.is_none()
+            && edit.datetime.is_none()
+            && edit.timezone.is_none()
+            && edit.recurrence.is_none()
+            && edit.reminders.is_none()
+            && edit.tags.is_none()
+        {
+            return Err("Usage: task edit <id> name=...; at=YYYY-MM-DD:HH:MM|in 20m|tomorrow 8am; repeat=daily|none; remind=30,5,0|default; tags=a,b; tz=Asia/Tokyo".to_string());
+        }
+        Ok(edit)
+    }
+}
+
+/// `task list` の絞り込み。None は絞らない
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaskFilter {
+    /// この日から (次に通知する日時をタスクのタイムゾーンの日付で比べる)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<NaiveDate>,
+    /// この日まで (この日を含む)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<TaskState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl TaskFilter {
+    /// `from=2025-12-01 to=2025-12-31 state=pending tag=care`
+    pub fn parse(args: &str) -> Result<Self, String> {
+        let mut filter = TaskFilter::default();
+        let date = |value: &str| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| format!("Invalid date '{}'. Use YYYY-MM-DD", value))
+        };
+        for field in args.split_whitespace() {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected field=value, got '{}'", field))?;
+            match key {
+                "from" => filter.from = Some(date(value)?),
+                "to" => filter.to = Some(date(value)?),
+                "state" => filter.state = Some(value.parse()?),
+                "tag" => filter.tag = Some(value.to_string()),
+                other => return Err(format!("Unknown filter '{}'. Use from, to, state or tag", other)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, task: &Task) -> bool {
+        let date = task.local_time().date_naive();
+        self.from.is_none_or(|from| date >= from)
+            && self.to.is_none_or(|to| date <= to)
+            && self.state.is_none_or(|state| task.state == state)
+            && self.tag.as_ref().is_none_or(|tag| task.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+}
+
+/// 音声の返事を応答にする。「はい」「わかった」は ack、「あとで」「10分まって」は snooze。
+/// 言葉は区切り (空白・句読点) ごとに丸ごと比べる。「いらない」「not ok」のように打ち消していれば ack にしない
+pub fn ack_from_speech(text: &str, snooze_mins: u32) -> Option<AckAction> {
+    const SNOOZE_WORDS: [&str; 4] = ["あとで", "後で", "まって", "待って"];
+    const SNOOZE_WORDS_EN: [&str; 3] = ["snooze", "later", "wait"];
+    const ACK_WORDS: [&str; 10] =
+        ["はい", "わかった", "分かった", "わかりました", "分かりました", "了解", "りょうかい", "おっけー", "オッケー", "承知"];
+    // 「了解です」「わかったよ」のように後ろに付いてもよいもの
+    const ACK_ENDINGS: [&str; 7] = ["", "です", "しました", "よ", "ね", "はい", "だよ"];
+    const ACK_WORDS_EN: [&str; 6] = ["ok", "okay", "yes", "yeah", "done", "sure"];
+    const NEGATIONS: [&str; 6] = ["ない", "ません", "だめ", "ダメ", "いや", "いいえ"];
+    const NEGATIONS_EN: [&str; 7] = ["no", "not", "nope", "never", "don't", "dont", "cancel"];
+
+    let text = text.trim().to_lowercase();
+    let tokens: Vec<&str> = text
+        .split(|c: char| !(c.is_alphanumeric() || c == '\'' || c == 'ー'))
+        .filter(|token| !token.is_empty())
+        .collect();
+    if tokens.iter().any(|t| SNOOZE_WORDS_EN.contains(t) || SNOOZE_WORDS.iter().any(|w| t.contains(w))) {
+        let digits: String = text.chars().skip_while(|c| !c.is_ascii_digit()).take_while(char::is_ascii_digit).collect();
+        return Some(AckAction::Snooze(digits.parse().ok().filter(|m| *m > 0).unwrap_or(snooze_mins)));
+    }
+    let negated = tokens.iter().any(|t| NEGATIONS_EN.contains(t) || NEGATIONS.iter().any(|w| t.contains(w)));
+    let ack = tokens.iter().any(|t| {
+        ACK_WORDS_EN.contains(t)
+            || ACK_WORDS.iter().any(|w| t.strip_prefix(w).is_some_and(|rest| ACK_ENDINGS.contains(&rest)))
+    });
+    (ack && !negated).then_some(AckAction::Ack)
+}
+
+// LLM (llm.backend) の応答とパラメータを結合し、lyric_file (lyric.txt) に保存する。
+// LLM が使えない (offline・失敗した) ときは paths.speech_templates の文章 (IOT::templates)
+pub async fn generate_and_save_lyric(
+    config: &OsaiConfig,
+    task: &Task,
+    stage: ReminderStage,
+) -> Result<(), Box<dyn Error>> {
+    let llm = &config.llm;
+    let task_name = &task.name;
+    let task_time_str = task.local_time().format("%H時%M分").to_string();
+
+    // 1. Task Doc (LLM呼び出し)。あと何分か (遅れて出すと段階より短い)、遅れた通知かで言い方を変える
+    let now = Utc::now();
+    let timing = match task.minutes_left(now) {
+        0 => "今がその時間であること".to_string(),
+        m => format!("実行{}分前であること", m),
+    };
+    let missed = if stage.late { "通知が遅れてしまったことを謝り、" } else { "" };
+    let repeat = if stage.repeat > 0 { "返事が無かったのでもう一度、" } else { "" };
     let user_query = format!(
-        "タスク: {} が{}にあります。このタスクの内容を要約し、実行5分前であることを含めて、私に親切に教えてください。絵文字は使わない", 
+        "タスク: {} が{}にあります。このタスクの内容を要約し、{}{}{}を含めて、私に親切に教えてください。絵文字は使わない", 
         task_name, 
-        task_time_str
+        task_time_str,
+        repeat,
+        missed,
+        timing
     );
 
-    let gemini_text = match gemini_call(&user_query).await {
-        Ok(text) => text,
-        Err(e) => {
-            eprintln!("Gemini Task Error (using fallback): {}", e);
-            format!("{}に{}があります。5分前です。起きるです。", task_time_str, task_name)
-        }
+    let llm_text = match llm.backend {
+        LlmBackendKind::Offline => SpeechTemplates::load(&config.paths.speech_templates).render(task, stage, now),
+        _ => match llm::ask(llm, &LlmRequest::new(llm, &user_query)).await {
+            Ok(text) => text,
+            Err(e) => {
+                eprintln!("LLM Task Error (using {}): {}", config.paths.speech_templates.display(), e);
+                SpeechTemplates::load(&config.paths.speech_templates).render(task, stage, now)
+            }
+        },
     };
     
     // 2. Write Task (パラメータの追加とファイルへの書き込み)
     
     // Vocaloidエンジンが単語とパラメータを認識できるフォーマット
-    let formatted_text = format!("{},{}", gemini_text, EMOTION_PARAMS);
+    let formatted_text = format!("{},{}", llm_text, EMOTION_PARAMS);
     
     // lyric.txt に書き込み
-    let file_io = FileIO::new(LYRIC_FILE);
+    let file_io = FileIO::new(&config.paths.lyric_file.to_string_lossy());
     file_io.write_text(&formatted_text)?;
     
     Ok(())
@@ -113,69 +660,129 @@ pub async fn generate_and_save_lyric(task_name: &str, task_time_str: &str) -> Re
 
 // --- Task File Management ---
 
-pub fn load_tasks() -> Vec<Task> {
-    if Path::new(TASK_FILE).exists() {
-        match fs::read_to_string(TASK_FILE) {
-            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|_| {
-                eprintln!("Warning: Failed to parse tasks, starting with empty list.");
-                Vec::new()
-            }),
-            Err(e) => {
-                eprintln!("Warning: Failed to read task file: {}", e);
-                Vec::new()
-            }
-        }
-    } else {
-        Vec::new()
-    }
+/// 読めない・壊れているときはエラーにする (空のリストにして上書きしないように)。ファイルが無ければ空
+pub fn load_tasks(path: &Path) -> Result<Vec<Task>, String> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read task file {}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&data).map_err(|e| {
+        format!(
+            "Failed to parse task file {}: {}. The file was left unchanged; fix it or restore {}",
+            path.display(),
+            e,
+            backup_path(path).display()
+        )
+    })
 }
 
-pub fn save_tasks(tasks: &[Task]) -> Result<(), Box<dyn Error>> {
+/// 一時ファイルに書いて fsync してから置き換える。途中で落ちても前のファイルか新しいファイルのどちらかが残る。
+/// 置き換える前のファイルは `<task_file>.bak` に残す
+pub fn save_tasks(path: &Path, tasks: &[Task]) -> Result<(), Box<dyn Error>> {
     let data = serde_json::to_string_pretty(tasks)?;
-    fs::write(TASK_FILE, data)?;
+    let tmp = sibling_path(path, ".tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        io::Write::write_all(&mut file, data.as_bytes())?;
+        file.sync_all()?;
+    }
+    if path.exists() {
+        fs::copy(path, backup_path(path))?;
+    }
+    fs::rename(&tmp, path)?;
+    // rename 自体を残すためにディレクトリも fsync する (Windows では開けないので飛ばす)
+    #[cfg(unix)]
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
     Ok(())
 }
 
-pub fn add_new_task(args: &str) -> Result<Task, Box<dyn Error>> {
-    // 入力全体を最大3つの部分に分割します: [日付, 時刻(HH:MM), タスク名]
-    // 注: chronoのパースのために、日付と時刻を結合して試行する
-    let parts: Vec<&str> = args.split(':').collect();
-
-    // 最低限 "YYYY-MM-DD", "HH", "MM", "NAME" が必要なので、partsの要素は4つ以上。
-    // 例: "2025-11-19:08:00:起床" -> ["2025-11-19", "08", "00", "起床"]
-    if parts.len() < 4 {
-        return Err("Invalid argument format. Use YYYY-MM-DD:HH:MM:Task Name.".into());
-    }
+pub fn backup_path(path: &Path) -> std::path::PathBuf {
+    sibling_path(path, ".bak")
+}
 
-    let date_str = parts[0];
-    let hour_str = parts[1];
-    let minute_str = parts[2];
+/// `scheduled_tasks.json` -> `scheduled_tasks.json.bak`
+fn sibling_path(path: &Path, suffix: &str) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
 
-    // 4番目以降の要素を全て結合してタスク名とする (タスク名にコロンが含まれてもOKにする)
-    let name_parts = &parts[3..];
-    let name = name_parts.join(":").trim().to_string();
+/// `<日時>:<name>` (日時の書き方は IOT::when。`in 20m 薬を飲む`、`毎朝7時に薬を飲む` なども) のあとに
+/// " @" で区切ってオプションを付けられる。
+/// `@ daily` (繰り返し、ルールは IOT::recurrence)、`@ remind 30,5,0` (何分前に通知するか)、`@ tags care,medicine`、
+/// `@ tz Europe/Berlin` (時刻を読むタイムゾーン。無ければ `zone`)
+pub fn add_new_task(args: &str, zone: Tz) -> Result<Task, Box<dyn Error>> {
+    // 名前の中の "bob@example.com" などは区切りにしない
+    let mut options = args.split(" @");
+    let args = options.next().unwrap_or("");
+    let mut recurrence = None;
+    let mut reminders = Vec::new();
+    let mut tags = Vec::new();
+    let mut timezone = zone;
+    for option in options {
+        let option = option.trim();
+        if let Some(offsets) = option.strip_prefix("remind") {
+            reminders = parse_reminders(offsets)?;
+        } else if let Some(list) = option.strip_prefix("tags").or_else(|| option.strip_prefix("tag")) {
+            tags = parse_tags(list);
+        } else if let Some(name) = option.strip_prefix("tz ").or_else(|| option.strip_prefix("timezone ")) {
+            timezone = parse_zone(name)?;
+        } else {
+            recurrence = Some(option.parse::<Recurrence>()?);
+        }
+    }
 
-    // 日付と時刻を結合して、パースできる形にする (秒は00として補完)
-    let datetime_str_to_parse = format!("{}:{}:{}:00", date_str, hour_str, minute_str);
+    let (when, name) = parse_when(args, Utc::now(), timezone)?;
+    if name.is_empty() {
+        return Err(format!("Missing task name after the date/time in '{}'", args.trim()).into());
+    }
+    Ok(Task {
+        id: Uuid::new_v4(),
+        datetime: when.at,
+        timezone,
+        name: name.to_string(),
+        state: TaskState::Pending,
+        // `@ daily` があればそちら
+        recurrence: recurrence.or(when.recurrence),
+        reminders,
+        fired: Vec::new(),
+        alert: None,
+        tags,
+        ical_uid: None,
+    })
+}
 
-    // YYYY-MM-DD:HH:MM:SS の形式でパースを試みる
-    let format = "%Y-%m-%d:%H:%M:%S";
+/// IANA のタイムゾーン名 ("Asia/Tokyo"、"UTC")
+fn parse_zone(name: &str) -> Result<Tz, String> {
+    name.trim()
+        .parse()
+        .map_err(|_| format!("Unknown time zone '{}'. Use an IANA name such as Asia/Tokyo or Europe/Berlin", name.trim()))
+}
 
-    match NaiveDateTime::parse_from_str(&datetime_str_to_parse, format) {
-        Ok(_) => {
-            // パースが成功したら、Taskを構築 (datetimeは YYYY-MM-DD:HH:MM 形式で保存)
-            let full_datetime_str = format!("{}:{}:{}", date_str, hour_str, minute_str);
-            Ok(Task {
-                datetime: full_datetime_str,
-                name,
-                notified: false, // <-- 修正: 初期値として false を設定
-            })
-        }
-        Err(_) => {
-            // パース失敗時、具体的なエラーメッセージを返す
-            Err("Invalid date/time format. Ensure date is YYYY-MM-DD and time is HH:MM.".into())
-        }
+/// "30,5,0" (分前)
+fn parse_reminders(offsets: &str) -> Result<Vec<u32>, String> {
+    let reminders = offsets
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(|o| o.trim_end_matches("min").trim().parse::<u32>().map_err(|_| format!("Invalid reminder offset '{}'", o)))
+        .collect::<Result<Vec<u32>, String>>()?;
+    if reminders.is_empty() {
+        return Err("Usage: @ remind 30,5,0 (minutes before)".to_string());
     }
+    Ok(reminders)
+}
+
+/// "care, medicine" (カンマか空白区切り)
+fn parse_tags(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|t| t.trim_start_matches('#'))
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 pub fn display_tasks(tasks: Vec<Task>) -> String {
@@ -184,8 +791,24 @@ pub fn display_tasks(tasks: Vec<Task>) -> String {
     }
     let mut output = String::from("--- Scheduled Tasks ---\n");
     for (i, task) in tasks.iter().enumerate() {
-        let status = if task.notified { "[DONE]" } else { "[PENDING]" };
-        output.push_str(&format!("{}. {} | {} {}\n", i + 1, task.datetime, task.name, status));
+        let reminders = if task.reminders.is_empty() {
+            String::new()
+        } else {
+            let offsets: Vec<String> = task.stages(&[]).iter().map(|m| m.to_string()).collect();
+            format!(" (remind {} min before)", offsets.join(","))
+        };
+        let tags: String = task.tags.iter().map(|t| format!(" #{}", t)).collect();
+        match &task.recurrence {
+            // 繰り返すタスクの datetime は次に通知する日時
+            Some(rule) => output.push_str(&format!(
+                "{}. {} next {} | {} [REPEAT {}] [{}]{}{}\n",
+                i + 1, task.short_id(), task.display_time(), task.name, rule, task.state, reminders, tags
+            )),
+            None => output.push_str(&format!(
+                "{}. {} {} | {} [{}]{}{}\n",
+                i + 1, task.short_id(), task.display_time(), task.name, task.state, reminders, tags
+            )),
+        }
     }
     output.push_str("-----------------------");
     output
@@ -194,67 +817,353 @@ pub fn display_tasks(tasks: Vec<Task>) -> String {
 
 // --- Task Scheduler Core ---
 
-pub async fn run_task_scheduler(osai: OSAI, mut initial_tasks: Vec<Task>) {
-    
-    let mut tasks = initial_tasks;
-
-    // 定数を再利用
-    const NOTIFICATION_WINDOW: Duration = Duration::minutes(5); 
+/// `shutdown` がキャンセルされるまで回す。通知の途中では止めず、その回の保存が終わってから抜ける。
+/// 次の通知の時刻まで眠り、TaskStore が変わったら (CLI やリモートで足された、ack が来た) 起きて計算し直す
+pub async fn run_task_scheduler(osai: OSAI, shutdown: CancellationToken) {
+    let config = std::sync::Arc::clone(osai.config());
+    let store = TaskStore::new(&config.paths.task_file);
+    let mut changes = TaskStore::subscribe();
+    // 返事を聞くマイクは1つなので、聞くタスクは順番にする
+    let listening = std::sync::Arc::new(tokio::sync::Mutex::new(()));
 
     loop {
-        // 5秒ごとにチェック
-        sleep(tokio::time::Duration::from_secs(5)).await;
-
-        let now = Local::now().naive_local();
-        let mut needs_save = false;
-        
-        // ファイルから最新のタスクをロードし直す
-        tasks = load_tasks();
-
-        for task in tasks.iter_mut() {
-            if task.notified {
-                continue;
-            }
+        let now = Utc::now();
+        changes.mark_unchanged();
+
+        // 状態はロックを持ったまま一度に更新して保存し、鳴らすのはその後。
+        // 通知の途中で止まっても同じ段階を繰り返さず、鳴らしている間に来た ack も消さない
+        let pass = store.modify(|tasks| due_notices(tasks, now, &config.scheduler));
+        let (notices, next) = pass.unwrap_or_else(|e| {
+            // 壊れたファイルは上書きしない。直されるまで poll_interval ごとに試す
+            eprintln!("Task scheduler: {}", e);
+            (Vec::new(), None)
+        });
+
+        for notice in notices {
+            deliver_notice(&osai, notice, &listening).await;
+        }
+
+        // 次の通知まで眠る。時計の変更や手で編集されたファイルのため poll_interval_secs より長くは眠らない
+        let max_wait = config.scheduler.poll_interval();
+        let wait = next
+            .map(|next| (next - Utc::now()).to_std().unwrap_or_default())
+            .map_or(max_wait, |wait| wait.min(max_wait));
+        tokio::select! {
+            _ = sleep(wait) => {}
+            _ = changes.changed() => {}
+            _ = shutdown.cancelled() => break,
+        }
+    }
+    // 変更は毎回保存済み。ここで書き直すと CLI が後から足したタスクを消してしまうので何もしない
+    println!("Task scheduler stopped");
+}
+
+/// スケジューラーの1回分。`now` に鳴らす・知らせるものと次に起きる日時を返し、
+/// タスクの状態 (fired・alert・次の回) を進める。((通知, 次に起きる日時), 変えたか)
+fn due_notices(
+    tasks: &mut [Task],
+    now: DateTime<Utc>,
+    scheduler: &SchedulerConfig,
+) -> ((Vec<Notice>, Option<DateTime<Utc>>), bool) {
+    let default_reminders = scheduler.default_reminders();
+    let ack_timeout = scheduler.ack_timeout();
+    let mut notices = Vec::new();
+    let mut changed = false;
+    for task in tasks.iter_mut() {
+        if task.is_done(now) {
+            continue;
+        }
+        if let Some(notice) = task.check_alert(now, ack_timeout, scheduler.max_repeats) {
+            notices.push(notice);
+            changed = true;
+        }
 
-            // `NaiveDateTime::parse_from_str` のフォーマットを修正 (":"で結合された形式に対応)
-            if let Ok(task_dt) = NaiveDateTime::parse_from_str(&task.datetime, "%Y-%m-%d:%H:%M") { 
-                
-                let five_min_before = task_dt - NOTIFICATION_WINDOW;
-                
-                // 5分前ウィンドウに入り、まだ通知されていないかチェック
-                if now >= five_min_before && now < task_dt && !task.notified {
-                    
-                    let task_time_str = task_dt.format("%H時%M分").to_string();
-
-                    // 1. Geminiからの応答を生成し、lyric.txtに保存
-                    let generate_result = generate_and_save_lyric(&task.name, &task_time_str).await;
-
-                    // 2. lyric.txt を読み込んで音声合成を実行
-                    let vocaloid_result = match generate_result {
-                        Ok(_) => osai.emotion_vocaloid(), // OSAIメソッドを使用
-                        Err(e) => {
-                            eprintln!("Task Preparation Error (Gemini/File Write): {}", e);
-                            continue;
-                        }
-                    };
-                    
-                    // 3. Aplay.sh コマンドを実行（音声再生）
-                    if vocaloid_result.is_ok() {
-                        osai.cmd("sh Aplay.sh");
-                        println!("\n[TASK ALERT: 5 Min] -> Task written to lyric.txt and Vocaloid triggered.");
-                    } else if let Err(e) = vocaloid_result {
-                        eprintln!("\n[TASK ALERT ERROR] Vocaloid failed to run: {}", e);
-                    }
-                    
-                    task.notified = true; 
-                    needs_save = true;
-                }
+        let fired = task.fired.len();
+        if let Some(stage) = task.take_due_stage(now, &default_reminders, scheduler.grace()) {
+            if task.alert.is_some() {
+                println!("Reminder '{}' was not acknowledged before the next one", task.name);
             }
+            task.alert = Some(Alert::new(stage, now + ack_timeout));
+            task.state = TaskState::Fired;
+            notices.push(Notice { task: task.clone(), stage, kind: NoticeKind::Ring });
         }
-        
-        // 状態が変更されたら保存
-        if needs_save {
-            let _ = save_tasks(&tasks).map_err(|e| eprintln!("Error saving tasks: {}", e));
+        changed |= task.fired.len() != fired;
+
+        // 全段階が済んで ack も済んだら次の回へ
+        changed |= task.finish_occurrence(now, &default_reminders);
+    }
+    let next = tasks.iter().filter_map(|task| task.next_event(now, &default_reminders)).min();
+    ((notices, next), changed)
+}
+
+/// 鳴らす・caregiver に知らせる。返事は別のタスクで聞くので、次の通知を待たせない
+async fn deliver_notice(osai: &OSAI, notice: Notice, listening: &std::sync::Arc<tokio::sync::Mutex<()>>) {
+    let config = osai.config();
+    if notice.kind != NoticeKind::GiveUp {
+        ring(osai, &notice).await;
+    }
+    if notice.kind != NoticeKind::Ring {
+        alert_caregiver(osai, &notice).await;
+    }
+    if notice.kind != NoticeKind::GiveUp && config.scheduler.listen_for_ack {
+        let osai = osai.clone();
+        let listening = std::sync::Arc::clone(listening);
+        tokio::spawn(async move {
+            let _turn = listening.lock().await;
+            listen_for_ack(&osai, &notice).await;
+        });
+    }
+}
+
+async fn ring(osai: &OSAI, notice: &Notice) {
+    let config = osai.config();
+    let stage = notice.stage;
+
+    // 1. LLMからの応答を生成し、lyric.txtに保存
+    let generate_result = generate_and_save_lyric(config, &notice.task, stage).await;
+
+    // 2. lyric.txt を読み込んで音声合成を実行
+    let vocaloid_result = match generate_result {
+        Ok(_) => osai.emotion_vocaloid(), // OSAIメソッドを使用
+        Err(e) => {
+            eprintln!("Task Preparation Error (LLM/File Write): {}", e);
+            return;
         }
+    };
+    
+    // 3. Aplay.sh コマンドを実行（音声再生）
+    match vocaloid_result {
+        Ok(()) => {
+            osai.cmd("sh Aplay.sh");
+            println!(
+                "\n[TASK ALERT: {} Min{}{}] -> Task written to {} and Vocaloid triggered.",
+                stage.minutes_before,
+                if stage.late { ", LATE" } else { "" },
+                if stage.repeat > 0 { format!(", REPEAT {}", stage.repeat) } else { String::new() },
+                config.paths.lyric_file.display()
+            );
+        }
+        Err(e) => eprintln!("\n[TASK ALERT ERROR] Vocaloid failed to run: {}", e),
+    }
+}
+
+/// scheduler.caregiver に TASK_ALERT を送る
+async fn alert_caregiver(osai: &OSAI, notice: &Notice) {
+    let config = osai.config();
+    let Some(caregiver) = &config.scheduler.caregiver else { return };
+    let alert = TaskAlert {
+        node: config.node_name(),
+        task: notice.task.name.clone(),
+        datetime: notice.task.display_time(),
+        minutes_before: notice.stage.minutes_before,
+        repeats: notice.stage.repeat,
+        kind: match notice.kind {
+            NoticeKind::GiveUp => TaskAlertKind::Missed,
+            _ => TaskAlertKind::Unanswered,
+        },
+    };
+    let sent = match osai.resolve_peer(caregiver) {
+        Ok(dst) => send_task_alert(&config.client, dst, &alert).await.map(|_| dst),
+        Err(e) => Err(e),
+    };
+    match sent {
+        Ok(dst) => println!("Caregiver {} ({}) alerted: '{}' {:?}", caregiver, dst, notice.task.name, alert.kind),
+        Err(e) => eprintln!("Could not alert caregiver {}: {}", caregiver, e),
+    }
+}
+
+/// OSAI::listen で返事を聞き、分かれば ack・snooze する
+async fn listen_for_ack(osai: &OSAI, notice: &Notice) {
+    let config = osai.config();
+    let listener = osai.clone();
+    let text = match tokio::task::spawn_blocking(move || listener.listen()).await {
+        Ok(Ok(text)) => text,
+        Ok(Err(e)) => return eprintln!("Listening for acknowledgement failed: {}", e),
+        Err(e) => return eprintln!("Listening for acknowledgement failed: {}", e),
+    };
+    let Some(action) = ack_from_speech(&text, config.scheduler.snooze_mins) else {
+        return println!("Heard '{}' but it was not an acknowledgement", text);
+    };
+    match TaskStore::new(&config.paths.task_file).respond(&notice.task.id.to_string(), action) {
+        Ok(_) => println!("Reminder '{}': {} by voice", notice.task.name, action),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Asia::Tokyo;
+
+    fn task(args: &str) -> Task {
+        add_new_task(args, Tokyo).unwrap()
+    }
+
+    fn scheduler(reminders_mins: Vec<u32>) -> SchedulerConfig {
+        SchedulerConfig { reminders_mins, ..SchedulerConfig::default() }
+    }
+
+    #[test]
+    fn reminders_due_together_are_all_delivered() {
+        let mut tasks = vec![task("2030-01-01:08:00:薬を飲む"), task("2030-01-01:08:00:散歩")];
+        let due = tasks[0].datetime;
+        let config = scheduler(vec![0]);
+
+        let ((notices, next), changed) = due_notices(&mut tasks, due, &config);
+        assert!(changed);
+        let names: Vec<&str> = notices.iter().map(|n| n.task.name.as_str()).collect();
+        assert_eq!(names, ["薬を飲む", "散歩"]);
+        assert!(notices.iter().all(|n| n.kind == NoticeKind::Ring));
+        assert!(tasks.iter().all(|t| t.state == TaskState::Fired));
+        // 次に起きるのは ack の期限
+        assert_eq!(next, Some(due + config.ack_timeout()));
+    }
+
+    fn minutes(m: i64) -> Duration {
+        Duration::minutes(m)
+    }
+
+    #[test]
+    fn stages_fire_in_order() {
+        let mut task = task("2030-01-01:08:00:薬を飲む @ remind 30,5,0");
+        let due = task.datetime;
+        let grace = minutes(15);
+
+        assert_eq!(task.take_due_stage(due - minutes(31), &[], grace), None);
+        let fired: Vec<u32> = [due - minutes(30), due - minutes(20), due - minutes(5), due]
+            .into_iter()
+            .filter_map(|now| task.take_due_stage(now, &[], grace))
+            .map(|stage| {
+                assert!(!stage.late && stage.repeat == 0);
+                stage.minutes_before
+            })
+            .collect();
+        assert_eq!(fired, [30, 5, 0]);
+        assert_eq!(task.take_due_stage(due + minutes(1), &[], grace), None);
+    }
+
+    #[test]
+    fn older_stages_are_skipped_after_a_restart() {
+        let mut task = task("2030-01-01:08:00:薬を飲む @ remind 30,5,0");
+        let due = task.datetime;
+
+        // 止まっていて 30分前と5分前を両方過ぎた。新しい方だけを遅れて出す
+        let stage = task.take_due_stage(due - minutes(3), &[], minutes(15)).unwrap();
+        assert_eq!(stage, ReminderStage { minutes_before: 5, late: true, repeat: 0 });
+        assert_eq!(task.fired, [30, 5]);
+        assert_eq!(task.take_due_stage(due, &[], minutes(15)).unwrap().minutes_before, 0);
+    }
+
+    #[test]
+    fn late_delivery_within_the_grace_period() {
+        let mut inside = task("2030-01-01:08:00:薬を飲む @ remind 0");
+        let due = inside.datetime;
+        let stage = inside.take_due_stage(due + minutes(10), &[], minutes(15)).unwrap();
+        assert!(stage.late);
+        // 1分未満の遅れは late にしない
+        let mut on_time = task("2030-01-01:08:00:薬を飲む @ remind 0");
+        assert!(!on_time.take_due_stage(due + Duration::seconds(30), &[], minutes(15)).unwrap().late);
+
+        let mut outside = task("2030-01-01:08:00:薬を飲む @ remind 0");
+        assert_eq!(outside.take_due_stage(due + minutes(16), &[], minutes(15)), None);
+        assert_eq!(outside.fired, [0]);
+        // 飛ばした1回だけのタスクは missed で終わる
+        assert!(outside.finish_occurrence(due + minutes(16), &[]));
+        assert_eq!(outside.state, TaskState::Missed);
+    }
+
+    #[test]
+    fn unanswered_reminders_escalate_then_give_up() {
+        let mut tasks = vec![task("2030-01-01:08:00:薬を飲む")];
+        let due = tasks[0].datetime;
+        let config = SchedulerConfig { max_repeats: 2, ..scheduler(vec![0]) };
+        let timeout = config.ack_timeout();
+
+        let ((notices, _), _) = due_notices(&mut tasks, due, &config);
+        assert_eq!(notices[0].kind, NoticeKind::Ring);
+
+        let ((notices, next), _) = due_notices(&mut tasks, due + timeout - Duration::seconds(1), &config);
+        assert!(notices.is_empty());
+        assert_eq!(next, Some(due + timeout));
+
+        let mut at = due;
+        for repeat in 1..=2 {
+            at += timeout;
+            let ((notices, _), _) = due_notices(&mut tasks, at, &config);
+            assert_eq!(notices.len(), 1);
+            assert_eq!(notices[0].kind, NoticeKind::Escalate);
+            assert_eq!(notices[0].stage.repeat, repeat);
+        }
+
+        let ((notices, next), _) = due_notices(&mut tasks, at + timeout, &config);
+        assert_eq!(notices[0].kind, NoticeKind::GiveUp);
+        assert_eq!(tasks[0].state, TaskState::Missed);
+        assert!(tasks[0].alert.is_none());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn snoozed_reminders_ring_again() {
+        let mut tasks = vec![task("2030-01-01:08:00:薬を飲む")];
+        let due = tasks[0].datetime;
+        let config = scheduler(vec![0]);
+        due_notices(&mut tasks, due, &config);
+
+        assert!(tasks[0].respond(AckAction::Snooze(10), due + minutes(1)));
+        let ((notices, _), _) = due_notices(&mut tasks, due + minutes(11), &config);
+        assert_eq!(notices[0].kind, NoticeKind::Ring);
+        assert_eq!(notices[0].stage.repeat, 0);
+    }
+
+    #[test]
+    fn recurring_tasks_move_to_the_next_occurrence() {
+        let mut tasks = vec![task("2030-01-01:08:00:薬を飲む @ daily @ remind 10,0")];
+        let due = tasks[0].datetime;
+        let config = scheduler(Vec::new());
+
+        due_notices(&mut tasks, due - minutes(10), &config);
+        assert!(tasks[0].respond(AckAction::Ack, due - minutes(9)));
+        due_notices(&mut tasks, due, &config);
+        // 最後の段階に ack が無いうちは次の回へ進まない
+        assert_eq!(tasks[0].datetime, due);
+        assert!(tasks[0].respond(AckAction::Ack, due + minutes(1)));
+
+        let ((notices, next), changed) = due_notices(&mut tasks, due + minutes(1), &config);
+        assert!(notices.is_empty() && changed);
+        assert_eq!(tasks[0].datetime, due + Duration::days(1));
+        assert_eq!(tasks[0].state, TaskState::Pending);
+        assert!(tasks[0].fired.is_empty());
+        assert_eq!(next, Some(due + Duration::days(1) - minutes(10)));
+    }
+
+    #[test]
+    fn ack_words() {
+        for text in ["はい", "はい、わかった", "了解です", "わかったよ。", "OK", "ok!", "Yes, done", "はいはい", "りょうかい"] {
+            assert_eq!(ack_from_speech(text, 10), Some(AckAction::Ack), "{}", text);
+        }
+    }
+
+    #[test]
+    fn words_inside_other_words_are_not_acks() {
+        for text in ["token", "はいらない", "はいけい", "yesterday", "bookkeeping", "了解できない", "", "   "] {
+            assert_eq!(ack_from_speech(text, 10), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn negations_are_not_acks() {
+        for text in ["no, not ok", "not ok", "Don't, yes... no", "わかったけど、だめ", "いいえ", "はい、いや、わかりません"] {
+            assert_eq!(ack_from_speech(text, 10), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn snooze_words() {
+        assert_eq!(ack_from_speech("あとで", 10), Some(AckAction::Snooze(10)));
+        assert_eq!(ack_from_speech("5分まって", 10), Some(AckAction::Snooze(5)));
+        assert_eq!(ack_from_speech("はい、15分後で", 10), Some(AckAction::Snooze(15)));
+        assert_eq!(ack_from_speech("no, later", 10), Some(AckAction::Snooze(10)));
+        assert_eq!(ack_from_speech("snooze 0", 7), Some(AckAction::Snooze(7)));
+        assert_eq!(ack_from_speech("translater", 10), None);
     }
 }
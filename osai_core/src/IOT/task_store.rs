@@ -0,0 +1,261 @@
+// タスクファイル (paths.task_file) の読み書き。CLI・リモート操作 (ハンドラー)・スケジューラーはここを通す。
+//
+// プロセスの中ではロックで順番にし、書き込みは一時ファイル + rename (IOT::task::save_tasks)。
+// 読めないファイルはエラーにして上書きしない。保存したら subscribe() で受けているスケジューラーが起きる
+//
+// タスクは ID (UUID) で指す。`task rm 3f2a9c1e` のように先頭だけ (4文字以上) でもよく、名前でもよい。
+// 当てはまるタスクが複数あればエラーにする
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::IOT::task::{load_tasks, save_tasks, AckAction, Task, TaskEdit, TaskFilter};
+
+/// タスクファイルを読み書きするときに持つ。同時に書いても消えないように
+static TASK_FILE_LOCK: Mutex<()> = Mutex::new(());
+
+/// 保存するたびに増える。中身ではなく「変わった」ことだけを伝える
+static TASK_CHANGES: Lazy<watch::Sender<u64>> = Lazy::new(|| watch::channel(0).0);
+
+#[derive(Debug, Clone)]
+pub struct TaskStore {
+    path: PathBuf,
+}
+
+impl TaskStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TaskStore { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// どれかの TaskStore が保存したら changed() が返る
+    pub fn subscribe() -> watch::Receiver<u64> {
+        TASK_CHANGES.subscribe()
+    }
+
+    /// ロードして `update` で書き換え、変わったら (`update` が true を返したら) 保存する。読むだけなら list
+    pub(crate) fn modify<T>(&self, update: impl FnOnce(&mut Vec<Task>) -> (T, bool)) -> Result<T, String> {
+        let _guard = TASK_FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut tasks = self.load()?;
+        let (result, updated) = update(&mut tasks);
+        if updated {
+            self.save(&tasks)?;
+        }
+        Ok(result)
+    }
+
+    /// TASK_FILE_LOCK を持って呼ぶ。ID の無いタスク (古いファイル) があればここで ID を振って一度だけ保存するので、
+    /// list と書き換えで同じ ID になる
+    fn load(&self) -> Result<Vec<Task>, String> {
+        let mut tasks = load_tasks(&self.path)?;
+        let mut migrated = false;
+        for task in tasks.iter_mut().filter(|task| task.id.is_nil()) {
+            task.id = Uuid::new_v4();
+            migrated = true;
+        }
+        if migrated {
+            self.save(&tasks)?;
+        }
+        Ok(tasks)
+    }
+
+    fn save(&self, tasks: &[Task]) -> Result<(), String> {
+        save_tasks(&self.path, tasks).map_err(|e| format!("Failed to save {}: {}", self.path.display(), e))?;
+        TASK_CHANGES.send_modify(|generation| *generation += 1);
+        Ok(())
+    }
+
+    /// `query` に当てはまるただ1つのタスクを書き換える
+    fn modify_one(&self, query: &str, update: impl FnOnce(&mut Vec<Task>, usize) -> Result<Task, String>) -> Result<Task, String> {
+        self.modify(|tasks| match find_one(tasks, query).and_then(|i| update(tasks, i)) {
+            Ok(task) => (Ok(task), true),
+            Err(e) => (Err(e), false),
+        })?
+    }
+
+    /// 読むだけで保存しない (ID の無い古いファイルだけは ID を振って保存する)
+    pub fn list(&self, filter: &TaskFilter) -> Result<Vec<Task>, String> {
+        let _guard = TASK_FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tasks = self.load()?;
+        Ok(tasks.into_iter().filter(|task| filter.matches(task)).collect())
+    }
+
+    pub fn add(&self, task: Task) -> Result<Task, String> {
+        self.modify(|tasks| {
+            tasks.push(task.clone());
+            (task, true)
+        })
+    }
+
+    /// 変えたあとのタスクを返す
+    pub fn update(&self, query: &str, edit: &TaskEdit) -> Result<Task, String> {
+        self.modify_one(query, |tasks, i| {
+            let mut task = tasks[i].clone();
+            task.apply(edit, Utc::now())?;
+            tasks[i] = task.clone();
+            Ok(task)
+        })
+    }
+
+    /// 消したタスクを返す
+    pub fn delete(&self, query: &str) -> Result<Task, String> {
+        self.modify_one(query, |tasks, i| Ok(tasks.remove(i)))
+    }
+
+    /// この回を終わらせる (Task::complete)
+    pub fn complete(&self, query: &str) -> Result<Task, String> {
+        let now = Utc::now();
+        self.modify_one(query, |tasks, i| {
+            tasks[i].complete(now);
+            Ok(tasks[i].clone())
+        })
+    }
+
+    /// `task import` で読んだタスクを足す。同じ ID か同じ UID のタスクがあれば足さずに置き換える (Task::update_from)。
+    /// (足した数, 置き換えた数, 変わらなかった数)
+    pub fn import(&self, imported: Vec<Task>) -> Result<(usize, usize, usize), String> {
+        self.modify(|tasks| {
+            let (mut added, mut updated, mut unchanged) = (0, 0, 0);
+            for task in imported {
+                let existing = tasks
+                    .iter_mut()
+                    .find(|t| t.id == task.id || (task.ical_uid.is_some() && t.ical_uid == task.ical_uid));
+                match existing {
+                    Some(existing) => {
+                        if existing.update_from(task) {
+                            updated += 1;
+                        } else {
+                            unchanged += 1;
+                        }
+                    }
+                    None => {
+                        tasks.push(task);
+                        added += 1;
+                    }
+                }
+            }
+            ((added, updated, unchanged), added + updated > 0)
+        })
+    }
+
+    /// 鳴っている通知に応答する。`query` が空なら鳴っているもの全部。応答したタスクを返す
+    pub fn respond(&self, query: &str, action: AckAction) -> Result<Vec<Task>, String> {
+        let query = query.trim();
+        let now = Utc::now();
+        let responded = self.modify(|tasks| {
+            let responded: Vec<Task> = tasks
+                .iter_mut()
+                .filter(|task| query.is_empty() || task.matches(query))
+                .filter_map(|task| task.respond(action, now).then(|| task.clone()))
+                .collect();
+            let changed = !responded.is_empty();
+            (responded, changed)
+        })?;
+        if responded.is_empty() {
+            return Err(match query {
+                "" => "No reminder is waiting for an acknowledgement".to_string(),
+                query => format!("No reminder for '{}' is waiting for an acknowledgement", query),
+            });
+        }
+        Ok(responded)
+    }
+}
+
+fn find_one(tasks: &[Task], query: &str) -> Result<usize, String> {
+    let query = query.trim();
+    if query.is_empty() {
+        return Err("Give a task ID (or the first 4+ characters of it) or a task name".to_string());
+    }
+    let found: Vec<usize> = (0..tasks.len()).filter(|i| tasks[*i].matches(query)).collect();
+    match found.as_slice() {
+        [i] => Ok(*i),
+        [] => Err(format!("No task matches '{}'", query)),
+        many => {
+            let candidates: Vec<String> = many
+                .iter()
+                .map(|i| format!("{} {}", tasks[*i].short_id(), tasks[*i].name))
+                .collect();
+            Err(format!("'{}' matches {} tasks: {}", query, many.len(), candidates.join(", ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IOT::task::add_new_task;
+    use chrono_tz::Asia::Tokyo;
+
+    fn temp_store(name: &str) -> TaskStore {
+        let dir = std::env::temp_dir().join(format!("osai-task-store-{}-{}", name, Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TaskStore::new(dir.join("tasks.json"))
+    }
+
+    /// ID の無い古い形式のタスクファイル
+    fn write_legacy_file(store: &TaskStore) -> String {
+        let task = add_new_task("2030-01-01:08:00:薬を飲む @ daily", Tokyo).unwrap();
+        let mut value = serde_json::to_value(vec![task]).unwrap();
+        value[0].as_object_mut().unwrap().remove("id");
+        let data = serde_json::to_string_pretty(&value).unwrap();
+        std::fs::write(store.path(), &data).unwrap();
+        data
+    }
+
+    #[test]
+    fn list_does_not_rewrite_the_file() {
+        let store = temp_store("list");
+        assert!(store.list(&TaskFilter::default()).unwrap().is_empty());
+        assert!(!store.path().exists());
+
+        store.add(add_new_task("2030-01-02:09:00:散歩", Tokyo).unwrap()).unwrap();
+        let data = std::fs::read_to_string(store.path()).unwrap();
+        assert_eq!(store.list(&TaskFilter::default()).unwrap().len(), 1);
+        assert_eq!(std::fs::read_to_string(store.path()).unwrap(), data);
+        let _ = std::fs::remove_dir_all(store.path().parent().unwrap());
+    }
+
+    #[test]
+    fn legacy_ids_are_assigned_once_and_usable() {
+        let store = temp_store("legacy");
+        write_legacy_file(&store);
+
+        let listed = store.list(&TaskFilter::default()).unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(!listed[0].id.is_nil());
+        // 保存したので次に読んでも同じ ID
+        let migrated = std::fs::read_to_string(store.path()).unwrap();
+        assert_eq!(store.list(&TaskFilter::default()).unwrap()[0].id, listed[0].id);
+        assert_eq!(std::fs::read_to_string(store.path()).unwrap(), migrated);
+
+        let done = store.complete(&listed[0].short_id()).unwrap();
+        assert_eq!(done.id, listed[0].id);
+        let deleted = store.delete(&listed[0].short_id()).unwrap();
+        assert_eq!(deleted.id, listed[0].id);
+        assert!(store.list(&TaskFilter::default()).unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(store.path().parent().unwrap());
+    }
+
+    #[test]
+    fn writes_assign_missing_ids() {
+        let store = temp_store("modify");
+        write_legacy_file(&store);
+        let mut changes = TaskStore::subscribe();
+        changes.mark_unchanged();
+        let added = store.add(add_new_task("2030-01-02:09:00:散歩", Tokyo).unwrap()).unwrap();
+        assert!(changes.has_changed().unwrap());
+
+        let tasks = store.list(&TaskFilter::default()).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert!(tasks.iter().all(|task| !task.id.is_nil()));
+        assert_eq!(tasks[1].id, added.id);
+        let _ = std::fs::remove_dir_all(store.path().parent().unwrap());
+    }
+}
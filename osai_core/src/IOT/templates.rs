@@ -0,0 +1,241 @@
+// LLM が使えないとき (ネットが無い・llm.backend = "offline") に読み上げる決まった文章。
+// paths.speech_templates (speech_templates.toml) に置き、無ければ DEFAULT_TEMPLATES を書き出すので手で直せる。
+//
+// 種類 ([[kind]]) はタスクのタグで選び、合うタグが無ければ名前に含まれる言葉 (words) で選ぶ。
+// どれにも合わなければ最後の tags も words も無い種類。上から順に調べる
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Timelike, Utc};
+use serde::Deserialize;
+
+use crate::IOT::task::{ReminderStage, Task};
+
+pub const DEFAULT_TEMPLATES: &str = r#"# 通知の読み上げに使う文章 (LLM が使えないとき)。
+# {name} タスク名、{time} 時刻 (8時30分)、{minutes} あと何分か
+# 種類はタスクのタグ (tags) か、名前に含まれる言葉 (words) で上から順に選ぶ
+
+# 遅れて通知するとき・返事が無くてもう一度言うときに前に付ける
+late = "遅れてごめんなさい。"
+repeat = "もう一度言います。"
+# ai コマンドで LLM につながらないときの返事 (空ならエラーを出す)
+ai_unavailable = "ごめんね、いまは こたえられないみたい。あとで もういちど きいてね。"
+
+[[kind]]
+name = "medicine"
+tags = ["medicine", "薬", "くすり"]
+words = ["薬", "くすり", "服薬"]
+before = "あと{minutes}分、{time}に{name}の時間です。お薬を用意しておいてね。"
+now = "{name}の時間です。お薬を飲むのを忘れないでね。"
+
+[[kind]]
+name = "meal"
+tags = ["meal", "食事", "ごはん"]
+words = ["ごはん", "ご飯", "食事", "朝食", "昼食", "夕食"]
+before = "あと{minutes}分で{name}です。{time}になったら、いただきましょう。"
+now = "{name}の時間です。ゆっくり、よく噛んで食べてね。"
+
+[[kind]]
+name = "wake-up"
+tags = ["wake", "wake-up", "起床"]
+words = ["起床", "起きる", "目覚まし"]
+before = "あと{minutes}分で{time}、{name}の時間です。"
+now = "おはようございます。{time}です。{name}の時間ですよ。"
+
+[[kind]]
+name = "appointment"
+tags = ["appointment", "予定", "会議", "病院"]
+words = ["会議", "予約", "病院", "面談", "約束"]
+before = "{time}から{name}があります。あと{minutes}分です。準備はできていますか。"
+now = "{name}の時間になりました。出かける準備をしましょう。"
+
+[[kind]]
+name = "default"
+before = "{time}に{name}があります。あと{minutes}分です。"
+now = "{time}です。{name}の時間です。"
+"#;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpeechTemplates {
+    #[serde(default)]
+    pub late: String,
+    #[serde(default)]
+    pub repeat: String,
+    #[serde(default)]
+    pub ai_unavailable: String,
+    #[serde(default, rename = "kind")]
+    pub kinds: Vec<TemplateKind>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateKind {
+    pub name: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// 前もって知らせるとき
+    pub before: String,
+    /// その時刻 (0分前) のとき
+    pub now: String,
+}
+
+impl Default for SpeechTemplates {
+    fn default() -> Self {
+        toml::from_str(DEFAULT_TEMPLATES).expect("DEFAULT_TEMPLATES is valid")
+    }
+}
+
+impl SpeechTemplates {
+    /// 無ければ DEFAULT_TEMPLATES を書き出して使う。読めない・壊れているときは理由を出してデフォルトを使う
+    /// (ファイルは直せるようにそのまま)
+    pub fn load(path: &Path) -> Self {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                if let Err(e) = fs::write(path, DEFAULT_TEMPLATES) {
+                    eprintln!("Failed to write {}: {}", path.display(), e);
+                }
+                return Self::default();
+            }
+            Err(e) => {
+                eprintln!("Failed to read {}: {} (using the built-in templates)", path.display(), e);
+                return Self::default();
+            }
+        };
+        match toml::from_str::<SpeechTemplates>(&data) {
+            Ok(templates) if !templates.kinds.is_empty() => templates,
+            Ok(_) => {
+                eprintln!("{} has no [[kind]] (using the built-in templates)", path.display());
+                Self::default()
+            }
+            Err(e) => {
+                eprintln!("Failed to parse {}: {} (using the built-in templates)", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// タグ、名前の言葉、tags も words も無い種類の順に選ぶ
+    pub fn kind_for(&self, task: &Task) -> Option<&TemplateKind> {
+        let by_tag = self.kinds.iter().find(|kind| {
+            kind.tags.iter().any(|tag| task.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+        });
+        let by_word = || {
+            self.kinds
+                .iter()
+                .find(|kind| kind.words.iter().any(|word| !word.is_empty() && task.name.contains(word.as_str())))
+        };
+        let fallback = || self.kinds.iter().rev().find(|kind| kind.tags.is_empty() && kind.words.is_empty());
+        by_tag.or_else(by_word).or_else(fallback)
+    }
+
+    /// `now` に読み上げる通知の文章。合う種類が無ければ時刻と名前だけ。
+    /// {minutes} は段階ではなく `now` からの残り時間 (遅れて出すと段階より短い。過ぎていれば now の文章)
+    pub fn render(&self, task: &Task, stage: ReminderStage, now: DateTime<Utc>) -> String {
+        let minutes = task.minutes_left(now);
+        let template = match (self.kind_for(task), minutes) {
+            (Some(kind), 0) => kind.now.as_str(),
+            (Some(kind), _) => kind.before.as_str(),
+            (None, _) => "{time}、{name}",
+        };
+        let mut text = String::new();
+        if stage.repeat > 0 {
+            text.push_str(&self.repeat);
+        }
+        if stage.late {
+            text.push_str(&self.late);
+        }
+        text.push_str(
+            &template
+                .replace("{name}", &task.name)
+                .replace("{time}", &spoken_time(task))
+                .replace("{minutes}", &minutes.to_string()),
+        );
+        text
+    }
+}
+
+/// タスクのゾーンで "8時30分" (ちょうどなら "8時")
+fn spoken_time(task: &Task) -> String {
+    let time = task.local_time();
+    match time.minute() {
+        0 => format!("{}時", time.hour()),
+        minute => format!("{}時{}分", time.hour(), minute),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IOT::task::add_new_task;
+    use chrono::Duration;
+    use chrono_tz::Asia::Tokyo;
+
+    fn task(args: &str) -> Task {
+        add_new_task(args, Tokyo).unwrap()
+    }
+
+    fn stage(minutes_before: u32, late: bool, repeat: u32) -> ReminderStage {
+        ReminderStage { minutes_before, late, repeat }
+    }
+
+    #[test]
+    fn kind_by_tag_then_word_then_fallback() {
+        let templates = SpeechTemplates::default();
+        // 名前は食事でもタグが先
+        let tagged = task("2030-01-01:08:00:朝ごはん @ tags 薬");
+        assert_eq!(templates.kind_for(&tagged).unwrap().name, "medicine");
+        let tagged = task("2030-01-01:08:00:朝ごはん @ tags MEDICINE");
+        assert_eq!(templates.kind_for(&tagged).unwrap().name, "medicine");
+
+        let named = task("2030-01-01:08:00:朝ごはん @ tags care");
+        assert_eq!(templates.kind_for(&named).unwrap().name, "meal");
+
+        let other = task("2030-01-01:08:00:散歩");
+        assert_eq!(templates.kind_for(&other).unwrap().name, "default");
+
+        let no_fallback = SpeechTemplates {
+            kinds: templates.kinds.iter().filter(|kind| kind.name != "default").cloned().collect(),
+            ..templates.clone()
+        };
+        assert!(no_fallback.kind_for(&other).is_none());
+        assert_eq!(no_fallback.render(&other, stage(0, false, 0), other.datetime), "8時、散歩");
+    }
+
+    #[test]
+    fn render_fills_placeholders() {
+        let templates = SpeechTemplates::default();
+        let task = task("2030-01-01:08:30:散歩");
+        let due = task.datetime;
+
+        assert_eq!(
+            templates.render(&task, stage(10, false, 0), due - Duration::minutes(10)),
+            "8時30分に散歩があります。あと10分です。"
+        );
+        assert_eq!(templates.render(&task, stage(0, false, 0), due), "8時30分です。散歩の時間です。");
+        assert_eq!(
+            templates.render(&task, stage(0, true, 1), due + Duration::minutes(3)),
+            "もう一度言います。遅れてごめんなさい。8時30分です。散歩の時間です。"
+        );
+    }
+
+    #[test]
+    fn minutes_are_the_time_left() {
+        let templates = SpeechTemplates::default();
+        let task = task("2030-01-01:08:00:散歩");
+        let due = task.datetime;
+
+        // 10分前の段階を4分遅れて出す
+        assert_eq!(
+            templates.render(&task, stage(10, true, 0), due - Duration::minutes(6)),
+            "遅れてごめんなさい。8時に散歩があります。あと6分です。"
+        );
+        // 時刻を過ぎてから出す前もっての段階は「今」の文章
+        assert_eq!(
+            templates.render(&task, stage(10, true, 0), due + Duration::minutes(2)),
+            "遅れてごめんなさい。8時です。散歩の時間です。"
+        );
+    }
+}
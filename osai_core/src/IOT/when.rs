@@ -0,0 +1,502 @@
+// タスクの日時の書き方。`task <日時>:<名前>`、`task edit <id> at=...`、remote_task で使う。
+//
+//   2025-12-01:08:00                           (今までの書き方)
+//   2025-12-01T08:00 / 2025-12-01 08:00        (ISO 8601)
+//   2025-12-01T08:00:00+09:00 / ...Z           (オフセット付きならそのまま)
+//   08:00 / 8am / 7:30pm                       (次のその時刻。今日か明日)
+//   today 18:00 / tomorrow 8am / mon 09:00
+//   in 20m / in 2 hours / in 1h30m
+//   今日18時 / 明日の朝8時半 / 月曜7時30分 / 20分後 / 1時間30分後
+//   毎朝7時 / 毎晩9時 / 毎日12時 / 平日7時      (繰り返し。`@ daily` などと同じ)
+//
+// オフセットの無い時刻はタスクのタイムゾーン (`@ tz Asia/Tokyo`、無ければ scheduler.timezone) で読む。
+// 夏時間で飛ばされて存在しない時刻はエラー、2回ある時刻は早い方。
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+use crate::IOT::recurrence::Recurrence;
+
+const USAGE: &str =
+    "Use YYYY-MM-DD:HH:MM, ISO 8601 (2025-12-01T08:00+09:00), 08:00, tomorrow 8am, in 20m, 明日8時, 20分後 or 毎朝7時";
+
+/// 読んだ日時
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct When {
+    pub at: DateTime<Utc>,
+    /// 毎朝7時 などで決まった繰り返し
+    pub recurrence: Option<Recurrence>,
+}
+
+impl When {
+    fn once(at: DateTime<Utc>) -> Self {
+        When { at, recurrence: None }
+    }
+}
+
+/// このマシンのタイムゾーン。分からなければ UTC
+pub fn local_zone() -> Tz {
+    iana_time_zone::get_timezone().ok().and_then(|name| name.parse().ok()).unwrap_or(Tz::UTC)
+}
+
+/// `zone` の壁時計の時刻を UTC にする。夏時間で飛ばされた時刻は1時間後、2回ある時刻は早い方。
+/// 繰り返すタスクを次の回へ進めるとき用 (エラーにできないので)
+pub fn resolve_local(zone: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    zone.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| zone.from_local_datetime(&(local + Duration::hours(1))).earliest())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// 入力された時刻は飛ばさずにエラーにする
+fn exact_local(zone: Tz, local: NaiveDateTime, text: &str) -> Result<DateTime<Utc>, String> {
+    zone.from_local_datetime(&local)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or_else(|| format!("'{}' does not exist in {} (skipped by daylight saving time)", text.trim(), zone))
+}
+
+/// `input` の先頭の日時を読み、残り (タスク名) を返す。
+/// 日時と名前の間は ':' か空白 (日本語の時刻なら「に」も。「毎朝7時に薬を飲む」)
+pub fn parse_when(input: &str, now: DateTime<Utc>, zone: Tz) -> Result<(When, &str), String> {
+    let text = input.trim_start();
+    let parsed = match parse_date(text, zone)? {
+        Some(parsed) => Some(parsed),
+        None => match parse_in(text, now)? {
+            Some(parsed) => Some(parsed),
+            None => parse_day(text, now, zone)?,
+        },
+    };
+    let Some((when, rest)) = parsed else {
+        return Err(format!("Could not parse the date/time at '{}'. {}", head(text), USAGE));
+    };
+    let japanese = text[..text.len() - rest.len()].ends_with(|c: char| !c.is_ascii());
+    let name = match rest.trim_start().strip_prefix(':') {
+        Some(name) => name,
+        None if japanese => rest.strip_prefix('に').unwrap_or(rest),
+        None if rest.is_empty() || rest.starts_with(char::is_whitespace) => rest,
+        None => {
+            return Err(format!("Could not parse '{}' after the date/time. Separate the task name with ':'", head(rest)));
+        }
+    };
+    Ok((when, name.trim()))
+}
+
+/// 日時だけ (`task edit <id> at=...`)
+pub fn parse_at(input: &str, now: DateTime<Utc>, zone: Tz) -> Result<When, String> {
+    let (when, rest) = parse_when(input, now, zone)?;
+    if !rest.is_empty() {
+        return Err(format!("Could not parse '{}' after the date/time", head(rest)));
+    }
+    Ok(when)
+}
+
+/// エラーに出す入力の先頭 (最初の空白まで)
+fn head(s: &str) -> &str {
+    let s = s.trim_start();
+    let end = s.find(char::is_whitespace).unwrap_or(s.len());
+    let end = s.char_indices().nth(24).map_or(end, |(i, _)| end.min(i));
+    &s[..end]
+}
+
+/// 先頭の数字 (max_len 桁まで)
+fn number(s: &str, max_len: usize) -> Option<(u32, &str)> {
+    let len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    if len == 0 || len > max_len {
+        return None;
+    }
+    Some((s[..len].parse().ok()?, &s[len..]))
+}
+
+/// 大文字小文字を区別せずに `word` で始まり、そのあとが英字でなければ残りを返す
+fn keyword<'a>(s: &'a str, word: &str) -> Option<&'a str> {
+    let rest = s.get(..word.len()).filter(|p| p.eq_ignore_ascii_case(word)).map(|_| &s[word.len()..])?;
+    (!rest.starts_with(|c: char| c.is_ascii_alphabetic())).then_some(rest)
+}
+
+/// 2025-12-01:08:00 / 2025-12-01T08:00[:SS][Z|+09:00] / 2025-12-01 08:00
+fn parse_date(s: &str, zone: Tz) -> Result<Option<(When, &str)>, String> {
+    let Some(date_text) = s.get(..10).filter(|d| {
+        d.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() })
+    }) else {
+        return Ok(None);
+    };
+    let date = NaiveDate::parse_from_str(date_text, "%Y-%m-%d").map_err(|_| format!("Invalid date '{}'", date_text))?;
+    let rest = &s[10..];
+    let iso = rest.starts_with('T');
+    let after = match rest.chars().next() {
+        Some('T' | ':' | ' ') => rest[1..].trim_start(),
+        _ => return Err(format!("Missing time after '{}'. Use {}:08:00", date_text, date_text)),
+    };
+    let Some((time, mut rest)) = parse_time(after, None)? else {
+        return Err(format!("Could not parse the time '{}' after '{}'", head(after), date_text));
+    };
+    let mut local = date.and_time(time);
+    // 秒とオフセットは ISO の書き方だけ (今までの書き方では ':' のあとはタスク名)
+    if iso {
+        if let Some((seconds, after)) = rest.strip_prefix(':').and_then(|r| number(r, 2)) {
+            if seconds > 59 {
+                return Err(format!("Invalid seconds in '{}'", &s[..s.len() - after.len()]));
+            }
+            local += Duration::seconds(i64::from(seconds));
+            rest = after;
+        }
+        if let Some((offset, after)) = parse_offset(rest)? {
+            return Ok(Some((When::once((local - offset).and_utc()), after)));
+        }
+    }
+    let text = &s[..s.len() - rest.len()];
+    Ok(Some((When::once(exact_local(zone, local, text)?), rest)))
+}
+
+/// Z / +09:00 / -0500 / +09
+fn parse_offset(s: &str) -> Result<Option<(Duration, &str)>, String> {
+    if let Some(rest) = s.strip_prefix('Z').filter(|r| !r.starts_with(|c: char| c.is_alphanumeric())) {
+        return Ok(Some((Duration::zero(), rest)));
+    }
+    let sign = match s.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Ok(None),
+    };
+    let invalid = || format!("Invalid UTC offset '{}'. Use Z, +09:00 or -05:00", head(s));
+    let (hours, rest) = number(&s[1..], 2).ok_or_else(invalid)?;
+    let (minutes, rest) = match rest.strip_prefix(':').unwrap_or(rest) {
+        r if r.as_bytes().get(..2).is_some_and(|b| b.iter().all(u8::is_ascii_digit)) => {
+            (r[..2].parse().map_err(|_| invalid())?, &r[2..])
+        }
+        _ => (0, rest),
+    };
+    if hours > 14 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(Some((Duration::minutes(sign * i64::from(hours * 60 + minutes)), rest)))
+}
+
+/// 08:00 / 8am / 7:30 pm / 7時 / 7時半 / 7時30分。`pm` は前に付いた 朝・夜 など
+fn parse_time(s: &str, pm: Option<bool>) -> Result<Option<(NaiveTime, &str)>, String> {
+    let Some((hour, rest)) = number(s, 2) else { return Ok(None) };
+    let (minute, rest, clock) = if let Some(r) = rest.strip_prefix(':') {
+        match number(r, 2).filter(|_| r.as_bytes().get(1).is_some_and(u8::is_ascii_digit)) {
+            Some((minute, r)) => (minute, r, true),
+            None => return Err(format!("Invalid time '{}'. Use HH:MM", head(s))),
+        }
+    } else if let Some(r) = rest.strip_prefix('時') {
+        // 「7時間後」は時刻ではない
+        if r.starts_with('間') {
+            return Ok(None);
+        }
+        match (r.strip_prefix('半'), number(r, 2).and_then(|(m, r)| Some((m, r.strip_prefix('分')?)))) {
+            (Some(r), _) => (30, r, true),
+            (None, Some((minute, r))) => (minute, r, true),
+            (None, None) => (0, r, true),
+        }
+    } else {
+        (0, rest, false)
+    };
+    let after = rest.trim_start();
+    let (suffix, rest) = match (keyword(after, "am"), keyword(after, "pm")) {
+        (Some(r), _) => (Some(false), r),
+        (_, Some(r)) => (Some(true), r),
+        _ => (None, rest),
+    };
+    // "8" だけは時刻にしない
+    if !clock && suffix.is_none() {
+        return Ok(None);
+    }
+    let text = s[..s.len() - rest.len()].trim();
+    if suffix.is_some() && hour > 12 {
+        return Err(format!("Invalid time '{}'", text));
+    }
+    let hour = match suffix.or(pm) {
+        Some(true) if hour < 12 => hour + 12,
+        Some(false) if hour == 12 => 0,
+        _ => hour,
+    };
+    NaiveTime::from_hms_opt(hour, minute, 0)
+        .map(|time| Some((time, rest)))
+        .ok_or_else(|| format!("Invalid time '{}'", text))
+}
+
+/// in 20m / in 2 hours / in 1h30m / 20分後 / 1時間30分後 / 3日後
+fn parse_in(s: &str, now: DateTime<Utc>) -> Result<Option<(When, &str)>, String> {
+    if let Some(rest) = keyword(s, "in") {
+        let rest = rest.trim_start();
+        let Some((mut total, mut rest)) = duration_part(rest) else {
+            return Err(match number(rest, 6) {
+                Some((_, unit)) => format!("Unknown unit '{}' after 'in'. Use m, min, h, hours or d", head(unit)),
+                None => format!("Could not parse the duration '{}' after 'in'. Use in 20m, in 2 hours or in 1h30m", head(rest)),
+            });
+        };
+        // 1h30m / 1 hour 30 minutes
+        while let Some((more, after)) = duration_part(rest).or_else(|| duration_part(rest.trim_start())) {
+            total = total.checked_add(&more).ok_or_else(|| too_far(s))?;
+            rest = after;
+        }
+        return Ok(Some((When::once(after(now, total, s)?), rest)));
+    }
+
+    let Some((n, rest)) = number(s, 4) else { return Ok(None) };
+    let n = i64::from(n);
+    // number は4桁までなので溢れない
+    let (total, rest) = if let Some(r) = rest.strip_prefix("時間") {
+        match number(r, 2).and_then(|(m, r)| Some((m, r.strip_prefix('分')?))) {
+            Some((minutes, r)) => (Duration::hours(n) + Duration::minutes(i64::from(minutes)), r),
+            None => (Duration::hours(n), r.strip_prefix('半').map_or(r, |r| r)),
+        }
+    } else if let Some(r) = rest.strip_prefix('分') {
+        (Duration::minutes(n), r)
+    } else if let Some(r) = rest.strip_prefix('日') {
+        (Duration::days(n), r)
+    } else {
+        return Ok(None);
+    };
+    // 「7時間」だけで「後」が無いものは分からない
+    match rest.strip_prefix('後') {
+        Some(rest) => Ok(Some((When::once(after(now, total, s)?), rest))),
+        None => Err(format!("Could not parse '{}'. Use 20分後 or 1時間30分後", head(s))),
+    }
+}
+
+/// `now` の `total` 後。表せないほど先ならエラー
+fn after(now: DateTime<Utc>, total: Duration, input: &str) -> Result<DateTime<Utc>, String> {
+    now.checked_add_signed(total).ok_or_else(|| too_far(input))
+}
+
+fn too_far(input: &str) -> String {
+    let input: String = input.trim().chars().take(40).collect();
+    format!("'{}' is too far in the future", input)
+}
+
+/// 20m / 2 hours / 1d
+fn duration_part(s: &str) -> Option<(Duration, &str)> {
+    let (n, rest) = number(s, 6)?;
+    let rest = rest.trim_start();
+    const UNITS: [(&str, i64); 13] = [
+        ("minutes", 1), ("minute", 1), ("mins", 1), ("min", 1), ("m", 1),
+        ("hours", 60), ("hour", 60), ("hrs", 60), ("hr", 60), ("h", 60),
+        ("days", 1440), ("day", 1440), ("d", 1440),
+    ];
+    UNITS
+        .iter()
+        .find_map(|(unit, minutes)| keyword(rest, unit).map(|r| (Duration::try_minutes(i64::from(n) * minutes), r)))
+        .and_then(|(duration, r)| Some((duration?, r)))
+}
+
+/// 時刻の前に付く日
+enum Day {
+    /// 今日から何日後
+    Offset(i64),
+    /// 次のその曜日
+    Weekday(Weekday),
+    /// 毎朝 など。時刻が来る最初の日
+    Every(Recurrence),
+    /// 時刻だけ。今日か明日
+    Next,
+}
+
+/// today / tomorrow / mon / 明日 / 毎朝 など + 時刻、または時刻だけ
+fn parse_day(s: &str, now: DateTime<Utc>, zone: Tz) -> Result<Option<(When, &str)>, String> {
+    const ENGLISH: [(&str, i64); 2] = [("today", 0), ("tomorrow", 1)];
+    const JAPANESE: [(&str, i64); 7] = [("今日", 0), ("きょう", 0), ("明後日", 2), ("あさって", 2), ("明日", 1), ("あした", 1), ("あす", 1)];
+    // 毎朝・毎晩は時刻が午前・午後のどちらか決まる
+    let every: [(&str, Recurrence, Option<bool>); 6] = [
+        ("毎朝", Recurrence::Daily, Some(false)),
+        ("毎晩", Recurrence::Daily, Some(true)),
+        ("毎夜", Recurrence::Daily, Some(true)),
+        ("毎日", Recurrence::Daily, None),
+        ("毎週", Recurrence::Weekly(Vec::new()), None),
+        ("平日", Recurrence::Weekdays, None),
+    ];
+
+    let mut day = Day::Next;
+    let mut pm = None;
+    let mut rest = s;
+    let mut word = "";
+    if let Some((w, offset, r)) = ENGLISH.iter().find_map(|(w, o)| keyword(s, w).map(|r| (*w, *o, r))) {
+        (day, rest, word) = (Day::Offset(offset), r, w);
+    } else if let Some((w, offset, r)) = JAPANESE.iter().find_map(|(w, o)| s.strip_prefix(w).map(|r| (*w, *o, r))) {
+        (day, rest, word) = (Day::Offset(offset), r, w);
+    } else if let Some((w, rule, period, r)) = every.iter().find_map(|(w, rule, p)| s.strip_prefix(w).map(|r| (*w, rule, *p, r))) {
+        (day, pm, rest, word) = (Day::Every(rule.clone()), period, r, w);
+    } else if let Some((w, weekday, r)) = weekday_prefix(s) {
+        (day, rest, word) = (Day::Weekday(weekday), r, w);
+    }
+    // 毎週月曜
+    if let (Day::Every(Recurrence::Weekly(days)), Some((_, weekday, r))) = (&mut day, weekday_prefix(rest)) {
+        days.push(weekday);
+        rest = r;
+        word = &s[..s.len() - r.len()];
+    }
+    let mut after_word = rest.trim_start();
+    after_word = keyword(after_word, "at").map_or(after_word, str::trim_start);
+    after_word = after_word.strip_prefix('の').unwrap_or(after_word);
+    let (period, after_period) = period_prefix(after_word);
+    pm = period.or(pm);
+
+    let time = parse_time(after_period, pm)?;
+    let Some((time, rest)) = time else {
+        return match (word, period) {
+            ("", None) => Ok(None),
+            ("", Some(_)) => Err(format!("Missing time after '{}'. Use 朝7時", &s[..s.len() - after_period.len()])),
+            (word, _) if after_period.trim().is_empty() || !after_period.starts_with(|c: char| c.is_ascii_digit()) => {
+                Err(format!("Missing time after '{}'. Use {} 08:00", word, word))
+            }
+            (word, _) => Err(format!("Could not parse the time '{}' after '{}'", head(after_period), word)),
+        };
+    };
+
+    let text = &s[..s.len() - rest.len()];
+    let local_now = now.with_timezone(&zone).naive_local();
+    let today = local_now.date();
+    let first_after = |matches: &dyn Fn(NaiveDate) -> bool| {
+        (0..=7)
+            .map(|n| today + Duration::days(n))
+            .find(|date| matches(*date) && date.and_time(time) > local_now)
+            .unwrap_or(today)
+    };
+    let (date, recurrence) = match day {
+        Day::Offset(offset) => (today + Duration::days(offset), None),
+        Day::Weekday(weekday) => (first_after(&|d| d.weekday() == weekday), None),
+        Day::Every(Recurrence::Weekly(days)) if days.is_empty() => {
+            return Err(format!("Missing weekday after '毎週'. Use 毎週月曜{}", &s[s.len() - after_word.len()..s.len() - rest.len()]));
+        }
+        Day::Every(rule) => {
+            let date = match &rule {
+                Recurrence::Weekdays => first_after(&|d| !matches!(d.weekday(), Weekday::Sat | Weekday::Sun)),
+                Recurrence::Weekly(days) => first_after(&|d| days.contains(&d.weekday())),
+                _ => first_after(&|_| true),
+            };
+            (date, Some(rule))
+        }
+        Day::Next => (first_after(&|_| true), None),
+    };
+    let at = exact_local(zone, date.and_time(time), text)?;
+    Ok(Some((When { at, recurrence }, rest)))
+}
+
+/// mon / monday / 月曜 / 月曜日
+fn weekday_prefix(s: &str) -> Option<(&str, Weekday, &str)> {
+    const JAPANESE: [(&str, Weekday); 7] = [
+        ("月曜", Weekday::Mon), ("火曜", Weekday::Tue), ("水曜", Weekday::Wed), ("木曜", Weekday::Thu),
+        ("金曜", Weekday::Fri), ("土曜", Weekday::Sat), ("日曜", Weekday::Sun),
+    ];
+    if let Some((word, weekday, rest)) = JAPANESE.iter().find_map(|(w, d)| s.strip_prefix(w).map(|r| (*w, *d, r))) {
+        return Some((word, weekday, rest.strip_prefix('日').unwrap_or(rest)));
+    }
+    let end = s.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let word = &s[..end];
+    // "mon" などは chrono が読む。"m" や "in" は曜日にしない
+    let weekday = word.parse::<Weekday>().ok().filter(|_| word.len() >= 3)?;
+    Some((word, weekday, &s[end..]))
+}
+
+/// 朝・午前 は午前、夜・晩・夕方・午後 は午後
+fn period_prefix(s: &str) -> (Option<bool>, &str) {
+    const PERIODS: [(&str, bool); 6] = [("午前", false), ("朝", false), ("午後", true), ("夕方", true), ("夜", true), ("晩", true)];
+    PERIODS
+        .iter()
+        .find_map(|(w, pm)| s.strip_prefix(w).map(|r| (Some(*pm), r)))
+        .unwrap_or((None, s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::{America::New_York, Asia::Tokyo};
+
+    /// 2025-06-01 09:00 (東京)
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 6, 1, 0, 0, 0).unwrap()
+    }
+
+    fn tokyo(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Tokyo.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().with_timezone(&Utc)
+    }
+
+    fn parse(input: &str) -> Result<(When, &str), String> {
+        parse_when(input, now(), Tokyo)
+    }
+
+    #[test]
+    fn legacy_and_iso_dates() {
+        assert_eq!(parse("2025-12-01:08:00:起床").unwrap(), (When::once(tokyo(2025, 12, 1, 8, 0)), "起床"));
+        assert_eq!(parse("2025-12-01T08:00 会議").unwrap(), (When::once(tokyo(2025, 12, 1, 8, 0)), "会議"));
+        assert_eq!(parse("2025-12-01T08:00:30 x").unwrap().0.at, tokyo(2025, 12, 1, 8, 0) + Duration::seconds(30));
+        assert_eq!(
+            parse("2025-12-01T08:00Z call").unwrap().0.at,
+            Utc.with_ymd_and_hms(2025, 12, 1, 8, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse("2025-12-01T08:00-05:00 call").unwrap().0.at,
+            Utc.with_ymd_and_hms(2025, 12, 1, 13, 0, 0).unwrap()
+        );
+        assert!(parse("2025-13-01:08:00:x").is_err());
+        assert!(parse("2025-12-01:25:00:x").is_err());
+        assert_eq!(parse("2025-12-01 08:00 x").unwrap().0.at, tokyo(2025, 12, 1, 8, 0));
+    }
+
+    #[test]
+    fn relative_durations() {
+        assert_eq!(parse("in 20m 洗濯物").unwrap(), (When::once(now() + Duration::minutes(20)), "洗濯物"));
+        assert_eq!(parse("in 2 hours x").unwrap().0.at, now() + Duration::hours(2));
+        assert_eq!(parse("in 1h30m x").unwrap().0.at, now() + Duration::minutes(90));
+        assert_eq!(parse("in 1 hour 30 minutes x").unwrap().0.at, now() + Duration::minutes(90));
+        assert_eq!(parse("in 3d x").unwrap().0.at, now() + Duration::days(3));
+        assert_eq!(parse("20分後にお茶").unwrap(), (When::once(now() + Duration::minutes(20)), "お茶"));
+        assert_eq!(parse("1時間30分後に散歩").unwrap().0.at, now() + Duration::minutes(90));
+        assert_eq!(parse("3日後 x").unwrap().0.at, now() + Duration::days(3));
+        assert!(parse("in 20 parsecs x").unwrap_err().contains("Unknown unit 'parsecs'"));
+        assert!(parse("7時間 x").is_err());
+    }
+
+    #[test]
+    fn huge_durations_are_errors() {
+        assert!(parse("in 99999999999999999d x").is_err());
+        let many = format!("in {} x", "999999d ".repeat(200));
+        assert!(parse(&many).unwrap_err().contains("too far in the future"));
+        let far = parse_when("9999日後 x", DateTime::<Utc>::MAX_UTC - Duration::days(1), Tokyo);
+        assert!(far.unwrap_err().contains("too far in the future"));
+    }
+
+    #[test]
+    fn clock_times_and_days() {
+        // 09:00 より前の時刻は明日
+        assert_eq!(parse("08:00 x").unwrap().0.at, tokyo(2025, 6, 2, 8, 0));
+        assert_eq!(parse("10:00 x").unwrap().0.at, tokyo(2025, 6, 1, 10, 0));
+        assert_eq!(parse("7:30pm x").unwrap().0.at, tokyo(2025, 6, 1, 19, 30));
+        assert_eq!(parse("tomorrow 8am 起床").unwrap(), (When::once(tokyo(2025, 6, 2, 8, 0)), "起床"));
+        assert_eq!(parse("today 18:00 x").unwrap().0.at, tokyo(2025, 6, 1, 18, 0));
+        // 2025-06-01 は日曜
+        assert_eq!(parse("mon 09:00 x").unwrap().0.at, tokyo(2025, 6, 2, 9, 0));
+        assert!(parse("13pm x").is_err());
+    }
+
+    #[test]
+    fn japanese_natural_language() {
+        assert_eq!(parse("明日8時半に薬を飲む").unwrap(), (When::once(tokyo(2025, 6, 2, 8, 30)), "薬を飲む"));
+        assert_eq!(parse("明日の朝8時 x").unwrap().0.at, tokyo(2025, 6, 2, 8, 0));
+        assert_eq!(parse("今日18時 x").unwrap().0.at, tokyo(2025, 6, 1, 18, 0));
+        let (when, name) = parse("毎朝7時に薬を飲む").unwrap();
+        assert_eq!((when.at, when.recurrence, name), (tokyo(2025, 6, 2, 7, 0), Some(Recurrence::Daily), "薬を飲む"));
+        let (when, _) = parse("毎晩9時に歯みがき").unwrap();
+        assert_eq!((when.at, when.recurrence), (tokyo(2025, 6, 1, 21, 0), Some(Recurrence::Daily)));
+        assert_eq!(parse("平日7時 x").unwrap().0.recurrence, Some(Recurrence::Weekdays));
+    }
+
+    #[test]
+    fn daylight_saving_time() {
+        // 2025-03-09 02:30 は New York に無い
+        let now = Utc.with_ymd_and_hms(2025, 3, 1, 0, 0, 0).unwrap();
+        assert!(parse_when("2025-03-09:02:30:x", now, New_York).unwrap_err().contains("does not exist"));
+        // 2025-11-02 01:30 は2回ある。早い方 (EDT)
+        let (when, _) = parse_when("2025-11-02:01:30:x", now, New_York).unwrap();
+        assert_eq!(when.at, Utc.with_ymd_and_hms(2025, 11, 2, 5, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn parse_at_rejects_trailing_text() {
+        assert_eq!(parse_at("tomorrow 09:00", now(), Tokyo).unwrap().at, tokyo(2025, 6, 2, 9, 0));
+        assert!(parse_at("tomorrow 09:00 extra", now(), Tokyo).is_err());
+        assert!(parse("someday x").unwrap_err().contains("Could not parse the date/time"));
+    }
+}
@@ -1,35 +1,7 @@
-use std::collections::HashSet;
-use std::hash::Hash;
-use std::hash::Hasher;
 use std::sync::Mutex;
 use once_cell::sync::Lazy;
-use serde::{Serialize, Deserialize};
-
-//serverList
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ServerInfo {
-    pub addr: String,
-    pub port: u16,
-}
-
-impl PartialEq for ServerInfo {
-    fn eq(&self, other: &Self) -> bool {
-        self.addr == other.addr
-    }
-}
-
-impl Eq for ServerInfo {}
-impl Hash for ServerInfo {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.addr.hash(state);
-    }
-}
-
-pub static SERVER_LIST: Lazy<Mutex<HashSet<ServerInfo>>> = Lazy::new(|| {
-    Mutex::new(HashSet::new())
-});
-
 
+// サーバー一覧は p2p::registry::PEER_REGISTRY
 
 //AI state
 pub static MY_VEC: Lazy<Mutex<[u8; 14]>> = Lazy::new(|| Mutex::new([0u8; 14]));
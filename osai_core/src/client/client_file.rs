@@ -1,155 +1,93 @@
 //use tauri::Emitter;
-use pnet::packet::MutablePacket;
-use pnet::packet::udp::MutableUdpPacket;
-//use tokio::task;
-
-const END_SIG: u64 = 0xFFFFFFFFFFFFFFFF;
-//const CHUNK_SIZE: usize = 1472 - 8 - 16 - 8 - 2 - 14;
-
-fn build_udp_packet<'a>(
-    buffer: &'a mut [u8],
-    src_port: u16,
-    dst_port: u16,
-    session_id: &[u8;16],
-    chunk: &[u8;8],
-    format_signal: &[u8;2],
-    data_vec: &[u8;14],
-    data: &[u8],
-) -> MutableUdpPacket<'a> {
-    let mut packet = MutableUdpPacket::new(buffer).expect("Failed to create UDP packet");
-    packet.set_source(src_port);
-    packet.set_destination(dst_port);
-    // UDP length: header(8) + payload length
-    packet.set_length((8 + session_id.len() + chunk.len() + format_signal.len() + data_vec.len() + data.len()) as u16);
-    packet.set_checksum(0);
-    let packet_payload = packet.payload_mut();
-
-    let mut offset = 0;
-    packet_payload[offset..offset + 16].copy_from_slice(session_id);
-    offset += 16;
-
-    packet_payload[offset..offset + 8].copy_from_slice(chunk);
-    offset += 8;
-
-    packet_payload[offset..offset + 2].copy_from_slice(format_signal);
-    offset += 2;
-
-    packet_payload[offset..offset + 14].copy_from_slice(data_vec);
-    offset += 14;
-
-    packet_payload[offset..offset + data.len()].copy_from_slice(data);
-
-    packet
+use std::net::SocketAddr;
+use std::path::Path;
+use sha2::{Digest, Sha256};
+
+use crate::client::client::CHUNK_SIZE;
+use crate::client::reliable::{DeliveryReport, ReliableConfig};
+use crate::client::sender::OsaiSender;
+use crate::config::ClientConfig;
+use crate::protocol::file::{is_plain_file_name, FileHeader};
+use crate::protocol::packet::{EmotionVec, Format};
+
+/// ファイルを Format::FILE で送る。
+/// チャンク0 に名前・サイズ・SHA-256 を載せ、残りを信頼モードで送る。
+/// 受信側が保存してハッシュを確認できたら `Ok`
+pub async fn send_file(
+    config: &ClientConfig,
+    path: impl AsRef<Path>,
+    dst: SocketAddr,
+) -> Result<DeliveryReport, String> {
+    let path = path.as_ref();
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or_else(|| format!("Not a file path: {}", path.display()))?;
+    if !is_plain_file_name(&name) {
+        return Err(format!("Unsupported file name: {}", name));
+    }
+
+    // 再送でどのチャンクも送り直せるように全体を読んでおく。大きいと時間がかかるので
+    // 読み込みとハッシュは blocking スレッドで
+    let file_path = path.to_path_buf();
+    let (data, sha256) = tokio::task::spawn_blocking(move || {
+        std::fs::read(&file_path).map(|data| {
+            let sha256: [u8; 32] = Sha256::digest(&data).into();
+            (data, sha256)
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    let header = FileHeader {
+        name,
+        size: data.len() as u64,
+        sha256,
+    }
+    .encode()
+    .map_err(|e| format!("Failed to build file header: {}", e))?;
+
+    let mut chunks: Vec<&[u8]> = vec![&header];
+    chunks.extend(data.chunks(CHUNK_SIZE));
+
+    // 締め切りはチャンク数に合わせて伸ばす
+    let reliable = ReliableConfig::default().scaled_to(chunks.len());
+
+    let sender = OsaiSender::bind(config.bind).await?;
+    println!("Sending {} ({} bytes) to {}", path.display(), data.len(), dst);
+    sender.send_reliable_chunks(dst, Format::FILE, EmotionVec::default(), &chunks, &reliable).await
 }
 
-pub async fn send_text() -> Result<String, String> {
-    use std::net::Ipv4Addr;
-    use pnet::transport::{transport_channel, TransportChannelType::Layer4, TransportProtocol};
-    use pnet::packet::ip::IpNextHeaderProtocols;
-    use tokio::task::spawn;
-    use std::fs::File;
-    use std::io::Read;
-
-    let src_ip = "127.0.0.1";
-    let src_port: u16 = 1234;
-    let dst_ip = "127.0.0.1";
-    let dst_port: u16 = 1234;
-    let filename = "ex.txt";
-
-    let _src_ip: Ipv4Addr = src_ip.parse().map_err(|e| format!("Invalid src_ip: {}", e))?;
-    let dst_ip: Ipv4Addr = dst_ip.parse().map_err(|e| format!("Invalid dst_ip: {}", e))?;
-
-    // ここで固定値や適当な値を用意
-    let session_id = [0u8; 16];       // 本当はランダム等にする
-    let format_signal = [0u8; 2];     // 適宜設定
-    let data_vec = [0u8; 14];         // 適宜設定
-
-    // filenameをcloneしてmoveに持ち込む
-    let filename_clone = filename;
-
-    //本当はasyncのほうが良いのかもしれないが
-    spawn( async move {
-        let protocol = TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp);
-        println!("create protocol");
-        let (mut tx, _) = match transport_channel(4096, Layer4(protocol)) {
-            Ok((tx, rx)) => {
-                (tx, rx)
-            }
-            Err(_e) => {
-                println!("thread error");
-                return;
-            }
-        };
-
-        let mut file = match File::open(&filename_clone) {
-            Ok(f) => f,
-            Err(_e) => {
-                return;
-            }
-        };
-
-        let mut buffer = [0u8; 1024];
-        let mut chunk_id = 0u32;
-
-        loop {
-            let read_bytes = match file.read(&mut buffer) {
-                Ok(n) => n,
-                Err(_e) => {
-                    return;
-                }
-            };
-
-            if read_bytes == 0 {
-                break;
-            }
-
-            // chunkを8バイトの配列に格納（例としてu64をBEで）
-            let chunk = (chunk_id as u64).to_be_bytes();
-
-            // 送信データ（ファイルの一部）
-            let data = &buffer[..read_bytes];
-
-            // UDPペイロードサイズ計算
-            let payload_len = 16 + 8 + 2 + 14 + data.len();
-            let mut packet_buffer = vec![0u8; 8 + payload_len];  // UDPヘッダー8バイト + payload
-
-            let mut packet = build_udp_packet(
-                &mut packet_buffer,
-                src_port,
-                dst_port,
-                &session_id,
-                &chunk,
-                &format_signal,
-                &data_vec,
-                data,
-            );
-
-            println!("Sending packet: {:?}", packet);
-            println!("To destination: {}", dst_ip);
-
-
-            if let Err(_e) = tx.send_to(packet, std::net::IpAddr::V4(dst_ip)) {
-                return;
-            }
-
-            chunk_id += 1;
-        }
-
-        // 終了パケット送信（chunk=END_SIG、dataなし）
-        let chunk = END_SIG.to_be_bytes();
-        let mut end_packet_buffer = vec![0u8; 8 + 16 + 8 + 2 + 14];
-        let mut end_packet = build_udp_packet(
-            &mut end_packet_buffer,
-            src_port,
-            dst_port,
-            &session_id,
-            &chunk,
-            &format_signal,
-            &data_vec,
-            &[],
-        );
-        let _ = tx.send_to(end_packet, std::net::IpAddr::V4(dst_ip));
-    });
-
-    Ok(format!("Started sending file: {}", filename))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+    use crate::config::OsaiConfig;
+    use crate::runtime::RunningServices;
+    use crate::server::server::start_server;
+
+    #[tokio::test]
+    async fn file_larger_than_one_window_arrives_intact() {
+        let config = Arc::new(OsaiConfig::loopback());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(start_server(Arc::clone(&config), RunningServices::default(), shutdown.clone()));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let window = ReliableConfig::default().window as usize;
+        let data: Vec<u8> = (0..CHUNK_SIZE * (window + 50) + 123).map(|i| (i % 253) as u8).collect();
+        let share_dir = config.paths.share_dir.clone().unwrap();
+        let source = share_dir.parent().unwrap().join("big.bin");
+        std::fs::write(&source, &data).unwrap();
+
+        let report = send_file(&config.client, &source, config.server.bind_addr()).await.unwrap();
+        assert_eq!(report.chunks as usize, window + 52);
+        // ACK が返った時点でハッシュを確かめて本来の名前になっている
+        assert_eq!(std::fs::read(share_dir.join("big.bin")).unwrap(), data);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(share_dir.parent().unwrap());
+    }
 }
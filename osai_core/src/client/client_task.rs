@@ -0,0 +1,169 @@
+// タスクを相手のノードに登録する (Format::TASK)、鳴っている通知に ack する (Format::TASK_ACK)、変更・削除・一覧 (protocol::task_ops)。
+// 信頼モードで送ったあと、同じソケットで受付・拒否 (Format::TASK_REPLY) を待つ。
+// caregiver への知らせ (Format::TASK_ALERT) は届けば終わり
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::client::reliable::ReliableConfig;
+use crate::client::sender::OsaiSender;
+use crate::config::ClientConfig;
+use crate::protocol::packet::{EmotionVec, Format, OsaiPacket, MAX_PACKET_SIZE};
+use crate::protocol::task_alert::{TaskAck, TaskAlert};
+use crate::protocol::task_ops::encode_body;
+use crate::protocol::task_reply::TaskReply;
+use crate::security::handshake::credential_for;
+
+/// 配達できてからこれだけ待っても返事が無ければエラー
+pub const TASK_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `spec` は `YYYY-MM-DD:HH:MM:name`。検証は受信側がして、断られたら理由が `TaskReply::error` に入る
+pub async fn send_task(config: &ClientConfig, dst: SocketAddr, spec: &str) -> Result<TaskReply, String> {
+    send_and_wait_reply(config, dst, Format::TASK, spec.trim().as_bytes()).await
+}
+
+/// 相手のノードで鳴っている通知に ack・snooze する。鳴っていなければ `TaskReply::error` に理由が入る
+pub async fn send_task_ack(config: &ClientConfig, dst: SocketAddr, ack: &TaskAck) -> Result<TaskReply, String> {
+    let body = ack.encode().map_err(|e| e.to_string())?;
+    send_and_wait_reply(config, dst, Format::TASK_ACK, &body).await
+}
+
+/// TASK_EDIT / TASK_DELETE / TASK_DONE / TASK_LIST (本文は protocol::task_ops)
+pub async fn send_task_op<T: Serialize>(config: &ClientConfig, dst: SocketAddr, format: Format, body: &T) -> Result<TaskReply, String> {
+    let body = encode_body(body).map_err(|e| e.to_string())?;
+    send_and_wait_reply(config, dst, format, &body).await
+}
+
+/// 通知に応答が無いことを caregiver に知らせる。届いたら Ok
+pub async fn send_task_alert(config: &ClientConfig, dst: SocketAddr, alert: &TaskAlert) -> Result<(), String> {
+    let body = alert.encode().map_err(|e| e.to_string())?;
+    let sender = OsaiSender::bind(config.bind).await?;
+    sender
+        .send_reliable(dst, Format::TASK_ALERT, EmotionVec::default(), &body, &ReliableConfig::default())
+        .await?;
+    Ok(())
+}
+
+/// 信頼モードで送り、同じソケットで TASK_REPLY を待つ
+async fn send_and_wait_reply(config: &ClientConfig, dst: SocketAddr, format: Format, body: &[u8]) -> Result<TaskReply, String> {
+    let sender = OsaiSender::bind(config.bind).await?;
+    let report = sender
+        .send_reliable(dst, format, EmotionVec::default(), body, &ReliableConfig::default())
+        .await?;
+    // 完了の ACK が落ちて再送している間に返事が来ていることがある
+    if let Some(reply) = report.replies.iter().find_map(|packet| task_reply(packet, dst)) {
+        return reply;
+    }
+
+    // 返事は送ったものと同じ鍵で封をされてくる (ハンドシェイク済みの鍵は保存されている)
+    let key = credential_for(sender.socket(), dst)
+        .await?
+        .map(|c| c.session_key(report.session_id));
+    let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+    let wait = async {
+        loop {
+            let (len, from) = sender
+                .socket()
+                .recv_from(&mut buf)
+                .await
+                .map_err(|e| format!("Recv error: {}", e))?;
+            if from != dst {
+                continue;
+            }
+            let packet = match OsaiPacket::decode(&buf[..len]) {
+                Ok(packet) if packet.session_id == report.session_id => packet,
+                _ => continue,
+            };
+            let packet = match &key {
+                Some(key) => match key.open(&packet) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                },
+                None => packet,
+            };
+            // 重複した END への ACK などは飛ばす
+            if let Some(reply) = task_reply(&packet, dst) {
+                return reply;
+            }
+        }
+    };
+    tokio::time::timeout(TASK_REPLY_TIMEOUT, wait)
+        .await
+        .map_err(|_| format!("Delivered to {} but no reply came within {:?}", dst, TASK_REPLY_TIMEOUT))?
+}
+
+/// TASK_REPLY でなければ None
+fn task_reply(packet: &OsaiPacket, dst: SocketAddr) -> Option<Result<TaskReply, String>> {
+    if packet.format != Format::TASK_REPLY {
+        return None;
+    }
+    Some(TaskReply::decode(&packet.payload).map_err(|e| format!("Bad task reply from {}: {}", dst, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::net::UdpSocket;
+    use tokio_util::sync::CancellationToken;
+    use crate::config::OsaiConfig;
+    use crate::protocol::ack::{AckBody, AckStatus};
+    use crate::runtime::RunningServices;
+    use crate::server::server::start_server;
+    use crate::IOT::task::TaskFilter;
+    use crate::IOT::task_store::TaskStore;
+
+    /// クライアントとサーバーの間に入り、サーバーからの最初の完了 ACK だけを落とす
+    async fn drop_first_complete_ack(server: SocketAddr, dropped: Arc<AtomicBool>) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let proxy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let mut client = None;
+            let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+            loop {
+                let (len, from) = proxy.recv_from(&mut buf).await.unwrap();
+                if from != server {
+                    client = Some(from);
+                    proxy.send_to(&buf[..len], server).await.unwrap();
+                    continue;
+                }
+                let complete = OsaiPacket::decode(&buf[..len])
+                    .ok()
+                    .filter(|packet| packet.format == Format::ACK)
+                    .and_then(|packet| AckBody::decode(&packet.payload).ok())
+                    .is_some_and(|ack| ack.status == AckStatus::Complete);
+                if complete && !dropped.swap(true, Ordering::SeqCst) {
+                    continue;
+                }
+                if let Some(client) = client {
+                    proxy.send_to(&buf[..len], client).await.unwrap();
+                }
+            }
+        });
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn reply_survives_a_lost_complete_ack() {
+        let config = Arc::new(OsaiConfig::loopback());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(start_server(Arc::clone(&config), RunningServices::default(), shutdown.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let dropped = Arc::new(AtomicBool::new(false));
+        let (proxy, proxy_task) = drop_first_complete_ack(config.server.bind_addr(), Arc::clone(&dropped)).await;
+
+        let reply = send_task(&config.client, proxy, "2099-01-01:09:00:water the plants").await.unwrap();
+        assert!(dropped.load(Ordering::SeqCst));
+        assert!(reply.accepted, "{:?}", reply.error);
+        // 再送した END は受信側で重複として扱われ、タスクは1つだけ
+        let tasks = TaskStore::new(&config.paths.task_file).list(&TaskFilter::default()).unwrap();
+        assert_eq!(tasks.len(), 1);
+
+        proxy_task.abort();
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(config.paths.share_dir.as_ref().unwrap().parent().unwrap());
+    }
+}
@@ -1,2 +1,8 @@
 pub mod client;
 pub mod client_file;
+pub mod client_task;
+pub mod ping;
+pub mod reliable;
+pub mod sender;
+#[cfg(feature = "raw-socket")]
+pub mod raw;
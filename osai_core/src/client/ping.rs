@@ -0,0 +1,202 @@
+// ping。ECHO の request を1つずつ送り、同じ session_id の reply が返るまでの時間を測る。
+// 鍵が設定されていれば封をして送り、封をされた reply だけを信じる
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::client::sender::OsaiSender;
+use crate::config::ClientConfig;
+use crate::protocol::echo::{EchoBody, EchoKind};
+use crate::protocol::packet::{Format, OsaiPacket, SessionId, MAX_PACKET_SIZE};
+use crate::security::context::encode_packet;
+use crate::security::handshake::credential_for;
+
+#[derive(Debug, Clone)]
+pub struct PingConfig {
+    pub count: u32,
+    /// request を送る間隔
+    pub interval: Duration,
+    /// これを過ぎても reply が無ければ失ったとみなす
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        PingConfig {
+            count: 4,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PingReport {
+    pub dst: SocketAddr,
+    pub sent: u32,
+    /// 返ってきた reply の RTT (送った順)
+    pub rtts: Vec<Duration>,
+}
+
+impl PingReport {
+    pub fn received(&self) -> u32 {
+        self.rtts.len() as u32
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        100.0 * (self.sent - self.received()) as f64 / self.sent as f64
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.rtts.len() as u32)
+    }
+}
+
+impl fmt::Display for PingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} sent, {} received, {:.0}% loss",
+            self.dst,
+            self.sent,
+            self.received(),
+            self.loss_percent()
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min(), self.avg(), self.max()) {
+            write!(
+                f,
+                ", rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+                min.as_secs_f64() * 1000.0,
+                avg.as_secs_f64() * 1000.0,
+                max.as_secs_f64() * 1000.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `dst` の OSAI サーバーに ping を送る。reply が1つも無くても Ok (loss 100%)
+pub async fn ping(config: &ClientConfig, dst: SocketAddr, ping: &PingConfig) -> Result<PingReport, String> {
+    let sender = OsaiSender::bind(config.bind).await?;
+    let socket = sender.socket();
+    let mut report = PingReport { dst, sent: 0, rtts: Vec::new() };
+    let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+
+    for seq in 0..ping.count {
+        let session_id = SessionId::random();
+        let key = credential_for(socket, dst).await?.map(|c| c.session_key(session_id));
+        let bytes = encode_packet(&EchoBody::request(seq).into_packet(session_id), key.as_ref())?;
+
+        let started = Instant::now();
+        socket
+            .send_to(&bytes, dst)
+            .await
+            .map_err(|e| format!("Failed to send ping to {}: {}", dst, e))?;
+        report.sent += 1;
+
+        loop {
+            let remaining = ping.timeout.saturating_sub(started.elapsed());
+            let (len, from) = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+                Ok(Ok(received)) => received,
+                Ok(Err(e)) => return Err(format!("Recv error: {}", e)),
+                Err(_) => {
+                    println!("Request timed out: seq={}", seq);
+                    break;
+                }
+            };
+            if from != dst {
+                continue;
+            }
+            let packet = match OsaiPacket::decode(&buf[..len]) {
+                Ok(packet) if packet.session_id == session_id => packet,
+                // 前の request への遅れた reply など
+                _ => continue,
+            };
+            let packet = match &key {
+                Some(key) => match key.open(&packet) {
+                    Ok(packet) => packet,
+                    Err(_) => continue,
+                },
+                None => packet,
+            };
+            if packet.format != Format::ECHO {
+                continue;
+            }
+            match EchoBody::decode(&packet.payload) {
+                Ok(EchoBody { kind: EchoKind::Reply, seq: reply_seq }) if reply_seq == seq => {
+                    let rtt = started.elapsed();
+                    println!("Reply from {}: seq={} time={:.2} ms", dst, seq, rtt.as_secs_f64() * 1000.0);
+                    report.rtts.push(rtt);
+                    break;
+                }
+                _ => continue,
+            }
+        }
+
+        if seq + 1 < ping.count {
+            tokio::time::sleep(ping.interval.saturating_sub(started.elapsed())).await;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_util::sync::CancellationToken;
+    use crate::config::OsaiConfig;
+    use crate::runtime::RunningServices;
+    use crate::server::server::start_server;
+
+    #[test]
+    fn report_statistics() {
+        let report = PingReport {
+            dst: "127.0.0.1:8080".parse().unwrap(),
+            sent: 4,
+            rtts: vec![Duration::from_millis(3), Duration::from_millis(1), Duration::from_millis(2)],
+        };
+        assert_eq!(report.received(), 3);
+        assert_eq!(report.loss_percent(), 25.0);
+        assert_eq!(report.min(), Some(Duration::from_millis(1)));
+        assert_eq!(report.avg(), Some(Duration::from_millis(2)));
+        assert_eq!(report.max(), Some(Duration::from_millis(3)));
+        assert_eq!(PingReport { sent: 0, rtts: Vec::new(), ..report }.loss_percent(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn running_server_answers_every_ping() {
+        let config = Arc::new(OsaiConfig::loopback());
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(start_server(Arc::clone(&config), RunningServices::default(), shutdown.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let settings = PingConfig {
+            count: 5,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_secs(1),
+        };
+        let report = ping(&config.client, config.server.bind_addr(), &settings).await.unwrap();
+        assert_eq!(report.sent, 5);
+        assert_eq!(report.received(), 5);
+        assert_eq!(report.loss_percent(), 0.0);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(config.paths.share_dir.as_ref().unwrap().parent().unwrap());
+    }
+}
@@ -0,0 +1,84 @@
+// raw ソケット (pnet) での送信。root / CAP_NET_RAW が必要。
+// UDP ヘッダーを自分で書くので送信元ポートを自由に指定できる。通常は client::sender を使う
+use std::net::{IpAddr, SocketAddr};
+use pnet::packet::MutablePacket;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::udp::MutableUdpPacket;
+use pnet::transport::{transport_channel, TransportChannelType::Layer4, TransportProtocol, TransportSender};
+
+use crate::client::client::CHUNK_SIZE;
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, PacketError, SessionId};
+use crate::security::context::SECURITY;
+
+/// UDPヘッダー(8バイト)の後ろに OsaiPacket をエンコードする。
+/// `buffer` は `8 + packet.encoded_len()` 以上必要
+pub fn build_udp_packet<'a>(
+    buffer: &'a mut [u8],
+    src_port: u16,
+    dst_port: u16,
+    packet: &OsaiPacket,
+) -> Result<MutableUdpPacket<'a>, PacketError> {
+    let mut udp = MutableUdpPacket::new(buffer).ok_or(PacketError::TooShort { len: 0 })?;
+    udp.set_source(src_port);
+    udp.set_destination(dst_port);
+    // UDP length: header(8) + payload length
+    udp.set_length((8 + packet.encoded_len()) as u16);
+    udp.set_checksum(0);
+    packet.encode_into(udp.payload_mut())?;
+    Ok(udp)
+}
+
+pub struct RawSender {
+    tx: TransportSender,
+    src_port: u16,
+}
+
+impl RawSender {
+    pub fn new(src_port: u16) -> Result<Self, String> {
+        let protocol = TransportProtocol::Ipv4(IpNextHeaderProtocols::Udp);
+        let (tx, _) = transport_channel(4096, Layer4(protocol))
+            .map_err(|e| format!("Failed to create channel: {e}"))?;
+        Ok(RawSender { tx, src_port })
+    }
+
+    pub fn send_packet(&mut self, packet: &OsaiPacket, dst_ip: IpAddr, dst_port: u16) -> Result<(), String> {
+        let mut buffer = vec![0u8; 8 + packet.encoded_len()];
+        let udp = build_udp_packet(&mut buffer, self.src_port, dst_port, packet)
+            .map_err(|e| format!("Failed to build packet: {e}"))?;
+        self.tx.send_to(udp, dst_ip)
+            .map_err(|e| format!("Error sending packet: {e}"))?;
+        Ok(())
+    }
+
+    /// CHUNK_SIZE ごとに分けて送り、最後に END を送る。
+    /// 返信を受けられないのでハンドシェイクはせず、済んでいる鍵か PSK があれば封をする
+    pub fn send_message(
+        &mut self,
+        dst_ip: IpAddr,
+        dst_port: u16,
+        format: Format,
+        data_vec: EmotionVec,
+        payload: &[u8],
+    ) -> Result<SessionId, String> {
+        let session_id = SessionId::random();
+        let key = SECURITY
+            .read()
+            .unwrap()
+            .outbound_credential(SocketAddr::new(dst_ip, dst_port))
+            .map(|c| c.session_key(session_id));
+        let seal = |packet: OsaiPacket| match &key {
+            Some(key) => key.seal(&packet).map_err(|e| format!("Failed to seal packet: {e}")),
+            None => Ok(packet),
+        };
+        let chunks: Vec<&[u8]> = payload.chunks(CHUNK_SIZE).collect();
+
+        for (chunk_id, data_chunk) in chunks.iter().enumerate() {
+            let packet = OsaiPacket::new(session_id, ChunkId::new(chunk_id as u32), format, data_vec, data_chunk.to_vec());
+            self.send_packet(&seal(packet)?, dst_ip, dst_port)?;
+        }
+
+        let end = OsaiPacket::end_of(session_id, format, data_vec, chunks.len() as u32);
+        self.send_packet(&seal(end)?, dst_ip, dst_port)?;
+        Ok(session_id)
+    }
+}
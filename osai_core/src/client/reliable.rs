@@ -0,0 +1,448 @@
+// 信頼モードの送信。
+// チャンクに ACK 要求ビットを立てて window 個ずつ送り、そのたびに END で受信側の選択的 ACK/NACK を聞く。
+// 抜けたチャンクを先に再送し、空いた分だけ新しいチャンクを送る。ACK が来なければバックオフしながら聞き直す。
+// 受信側のピアごとのレート制限を超えないように max_rate で間隔を空ける。
+// 鍵が設定されていればチャンクは送るたびに封をし、ACK も封をされたものだけを信じる。
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+use crate::client::client::CHUNK_SIZE;
+use crate::protocol::ack::{AckBody, AckStatus};
+use crate::protocol::packet::{
+    ChunkId, EmotionVec, Format, OsaiPacket, SessionId, MAX_PACKET_SIZE,
+};
+use crate::security::context::{encode_packet, SessionKey};
+use crate::security::handshake::credential_for;
+
+#[derive(Debug, Clone)]
+pub struct ReliableConfig {
+    /// 最初の再送までの待ち時間
+    pub initial_timeout: Duration,
+    /// 再送間隔の上限 (倍々で伸ばす)
+    pub max_timeout: Duration,
+    /// これを過ぎたら諦める
+    pub deadline: Duration,
+    /// ACK を待たずに送るチャンク数 (再送を含む)
+    pub window: u32,
+    /// 1秒あたりに送るパケット数の上限。受信側のピアごとの制限 (RateLimits::per_peer) より低くする
+    pub max_rate: u32,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        ReliableConfig {
+            initial_timeout: Duration::from_millis(300),
+            max_timeout: Duration::from_secs(3),
+            deadline: Duration::from_secs(15),
+            window: 256,
+            max_rate: 4000,
+        }
+    }
+}
+
+impl ReliableConfig {
+    /// `chunks` 個を max_rate で送りきる時間の2倍を deadline に足す (ファイルなど大きいもの)
+    pub fn scaled_to(self, chunks: usize) -> Self {
+        let sending = Duration::from_secs_f64(chunks as f64 / self.max_rate.max(1) as f64);
+        ReliableConfig { deadline: self.deadline + sending * 2, ..self }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeliveryReport {
+    pub session_id: SessionId,
+    pub chunks: u32,
+    pub bytes: usize,
+    /// 再送したチャンク数 (END を除く)
+    pub retransmissions: u32,
+    pub elapsed: Duration,
+    /// ACK を待つ間に届いた同じセッションの ACK 以外のパケット (封は開けてある)。
+    /// 最後の ACK を落とすと、受信側の返事が ACK より先に届く
+    pub replies: Vec<OsaiPacket>,
+}
+
+/// `payload` を分割して送り、受信側が全チャンクを受け取るまで待つ
+pub async fn send_reliable(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    format: Format,
+    data_vec: EmotionVec,
+    payload: &[u8],
+    config: &ReliableConfig,
+) -> Result<DeliveryReport, String> {
+    // 空のメッセージでも ACK 要求を伝えるため最低1チャンクは送る
+    let mut chunks: Vec<&[u8]> = payload.chunks(CHUNK_SIZE).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    send_reliable_chunks(socket, dst, format, data_vec, &chunks, config).await
+}
+
+/// 分割済みのチャンクを信頼モードで送る。各チャンクは CHUNK_SIZE 以下
+pub async fn send_reliable_chunks(
+    socket: &UdpSocket,
+    dst: SocketAddr,
+    format: Format,
+    data_vec: EmotionVec,
+    chunks: &[&[u8]],
+    config: &ReliableConfig,
+) -> Result<DeliveryReport, String> {
+    let session_id = SessionId::random();
+    let started = Instant::now();
+
+    if chunks.is_empty() {
+        return Err("nothing to send".to_string());
+    }
+    let total = u32::try_from(chunks.len()).map_err(|_| "payload is too large".to_string())?;
+    let bytes: usize = chunks.iter().map(|c| c.len()).sum();
+
+    let key = credential_for(socket, dst).await?.map(|c| c.session_key(session_id));
+
+    let packets: Vec<OsaiPacket> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            OsaiPacket::new(session_id, ChunkId::reliable(i as u32), format, data_vec, data.to_vec())
+        })
+        .collect();
+    let end = OsaiPacket::end_of(session_id, format, data_vec, total);
+
+    let mut transmitter = Transmitter {
+        socket,
+        dst,
+        packets: &packets,
+        end: &end,
+        key: key.as_ref(),
+        pacer: Pacer::new(config.max_rate),
+    };
+    let window = config.window.max(1);
+    // これより前のチャンクは一度は送った
+    let mut next = window.min(total);
+    transmitter.send((0..next).collect()).await?;
+
+    let mut retransmissions = 0u32;
+    let mut timeout = config.initial_timeout;
+    let mut last_ack: Option<AckBody> = None;
+    let mut replies = Vec::new();
+    let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+
+    loop {
+        let elapsed = started.elapsed();
+        if elapsed >= config.deadline {
+            let held = last_ack
+                .as_ref()
+                .map(|ack| next - ack.missing(next).len() as u32)
+                .unwrap_or(0);
+            return Err(format!(
+                "Delivery to {} timed out after {:?}: {} of {} chunks acknowledged",
+                dst, elapsed, held, total
+            ));
+        }
+        let wait = timeout.min(config.deadline - elapsed);
+
+        let ack = match tokio::time::timeout(wait, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, from))) => {
+                if from != dst {
+                    continue;
+                }
+                let packet = match OsaiPacket::decode(&buf[..len]) {
+                    Ok(packet) if packet.session_id == session_id => packet,
+                    // 別セッションの ACK などは無視
+                    _ => continue,
+                };
+                // 封をして送ったなら平文の ACK は偽物かもしれないので信じない
+                let packet = match &key {
+                    Some(key) => match key.open(&packet) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            eprintln!("Bad ack from {}: {}", from, e);
+                            continue;
+                        }
+                    },
+                    None => packet,
+                };
+                if packet.format != Format::ACK {
+                    replies.push(packet);
+                    continue;
+                }
+                match AckBody::decode(&packet.payload) {
+                    Ok(ack) => Some(ack),
+                    Err(e) => {
+                        eprintln!("Bad ack from {}: {}", from, e);
+                        continue;
+                    }
+                }
+            }
+            Ok(Err(e)) => return Err(format!("Recv error: {}", e)),
+            Err(_) => None,
+        };
+
+        match ack {
+            Some(ack) => match ack.status {
+                AckStatus::Complete => {
+                    return Ok(DeliveryReport {
+                        session_id,
+                        chunks: total,
+                        bytes,
+                        retransmissions,
+                        elapsed: started.elapsed(),
+                        replies,
+                    });
+                }
+                AckStatus::Failed => {
+                    return Err(format!("Receiver {} gave up on session {}", dst, session_id));
+                }
+                AckStatus::Partial => {
+                    // 抜けを先に埋め、窓に空きがあれば続きを送る
+                    let mut indices = ack.missing(next);
+                    indices.truncate(window as usize);
+                    retransmissions += indices.len() as u32;
+                    let fresh = (window - indices.len() as u32).min(total - next);
+                    indices.extend(next..next + fresh);
+                    next += fresh;
+                    transmitter.send(indices).await?;
+                    timeout = config.initial_timeout;
+                    last_ack = Some(ack);
+                }
+            },
+            None => {
+                // ACK が来ない。END だけ送り直せば受信側が抜けを NACK で返してくる
+                transmitter.send(Vec::new()).await?;
+                timeout = (timeout * 2).min(config.max_timeout);
+            }
+        }
+    }
+}
+
+/// チャンクと END を送る。封のタイムスタンプが古くならないよう、再送のたびにエンコードし直す
+struct Transmitter<'a> {
+    socket: &'a UdpSocket,
+    dst: SocketAddr,
+    packets: &'a [OsaiPacket],
+    end: &'a OsaiPacket,
+    key: Option<&'a SessionKey>,
+    pacer: Pacer,
+}
+
+impl Transmitter<'_> {
+    /// `indices` のチャンクを送り、最後に END を送る
+    async fn send(&mut self, indices: Vec<u32>) -> Result<(), String> {
+        for i in indices {
+            let bytes = encode_packet(&self.packets[i as usize], self.key)?;
+            self.pacer.wait().await;
+            self.socket.send_to(&bytes, self.dst).await
+                .map_err(|e| format!("Failed to send chunk {}: {}", i, e))?;
+        }
+        self.pacer.wait().await;
+        self.socket.send_to(&encode_packet(self.end, self.key)?, self.dst).await
+            .map_err(|e| format!("Failed to send end: {}", e))?;
+        Ok(())
+    }
+}
+
+/// 送ったパケット数が max_rate を超えそうなら待つ。タイマーの粒度より細かくは待たない
+struct Pacer {
+    interval: Duration,
+    next: Instant,
+}
+
+/// これより先の送信予定まで待つときだけ sleep する
+const PACING_SLACK: Duration = Duration::from_millis(5);
+
+impl Pacer {
+    fn new(max_rate: u32) -> Self {
+        Pacer {
+            interval: Duration::from_secs(1) / max_rate.max(1),
+            next: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self) {
+        let now = Instant::now();
+        // しばらく送っていなければ溜めずにそこから数え直す
+        self.next = self.next.max(now) + self.interval;
+        let ahead = self.next - now;
+        if ahead > PACING_SLACK {
+            tokio::time::sleep(ahead - PACING_SLACK).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::security::context::SECURITY;
+    use crate::server::reassembly::{Reassembler, ReassemblyEvent, ReassemblyLimits};
+
+    fn fast() -> ReliableConfig {
+        ReliableConfig {
+            initial_timeout: Duration::from_millis(20),
+            max_timeout: Duration::from_millis(40),
+            deadline: Duration::from_millis(300),
+            ..ReliableConfig::default()
+        }
+    }
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    /// 受信ループの代わり。`drop_once` の番号は最初の1回だけ捨てる。
+    /// 揃ったメッセージと、チャンク番号ごとに届いた回数と、END と END の間に届いたチャンク数を返す
+    async fn receive(socket: UdpSocket, mut drop_once: Vec<u32>) -> (Vec<u8>, HashMap<u32, u32>, Vec<u32>) {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let mut seen = HashMap::new();
+        let mut rounds = vec![0];
+        let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = OsaiPacket::decode(&buf[..len]).unwrap();
+            let (packet, key) = SECURITY.read().unwrap().open_incoming(packet).unwrap();
+            if packet.chunk.is_end() {
+                rounds.push(0);
+            }
+            if let Some(index) = packet.chunk.index() {
+                *rounds.last_mut().unwrap() += 1;
+                *seen.entry(index).or_insert(0) += 1;
+                if let Some(pos) = drop_once.iter().position(|i| *i == index) {
+                    drop_once.remove(pos);
+                    continue;
+                }
+            }
+            let session_id = packet.session_id;
+            let (ack, message) = match reassembler.push(from, packet, key.as_ref(), Instant::now()) {
+                ReassemblyEvent::Complete { message, ack, .. } => (ack, Some(message)),
+                ReassemblyEvent::Incomplete { ack, .. } => (Some(ack), None),
+                _ => (None, None),
+            };
+            if let Some(ack) = ack {
+                let reply = encode_packet(&ack.into_packet(session_id), key.as_ref()).unwrap();
+                socket.send_to(&reply, from).await.unwrap();
+            }
+            if let Some(message) = message {
+                return (message.payload, seen, rounds);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_chunks_are_resent() {
+        let receiver = bind().await;
+        let dst = receiver.local_addr().unwrap();
+        let received = tokio::spawn(receive(receiver, vec![1, 3]));
+
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 4 + 7).map(|i| i as u8).collect();
+        let sender = bind().await;
+        let report = send_reliable(&sender, dst, Format::TEXT, EmotionVec::default(), &payload, &fast())
+            .await
+            .unwrap();
+
+        let (message, seen, _) = received.await.unwrap();
+        assert_eq!(message, payload);
+        assert_eq!(report.chunks, 5);
+        assert_eq!(report.retransmissions, 2);
+        // 捨てたチャンクだけが2回届いた
+        let mut resent: Vec<u32> = seen.iter().filter(|(_, n)| **n > 1).map(|(i, _)| *i).collect();
+        resent.sort();
+        assert_eq!(resent, [1, 3]);
+    }
+
+    #[tokio::test]
+    async fn chunks_wait_for_ack_progress() {
+        let receiver = bind().await;
+        let dst = receiver.local_addr().unwrap();
+        let received = tokio::spawn(receive(receiver, vec![2, 9, 17]));
+
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 20).map(|i| (i % 251) as u8).collect();
+        let config = ReliableConfig { window: 4, ..fast() };
+        let sender = bind().await;
+        let report = send_reliable(&sender, dst, Format::TEXT, EmotionVec::default(), &payload, &config)
+            .await
+            .unwrap();
+
+        let (message, seen, rounds) = received.await.unwrap();
+        assert_eq!(message, payload);
+        assert_eq!(report.retransmissions, 3);
+        assert!(seen.values().all(|n| *n <= 2));
+        // END で ACK を聞くまでに送るのは window 個まで
+        assert!(rounds.iter().all(|n| *n <= 4), "{:?}", rounds);
+        assert!(rounds.len() >= 20 / 4);
+    }
+
+    #[tokio::test]
+    async fn sending_is_paced() {
+        let receiver = bind().await;
+        let dst = receiver.local_addr().unwrap();
+        let received = tokio::spawn(receive(receiver, Vec::new()));
+
+        let payload = vec![7u8; CHUNK_SIZE * 50];
+        let config = ReliableConfig { max_rate: 1000, deadline: Duration::from_secs(2), ..fast() };
+        let sender = bind().await;
+        let report = send_reliable(&sender, dst, Format::TEXT, EmotionVec::default(), &payload, &config)
+            .await
+            .unwrap();
+        assert_eq!(received.await.unwrap().0, payload);
+        // 50 チャンクと END を 1000 パケット/秒で送ると 50ms 程度かかる (タイマーの粒度の分は早くてよい)
+        assert!(report.elapsed >= Duration::from_millis(40), "{:?}", report.elapsed);
+    }
+
+    #[test]
+    fn deadline_grows_with_chunk_count() {
+        let base = ReliableConfig::default();
+        assert_eq!(base.clone().scaled_to(0).deadline, base.deadline);
+        let big = base.clone().scaled_to(base.max_rate as usize * 30);
+        assert_eq!(big.deadline, base.deadline + Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn receiver_failure_is_reported() {
+        let receiver = bind().await;
+        let dst = receiver.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+            loop {
+                let (len, from) = receiver.recv_from(&mut buf).await.unwrap();
+                let packet = OsaiPacket::decode(&buf[..len]).unwrap();
+                if packet.chunk.is_end() {
+                    let reply = AckBody::failed(packet.total_chunks()).into_packet(packet.session_id);
+                    receiver.send_to(&reply.encode().unwrap(), from).await.unwrap();
+                }
+            }
+        });
+
+        let sender = bind().await;
+        let error = send_reliable(&sender, dst, Format::TEXT, EmotionVec::default(), b"hello", &fast())
+            .await
+            .unwrap_err();
+        assert!(error.contains("gave up"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn silent_receiver_times_out_after_bounded_retries() {
+        let receiver = bind().await;
+        let dst = receiver.local_addr().unwrap();
+        let ends = tokio::spawn(async move {
+            let mut ends = 0u32;
+            let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+            while let Ok(Ok((len, _))) =
+                tokio::time::timeout(Duration::from_millis(500), receiver.recv_from(&mut buf)).await
+            {
+                if OsaiPacket::decode(&buf[..len]).unwrap().chunk.is_end() {
+                    ends += 1;
+                }
+            }
+            ends
+        });
+
+        let sender = bind().await;
+        let error = send_reliable(&sender, dst, Format::TEXT, EmotionVec::default(), b"hello", &fast())
+            .await
+            .unwrap_err();
+        assert!(error.contains("timed out") && error.contains("0 of 1"), "{}", error);
+        // END を送り直しながら待ち、間隔は max_timeout で頭打ちになる (300ms / 40ms に最初の分を足した程度)
+        let ends = ends.await.unwrap();
+        assert!((3..=12).contains(&ends), "{} END packets", ends);
+    }
+}
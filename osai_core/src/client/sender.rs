@@ -0,0 +1,102 @@
+// 普通の UDP ソケットで OSAI パケットを送る。root / CAP_NET_RAW は不要。
+// 送信元ポートを偽装したいときだけ raw-socket feature の client::raw を使う
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+use crate::client::client::CHUNK_SIZE;
+use crate::client::reliable::{send_reliable, send_reliable_chunks, DeliveryReport, ReliableConfig};
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, SessionId};
+use crate::security::context::encode_packet;
+use crate::security::handshake::credential_for;
+
+pub struct OsaiSender {
+    socket: UdpSocket,
+}
+
+impl OsaiSender {
+    /// `local` に bind する。ポート0ならOSが空きポートを選ぶ
+    pub async fn bind(local: SocketAddr) -> Result<Self, String> {
+        let socket = UdpSocket::bind(local).await
+            .map_err(|e| format!("Failed to bind sender to {}: {}", local, e))?;
+        Ok(OsaiSender { socket })
+    }
+
+    /// 0.0.0.0 の空きポートに bind する
+    pub async fn bind_any() -> Result<Self, String> {
+        Self::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, String> {
+        self.socket.local_addr().map_err(|e| e.to_string())
+    }
+
+    /// ACK などの返信を自分で受けたいとき用
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+
+    pub fn set_broadcast(&self, on: bool) -> Result<(), String> {
+        self.socket.set_broadcast(on)
+            .map_err(|e| format!("Failed to enable broadcast: {}", e))
+    }
+
+    /// そのまま (封をせずに) 送る
+    pub async fn send_packet(&self, packet: &OsaiPacket, dst: SocketAddr) -> Result<(), String> {
+        let bytes = packet.encode().map_err(|e| format!("Failed to build packet: {}", e))?;
+        self.send_bytes(&bytes, dst).await
+    }
+
+    async fn send_bytes(&self, bytes: &[u8], dst: SocketAddr) -> Result<(), String> {
+        self.socket.send_to(bytes, dst).await
+            .map_err(|e| format!("Failed to send to {}: {}", dst, e))?;
+        Ok(())
+    }
+
+    /// CHUNK_SIZE ごとに分けて送り、最後に END を送る (ACK は待たない)。
+    /// 鍵が設定されていれば封をして送る
+    pub async fn send_message(
+        &self,
+        dst: SocketAddr,
+        format: Format,
+        data_vec: EmotionVec,
+        payload: &[u8],
+    ) -> Result<SessionId, String> {
+        let session_id = SessionId::random();
+        let key = credential_for(&self.socket, dst).await?.map(|c| c.session_key(session_id));
+        let chunks: Vec<&[u8]> = payload.chunks(CHUNK_SIZE).collect();
+
+        for (chunk_id, data_chunk) in chunks.iter().enumerate() {
+            let packet = OsaiPacket::new(session_id, ChunkId::new(chunk_id as u32), format, data_vec, data_chunk.to_vec());
+            self.send_bytes(&encode_packet(&packet, key.as_ref())?, dst).await?;
+        }
+
+        // 終了パケット送信
+        let end = OsaiPacket::end_of(session_id, format, data_vec, chunks.len() as u32);
+        self.send_bytes(&encode_packet(&end, key.as_ref())?, dst).await?;
+        Ok(session_id)
+    }
+
+    /// 信頼モードで送り、受信側が全チャンクを受け取るまで待つ
+    pub async fn send_reliable(
+        &self,
+        dst: SocketAddr,
+        format: Format,
+        data_vec: EmotionVec,
+        payload: &[u8],
+        config: &ReliableConfig,
+    ) -> Result<DeliveryReport, String> {
+        send_reliable(&self.socket, dst, format, data_vec, payload, config).await
+    }
+
+    /// 分割済みのチャンクを信頼モードで送る (ファイル転送のヘッダーなど、チャンク境界を決めたいとき)
+    pub async fn send_reliable_chunks(
+        &self,
+        dst: SocketAddr,
+        format: Format,
+        data_vec: EmotionVec,
+        chunks: &[&[u8]],
+        config: &ReliableConfig,
+    ) -> Result<DeliveryReport, String> {
+        send_reliable_chunks(&self.socket, dst, format, data_vec, chunks, config).await
+    }
+}
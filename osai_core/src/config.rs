@@ -0,0 +1,752 @@
+// ノードの設定。TOML ファイルを読み、OSAI_* 環境変数で上書きする。
+//
+// ファイルは `--config <path>`、OSAI_CONFIG、カレントディレクトリの osai.toml の順に探す。
+// 書かなかった項目はデフォルトのまま (これまでハードコードしていた値) なので、
+// 1台で複数ノードを動かすときは port と paths だけ変えればよい。
+//
+// ```toml
+// [server]
+// port = 8081
+// announce_ports = [8080, 8081]
+//
+// [http]
+// port = 1235
+//
+// [paths]
+// share_dir = "node2/share"
+// task_file = "node2/scheduled_tasks.json"
+// ```
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use chrono_tz::Tz;
+
+use crate::IOT::when::local_zone;
+use crate::llm::LlmBackendKind;
+use crate::p2p::node::{DeviceKind, NodeId};
+use crate::server::file_server::get_or_create_share_dir;
+
+pub const DEFAULT_CONFIG_FILE: &str = "osai.toml";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NodeConfig {
+    /// 省略すると paths.node_id_file に保存したID (無ければ作る)
+    pub id: Option<NodeId>,
+    /// 人が読む名前。省略すると osai-<IDの先頭8文字>
+    pub name: Option<String>,
+    /// doll / tablet / sensor / pc
+    pub device: DeviceKind,
+    /// 生存シグナルで知らせる「できること」
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// 生存シグナルの間隔 (秒)
+    pub announce_interval_secs: u64,
+    /// 生存シグナルをブロードキャストするポート。空なら自分の port
+    pub announce_ports: Vec<u16>,
+    /// 受け取るファイルの最大サイズ (MiB)。ヘッダーのサイズがこれを超える転送は断る
+    pub max_file_size_mb: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            announce_interval_secs: 2,
+            announce_ports: Vec::new(),
+            max_file_size_mb: 256,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn announce_interval(&self) -> Duration {
+        Duration::from_secs(self.announce_interval_secs.max(1))
+    }
+
+    pub fn announce_ports(&self) -> Vec<u16> {
+        if self.announce_ports.is_empty() {
+            vec![self.port]
+        } else {
+            self.announce_ports.clone()
+        }
+    }
+
+    /// 受け取るファイルの最大サイズ (バイト)
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size_mb.saturating_mul(1024 * 1024)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// 省略するとローカルIP
+    pub bind: Option<IpAddr>,
+    pub port: u16,
+    /// /tasks.ics でまだ終わっていないタスクを iCalendar で読めるようにする (読むだけ)
+    pub task_feed: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig { bind: None, port: 1234, task_feed: true }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig { bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED), port: 8765 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// 送信に使うローカルアドレス。ポート0ならOSが選ぶ
+    pub bind: SocketAddr,
+    /// 宛先ポートを省略したときのポート
+    pub default_port: u16,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            default_port: 8080,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathsConfig {
+    /// 省略すると実行ファイルの隣の share
+    pub share_dir: Option<PathBuf>,
+    pub task_file: PathBuf,
+    /// 受信した歌詞とタスク通知の書き込み先。
+    /// vocaloid クレートはカレントディレクトリの lyric.txt を読むので、読み上げに使うならそのままにする
+    pub lyric_file: PathBuf,
+    pub identity_file: PathBuf,
+    pub node_id_file: PathBuf,
+    /// ai コマンドの会話の履歴 (<user>.json)
+    pub conversation_dir: PathBuf,
+    /// LLM が使えないときに読み上げる文章 (IOT::templates)。無ければデフォルトを書き出す
+    pub speech_templates: PathBuf,
+}
+
+impl Default for PathsConfig {
+    fn default() -> Self {
+        PathsConfig {
+            share_dir: None,
+            task_file: PathBuf::from("scheduled_tasks.json"),
+            lyric_file: PathBuf::from("lyric.txt"),
+            identity_file: PathBuf::from("osai_identity.key"),
+            node_id_file: PathBuf::from("osai_node_id"),
+            conversation_dir: PathBuf::from("conversations"),
+            speech_templates: PathBuf::from("speech_templates.toml"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// スケジューラーは次の通知の時刻か、タスクが変わったときに起きる。
+    /// 時計の変更や手で編集したファイルのために、予定が無くてもこの秒数ごとに確認する
+    pub poll_interval_secs: u64,
+    /// 何分前に通知するか (reminders_mins が空のとき)
+    pub notify_before_mins: i64,
+    /// タスクに指定が無いときの通知 (何分前か)。例: [30, 5, 0]
+    pub reminders_mins: Vec<u32>,
+    /// 止まっていて過ぎた通知を、この分数以内なら遅れて出す
+    pub grace_mins: u32,
+    /// 通知してからこの分数 ack が無ければもう一度鳴らし、caregiver に知らせる
+    pub ack_timeout_mins: u32,
+    /// ack が無いときに鳴らし直す回数。使い切ったら missed
+    pub max_repeats: u32,
+    /// `snooze` で分数を省いたとき
+    pub snooze_mins: u32,
+    /// 応答が無いときに知らせるピア (名前・ノードID・ip:port)
+    pub caregiver: Option<String>,
+    /// 通知のあと OSAI::listen で返事 (「はい」「あとで」など) を聞く。SpeechToText.sh が要る
+    pub listen_for_ack: bool,
+    /// タスクの時刻を読むタイムゾーン (IANA 名、例: "Asia/Tokyo")。省略するとこのマシンのゾーン
+    pub timezone: Option<Tz>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            poll_interval_secs: 60,
+            notify_before_mins: 5,
+            reminders_mins: Vec::new(),
+            grace_mins: 15,
+            ack_timeout_mins: 5,
+            max_repeats: 2,
+            snooze_mins: 10,
+            caregiver: None,
+            listen_for_ack: false,
+            timezone: None,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs.max(1))
+    }
+
+    pub fn default_reminders(&self) -> Vec<u32> {
+        if self.reminders_mins.is_empty() {
+            vec![u32::try_from(self.notify_before_mins).unwrap_or(0)]
+        } else {
+            self.reminders_mins.clone()
+        }
+    }
+
+    pub fn ack_timeout(&self) -> chrono::Duration {
+        chrono::Duration::minutes(i64::from(self.ack_timeout_mins.max(1)))
+    }
+
+    pub fn grace(&self) -> chrono::Duration {
+        chrono::Duration::minutes(i64::from(self.grace_mins))
+    }
+
+    /// 新しいタスクのタイムゾーン
+    pub fn zone(&self) -> Tz {
+        self.timezone.unwrap_or_else(local_zone)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// この秒数シグナルが届かないピアは一覧から消す
+    pub peer_ttl_secs: u64,
+    /// 255.255.255.255 へのブロードキャスト (同じサブネットだけ)
+    pub broadcast: bool,
+    /// IPv4 マルチキャストグループ。ブロードキャストを通さない AP 向け。"off" で使わない
+    #[serde(deserialize_with = "deserialize_optional")]
+    pub multicast_v4: Option<Ipv4Addr>,
+    /// IPv6 リンクローカルマルチキャストグループ (ff02::/16)
+    #[serde(deserialize_with = "deserialize_optional")]
+    pub multicast_v6: Option<Ipv6Addr>,
+    /// IPv6 マルチキャストのインターフェース番号。0 なら OS に任せる
+    pub interface_v6: u32,
+    /// シグナルをユニキャストで送る相手 (別サブネットのノードなど)
+    pub seeds: Vec<SocketAddr>,
+    /// 起動時にプローブを送って、次のシグナルを待たずにピアを集める
+    pub probe_on_start: bool,
+}
+
+/// マルチキャストのデフォルトグループ (239.255.0.0/16 は組織内スコープ)
+pub const DEFAULT_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 79, 83);
+/// IPv6 は環境によって使えないのでデフォルトでは使わない。使うときの例
+pub const DEFAULT_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x4f53);
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            peer_ttl_secs: 10,
+            broadcast: true,
+            multicast_v4: Some(DEFAULT_MULTICAST_V4),
+            multicast_v6: None,
+            interface_v6: 0,
+            seeds: Vec::new(),
+            probe_on_start: true,
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn peer_ttl(&self) -> Duration {
+        Duration::from_secs(self.peer_ttl_secs.max(1))
+    }
+
+    /// シグナルとプローブの送り先。`ports` は server.announce_ports()
+    pub fn targets(&self, ports: &[u16]) -> Vec<SocketAddr> {
+        let mut targets = Vec::new();
+        for &port in ports {
+            if self.broadcast {
+                targets.push(SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port));
+            }
+            if let Some(group) = self.multicast_v4 {
+                targets.push(SocketAddr::new(IpAddr::V4(group), port));
+            }
+            if let Some(group) = self.multicast_v6 {
+                targets.push(SocketAddr::V6(SocketAddrV6::new(group, port, 0, self.interface_v6)));
+            }
+        }
+        targets.extend(self.seeds.iter().copied());
+        targets
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityConfig {
+    /// 事前共有鍵のパスフレーズ
+    pub psk: Option<String>,
+    /// 信頼する X25519 公開鍵 (hex)
+    pub trusted_peers: Vec<String>,
+    /// 省略すると鍵が無いときだけ open モード
+    pub open_mode: Option<bool>,
+    pub max_clock_skew_secs: u64,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig {
+            psk: None,
+            trusted_peers: Vec::new(),
+            open_mode: None,
+            max_clock_skew_secs: 120,
+        }
+    }
+}
+
+/// ai コマンドと通知の文章を作る LLM (crate::llm)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LlmConfig {
+    /// gemini / openai / offline
+    pub backend: LlmBackendKind,
+    /// 省略するとバックエンドのデフォルト
+    pub model: Option<String>,
+    /// API の URL。openai なら /chat/completions の前まで (例: "http://localhost:8080/v1")
+    pub url: Option<String>,
+    /// API キーを読む環境変数。省略すると GEMINI_API_KEY / OPENAI_API_KEY
+    pub api_key_env: Option<String>,
+    pub system_prompt: String,
+    /// ai コマンドのときだけ system_prompt に足す指示 (vocaloid で読めるように)
+    pub ai_instruction: String,
+    /// gemini の google_search を使う
+    pub web_search: bool,
+    /// つながらない・混んでいるときに何回まで送るか
+    pub max_attempts: u32,
+    pub timeout_secs: u64,
+    /// offline の返事。{query} は問い合わせ
+    pub offline_reply: String,
+    /// ai コマンドで `@name` を省いたときのユーザー (会話の履歴を分ける)
+    pub user: String,
+    /// ai コマンドで一緒に送る前の会話のトークン数 (目安)。0 なら毎回前の会話を忘れる
+    pub history_tokens: usize,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            backend: LlmBackendKind::Gemini,
+            model: None,
+            url: None,
+            api_key_env: None,
+            system_prompt: "Act as a helpful and friendly small AI assistant. Respond concisely and clearly in Japanese."
+                .to_string(),
+            ai_instruction: "ひらがなで返して".to_string(),
+            web_search: true,
+            max_attempts: 3,
+            timeout_secs: 60,
+            offline_reply: "{query}".to_string(),
+            user: "default".to_string(),
+            history_tokens: 2000,
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.max(1))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OsaiConfig {
+    pub node: NodeConfig,
+    pub server: ServerConfig,
+    pub http: HttpConfig,
+    pub websocket: WebSocketConfig,
+    pub client: ClientConfig,
+    pub paths: PathsConfig,
+    pub scheduler: SchedulerConfig,
+    pub discovery: DiscoveryConfig,
+    pub security: SecurityConfig,
+    pub llm: LlmConfig,
+}
+
+impl OsaiConfig {
+    /// `path` (無ければ OSAI_CONFIG、osai.toml) を読み、環境変数で上書きする。
+    /// 明示したファイルが読めなければエラー、osai.toml が無いだけならデフォルト
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let explicit = path
+            .map(Path::to_path_buf)
+            .or_else(|| env::var("OSAI_CONFIG").ok().map(PathBuf::from));
+
+        let mut config = match explicit {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// ファイルは読まずにデフォルト + 環境変数
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Err(e) = config.apply_env() {
+            eprintln!("Ignoring environment overrides: {}", e);
+        }
+        config
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// OSAI_* 環境変数で上書きする
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        self.apply_vars(env_var)
+    }
+
+    /// `var` が返す OSAI_* の値で上書きする (apply_env は環境変数を渡す)
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(v) = var("OSAI_NODE_ID") {
+            self.node.id = Some(v.parse()?);
+        }
+        if let Some(v) = var("OSAI_NODE_NAME") {
+            self.node.name = Some(v);
+        }
+        if let Some(v) = var("OSAI_DEVICE") {
+            self.node.device = v.parse()?;
+        }
+        if let Some(v) = var("OSAI_BIND") {
+            self.server.bind = parse_env("OSAI_BIND", &v)?;
+        }
+        if let Some(v) = var("OSAI_PORT") {
+            self.server.port = parse_env("OSAI_PORT", &v)?;
+        }
+        if let Some(v) = var("OSAI_ANNOUNCE_INTERVAL") {
+            self.server.announce_interval_secs = parse_env("OSAI_ANNOUNCE_INTERVAL", &v)?;
+        }
+        if let Some(v) = var("OSAI_ANNOUNCE_PORTS") {
+            self.server.announce_ports = v
+                .split(',')
+                .map(|p| parse_env("OSAI_ANNOUNCE_PORTS", p.trim()))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(v) = var("OSAI_MAX_FILE_SIZE_MB") {
+            self.server.max_file_size_mb = parse_env("OSAI_MAX_FILE_SIZE_MB", &v)?;
+        }
+        if let Some(v) = var("OSAI_HTTP_BIND") {
+            self.http.bind = Some(parse_env("OSAI_HTTP_BIND", &v)?);
+        }
+        if let Some(v) = var("OSAI_HTTP_PORT") {
+            self.http.port = parse_env("OSAI_HTTP_PORT", &v)?;
+        }
+        if let Some(v) = var("OSAI_WS_BIND") {
+            self.websocket.bind = parse_env("OSAI_WS_BIND", &v)?;
+        }
+        if let Some(v) = var("OSAI_WS_PORT") {
+            self.websocket.port = parse_env("OSAI_WS_PORT", &v)?;
+        }
+        if let Some(v) = var("OSAI_CLIENT_BIND") {
+            self.client.bind = parse_env("OSAI_CLIENT_BIND", &v)?;
+        }
+        if let Some(v) = var("OSAI_SHARE_DIR") {
+            self.paths.share_dir = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("OSAI_TASK_FILE") {
+            self.paths.task_file = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_TIMEZONE") {
+            self.scheduler.timezone = Some(parse_env("OSAI_TIMEZONE", &v)?);
+        }
+        if let Some(v) = var("OSAI_LYRIC_FILE") {
+            self.paths.lyric_file = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_IDENTITY") {
+            self.paths.identity_file = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_CONVERSATION_DIR") {
+            self.paths.conversation_dir = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_SPEECH_TEMPLATES") {
+            self.paths.speech_templates = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_NODE_ID_FILE") {
+            self.paths.node_id_file = PathBuf::from(v);
+        }
+        if let Some(v) = var("OSAI_PEER_TTL") {
+            self.discovery.peer_ttl_secs = parse_env("OSAI_PEER_TTL", &v)?;
+        }
+        if let Some(v) = var("OSAI_BROADCAST") {
+            self.discovery.broadcast = parse_bool(&v);
+        }
+        if let Some(v) = var("OSAI_MULTICAST_V4") {
+            self.discovery.multicast_v4 = parse_optional("OSAI_MULTICAST_V4", &v)?;
+        }
+        if let Some(v) = var("OSAI_MULTICAST_V6") {
+            self.discovery.multicast_v6 = parse_optional("OSAI_MULTICAST_V6", &v)?;
+        }
+        if let Some(v) = var("OSAI_SEEDS") {
+            self.discovery.seeds = v
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| parse_env("OSAI_SEEDS", p))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(v) = var("OSAI_PSK") {
+            self.security.psk = Some(v);
+        }
+        if let Some(v) = var("OSAI_TRUSTED_PEERS") {
+            self.security.trusted_peers = v
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(v) = var("OSAI_OPEN_MODE") {
+            self.security.open_mode = Some(parse_bool(&v));
+        }
+        if let Some(v) = var("OSAI_LLM_BACKEND") {
+            self.llm.backend = v.parse()?;
+        }
+        if let Some(v) = var("OSAI_LLM_MODEL") {
+            self.llm.model = Some(v);
+        }
+        if let Some(v) = var("OSAI_LLM_URL") {
+            self.llm.url = Some(v);
+        }
+        Ok(())
+    }
+
+    /// node.name、無ければノードIDから "osai-xxxxxxxx"
+    pub fn node_name(&self) -> String {
+        match (&self.node.name, self.node.id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => format!("osai-{}", id.short()),
+            (None, None) => "osai".to_string(),
+        }
+    }
+
+    /// node.id か、paths.node_id_file に保存したID
+    pub fn node_id(&self) -> Result<NodeId, String> {
+        match self.node.id {
+            Some(id) => Ok(id),
+            None => NodeId::load_or_create(&self.paths.node_id_file),
+        }
+    }
+
+    /// 共有ディレクトリ。無ければ作る
+    pub fn share_dir(&self) -> Result<PathBuf, String> {
+        match &self.paths.share_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)
+                    .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+                Ok(dir.clone())
+            }
+            None => get_or_create_share_dir().map(PathBuf::from),
+        }
+    }
+}
+
+#[cfg(test)]
+impl OsaiConfig {
+    /// テスト用。127.0.0.1 の空いているポートで動き、ファイルは一時ディレクトリに置き、
+    /// ディスカバリーは何も送らない
+    pub(crate) fn loopback() -> Self {
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let dir = env::temp_dir().join(format!("osai-node-{}", uuid::Uuid::new_v4()));
+        let mut config = OsaiConfig::default();
+        config.node.id = Some(NodeId::random());
+        config.server.bind = loopback;
+        config.server.port = free_port(|addr| std::net::UdpSocket::bind(addr).and_then(|s| s.local_addr()));
+        config.http.bind = Some(loopback);
+        config.http.port = free_port(|addr| std::net::TcpListener::bind(addr).and_then(|s| s.local_addr()));
+        config.websocket.bind = loopback;
+        config.websocket.port = free_port(|addr| std::net::TcpListener::bind(addr).and_then(|s| s.local_addr()));
+        config.client.bind = SocketAddr::new(loopback, 0);
+        config.client.default_port = config.server.port;
+        config.paths.share_dir = Some(dir.join("share"));
+        config.paths.task_file = dir.join("tasks.json");
+        config.paths.lyric_file = dir.join("lyric.txt");
+        config.paths.identity_file = dir.join("identity.key");
+        config.paths.node_id_file = dir.join("node_id");
+        config.discovery.broadcast = false;
+        config.discovery.multicast_v4 = None;
+        config.discovery.multicast_v6 = None;
+        config.discovery.seeds.clear();
+        config.discovery.probe_on_start = false;
+        config
+    }
+}
+
+/// OS に選ばせたポートを閉じて返す
+#[cfg(test)]
+fn free_port(bind: impl Fn(SocketAddr) -> std::io::Result<SocketAddr>) -> u16 {
+    bind(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap().port()
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e| format!("Invalid {}={}: {}", name, value, e))
+}
+
+fn parse_bool(value: &str) -> bool {
+    matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// "off" / "none" なら None
+fn parse_optional<T: std::str::FromStr>(name: &str, value: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match value.trim().to_ascii_lowercase().as_str() {
+        "off" | "none" => Ok(None),
+        _ => parse_env(name, value.trim()).map(Some),
+    }
+}
+
+/// TOML には null が無いので、アドレスの代わりに "off" と書けるようにする
+fn deserialize_optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_optional("address", &value).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    fn write_config(text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("osai-config-{}.toml", uuid::Uuid::new_v4()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn partial_file_keeps_defaults() {
+        let path = write_config("[server]\nport = 8081\n\n[paths]\ntask_file = \"node2/tasks.json\"\n");
+        let config = OsaiConfig::from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let defaults = OsaiConfig::default();
+        assert_eq!(config.server.port, 8081);
+        assert_eq!(config.server.bind, defaults.server.bind);
+        assert_eq!(config.server.announce_interval_secs, defaults.server.announce_interval_secs);
+        assert_eq!(config.paths.task_file, PathBuf::from("node2/tasks.json"));
+        assert_eq!(config.paths.lyric_file, defaults.paths.lyric_file);
+        assert_eq!(config.http.port, defaults.http.port);
+        assert_eq!(config.discovery.multicast_v4, Some(DEFAULT_MULTICAST_V4));
+    }
+
+    #[test]
+    fn explicit_missing_file_is_an_error() {
+        let missing = env::temp_dir().join(format!("osai-config-{}.toml", uuid::Uuid::new_v4()));
+        assert!(OsaiConfig::load(Some(&missing)).is_err());
+        let path = write_config("[server\nport = 1");
+        assert!(OsaiConfig::from_file(&path).unwrap_err().contains("Invalid config"));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn off_disables_multicast() {
+        let path = write_config("[discovery]\nmulticast_v4 = \"off\"\nmulticast_v6 = \"ff02::1\"\n");
+        let config = OsaiConfig::from_file(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(config.discovery.multicast_v4, None);
+        assert_eq!(config.discovery.multicast_v6, Some("ff02::1".parse().unwrap()));
+
+        let mut config = OsaiConfig::default();
+        config.apply_vars(vars(&[("OSAI_MULTICAST_V4", "None"), ("OSAI_MULTICAST_V6", "ff02::4f53")])).unwrap();
+        assert_eq!(config.discovery.multicast_v4, None);
+        assert_eq!(config.discovery.multicast_v6, Some(DEFAULT_MULTICAST_V6));
+        assert!(parse_optional::<Ipv4Addr>("OSAI_MULTICAST_V4", "not-an-ip").is_err());
+    }
+
+    #[test]
+    fn list_variables() {
+        let mut config = OsaiConfig::default();
+        config
+            .apply_vars(vars(&[
+                ("OSAI_SEEDS", "192.168.2.10:8080, ,10.0.0.5:9000"),
+                ("OSAI_ANNOUNCE_PORTS", "8080, 8081"),
+                ("OSAI_TRUSTED_PEERS", "aa,bb"),
+            ]))
+            .unwrap();
+        assert_eq!(
+            config.discovery.seeds,
+            ["192.168.2.10:8080".parse::<SocketAddr>().unwrap(), "10.0.0.5:9000".parse().unwrap()]
+        );
+        assert_eq!(config.server.announce_ports, [8080, 8081]);
+        assert_eq!(config.security.trusted_peers, ["aa", "bb"]);
+
+        assert!(config.apply_vars(vars(&[("OSAI_SEEDS", "192.168.2.10")])).is_err());
+        assert!(config.apply_vars(vars(&[("OSAI_ANNOUNCE_PORTS", "8080,http")])).is_err());
+    }
+
+    #[test]
+    fn invalid_port_is_an_error() {
+        let mut config = OsaiConfig::default();
+        let error = config.apply_vars(vars(&[("OSAI_PORT", "80800")])).unwrap_err();
+        assert!(error.contains("OSAI_PORT=80800"), "{}", error);
+        assert_eq!(config.server.port, ServerConfig::default().port);
+
+        config.apply_vars(vars(&[("OSAI_PORT", "9000"), ("OSAI_BROADCAST", "off")])).unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert!(!config.discovery.broadcast);
+    }
+
+    #[test]
+    fn announce_ports_fall_back_to_port() {
+        let mut server = ServerConfig { port: 9000, ..ServerConfig::default() };
+        assert_eq!(server.announce_ports(), [9000]);
+        server.announce_ports = vec![8080, 8081];
+        assert_eq!(server.announce_ports(), [8080, 8081]);
+    }
+}
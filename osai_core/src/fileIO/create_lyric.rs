@@ -1,19 +1,20 @@
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::path::Path;
 
-pub fn create_lyric(text: &str, vec14: [u8; 14]){
+pub fn create_lyric(path: &Path, text: &str, vec14: [u8; 14]){
     let vec_str: Vec<String> = vec14.iter().map(|v| v.to_string()).collect();
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open("lyric.txt")
-        .expect("Failed to open lyric.txt");
+        .open(path)
+        .unwrap_or_else(|e| panic!("Failed to open {}: {}", path.display(), e));
 
     // ここでは簡単にカンマ区切りに変換
     let line = format!("{},{}\n", text, vec_str.join(","));
 
     file.write_all(line.as_bytes())
-        .expect("Failed to write to lyric.txt");
+        .unwrap_or_else(|e| panic!("Failed to write to {}: {}", path.display(), e));
 
     println!("Created lyric for '{}'", text);
 }
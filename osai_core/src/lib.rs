@@ -1,11 +1,22 @@
 use std::io::{Write};
 use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
 
+pub mod config;
 pub mod fileIO;
 
 pub mod server;
 pub mod client;
 pub mod IOT;
+pub mod p2p;
+pub mod protocol;
+pub mod runtime;
+pub mod security;
+pub mod llm;
 use vocaloid;
 use std::process::Command;
 
@@ -14,13 +25,28 @@ pub mod file_copy;
 pub mod websocket;
 pub mod file_server;
 pub mod file_read;
-pub mod server_list;
 use std::net::UdpSocket;
 */
-use server::server::start_server;
-use client::client::send_text;
+use client::client::{send_text, send_text_reliable};
+use client::client_file::send_file;
+use client::client_task::{send_task, send_task_ack, send_task_op};
+use client::ping::{ping, PingConfig};
+use config::OsaiConfig;
+use chrono::Utc;
+use IOT::ical;
+use IOT::templates::SpeechTemplates;
+use llm::conversation::{self, Conversation};
+use IOT::task::{add_new_task, display_tasks, AckAction, Task, TaskEdit, TaskFilter};
+use IOT::task_store::TaskStore;
+use protocol::packet::Format;
+use protocol::task_alert::TaskAck;
+use protocol::task_ops::{TaskEditRequest, TaskRef};
+use p2p::registry::PEER_REGISTRY;
+use security::context::{SecurityContext, SECURITY};
+use server::stats::SERVER_STATS;
 // Fixed: Removed `request_file` from imports to resolve unused import warning.
-use server::web::http_server::{http_server, fetch_file_list}; 
+use server::web::http_server::fetch_file_list;
+use runtime::{OsaiRuntime, Service};
 use std::process::Stdio;
 
 /*
@@ -29,53 +55,402 @@ use file_copy::{get_world_list, open_world};
 use websocket::start_websocket_server;
 use file_server::get_file_list;
 use file_read::read_file_content;
-use server_list::request_server_list;
 */
 mod ai;
 
 #[derive(Clone)]
-pub struct OSAI;
+pub struct OSAI {
+    config: Arc<OsaiConfig>,
+}
+
+impl Default for OSAI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl OSAI{
+    /// osai.toml (OSAI_CONFIG) と環境変数から設定を読む。読めなければデフォルト
     pub fn new() -> Self {
-        Self
+        Self::load(None).unwrap_or_else(|e| {
+            eprintln!("{} (using defaults)", e);
+            Self::with_config(OsaiConfig::from_env())
+        })
     }
 
-    pub async fn send_text_cli(){
-        let mut dst_ip = String::new();
-        let mut dst_port = String::new();
-        let mut text = String::new();
-        print!("sendTo:");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut dst_ip).unwrap();
-        print!("sendPort:");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut dst_port).unwrap();
-        print!("sendText:");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut text).unwrap();
+    /// `--config` で渡されたファイルを読む
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        Ok(Self::with_config(OsaiConfig::load(path)?))
+    }
 
-        let dst_ip = dst_ip.trim().to_string();
-        let dst_port = dst_port.trim().parse::<u16>().unwrap_or(8080);
-        let text = text.trim().to_string();
+    /// 鍵の設定もここで入れ替える。ノードIDはここで決めておく
+    pub fn with_config(mut config: OsaiConfig) -> Self {
+        match config.node_id() {
+            Ok(id) => config.node.id = Some(id),
+            Err(e) => eprintln!("{}", e),
+        }
+        *SECURITY.write().unwrap() = SecurityContext::from_config(&config);
+        OSAI { config: Arc::new(config) }
+    }
 
-        send_text(dst_ip, dst_port, text).await;
+    pub fn config(&self) -> &Arc<OsaiConfig> {
+        &self.config
+    }
+
+    /// ピアの名前・ノードID (先頭だけでもよい)・`ip:port`・`ip` (client.default_port) を宛先にする
+    pub fn resolve_peer(&self, target: &str) -> Result<SocketAddr, String> {
+        let target = target.trim();
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        if let Ok(ip) = target.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, self.config.client.default_port));
+        }
+        PEER_REGISTRY.read().unwrap().find(target).map(|peer| peer.udp_addr())
+    }
+
+    /// `text <peer> <message>` なら信頼モードですぐ送る。
+    /// 引数が無ければ宛先とテキストを聞き、信頼モードなら受信側の ACK まで待って結果を返す
+    pub async fn send_text_cli(&self, args: &str) -> Result<String, String> {
+        let (dst, text, reliable) = match args.trim().split_once(' ') {
+            Some((peer, text)) => (self.resolve_peer(peer)?, text.trim().to_string(), true),
+            None => {
+                let mut dst = String::new();
+                let mut text = String::new();
+                let mut reliable = String::new();
+                print!("sendTo (peer or ip[:port]):");
+                io::stdout().flush().unwrap();
+                io::stdin().read_line(&mut dst).unwrap();
+                let dst = self.resolve_peer(&dst)?;
+                print!("sendText:");
+                io::stdout().flush().unwrap();
+                io::stdin().read_line(&mut text).unwrap();
+                print!("reliable(Y/n):");
+                io::stdout().flush().unwrap();
+                io::stdin().read_line(&mut reliable).unwrap();
+                (dst, text.trim().to_string(), !reliable.trim().eq_ignore_ascii_case("n"))
+            }
+        };
+
+        if !reliable {
+            return send_text(&self.config.client, dst.ip().to_string(), dst.port(), text).await;
+        }
+
+        let report = send_text_reliable(&self.config.client, dst.ip().to_string(), dst.port(), text).await?;
+        Ok(format!(
+            "Delivered to {} ({} chunks, {} retransmitted, {:?})",
+            dst, report.chunks, report.retransmissions, report.elapsed
+        ))
+    }
+
+    /// `send_file <path> <peer|ip:port>` の引数を受け取ってファイルを送る
+    pub async fn send_file_cli(&self, args: &str) -> Result<String, String> {
+        let mut parts = args.split_whitespace();
+        let (path, dst) = match (parts.next(), parts.next()) {
+            (Some(path), Some(dst)) => (path, dst),
+            _ => return Err("Usage: send_file <path> <peer|ip:port>".to_string()),
+        };
+        let dst = self.resolve_peer(dst)?;
+
+        let report = send_file(&self.config.client, path, dst).await?;
+        Ok(format!(
+            "File sent to {} ({} bytes, {} chunks, {} retransmitted, {:?})",
+            dst, report.bytes, report.chunks, report.retransmissions, report.elapsed
+        ))
+    }
+
+    /// 自分の X25519 公開鍵と受信モードを返す。相手の OSAI_TRUSTED_PEERS に登録してもらう
+    pub fn identity_cli() -> Result<String, String> {
+        let mut security = SECURITY.write().unwrap();
+        let public = security.identity_public()?;
+        let mode = if security.is_open() {
+            "open (unauthenticated packets accepted)"
+        } else {
+            "authenticated packets only"
+        };
+        Ok(format!("X25519 public key: {}\nMode: {}", hex::encode(public), mode))
+    }
+
+    /// 見つけたピアの一覧 (見つけた順)
+    pub fn peers_cli() -> String {
+        let peers = PEER_REGISTRY.read().unwrap().peers();
+        if peers.is_empty() {
+            return "No peers found yet (start `server` and wait for signals, or run `discover`).".to_string();
+        }
+        let now = Instant::now();
+        let mut out = format!(
+            "{:<20} {:<8} {:<8} {:<24} {:<6} {:<6} {}",
+            "NAME", "ID", "DEVICE", "UDP", "HTTP", "WS", "SEEN"
+        );
+        for peer in &peers {
+            let port = |port: Option<u16>| port.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string());
+            out.push_str(&format!(
+                "\n{:<20} {:<8} {:<8} {:<24} {:<6} {:<6} {}s ago",
+                peer.info.name,
+                peer.node_id.short(),
+                peer.info.device.to_string(),
+                peer.udp_addr().to_string(),
+                port(peer.info.ports.http),
+                port(peer.info.ports.ws),
+                now.saturating_duration_since(peer.last_seen).as_secs()
+            ));
+        }
+        out
+    }
+
+    /// `peer <name|id>`: シグナルの中身を全部見せる
+    pub fn peer_cli(query: &str) -> Result<String, String> {
+        let peer = PEER_REGISTRY.read().unwrap().find(query)?;
+        let now = Instant::now();
+        let formats: Vec<String> = peer.info.formats.iter().map(|f| f.to_string()).collect();
+        let optional = |addr: Option<SocketAddr>| addr.map(|a| a.to_string()).unwrap_or_else(|| "-".to_string());
+        Ok(format!(
+            "Name: {}\nNode ID: {}\nDevice: {}\nSoftware: {} (announce v{})\nUDP: {}\nHTTP: {}\nWebSocket: {}\nFormats: {}\nCapabilities: {}\nFirst seen: {}s ago\nLast seen: {}s ago",
+            peer.info.name,
+            peer.node_id,
+            peer.info.device,
+            peer.info.software,
+            peer.info.version,
+            peer.udp_addr(),
+            optional(peer.http_addr()),
+            optional(peer.ws_addr()),
+            formats.join(" "),
+            peer.info.capabilities.join(", "),
+            now.saturating_duration_since(peer.first_seen).as_secs(),
+            now.saturating_duration_since(peer.last_seen).as_secs()
+        ))
+    }
+
+    pub fn task_store(&self) -> TaskStore {
+        TaskStore::new(&self.config.paths.task_file)
+    }
+
+    /// `task <spec>` で追加、`task edit|rm|done <id> ...`、`task list [filters]`、`task import|export <file.ics>`
+    pub fn task_cli(&self, args: &str) -> Result<String, String> {
+        let store = self.task_store();
+        match TaskCommand::parse(args)? {
+            TaskCommand::Add(spec) => {
+                let task = store.add(add_new_task(&spec, self.config.scheduler.zone()).map_err(|e| e.to_string())?)?;
+                Ok(format!("Task added and saved: {} {} {}", task.short_id(), task.display_time(), task.name))
+            }
+            TaskCommand::Edit(id, edit) => {
+                let task = store.update(&id, &edit)?;
+                Ok(format!("Task updated: {} {} {}", task.short_id(), task.display_time(), task.name))
+            }
+            TaskCommand::Remove(id) => {
+                let task = store.delete(&id)?;
+                Ok(format!("Task removed: {} {}", task.short_id(), task.name))
+            }
+            TaskCommand::Done(id) => {
+                let task = store.complete(&id)?;
+                Ok(format!("Task done: {} {} [{}]", task.short_id(), task.name, task.state))
+            }
+            TaskCommand::List(filter) => Ok(display_tasks(store.list(&filter)?)),
+            TaskCommand::Import(file) => {
+                let text = fs::read_to_string(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+                let import = ical::from_ics(&text, self.config.scheduler.zone())?;
+                let (added, updated, unchanged) = store.import(import.tasks)?;
+                let mut out = format!(
+                    "Imported {}: {} added, {} updated, {} unchanged",
+                    file.display(),
+                    added,
+                    updated,
+                    unchanged
+                );
+                for warning in import.warnings {
+                    out.push_str(&format!("\n  warning: {}", warning));
+                }
+                Ok(out)
+            }
+            TaskCommand::Export(file, filter) => {
+                let tasks = store.list(&filter)?;
+                let ics = ical::to_ics(&tasks, &self.config.scheduler.default_reminders(), Utc::now());
+                fs::write(&file, ics).map_err(|e| format!("Failed to write {}: {}", file.display(), e))?;
+                Ok(format!("Exported {} tasks to {}", tasks.len(), file.display()))
+            }
+        }
+    }
+
+    /// `remote_task <peer|ip:port> <spec>` で相手のノードに登録する。`edit|rm|done|list` は task と同じ。
+    /// 相手が受け付けたら Ok、断られたら理由をエラーで返す
+    pub async fn remote_task_cli(&self, args: &str) -> Result<String, String> {
+        let (target, rest) = args
+            .trim()
+            .split_once(' ')
+            .ok_or("Usage: remote_task <peer|ip:port> YYYY-MM-DD:HH:MM:name | edit|rm|done <id> ... | list [filters]")?;
+        let dst = self.resolve_peer(target)?;
+        let client = &self.config.client;
+        let (reply, action) = match TaskCommand::parse(rest)? {
+            TaskCommand::Add(spec) => (send_task(client, dst, &spec).await?, "accepted"),
+            TaskCommand::Edit(id, edit) => {
+                (send_task_op(client, dst, Format::TASK_EDIT, &TaskEditRequest { id, edit: *edit }).await?, "updated")
+            }
+            TaskCommand::Remove(id) => (send_task_op(client, dst, Format::TASK_DELETE, &TaskRef { id }).await?, "removed"),
+            TaskCommand::Done(id) => (send_task_op(client, dst, Format::TASK_DONE, &TaskRef { id }).await?, "done"),
+            TaskCommand::List(filter) => (send_task_op(client, dst, Format::TASK_LIST, &filter).await?, "listed"),
+            TaskCommand::Import(_) | TaskCommand::Export(..) => {
+                return Err("task import/export works on local files only; run it on that node".to_string())
+            }
+        };
+        if !reply.accepted {
+            return Err(format!(
+                "Task rejected by {}: {}",
+                dst,
+                reply.error.unwrap_or_else(|| "no reason given".to_string())
+            ));
+        }
+        match reply.task {
+            Some(task) => Ok(format!("Task {} by {}: {} {} {}", action, dst, task.short_id(), task.display_time(), task.name)),
+            None if action == "listed" => {
+                let mut out = format!("Tasks on {}:\n{}", dst, display_tasks(reply.tasks));
+                if reply.omitted > 0 {
+                    out.push_str(&format!("\n({} more not shown)", reply.omitted));
+                }
+                Ok(out)
+            }
+            None => Ok(format!("Task {} by {}", action, dst)),
+        }
+    }
+
+    /// `ai [@user] <query>`: llm.backend に問い合わせる (llm.ai_instruction を付けて)。
+    /// そのユーザーの前の会話も送り、返事をもらえたら履歴に足す。
+    /// 返事をもらえなければ paths.speech_templates の ai_unavailable (空ならエラー)
+    pub async fn ask_ai(&self, args: &str) -> Result<String, String> {
+        let llm = &self.config.llm;
+        let (user, query) = self.ai_user(args);
+        if query.is_empty() {
+            return Err("Error: 'ai' command requires a query.".to_string());
+        }
+        let _guard = conversation::lock().await;
+        let mut history = Conversation::load(&self.config.paths.conversation_dir, user)?;
+        let request = history.request(llm, query).with_instruction(&llm.ai_instruction);
+        let reply = match llm::ask(llm, &request).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                let fallback = SpeechTemplates::load(&self.config.paths.speech_templates).ai_unavailable;
+                if fallback.trim().is_empty() {
+                    return Err(e);
+                }
+                // 言えなかった会話は履歴に残さない
+                eprintln!("{}", e);
+                return Ok(fallback);
+            }
+        };
+        history.push(query, &reply, llm.history_tokens);
+        // 返事はもらえたので、保存できなくても返す
+        if let Err(e) = history.save() {
+            eprintln!("{}", e);
+        }
+        Ok(reply)
+    }
+
+    /// `ai reset [@user]`: 会話の履歴を消す
+    pub async fn reset_ai(&self, args: &str) -> Result<String, String> {
+        let (user, _) = self.ai_user(args);
+        let _guard = conversation::lock().await;
+        match Conversation::reset(&self.config.paths.conversation_dir, user)? {
+            None => Ok(format!("No conversation history for {}", user)),
+            Some(count) => Ok(format!("Conversation history for {} cleared ({} messages)", user, count)),
+        }
+    }
+
+    /// 先頭の `@name` がユーザー。無ければ llm.user
+    fn ai_user<'a>(&'a self, args: &'a str) -> (&'a str, &'a str) {
+        let args = args.trim();
+        match args.strip_prefix('@') {
+            Some(rest) => {
+                let (user, query) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                (user, query.trim())
+            }
+            None => (self.config.llm.user.as_str(), args),
+        }
+    }
+
+    /// `ack [task]`: 鳴っている通知を止める。タスク名を省くと鳴っているもの全部
+    pub fn ack_cli(&self, args: &str) -> Result<String, String> {
+        let tasks = self.task_store().respond(args, AckAction::Ack)?;
+        Ok(format!("Acknowledged: {}", task_names(&tasks)))
+    }
+
+    /// `snooze [minutes] [task]`: 分数を省くと scheduler.snooze_mins
+    pub fn snooze_cli(&self, args: &str) -> Result<String, String> {
+        let (minutes, query) = self.parse_snooze(args);
+        let tasks = self.task_store().respond(query, AckAction::Snooze(minutes))?;
+        Ok(format!("Snoozed for {} min: {}", minutes, task_names(&tasks)))
+    }
+
+    /// `remote_ack <peer|ip:port> [task]`: caregiver から相手のノードの通知を止める
+    pub async fn remote_ack_cli(&self, args: &str) -> Result<String, String> {
+        let (target, query) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        if target.is_empty() {
+            return Err("Usage: remote_ack <peer|ip:port> [task]".to_string());
+        }
+        self.send_ack(target, query, AckAction::Ack).await
+    }
+
+    /// `remote_snooze <peer|ip:port> [minutes] [task]`
+    pub async fn remote_snooze_cli(&self, args: &str) -> Result<String, String> {
+        let (target, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        if target.is_empty() {
+            return Err("Usage: remote_snooze <peer|ip:port> [minutes] [task]".to_string());
+        }
+        let (minutes, query) = self.parse_snooze(rest);
+        self.send_ack(target, query, AckAction::Snooze(minutes)).await
+    }
+
+    async fn send_ack(&self, target: &str, query: &str, action: AckAction) -> Result<String, String> {
+        let dst = self.resolve_peer(target)?;
+        let ack = TaskAck { task: query.trim().to_string(), action };
+        let reply = send_task_ack(&self.config.client, dst, &ack).await?;
+        match (reply.accepted, reply.task) {
+            (true, Some(task)) => Ok(format!("{} accepted {} for '{}'", dst, action, task.name)),
+            (true, None) => Ok(format!("{} accepted {}", dst, action)),
+            (false, _) => Err(format!("{}: {}", dst, reply.error.unwrap_or_else(|| "no reason given".to_string()))),
+        }
+    }
+
+    /// 先頭が数字なら分数、残りはタスク名
+    fn parse_snooze<'a>(&self, args: &'a str) -> (u32, &'a str) {
+        let args = args.trim();
+        let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+        match first.parse::<u32>() {
+            Ok(minutes) if minutes > 0 => (minutes, rest.trim()),
+            _ => (self.config.scheduler.snooze_mins, args),
+        }
+    }
+
+    /// `ping <peer|ip:port> [count]`
+    pub async fn ping_cli(&self, args: &str) -> Result<String, String> {
+        let mut parts = args.split_whitespace();
+        let target = parts.next().ok_or("Usage: ping <peer|ip:port> [count]")?;
+        let mut config = PingConfig::default();
+        if let Some(count) = parts.next() {
+            config.count = count.parse().map_err(|e| format!("Invalid count {}: {}", count, e))?;
+        }
+        let dst = self.resolve_peer(target)?;
+        let report = ping(&self.config.client, dst, &config).await?;
+        Ok(report.to_string())
+    }
+
+    /// 受信ループのカウンター (破棄した数など)
+    pub fn server_stats() -> String {
+        SERVER_STATS.report()
     }
 
     // Fixed: Changed `ip` to `_ip` to resolve the unused variable warning.
-    pub fn request_http(_ip: &str){
+    pub fn request_http(&self, _ip: &str){
         let mut ip = String::new();
         // Note: The argument `_ip` is unused as the value is read from stdin.
         io::stdin().read_line(&mut ip).expect("Failed to read line for IP");
 
         let ip_trimmed = ip.trim();
-        let url = format!("http://{}/share/files.json", ip_trimmed);
+        let url = format!("http://{}:{}/share/files.json", ip_trimmed, self.config.http.port);
         fetch_file_list(url);
     }
 
-    pub async fn http_server() -> Result<(), warp::Error>{
-        let _ = http_server().await?;
-        Ok(())
+    /// HTTP サーバーだけを Ctrl-C まで動かす
+    pub async fn http_server(&self) -> Result<(), String>{
+        OsaiRuntime::start(self.clone(), &[Service::Http])?.run_until_signal().await
     }
 
     pub fn vocaloid(text: &str) -> Result<(), hound::Error>{
@@ -94,15 +469,15 @@ impl OSAI{
         print!("stderr: {}", String::from_utf8_lossy(&output.stderr));
     }
 
+    /// UDP サーバーを Ctrl-C まで動かす。ほかのサービスと一緒に動かすなら OsaiRuntime を使う
     pub async fn run(&self) -> Result<(), String>{
-        let _ = start_server("8080".to_string()).await?;
+        OsaiRuntime::start(self.clone(), &[Service::Server])?.run_until_signal().await?;
         
         //start_websoket_server();
         //send_text();
         //get_world_list();
         //get_file_list();
         //read_file_content();
-        //request_server_list();
         //fetch_file_list();
         //request_file();
         Ok(())
@@ -179,3 +554,43 @@ impl OSAI{
         }
     }
 }
+
+fn task_names(tasks: &[Task]) -> String {
+    tasks.iter().map(|t| t.name.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+/// `task` と `remote_task` の引数
+enum TaskCommand {
+    Add(String),
+    Edit(String, Box<TaskEdit>),
+    Remove(String),
+    Done(String),
+    List(TaskFilter),
+    Import(PathBuf),
+    Export(PathBuf, TaskFilter),
+}
+
+impl TaskCommand {
+    fn parse(args: &str) -> Result<Self, String> {
+        let args = args.trim();
+        let (command, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let rest = rest.trim();
+        match command {
+            "edit" => {
+                let (id, fields) = rest.split_once(' ').ok_or("Usage: task edit <id> name=...; at=YYYY-MM-DD:HH:MM; repeat=...; remind=...; tags=...; tz=...")?;
+                Ok(TaskCommand::Edit(id.to_string(), Box::new(TaskEdit::parse(fields)?)))
+            }
+            "rm" | "done" if rest.is_empty() => Err(format!("Usage: task {} <id|name>", command)),
+            "rm" => Ok(TaskCommand::Remove(rest.to_string())),
+            "done" => Ok(TaskCommand::Done(rest.to_string())),
+            "list" => Ok(TaskCommand::List(TaskFilter::parse(rest)?)),
+            "import" | "export" if rest.is_empty() => Err(format!("Usage: task {} <file.ics>", command)),
+            "import" => Ok(TaskCommand::Import(PathBuf::from(rest))),
+            "export" => {
+                let (file, filters) = rest.split_once(' ').unwrap_or((rest, ""));
+                Ok(TaskCommand::Export(PathBuf::from(file), TaskFilter::parse(filters)?))
+            }
+            _ => Ok(TaskCommand::Add(args.to_string())),
+        }
+    }
+}
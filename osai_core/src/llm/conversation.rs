@@ -0,0 +1,225 @@
+// ai コマンドの会話の履歴。ユーザーごとに paths.conversation_dir/<user>.json に残し、
+// 次の問い合わせでは前の発言も一緒に送る (LlmRequest::turns)。
+//
+// 長くなったら古い発言から捨てて llm.history_tokens に収める。トークン数は estimate_tokens の目安
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::{LlmRequest, Role, Turn};
+use crate::config::LlmConfig;
+
+/// 読んでから保存するまで (返事を待つあいだも) 持つ。同時に聞いても発言の順番が崩れないように
+static CONVERSATION_LOCK: Mutex<()> = Mutex::const_new(());
+
+pub async fn lock() -> MutexGuard<'static, ()> {
+    CONVERSATION_LOCK.lock().await
+}
+
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    path: PathBuf,
+    turns: Vec<Turn>,
+}
+
+impl Conversation {
+    /// ファイルが無ければ空の会話。壊れていれば上書きしないようにエラー
+    pub fn load(dir: &Path, user: &str) -> Result<Self, String> {
+        let path = history_path(dir, user)?;
+        let turns = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| {
+                format!(
+                    "Failed to parse conversation history {}: {}. Run 'ai reset @{}' to start over",
+                    path.display(),
+                    e,
+                    user
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Failed to read conversation history {}: {}", path.display(), e)),
+        };
+        Ok(Conversation { path, turns })
+    }
+
+    /// 履歴 (llm.history_tokens に収まる分) のあとに `query` を続けた問い合わせ
+    pub fn request(&self, config: &LlmConfig, query: &str) -> LlmRequest {
+        let mut turns = self.turns.clone();
+        trim_to_budget(&mut turns, config.history_tokens);
+        turns.push(Turn::user(query));
+        LlmRequest { turns, ..LlmRequest::new(config, query) }
+    }
+
+    /// 返事をもらえた問い合わせを足す。`budget` を超えたら古い発言を捨てる
+    pub fn push(&mut self, query: &str, reply: &str, budget: usize) {
+        self.turns.push(Turn::user(query));
+        self.turns.push(Turn::model(reply));
+        trim_to_budget(&mut self.turns, budget);
+    }
+
+    /// 一時ファイルに書いてから置き換える
+    pub fn save(&self) -> Result<(), String> {
+        let err = |e: &dyn std::fmt::Display| format!("Failed to save conversation history {}: {}", self.path.display(), e);
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| err(&e))?;
+        }
+        let data = serde_json::to_string_pretty(&self.turns).map_err(|e| err(&e))?;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        {
+            let mut file = fs::File::create(&tmp).map_err(|e| err(&e))?;
+            file.write_all(data.as_bytes()).map_err(|e| err(&e))?;
+            file.sync_all().map_err(|e| err(&e))?;
+        }
+        fs::rename(&tmp, &self.path).map_err(|e| err(&e))
+    }
+
+    /// `ai reset`。消した発言の数 (履歴が無ければ None)
+    pub fn reset(dir: &Path, user: &str) -> Result<Option<usize>, String> {
+        let path = history_path(dir, user)?;
+        // 壊れたファイルも消せるように
+        let count = Self::load(dir, user).map_or(0, |conversation| conversation.turns.len());
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(Some(count)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to remove {}: {}", path.display(), e)),
+        }
+    }
+}
+
+/// ユーザー名はそのままファイル名にするので、"/" や ".." などは使えない
+fn history_path(dir: &Path, user: &str) -> Result<PathBuf, String> {
+    let valid = !user.is_empty()
+        && !user.starts_with('.')
+        && user.chars().all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(format!("Invalid user name '{}'. Use letters, digits, '-', '_' or '.'", user));
+    }
+    Ok(dir.join(format!("{}.json", user)))
+}
+
+/// だいたいのトークン数。英数字は4文字で1つ、日本語などは1文字で1つと数える
+pub fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.chars().filter(char::is_ascii).count();
+    let other = text.chars().count() - ascii;
+    ascii.div_ceil(4) + other
+}
+
+/// 古い発言から捨てて `budget` 以下にする。残りは User の発言から始める
+fn trim_to_budget(turns: &mut Vec<Turn>, budget: usize) {
+    let mut total: usize = turns.iter().map(|turn| estimate_tokens(&turn.text)).sum();
+    let mut drop = 0;
+    while drop < turns.len() && (total > budget || turns[drop].role != Role::User) {
+        total -= estimate_tokens(&turns[drop].text);
+        drop += 1;
+    }
+    turns.drain(..drop);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("osai-conversation-{}-{}", name, uuid::Uuid::new_v4()))
+    }
+
+    fn config(history_tokens: usize) -> LlmConfig {
+        LlmConfig { system_prompt: "やさしく答えて".to_string(), history_tokens, ..LlmConfig::default() }
+    }
+
+    #[test]
+    fn estimate() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("abcde"), 2);
+        assert_eq!(estimate_tokens("こんにちは"), 5);
+    }
+
+    #[test]
+    fn oldest_turns_are_dropped_first() {
+        let dir = temp_dir("trim");
+        let mut conversation = Conversation::load(&dir, "alice").unwrap();
+        // 1往復 = 3 + 3 トークン
+        for i in 0..5 {
+            conversation.push(&format!("質問{:02}", i), &format!("答え{:02}", i), 13);
+        }
+        let texts: Vec<&str> = conversation.turns.iter().map(|turn| turn.text.as_str()).collect();
+        assert_eq!(texts, ["質問03", "答え03", "質問04", "答え04"]);
+
+        // 残りが User から始まるように、はみ出した返事だけの発言も捨てる
+        let mut turns = vec![Turn::user("あ"), Turn::model("いいい"), Turn::user("う"), Turn::model("え")];
+        trim_to_budget(&mut turns, 4);
+        assert_eq!(turns, [Turn::user("う"), Turn::model("え")]);
+    }
+
+    #[test]
+    fn request_keeps_system_prompt_and_latest_query() {
+        let dir = temp_dir("request");
+        let mut conversation = Conversation::load(&dir, "alice").unwrap();
+        conversation.push("一つ目", "はい", 1000);
+        conversation.push("二つ目", "いいえ", 1000);
+
+        let request = conversation.request(&config(8), "三つ目");
+        assert_eq!(request.system, "やさしく答えて");
+        assert_eq!(request.turns, [Turn::user("二つ目"), Turn::model("いいえ"), Turn::user("三つ目")]);
+        assert_eq!(request.query(), "三つ目");
+
+        // 予算が 0 でも今の問い合わせは送る
+        let request = conversation.request(&config(0), &"長い".repeat(100));
+        assert_eq!(request.turns, [Turn::user("長い".repeat(100))]);
+        assert_eq!(request.system, "やさしく答えて");
+        // 問い合わせを作るだけでは履歴は変わらない
+        assert_eq!(conversation.turns.len(), 4);
+    }
+
+    #[test]
+    fn users_have_separate_histories() {
+        let dir = temp_dir("users");
+        let mut alice = Conversation::load(&dir, "alice").unwrap();
+        alice.push("私はアリス", "よろしく、アリス", 1000);
+        alice.save().unwrap();
+        let mut bob = Conversation::load(&dir, "bob").unwrap();
+        bob.push("私はボブ", "よろしく、ボブ", 1000);
+        bob.save().unwrap();
+
+        let alice = Conversation::load(&dir, "alice").unwrap();
+        let bob = Conversation::load(&dir, "bob").unwrap();
+        assert_eq!(alice.turns, [Turn::user("私はアリス"), Turn::model("よろしく、アリス")]);
+        assert_eq!(bob.turns, [Turn::user("私はボブ"), Turn::model("よろしく、ボブ")]);
+        assert!(Conversation::load(&dir, "carol").unwrap().turns.is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn reset_removes_only_that_user() {
+        let dir = temp_dir("reset");
+        for user in ["alice", "bob"] {
+            let mut conversation = Conversation::load(&dir, user).unwrap();
+            conversation.push("こんにちは", "こんにちは", 1000);
+            conversation.save().unwrap();
+        }
+        assert_eq!(Conversation::reset(&dir, "alice").unwrap(), Some(2));
+        assert_eq!(Conversation::reset(&dir, "alice").unwrap(), None);
+        assert!(Conversation::load(&dir, "alice").unwrap().turns.is_empty());
+        assert_eq!(Conversation::load(&dir, "bob").unwrap().turns.len(), 2);
+
+        // 壊れた履歴は読めないが、reset で消せる
+        fs::write(dir.join("bob.json"), "{").unwrap();
+        assert!(Conversation::load(&dir, "bob").unwrap_err().contains("ai reset @bob"));
+        assert_eq!(Conversation::reset(&dir, "bob").unwrap(), Some(0));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn user_names_cannot_leave_the_directory() {
+        let dir = temp_dir("names");
+        for user in ["", "../etc", ".hidden", "a/b", "a b"] {
+            assert!(Conversation::load(&dir, user).is_err(), "{}", user);
+            assert!(Conversation::reset(&dir, user).is_err(), "{}", user);
+        }
+        assert!(Conversation::load(&dir, "ユーザー_1.x").is_ok());
+    }
+}
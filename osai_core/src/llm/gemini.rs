@@ -0,0 +1,80 @@
+// Gemini API (generateContent)
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde_json::json;
+
+use super::{api_key, http_client, post_json, LlmBackend, LlmRequest, Role};
+use crate::config::LlmConfig;
+
+pub const DEFAULT_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+pub const DEFAULT_MODEL: &str = "gemini-2.5-flash-preview-09-2025";
+pub const API_KEY_ENV: &str = "GEMINI_API_KEY";
+
+pub struct GeminiBackend {
+    client: Client,
+    config: LlmConfig,
+    api_key: String,
+}
+
+impl GeminiBackend {
+    pub fn new(config: &LlmConfig) -> Result<Self, String> {
+        let api_key = api_key(config, API_KEY_ENV).ok_or_else(|| {
+            format!(
+                "Error: {} environment variable not set. Please set your API key.",
+                config.api_key_env.as_deref().unwrap_or(API_KEY_ENV)
+            )
+        })?;
+        Ok(GeminiBackend { client: http_client(config)?, config: config.clone(), api_key })
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "{}/models/{}:generateContent",
+            self.config.url.as_deref().unwrap_or(DEFAULT_URL).trim_end_matches('/'),
+            self.config.model.as_deref().unwrap_or(DEFAULT_MODEL)
+        )
+    }
+}
+
+impl LlmBackend for GeminiBackend {
+    fn name(&self) -> &str {
+        "gemini"
+    }
+
+    fn generate<'a>(&'a self, request: &'a LlmRequest) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let contents: Vec<serde_json::Value> = request
+                .turns
+                .iter()
+                .map(|turn| {
+                    let role = match turn.role {
+                        Role::User => "user",
+                        Role::Model => "model",
+                    };
+                    json!({ "role": role, "parts": [{ "text": turn.text }] })
+                })
+                .collect();
+            let mut payload = json!({ "contents": contents });
+            if !request.system.is_empty() {
+                payload["systemInstruction"] = json!({ "parts": [{ "text": request.system }] });
+            }
+            if request.web_search {
+                payload["tools"] = json!([{ "google_search": {} }]);
+            }
+
+            let headers = [("x-goog-api-key", self.api_key.clone())];
+            let response = post_json(&self.client, &self.config, "Gemini", &self.url(), &headers, &payload).await?;
+            // google_search を使うと parts が分かれることがある
+            let text: String = response["candidates"][0]["content"]["parts"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|part| part["text"].as_str())
+                .collect();
+            if text.is_empty() {
+                return Err("API response format error: missing text content.".to_string());
+            }
+            Ok(text)
+        })
+    }
+}
@@ -0,0 +1,375 @@
+// 文章を作る LLM。どれを使うかは OsaiConfig の llm.backend で選ぶ。
+//
+// - gemini: Google の Gemini API (GEMINI_API_KEY)
+// - openai: OpenAI 互換の chat/completions。llama.cpp の server や Ollama もこれで使える
+// - offline: ネットワークを使わず、決まった形の返事をする (ネットが無いとき・動作確認用)
+//
+// 失敗したときのやり直しはどのバックエンドも post_json で同じにする。
+// ai コマンドの会話の履歴は conversation
+pub mod conversation;
+pub mod gemini;
+pub mod offline;
+pub mod openai;
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::config::LlmConfig;
+
+/// llm.backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmBackendKind {
+    #[default]
+    Gemini,
+    OpenAi,
+    Offline,
+}
+
+impl fmt::Display for LlmBackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LlmBackendKind::Gemini => "gemini",
+            LlmBackendKind::OpenAi => "openai",
+            LlmBackendKind::Offline => "offline",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for LlmBackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gemini" => Ok(LlmBackendKind::Gemini),
+            "openai" => Ok(LlmBackendKind::OpenAi),
+            "offline" => Ok(LlmBackendKind::Offline),
+            other => Err(format!("Unknown LLM backend: {} (use gemini, openai or offline)", other)),
+        }
+    }
+}
+
+/// 会話の1つの発言
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Model,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Turn {
+    pub role: Role,
+    pub text: String,
+}
+
+impl Turn {
+    pub fn user(text: impl Into<String>) -> Self {
+        Turn { role: Role::User, text: text.into() }
+    }
+
+    pub fn model(text: impl Into<String>) -> Self {
+        Turn { role: Role::Model, text: text.into() }
+    }
+}
+
+/// バックエンドに渡す問い合わせ。最後の turn が User
+#[derive(Debug, Clone)]
+pub struct LlmRequest {
+    pub system: String,
+    pub turns: Vec<Turn>,
+    /// 使えるバックエンドなら検索させる (gemini の google_search)
+    pub web_search: bool,
+}
+
+impl LlmRequest {
+    /// llm.system_prompt と `query` だけの問い合わせ
+    pub fn new(config: &LlmConfig, query: &str) -> Self {
+        LlmRequest {
+            system: config.system_prompt.clone(),
+            turns: vec![Turn::user(query)],
+            web_search: config.web_search,
+        }
+    }
+
+    /// system に指示を足す (ai コマンドの llm.ai_instruction など)
+    pub fn with_instruction(mut self, instruction: &str) -> Self {
+        if !instruction.trim().is_empty() {
+            self.system = format!("{}\n{}", self.system, instruction.trim()).trim().to_string();
+        }
+        self
+    }
+
+    /// 最後の User の発言
+    pub fn query(&self) -> &str {
+        self.turns
+            .iter()
+            .rev()
+            .find(|turn| turn.role == Role::User)
+            .map_or("", |turn| turn.text.as_str())
+    }
+}
+
+pub trait LlmBackend: Send + Sync {
+    /// ログ用 ("gemini" など)
+    fn name(&self) -> &str;
+
+    /// 返事の文章。やり直しても駄目なら理由をエラーで返す
+    fn generate<'a>(&'a self, request: &'a LlmRequest) -> BoxFuture<'a, Result<String, String>>;
+}
+
+/// llm.backend のバックエンドを作る。API キーが要るのに無ければエラー
+pub fn backend(config: &LlmConfig) -> Result<Box<dyn LlmBackend>, String> {
+    Ok(match config.backend {
+        LlmBackendKind::Gemini => Box::new(gemini::GeminiBackend::new(config)?),
+        LlmBackendKind::OpenAi => Box::new(openai::OpenAiBackend::new(config)?),
+        LlmBackendKind::Offline => Box::new(offline::OfflineBackend::new(config)),
+    })
+}
+
+/// llm.backend に1回問い合わせる
+pub async fn ask(config: &LlmConfig, request: &LlmRequest) -> Result<String, String> {
+    backend(config)?.generate(request).await
+}
+
+/// llm.api_key_env (無ければ `default_env`) の API キー
+fn api_key(config: &LlmConfig, default_env: &str) -> Option<String> {
+    let name = config.api_key_env.as_deref().unwrap_or(default_env);
+    std::env::var(name).ok().filter(|key| !key.trim().is_empty())
+}
+
+fn http_client(config: &LlmConfig) -> Result<Client, String> {
+    Client::builder()
+        .timeout(config.timeout())
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+/// JSON を POST して JSON を受け取る。つながらない・429・5xx なら 1, 2, 4... 秒待って llm.max_attempts 回まで。
+/// それ以外の 4xx (キーが違うなど) はやり直さない
+pub(crate) async fn post_json(
+    client: &Client,
+    config: &LlmConfig,
+    backend: &str,
+    url: &str,
+    headers: &[(&str, String)],
+    payload: &serde_json::Value,
+) -> Result<serde_json::Value, String> {
+    let attempts = config.max_attempts.max(1);
+    let mut last_error = String::new();
+    for i in 0..attempts {
+        if i > 0 {
+            sleep(Duration::from_secs(1 << (i - 1).min(5))).await;
+        }
+        let mut request = client.post(url).json(payload);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                return response
+                    .json()
+                    .await
+                    .map_err(|e| format!("{} API returned a response that is not JSON: {}", backend, e));
+            }
+            Ok(response) => {
+                let status = response.status();
+                let body = response.text().await.unwrap_or_else(|_| "No body".to_string());
+                last_error = format!("{} API error: Status: {}, Body: {}", backend, status, body.trim());
+                if !retryable(status) {
+                    return Err(last_error);
+                }
+            }
+            Err(e) => last_error = format!("{} API network error: {}", backend, e),
+        }
+        eprintln!("{} (attempt {}/{})", last_error, i + 1, attempts);
+    }
+    Err(format!("{} API failed after {} attempts. {}", backend, attempts, last_error))
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    const KEY_ENV: &str = "OSAI_TEST_LLM_API_KEY";
+
+    /// `responses` を順に1つずつ返す HTTP サーバー。受け取ったリクエスト (ヘッダーと本文) を返す
+    async fn stub(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut data = Vec::new();
+                let mut buf = [0u8; 4096];
+                loop {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&data).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let length = text[..end]
+                            .lines()
+                            .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                            .and_then(|v| v.parse::<usize>().ok())
+                            .unwrap_or(0);
+                        if data.len() >= end + 4 + length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                requests.push(String::from_utf8_lossy(&data).to_string());
+                let response = format!(
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn config(backend: LlmBackendKind, url: &str) -> LlmConfig {
+        std::env::set_var(KEY_ENV, "test-key");
+        LlmConfig {
+            backend,
+            model: Some("test-model".to_string()),
+            url: Some(url.to_string()),
+            api_key_env: Some(KEY_ENV.to_string()),
+            system_prompt: "短く答えて".to_string(),
+            web_search: false,
+            max_attempts: 1,
+            timeout_secs: 5,
+            ..LlmConfig::default()
+        }
+    }
+
+    fn request(config: &LlmConfig) -> LlmRequest {
+        LlmRequest {
+            turns: vec![Turn::user("前の質問"), Turn::model("前の答え"), Turn::user("こんにちは")],
+            ..LlmRequest::new(config, "こんにちは")
+        }
+    }
+
+    /// リクエストの本文 (JSON)
+    fn body(request: &str) -> serde_json::Value {
+        serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn gemini_success() {
+        let (url, server) =
+            stub(vec![(200, r#"{"candidates":[{"content":{"parts":[{"text":"こん"},{"text":"にちは"}]}}]}"#)]).await;
+        let config = config(LlmBackendKind::Gemini, &url);
+        assert_eq!(ask(&config, &request(&config)).await.unwrap(), "こんにちは");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /v1/models/test-model:generateContent "), "{}", requests[0]);
+        assert!(requests[0].to_ascii_lowercase().contains("x-goog-api-key: test-key"));
+        let body = body(&requests[0]);
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "短く答えて");
+        let roles: Vec<&str> = body["contents"].as_array().unwrap().iter().map(|c| c["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, ["user", "model", "user"]);
+        assert_eq!(body["contents"][2]["parts"][0]["text"], "こんにちは");
+        assert!(body.get("tools").is_none());
+    }
+
+    #[tokio::test]
+    async fn gemini_http_error_is_not_retried() {
+        let (url, server) = stub(vec![(401, r#"{"error":"bad key"}"#)]).await;
+        let config = LlmConfig { max_attempts: 3, ..config(LlmBackendKind::Gemini, &url) };
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("401") && error.contains("bad key"), "{}", error);
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gemini_malformed_json() {
+        let (url, _server) = stub(vec![(200, "<html>oops</html>")]).await;
+        let config = config(LlmBackendKind::Gemini, &url);
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("not JSON"), "{}", error);
+
+        let (url, _server) = stub(vec![(200, r#"{"candidates":[]}"#)]).await;
+        let config = self::config(LlmBackendKind::Gemini, &url);
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("missing text content"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn openai_success() {
+        let (url, server) = stub(vec![(200, r#"{"choices":[{"message":{"role":"assistant","content":"やあ"}}]}"#)]).await;
+        let config = config(LlmBackendKind::OpenAi, &url);
+        assert_eq!(ask(&config, &request(&config)).await.unwrap(), "やあ");
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("POST /v1/chat/completions "), "{}", requests[0]);
+        assert!(requests[0].to_ascii_lowercase().contains("authorization: bearer test-key"));
+        let body = body(&requests[0]);
+        assert_eq!(body["model"], "test-model");
+        let roles: Vec<&str> = body["messages"].as_array().unwrap().iter().map(|m| m["role"].as_str().unwrap()).collect();
+        assert_eq!(roles, ["system", "user", "assistant", "user"]);
+    }
+
+    #[tokio::test]
+    async fn openai_server_error_is_retried() {
+        let (url, server) = stub(vec![(500, "overloaded"), (200, r#"{"choices":[{"message":{"content":"ok"}}]}"#)]).await;
+        let config = LlmConfig { max_attempts: 2, ..config(LlmBackendKind::OpenAi, &url) };
+        assert_eq!(ask(&config, &request(&config)).await.unwrap(), "ok");
+        assert_eq!(server.await.unwrap().len(), 2);
+
+        let (url, _server) = stub(vec![(500, "overloaded")]).await;
+        let config = self::config(LlmBackendKind::OpenAi, &url);
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("500") && error.contains("after 1 attempts"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn openai_malformed_json() {
+        let (url, _server) = stub(vec![(200, "not json")]).await;
+        let config = config(LlmBackendKind::OpenAi, &url);
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("not JSON"), "{}", error);
+
+        let (url, _server) = stub(vec![(200, r#"{"choices":[{"message":{"content":""}}]}"#)]).await;
+        let config = self::config(LlmBackendKind::OpenAi, &url);
+        let error = ask(&config, &request(&config)).await.unwrap_err();
+        assert!(error.contains("missing text content"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn offline_backend_from_config() {
+        let path = std::env::temp_dir().join(format!("osai-llm-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[llm]\nbackend = \"offline\"\noffline_reply = \"「{query}」はいまは答えられません\"\n").unwrap();
+        let config = crate::config::OsaiConfig::from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.llm.backend, LlmBackendKind::Offline);
+        let backend = backend(&config.llm).unwrap();
+        assert_eq!(backend.name(), "offline");
+        let reply = backend.generate(&request(&config.llm)).await.unwrap();
+        assert_eq!(reply, "「こんにちは」はいまは答えられません");
+        assert_eq!("Offline".parse::<LlmBackendKind>().unwrap(), LlmBackendKind::Offline);
+    }
+}
@@ -0,0 +1,26 @@
+// ネットワークを使わないバックエンド。llm.offline_reply の {query} を最後の問い合わせに置き換えて返す。
+// 同じ問い合わせには必ず同じ返事をする
+use futures_util::future::BoxFuture;
+
+use super::{LlmBackend, LlmRequest};
+use crate::config::LlmConfig;
+
+pub struct OfflineBackend {
+    template: String,
+}
+
+impl OfflineBackend {
+    pub fn new(config: &LlmConfig) -> Self {
+        OfflineBackend { template: config.offline_reply.clone() }
+    }
+}
+
+impl LlmBackend for OfflineBackend {
+    fn name(&self) -> &str {
+        "offline"
+    }
+
+    fn generate<'a>(&'a self, request: &'a LlmRequest) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move { Ok(self.template.replace("{query}", request.query())) })
+    }
+}
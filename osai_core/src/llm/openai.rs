@@ -0,0 +1,67 @@
+// OpenAI 互換の chat/completions。llm.url を変えれば llama.cpp の server (http://localhost:8080/v1) や
+// Ollama (http://localhost:11434/v1) でも動く。API キーは無くてもよい (ローカルのサーバーは要らない)
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde_json::json;
+
+use super::{api_key, http_client, post_json, LlmBackend, LlmRequest, Role};
+use crate::config::LlmConfig;
+
+pub const DEFAULT_URL: &str = "https://api.openai.com/v1";
+pub const DEFAULT_MODEL: &str = "gpt-4o-mini";
+pub const API_KEY_ENV: &str = "OPENAI_API_KEY";
+
+pub struct OpenAiBackend {
+    client: Client,
+    config: LlmConfig,
+    api_key: Option<String>,
+}
+
+impl OpenAiBackend {
+    pub fn new(config: &LlmConfig) -> Result<Self, String> {
+        Ok(OpenAiBackend {
+            client: http_client(config)?,
+            config: config.clone(),
+            api_key: api_key(config, API_KEY_ENV),
+        })
+    }
+
+    fn url(&self) -> String {
+        format!("{}/chat/completions", self.config.url.as_deref().unwrap_or(DEFAULT_URL).trim_end_matches('/'))
+    }
+}
+
+impl LlmBackend for OpenAiBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn generate<'a>(&'a self, request: &'a LlmRequest) -> BoxFuture<'a, Result<String, String>> {
+        Box::pin(async move {
+            let mut messages = Vec::new();
+            if !request.system.is_empty() {
+                messages.push(json!({ "role": "system", "content": request.system }));
+            }
+            for turn in &request.turns {
+                let role = match turn.role {
+                    Role::User => "user",
+                    Role::Model => "assistant",
+                };
+                messages.push(json!({ "role": role, "content": turn.text }));
+            }
+            let payload = json!({
+                "model": self.config.model.as_deref().unwrap_or(DEFAULT_MODEL),
+                "messages": messages,
+            });
+
+            let headers: Vec<(&str, String)> =
+                self.api_key.iter().map(|key| ("authorization", format!("Bearer {}", key))).collect();
+            let response = post_json(&self.client, &self.config, "OpenAI", &self.url(), &headers, &payload).await?;
+            response["choices"][0]["message"]["content"]
+                .as_str()
+                .filter(|text| !text.is_empty())
+                .map(str::to_string)
+                .ok_or_else(|| "API response format error: missing text content.".to_string())
+        })
+    }
+}
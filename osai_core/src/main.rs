@@ -1,31 +1,26 @@
 use osai_core::OSAI;
-use std::io::{self, stdout, Write};
-use std::process;
+use osai_core::p2p::registry::PEER_REGISTRY;
+use osai_core::runtime::{OsaiRuntime, Service};
+use std::io::{stdout, Write};
 use tokio::io::{AsyncBufReadExt, BufReader};
 
-// osai_core::IOT::task から必要な関数をインポート
-use osai_core::IOT::task::add_new_task;
-use osai_core::IOT::task::load_tasks;
-use osai_core::IOT::task::gemini_call;
-use osai_core::IOT::task::display_tasks;
-use osai_core::IOT::task::save_tasks;
-use osai_core::IOT::task::run_task_scheduler;
-
 
 // 戻り値の型を、エラー時に Box<dyn std::error::Error> を返すように修正します。
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
-    // OSAI インスタンスの初期化
-    let osai = OSAI::new();
-
-    let initial_tasks = load_tasks();
-    println!("Loaded {} tasks.", initial_tasks.len());
+    // OSAI インスタンスの初期化。`--config <path>` があればその設定ファイルを読む
+    let args: Vec<String> = std::env::args().collect();
+    let osai = match args.iter().position(|a| a == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).ok_or("Usage: osai-runner [--config <path>]")?;
+            OSAI::load(Some(std::path::Path::new(path)))?
+        }
+        None => OSAI::new(),
+    };
 
-    let scheduler_osai = osai.clone(); // OSAIをクローンしてタスクに所有権を渡す
-    tokio::spawn(async move {
-        run_task_scheduler(scheduler_osai, initial_tasks).await;
-    });
+    // サービスは runtime が持ち、exit で止める
+    let mut runtime = OsaiRuntime::start(osai.clone(), &[Service::Scheduler])?;
     println!("\nOSAI-Core Task Manager is RUNNING.");
 
 
@@ -37,7 +32,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // ターミナルの初期表示
     println!("--- OSAI CLI Interface ---");
-    println!("Commands: server, http_server, text, r_file, vocaloid, play, task <date:time:name>, show_tasks, ai <query>, exit");
+    println!("Commands: server, stats, discover, peers, peer <name|id>, ping <name|id>, http_server, websocket, text [peer message], send_file <path> <peer|ip:port>, identity, r_file, vocaloid, play, task <date:time:name>, task edit|rm|done <id>, task list [filters], task import|export <file.ics>, remote_task <peer> <date:time:name|edit|rm|done|list>, ack [task], snooze [min] [task], remote_ack <peer> [task], remote_snooze <peer> [min] [task], show_tasks, ai [@user] <query>, ai reset [@user], exit");
     
     // 実行結果を保持する変数。ループ内で使用
     let mut output: Result<String, Box<dyn std::error::Error>> = Ok(String::new());
@@ -74,10 +69,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         
         // マッチする対象を main_command に変更
         match main_command {
-            "server" => { let _ = osai.run().await; }
-            "http_server" => OSAI::http_server().await?,
-            "text" => OSAI::send_text_cli().await,
-            "r_file" => OSAI::request_http("172.20.10.2"),
+            "server" => {
+                // 受信ループはバックグラウンドで回し、CLI から stats などを使えるようにする
+                output = runtime.spawn(Service::Server)
+                    .map(|_| "Server started in the background.".to_string())
+                    .map_err(|e| e.into());
+            }
+            "stats" => output = Ok(OSAI::server_stats()),
+            "discover" => {
+                // 返事はサーバーが受けるので、少し待ってから数える
+                output = match runtime.probe().await {
+                    Ok(()) => {
+                        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                        Ok(format!("{} peers known.", PEER_REGISTRY.read().unwrap().len()))
+                    }
+                    Err(e) => Err(e.into()),
+                };
+            }
+            "http_server" => {
+                output = runtime.spawn(Service::Http)
+                    .map(|_| "HTTP server started in the background.".to_string())
+                    .map_err(|e| e.into());
+            }
+            "websocket" => {
+                output = runtime.spawn(Service::WebSocket)
+                    .map(|_| "WebSocket server started in the background.".to_string())
+                    .map_err(|e| e.into());
+            }
+            "peers" => output = Ok(OSAI::peers_cli()),
+            "peer" => output = OSAI::peer_cli(args_str).map_err(|e| e.into()),
+            "ping" => output = osai.ping_cli(args_str).await.map_err(|e| e.into()),
+            "text" => output = osai.send_text_cli(args_str).await.map_err(|e| e.into()),
+            "send_file" => output = osai.send_file_cli(args_str).await.map_err(|e| e.into()),
+            "identity" => output = OSAI::identity_cli().map_err(|e| e.into()),
+            "r_file" => osai.request_http("172.20.10.2"),
             
             // vocaloid コマンドの呼び出し
             "vocaloid" => { 
@@ -86,29 +111,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 output = Ok(format!("Vocaloid processing complete for: '{}'", args_str));
             }
             "play" => OSAI::play(),
-            "task" => {
-                // ... task ロジックは省略せずにそのまま
-                match add_new_task(args_str) {
-                    Ok(new_task) => {
-                        let mut loaded_tasks = load_tasks();
-                        loaded_tasks.push(new_task.clone());
-                        save_tasks(&loaded_tasks)?;
-                        output = Ok(format!("Task added and saved: {}:{}", new_task.datetime, new_task.name));
-                    }
-                    Err(e) => output = Err(e.into()), // エラー型を Box<dyn Error> に変換
-                }
-            }
-            "show_tasks" => {
-                let current_tasks = load_tasks();
-                output = Ok(display_tasks(current_tasks));
-            }
+            // 追加と task edit / rm / done / list
+            "task" => output = osai.task_cli(args_str).map_err(|e| e.into()),
+            "remote_task" => output = osai.remote_task_cli(args_str).await.map_err(|e| e.into()),
+            "ack" => output = osai.ack_cli(args_str).map_err(|e| e.into()),
+            "snooze" => output = osai.snooze_cli(args_str).map_err(|e| e.into()),
+            "remote_ack" => output = osai.remote_ack_cli(args_str).await.map_err(|e| e.into()),
+            "remote_snooze" => output = osai.remote_snooze_cli(args_str).await.map_err(|e| e.into()),
+            "show_tasks" => output = osai.task_cli("list").map_err(|e| e.into()),
             
             "ai" => {
                 if args_str.is_empty() {
                     output = Err("Error: 'ai' command requires a query.".into());
+                } else if args_str == "reset" || args_str.starts_with("reset ") {
+                    output = osai.reset_ai(&args_str["reset".len()..]).await.map_err(|e| e.into());
                 } else {
-                    // Gemini APIを呼び出し、応答を得る
-                    match gemini_call(&(args_str.to_owned() + "ひらがなで返して")).await {
+                    // LLM (llm.backend) を呼び出し、応答を得る
+                    match osai.ask_ai(args_str).await {
                         Ok(response_text) => {
                             println!("[AI Text Generated]: {}", response_text);
                             
@@ -126,18 +145,50 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
             "help" => {
-                output = Ok(format!(
-                    "Available commands:
-  ai <query>         : Ask the Gemini AI a question and get a vocal response.
+                output = Ok("Available commands:
+  ai <query>         : Ask the AI (llm.backend) a question and get a vocal response. The AI remembers the conversation.
+  ai @name <query>   : Talk as another user (each user has their own conversation history).
+  ai reset [@name]   : Forget the conversation history.
   task <date:time:name> : Add a new task (e.g., task 2025-12-25:08:30:Wake up call)
+  task <date:time:name @ rule> : Repeat a task: daily, weekdays, weekly mon,wed, every 30 minutes, cron 0 8 * * 1-5.
+  task <date:time:name @ remind 30,5,0> : Remind 30 and 5 minutes before and at the time (combine with a rule: @ daily @ remind 30,5,0).
+  task <date:time:name @ tags care,medicine> : Tag a task (filter with task list tag=care).
+  task <time name>   : The time can also be ISO 8601 (2025-12-25T08:30+09:00), 08:00, tomorrow 8am, mon 09:00, in 20m, 明日8時, 20分後 or 毎朝7時.
+  task <date:time:name @ tz Europe/Berlin> : Read the time in another time zone (default: scheduler.timezone or this machine).
+  task edit <id> <field=value; ...> : Change name, at (any task time), repeat (rule or none), remind (30,5,0 or default), tags or tz.
+  task rm <id>       : Delete a task. <id> is the ID shown by show_tasks (4+ leading characters) or the task name.
+  task done <id>     : Finish a task now (a repeating task skips to its next occurrence).
+  task list [from=YYYY-MM-DD] [to=YYYY-MM-DD] [state=pending] [tag=care] : List tasks matching the filters.
+  task import <file.ics> : Add the events of an iCalendar file as tasks (importing the same file again updates them).
+  task export <file.ics> [filters] : Write tasks (with the same filters as task list) to an iCalendar file.
   show_tasks         : Display all scheduled tasks.
+  remote_task <peer> <date:time:name> : Register a task on another node (e.g. the doll) and show whether it was accepted.
+  remote_task <peer> edit|rm|done|list ... : Same as task edit / rm / done / list on another node.
+  ack [task]         : Acknowledge a ringing reminder (all ringing reminders if no task is given).
+  snooze [min] [task] : Ring the reminder again after min minutes (scheduler.snooze_mins by default).
+  remote_ack <peer> [task] : Acknowledge a reminder ringing on another node (e.g. after a caregiver alert).
+  remote_snooze <peer> [min] [task] : Snooze a reminder ringing on another node.
+  send_file <path> <peer|ip:port> : Send a file to another OSAI server (saved in its share dir).
+  stats              : Show receive counters and dropped packets of the running server.
+  discover           : Ask nodes on the network to announce themselves now (server must be running).
+  peers              : List peers found by signals (name, id, device, ports).
+  peer <name|id>     : Show everything a peer announced.
+  ping <name|id|ip:port> [count] : Measure round-trip time and packet loss to a peer.
+  text [peer message] : Send text to a peer by name (asks for the destination if omitted).
+  identity           : Show this node's X25519 public key and whether unauthenticated packets are accepted.
   vocaloid <text>    : Speak custom text.
   exit | quit        : Stop the application."
-                ));
+                    .to_string());
             }
-            "exit" | "quit" => process::exit(0),
+            "exit" | "quit" => break,
             "" => continue,
             _ => output = Err(format!("Unknown command: {}", full_cmd).into()), // 未知のコマンドもエラーとして扱う
         }
     }
+
+    // サーバーやスケジューラーを止めてから終わる
+    if let Err(e) = runtime.shutdown().await {
+        eprintln!("Shutdown error: {}", e);
+    }
+    Ok(())
 }
@@ -0,0 +1,3 @@
+// src/p2p/mod.rs
+pub mod node;
+pub mod registry;
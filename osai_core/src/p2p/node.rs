@@ -0,0 +1,121 @@
+// ノードIDと機器の種類。生存シグナルに載せて、アドレスやポートが変わっても同じノードだとわかるようにする
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use rand::Rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const NODE_ID_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; NODE_ID_LEN]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0u8; NODE_ID_LEN];
+        rand::rng().fill(&mut id);
+        NodeId(id)
+    }
+
+    /// `path` に保存したIDを読む。無ければ作って保存する
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read node id {}: {}", path.display(), e))?;
+            return text.trim().parse();
+        }
+        let id = Self::random();
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        fs::write(path, format!("{}\n", id))
+            .map_err(|e| format!("Failed to write node id {}: {}", path.display(), e))?;
+        Ok(id)
+    }
+
+    /// 表示用の短い形 (先頭8文字)
+    pub fn short(&self) -> String {
+        hex::encode(&self.0[..4])
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+impl FromStr for NodeId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s.trim()).map_err(|e| format!("Invalid node id {}: {}", s, e))?;
+        let id: [u8; NODE_ID_LEN] = bytes
+            .try_into()
+            .map_err(|_| format!("Node id must be {} hex characters", NODE_ID_LEN * 2))?;
+        Ok(NodeId(id))
+    }
+}
+
+impl Serialize for NodeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// どんな機器で動いているか。知らない値は Unknown として読む
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    /// 人形 (スピーカー付き)
+    Doll,
+    Tablet,
+    Sensor,
+    Pc,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DeviceKind::Doll => "doll",
+            DeviceKind::Tablet => "tablet",
+            DeviceKind::Sensor => "sensor",
+            DeviceKind::Pc => "pc",
+            DeviceKind::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for DeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "doll" => Ok(DeviceKind::Doll),
+            "tablet" => Ok(DeviceKind::Tablet),
+            "sensor" => Ok(DeviceKind::Sensor),
+            "pc" => Ok(DeviceKind::Pc),
+            "unknown" => Ok(DeviceKind::Unknown),
+            other => Err(format!("Unknown device kind: {}", other)),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+// 生存シグナルで見つけたピアの一覧。ノードIDで管理し、TTL の間シグナルが無いピアは消す。
+// 参加・離脱は subscribe() で受け取れる
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::p2p::node::NodeId;
+use crate::protocol::announce::Announce;
+use crate::protocol::packet::Format;
+
+/// シグナルが途切れてから消すまでの時間のデフォルト (シグナル5回分)
+pub const DEFAULT_PEER_TTL: Duration = Duration::from_secs(10);
+/// これ以上は覚えない (偽装したシグナルを大量に送られても膨らまないように)
+const MAX_PEERS: usize = 1024;
+/// subscribe() 側が読み遅れたときに溜めておくイベント数
+const EVENT_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRecord {
+    pub node_id: NodeId,
+    /// 最後にシグナルを受けたアドレス
+    pub ip: IpAddr,
+    /// 最後に受けたシグナルの本文 (名前、ポート、対応フォーマットなど)
+    pub info: Announce,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+}
+
+impl PeerRecord {
+    /// OSAI UDP サーバーのアドレス
+    pub fn udp_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.info.ports.udp)
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.info.ports.http.map(|port| SocketAddr::new(self.ip, port))
+    }
+
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.info.ports.ws.map(|port| SocketAddr::new(self.ip, port))
+    }
+
+    /// このピアに `format` を送って処理してもらえるか
+    pub fn supports(&self, format: Format) -> bool {
+        self.info.supports(format)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PeerEvent {
+    Joined(PeerRecord),
+    /// TTL の間シグナルが無かった
+    Left(PeerRecord),
+}
+
+pub struct PeerRegistry {
+    ttl: Duration,
+    peers: HashMap<NodeId, PeerRecord>,
+    events: broadcast::Sender<PeerEvent>,
+}
+
+impl PeerRegistry {
+    pub fn new(ttl: Duration) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        PeerRegistry { ttl, peers: HashMap::new(), events }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+        self.events.subscribe()
+    }
+
+    /// `ip` から届いたシグナルを記録する。初めてのピアなら Joined を流して true
+    pub fn observe(&mut self, ip: IpAddr, announce: &Announce, now: Instant) -> bool {
+        if let Some(peer) = self.peers.get_mut(&announce.node_id) {
+            peer.ip = ip;
+            peer.info = announce.clone();
+            peer.last_seen = now;
+            return false;
+        }
+
+        if self.peers.len() >= MAX_PEERS {
+            self.expire(now);
+            if self.peers.len() >= MAX_PEERS {
+                return false;
+            }
+        }
+        let peer = PeerRecord {
+            node_id: announce.node_id,
+            ip,
+            info: announce.clone(),
+            first_seen: now,
+            last_seen: now,
+        };
+        self.peers.insert(peer.node_id, peer.clone());
+        // 受け手がいなくてもエラーになるだけなので無視する
+        let _ = self.events.send(PeerEvent::Joined(peer));
+        true
+    }
+
+    /// TTL を過ぎたピアを消して返す
+    pub fn expire(&mut self, now: Instant) -> Vec<PeerRecord> {
+        let ttl = self.ttl;
+        let expired: Vec<NodeId> = self
+            .peers
+            .values()
+            .filter(|p| now.saturating_duration_since(p.last_seen) > ttl)
+            .map(|p| p.node_id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.peers.remove(&id))
+            .inspect(|peer| {
+                let _ = self.events.send(PeerEvent::Left(peer.clone()));
+            })
+            .collect()
+    }
+
+    pub fn get(&self, node_id: &NodeId) -> Option<&PeerRecord> {
+        self.peers.get(node_id)
+    }
+
+    /// 名前 (大文字小文字は区別しない) かノードIDの先頭 (`short()` の8文字など) で探す。
+    /// 複数当てはまればエラーにして候補を返す
+    pub fn find(&self, query: &str) -> Result<PeerRecord, String> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Err("No peer given".to_string());
+        }
+        if let Some(peer) = self.peers.values().find(|p| p.info.name.eq_ignore_ascii_case(query)) {
+            return Ok(peer.clone());
+        }
+
+        let prefix = query.to_ascii_lowercase();
+        let matches: Vec<&PeerRecord> = self
+            .peers
+            .values()
+            .filter(|p| p.node_id.to_string().starts_with(&prefix))
+            .collect();
+        match matches.as_slice() {
+            [peer] => Ok((*peer).clone()),
+            [] => Err(format!("No peer named {} (try `peers`)", query)),
+            _ => Err(format!(
+                "{} matches several peers: {}",
+                query,
+                matches
+                    .iter()
+                    .map(|p| format!("{} [{}]", p.info.name, p.node_id.short()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    /// 見つけた順
+    pub fn peers(&self) -> Vec<PeerRecord> {
+        let mut peers: Vec<PeerRecord> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|p| p.first_seen);
+        peers
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+impl Default for PeerRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_PEER_TTL)
+    }
+}
+
+/// TTL は start_server が discovery.peer_ttl_secs で設定する
+pub static PEER_REGISTRY: Lazy<RwLock<PeerRegistry>> = Lazy::new(|| {
+    RwLock::new(PeerRegistry::default())
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::p2p::node::DeviceKind;
+    use crate::protocol::announce::{ServicePorts, ANNOUNCE_VERSION};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn announce(name: &str) -> Announce {
+        Announce {
+            version: ANNOUNCE_VERSION,
+            node_id: NodeId::random(),
+            name: name.to_string(),
+            software: Announce::software_version(),
+            device: DeviceKind::default(),
+            ports: ServicePorts { udp: 8080, http: None, ws: None },
+            formats: vec![Format::TEXT],
+            capabilities: Vec::new(),
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    #[test]
+    fn peers_expire_after_the_ttl() {
+        let start = Instant::now();
+        let ttl = Duration::from_secs(10);
+        let mut registry = PeerRegistry::new(ttl);
+        let doll = announce("doll");
+        let tablet = announce("tablet");
+        registry.observe(ip(2), &doll, start);
+        registry.observe(ip(3), &tablet, start + Duration::from_secs(5));
+
+        // ちょうど TTL ではまだ消さない
+        assert!(registry.expire(start + ttl).is_empty());
+        let expired = registry.expire(start + ttl + Duration::from_millis(1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].node_id, doll.node_id);
+        assert_eq!(registry.len(), 1);
+
+        // シグナルが届けば last_seen が進んで残る
+        assert!(!registry.observe(ip(4), &tablet, start + Duration::from_secs(14)));
+        assert!(registry.expire(start + Duration::from_secs(20)).is_empty());
+        let peer = registry.get(&tablet.node_id).unwrap();
+        assert_eq!(peer.ip, ip(4));
+        assert_eq!(peer.first_seen, start + Duration::from_secs(5));
+        assert_eq!(registry.expire(start + Duration::from_secs(25)).len(), 1);
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn joined_and_left_events() {
+        let start = Instant::now();
+        let mut registry = PeerRegistry::new(Duration::from_secs(10));
+        let mut events = registry.subscribe();
+        let doll = announce("doll");
+
+        assert!(registry.observe(ip(2), &doll, start));
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Joined(peer)) if peer.node_id == doll.node_id));
+        // 知っているピアのシグナルではイベントを流さない
+        assert!(!registry.observe(ip(2), &doll, start + Duration::from_secs(1)));
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+
+        registry.expire(start + Duration::from_secs(12));
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Left(peer)) if peer.node_id == doll.node_id));
+
+        // 消えたあとにまた届けば Joined
+        assert!(registry.observe(ip(2), &doll, start + Duration::from_secs(13)));
+        assert!(matches!(events.try_recv(), Ok(PeerEvent::Joined(_))));
+    }
+
+    #[test]
+    fn full_registry_makes_room_from_expired_peers() {
+        let start = Instant::now();
+        let mut registry = PeerRegistry::new(Duration::from_secs(10));
+        for n in 0..MAX_PEERS {
+            registry.observe(ip((n % 250) as u8), &announce(&format!("node-{}", n)), start);
+        }
+        let late = announce("late");
+        assert!(!registry.observe(ip(1), &late, start + Duration::from_secs(5)));
+        assert!(registry.get(&late.node_id).is_none());
+
+        assert!(registry.observe(ip(1), &late, start + Duration::from_secs(11)));
+        assert_eq!(registry.peers().len(), 1);
+    }
+}
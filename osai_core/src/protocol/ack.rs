@@ -0,0 +1,188 @@
+// 信頼モードの選択的 ACK/NACK (Format::ACK)
+//
+// payload レイアウト:
+// | status 1 | total 4 (u32 BE, 0xFFFFFFFF = 不明) | range数 2 | (start 4, end 4) * range数 |
+//
+// range は受信側が持っているチャンク番号の半開区間 [start, end)。
+// 入りきらない range は後ろから捨てる。range が MAX_ACK_RANGES 個ある ACK を受けた送信側は、
+// 最後の range より後ろを分からないものとして扱い、前の抜けが埋まってから改めて聞く
+use crate::protocol::packet::{
+    ChunkId, EmotionVec, Format, OsaiPacket, PacketError, SessionId,
+};
+use crate::protocol::secure::MAX_SEALABLE_PAYLOAD;
+
+const UNKNOWN_TOTAL: u32 = u32::MAX;
+const ACK_HEADER_LEN: usize = 1 + 4 + 2;
+const RANGE_LEN: usize = 8;
+// 暗号化しても1パケットに収まるようにする
+pub const MAX_ACK_RANGES: usize = (MAX_SEALABLE_PAYLOAD - ACK_HEADER_LEN) / RANGE_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStatus {
+    /// 全チャンク受信済み
+    Complete,
+    /// END は届いたが抜けがある (NACK)
+    Partial,
+    /// タイムアウトなどで受信側が諦めた
+    Failed,
+}
+
+impl AckStatus {
+    fn to_byte(self) -> u8 {
+        match self {
+            AckStatus::Complete => 0,
+            AckStatus::Partial => 1,
+            AckStatus::Failed => 2,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(AckStatus::Complete),
+            1 => Some(AckStatus::Partial),
+            2 => Some(AckStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckBody {
+    pub status: AckStatus,
+    /// 受信側が知っている総チャンク数
+    pub total: Option<u32>,
+    /// 受信済みチャンクの半開区間
+    pub held: Vec<(u32, u32)>,
+}
+
+impl AckBody {
+    pub fn complete(total: u32) -> Self {
+        AckBody { status: AckStatus::Complete, total: Some(total), held: vec![(0, total)] }
+    }
+
+    pub fn failed(total: Option<u32>) -> Self {
+        AckBody { status: AckStatus::Failed, total, held: Vec::new() }
+    }
+
+    /// 受信済み番号 (昇順) から Partial を作る
+    pub fn partial<I: IntoIterator<Item = u32>>(total: Option<u32>, held: I) -> Self {
+        AckBody { status: AckStatus::Partial, total, held: to_ranges(held) }
+    }
+
+    pub fn holds(&self, index: u32) -> bool {
+        self.held.iter().any(|&(start, end)| start <= index && index < end)
+    }
+
+    /// `total` 個のうち受信側が持っていないチャンク番号 (range は昇順)。
+    /// 切り詰められたかもしれない ACK では最後の range より後ろは数えない
+    pub fn missing(&self, total: u32) -> Vec<u32> {
+        let known = match self.held.last() {
+            Some(&(_, end)) if self.held.len() >= MAX_ACK_RANGES => end.min(total),
+            _ => total,
+        };
+        let mut missing = Vec::new();
+        let mut next = 0;
+        for &(start, end) in &self.held {
+            missing.extend(next..start.min(known));
+            next = next.max(end);
+        }
+        missing.extend(next..known);
+        missing
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let ranges = &self.held[..self.held.len().min(MAX_ACK_RANGES)];
+        let mut buf = Vec::with_capacity(ACK_HEADER_LEN + ranges.len() * RANGE_LEN);
+        buf.push(self.status.to_byte());
+        buf.extend_from_slice(&self.total.unwrap_or(UNKNOWN_TOTAL).to_be_bytes());
+        buf.extend_from_slice(&(ranges.len() as u16).to_be_bytes());
+        for (start, end) in ranges {
+            buf.extend_from_slice(&start.to_be_bytes());
+            buf.extend_from_slice(&end.to_be_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        if payload.len() < ACK_HEADER_LEN {
+            return Err(PacketError::TooShort { len: payload.len() });
+        }
+        let status = AckStatus::from_byte(payload[0])
+            .ok_or(PacketError::Malformed("unknown ack status"))?;
+        let total = u32::from_be_bytes(payload[1..5].try_into().unwrap());
+        let count = u16::from_be_bytes(payload[5..7].try_into().unwrap()) as usize;
+
+        let body = &payload[ACK_HEADER_LEN..];
+        if body.len() != count * RANGE_LEN {
+            return Err(PacketError::Malformed("ack range count does not match length"));
+        }
+        let held = body
+            .chunks_exact(RANGE_LEN)
+            .map(|r| {
+                (
+                    u32::from_be_bytes(r[0..4].try_into().unwrap()),
+                    u32::from_be_bytes(r[4..8].try_into().unwrap()),
+                )
+            })
+            .collect();
+
+        Ok(AckBody {
+            status,
+            total: if total == UNKNOWN_TOTAL { None } else { Some(total) },
+            held,
+        })
+    }
+
+    pub fn into_packet(self, session_id: SessionId) -> OsaiPacket {
+        OsaiPacket::new(session_id, ChunkId::SINGLE, Format::ACK, EmotionVec::default(), self.encode())
+    }
+}
+
+fn to_ranges<I: IntoIterator<Item = u32>>(indices: I) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for index in indices {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == index => *end += 1,
+            _ => ranges.push((index, index.saturating_add(1))),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let ack = AckBody::partial(Some(10), [0, 1, 2, 5, 7, 8]);
+        assert_eq!(ack.held, [(0, 3), (5, 6), (7, 9)]);
+        assert_eq!(AckBody::decode(&ack.encode()).unwrap(), ack);
+        assert_eq!(AckBody::decode(&AckBody::failed(None).encode()).unwrap(), AckBody::failed(None));
+        assert!(AckBody::decode(&ack.encode()[..ACK_HEADER_LEN + 3]).is_err());
+    }
+
+    #[test]
+    fn missing_walks_the_ranges() {
+        let ack = AckBody::partial(Some(10), [0, 1, 2, 5, 7, 8]);
+        assert_eq!(ack.missing(10), [3, 4, 6, 9]);
+        // まだ送っていないチャンクは聞かない
+        assert_eq!(ack.missing(6), [3, 4]);
+        assert!(AckBody::complete(10).missing(10).is_empty());
+        assert_eq!(AckBody::partial(Some(3), []).missing(3), [0, 1, 2]);
+    }
+
+    #[test]
+    fn truncated_ranges_do_not_count_as_missing() {
+        // 1つおきに持っている: range が入りきらない
+        let total = (MAX_ACK_RANGES as u32 + 10) * 2;
+        let ack = AckBody::partial(Some(total), (0..total).step_by(2));
+        let decoded = AckBody::decode(&ack.encode()).unwrap();
+        assert_eq!(decoded.held.len(), MAX_ACK_RANGES);
+        assert!(ack.encode().len() <= MAX_SEALABLE_PAYLOAD);
+
+        let missing = decoded.missing(total);
+        assert_eq!(missing.len(), MAX_ACK_RANGES - 1);
+        assert!(missing.iter().all(|i| i % 2 == 1 && !ack.holds(*i)));
+    }
+}
@@ -0,0 +1,177 @@
+// サーバー生存シグナル (Format::SIGNAL) の本文。JSON で送る。
+//
+// ```json
+// {"version":1,"node_id":"<hex 32文字>","name":"living-doll","software":"osai_core/0.1.0",
+//  "device":"doll","ports":{"udp":8080,"http":1234,"ws":null},
+//  "formats":[[0,0],[0,2],[0,3]],"capabilities":["speaker"]}
+// ```
+//
+// 送信元ポートはシグナル用の一時ソケットのものなので、受信側は ports.udp を使って返信する。
+// プローブ (Format::PROBE) も同じ本文で、受けたノードは ports.udp へすぐにシグナルを返す。
+// 同じ version で増えた知らないフィールドは無視して読む。形が変わるときは version を上げ、
+// 知らない version の本文は読まずに捨てる。
+use serde::{Deserialize, Serialize};
+
+use crate::p2p::node::{DeviceKind, NodeId};
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, PacketError, SessionId, MAX_PAYLOAD_SIZE};
+
+pub const ANNOUNCE_VERSION: u8 = 1;
+/// name はこれより長ければ切り詰める (文字数)
+pub const MAX_NAME_LEN: usize = 64;
+/// formats / capabilities はこれより多ければ捨てる
+const MAX_LIST_LEN: usize = 64;
+
+/// このノードで動いているサービスのポート。動いていなければ None
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServicePorts {
+    /// OSAI UDP サーバー
+    pub udp: u16,
+    pub http: Option<u16>,
+    pub ws: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announce {
+    pub version: u8,
+    pub node_id: NodeId,
+    /// 人が読む名前
+    pub name: String,
+    /// "osai_core/0.1.0" など
+    pub software: String,
+    #[serde(default)]
+    pub device: DeviceKind,
+    pub ports: ServicePorts,
+    /// ハンドラーが登録されているフォーマット (このノードに頼めること)
+    #[serde(default)]
+    pub formats: Vec<Format>,
+    /// できること ("speaker", "display" など)。中身は決めていない
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Announce {
+    pub fn software_version() -> String {
+        format!("osai_core/{}", env!("CARGO_PKG_VERSION"))
+    }
+
+    pub fn supports(&self, format: Format) -> bool {
+        self.formats.contains(&format)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        let body = serde_json::to_vec(self).map_err(|_| PacketError::Malformed("announce could not be serialized"))?;
+        if body.len() > MAX_PAYLOAD_SIZE {
+            return Err(PacketError::TooLarge { len: body.len() });
+        }
+        Ok(body)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        let mut announce: Announce = serde_json::from_slice(payload)
+            .map_err(|_| PacketError::Malformed("invalid announce body"))?;
+        if announce.version == 0 || announce.version > ANNOUNCE_VERSION {
+            return Err(PacketError::Malformed("unsupported announce version"));
+        }
+        if announce.name.chars().count() > MAX_NAME_LEN {
+            announce.name = announce.name.chars().take(MAX_NAME_LEN).collect();
+        }
+        announce.formats.truncate(MAX_LIST_LEN);
+        announce.capabilities.truncate(MAX_LIST_LEN);
+        Ok(announce)
+    }
+
+    /// 1パケットで完結するので chunk は SINGLE
+    pub fn into_packet(self) -> Result<OsaiPacket, PacketError> {
+        self.into_packet_as(Format::SIGNAL)
+    }
+
+    /// 同じ本文のプローブ
+    pub fn into_probe_packet(self) -> Result<OsaiPacket, PacketError> {
+        self.into_packet_as(Format::PROBE)
+    }
+
+    fn into_packet_as(self, format: Format) -> Result<OsaiPacket, PacketError> {
+        Ok(OsaiPacket::new(
+            SessionId::random(),
+            ChunkId::SINGLE,
+            format,
+            EmotionVec::default(), // シグナル用なので全て0でOK
+            self.encode()?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Announce {
+        Announce {
+            version: ANNOUNCE_VERSION,
+            node_id: NodeId::random(),
+            name: "living-doll".to_string(),
+            software: Announce::software_version(),
+            device: DeviceKind::Doll,
+            ports: ServicePorts { udp: 8080, http: Some(1234), ws: None },
+            formats: vec![Format::TEXT, Format::TASK],
+            capabilities: vec!["speaker".to_string()],
+        }
+    }
+
+    fn with_version(version: u8) -> Vec<u8> {
+        let mut body: serde_json::Value = serde_json::from_slice(&sample().encode().unwrap()).unwrap();
+        body["version"] = version.into();
+        serde_json::to_vec(&body).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let announce = sample();
+        let decoded = Announce::decode(&announce.encode().unwrap()).unwrap();
+        assert_eq!(decoded, announce);
+        assert!(decoded.supports(Format::TASK));
+        assert!(!decoded.supports(Format::FILE));
+
+        let packet = OsaiPacket::decode(&announce.clone().into_packet().unwrap().encode().unwrap()).unwrap();
+        assert_eq!(packet.format, Format::SIGNAL);
+        assert!(packet.chunk.is_single());
+        assert_eq!(Announce::decode(&packet.payload).unwrap(), announce);
+    }
+
+    #[test]
+    fn unknown_fields_and_device_are_ignored() {
+        let mut body: serde_json::Value = serde_json::from_slice(&sample().encode().unwrap()).unwrap();
+        body["device"] = "toaster".into();
+        body["battery"] = 80.into();
+        let decoded = Announce::decode(&serde_json::to_vec(&body).unwrap()).unwrap();
+        assert_eq!(decoded.device, DeviceKind::Unknown);
+        assert_eq!(decoded.ports.udp, 8080);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert!(Announce::decode(&with_version(ANNOUNCE_VERSION)).is_ok());
+        assert!(Announce::decode(&with_version(0)).is_err());
+        assert!(Announce::decode(&with_version(ANNOUNCE_VERSION + 1)).is_err());
+        assert!(Announce::decode(&with_version(u8::MAX)).is_err());
+    }
+
+    #[test]
+    fn truncated_bodies_are_rejected() {
+        let body = sample().encode().unwrap();
+        for len in 0..body.len() {
+            assert!(Announce::decode(&body[..len]).is_err(), "accepted {} of {} bytes", len, body.len());
+        }
+        assert!(Announce::decode(b"\xff\xfe").is_err());
+    }
+
+    #[test]
+    fn long_names_are_cut() {
+        let mut announce = sample();
+        announce.name = "あ".repeat(MAX_NAME_LEN + 10);
+        announce.formats = vec![Format::TEXT; MAX_LIST_LEN + 1];
+        let decoded = Announce::decode(&announce.encode().unwrap()).unwrap();
+        assert_eq!(decoded.name.chars().count(), MAX_NAME_LEN);
+        assert_eq!(decoded.formats.len(), MAX_LIST_LEN);
+    }
+}
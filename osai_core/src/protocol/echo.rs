@@ -0,0 +1,84 @@
+// ping の本文 (Format::ECHO)
+//
+// payload レイアウト:
+// | kind 1 (0 = request, 1 = reply) | seq 4 (u32 BE) |
+//
+// 受信側は同じ session_id と seq で reply を返すだけ。RTT は送信側が自分の時計で測る。
+// 鍵が設定されていれば request と同じ鍵で封をして返す
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, PacketError, SessionId};
+
+const ECHO_LEN: usize = 1 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoKind {
+    Request,
+    Reply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EchoBody {
+    pub kind: EchoKind,
+    pub seq: u32,
+}
+
+impl EchoBody {
+    pub fn request(seq: u32) -> Self {
+        EchoBody { kind: EchoKind::Request, seq }
+    }
+
+    /// request への返事
+    pub fn reply(&self) -> Self {
+        EchoBody { kind: EchoKind::Reply, seq: self.seq }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(ECHO_LEN);
+        buf.push(match self.kind {
+            EchoKind::Request => 0,
+            EchoKind::Reply => 1,
+        });
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        if payload.len() != ECHO_LEN {
+            return Err(PacketError::Malformed("echo body has wrong length"));
+        }
+        let kind = match payload[0] {
+            0 => EchoKind::Request,
+            1 => EchoKind::Reply,
+            _ => return Err(PacketError::Malformed("unknown echo kind")),
+        };
+        let seq = u32::from_be_bytes(payload[1..].try_into().unwrap());
+        Ok(EchoBody { kind, seq })
+    }
+
+    /// 1パケットで完結するので chunk は SINGLE
+    pub fn into_packet(self, session_id: SessionId) -> OsaiPacket {
+        OsaiPacket::new(session_id, ChunkId::SINGLE, Format::ECHO, EmotionVec::default(), self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for body in [EchoBody::request(0), EchoBody::request(7).reply(), EchoBody::request(u32::MAX)] {
+            let encoded = body.encode();
+            assert_eq!(encoded.len(), ECHO_LEN);
+            assert_eq!(EchoBody::decode(&encoded).unwrap(), body);
+        }
+        assert_eq!(EchoBody::request(0x01020304).reply().encode(), [1, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn malformed_bodies_are_rejected() {
+        assert!(EchoBody::decode(&[]).is_err());
+        assert!(EchoBody::decode(&[0, 0, 0, 1]).is_err());
+        assert!(EchoBody::decode(&[0, 0, 0, 0, 1, 0]).is_err());
+        assert!(EchoBody::decode(&[2, 0, 0, 0, 1]).is_err());
+    }
+}
@@ -0,0 +1,71 @@
+// ファイル転送 (Format::FILE) のヘッダー
+//
+// チャンク0 がヘッダー、チャンク1以降がファイルの中身。
+// 中身のチャンクは最後以外すべて CHUNK_SIZE バイトなので、受信側は
+// チャンク番号 i を (i - 1) * CHUNK_SIZE の位置に書けばよい。
+//
+// ヘッダーのレイアウト:
+// | version 1 | size 8 (u64 BE) | sha256 32 | name長 2 (u16 BE) | name (UTF-8) |
+use std::path::Path;
+
+use crate::protocol::packet::PacketError;
+use crate::protocol::secure::MAX_SEALABLE_PAYLOAD;
+
+pub const FILE_HEADER_VERSION: u8 = 1;
+const FIXED_LEN: usize = 1 + 8 + 32 + 2;
+pub const MAX_FILE_NAME_LEN: usize = MAX_SEALABLE_PAYLOAD - FIXED_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    /// ディレクトリを含まないファイル名
+    pub name: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+impl FileHeader {
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        let name = self.name.as_bytes();
+        if name.len() > MAX_FILE_NAME_LEN {
+            return Err(PacketError::TooLarge { len: FIXED_LEN + name.len() });
+        }
+        let mut buf = Vec::with_capacity(FIXED_LEN + name.len());
+        buf.push(FILE_HEADER_VERSION);
+        buf.extend_from_slice(&self.size.to_be_bytes());
+        buf.extend_from_slice(&self.sha256);
+        buf.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(name);
+        Ok(buf)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        if payload.len() < FIXED_LEN {
+            return Err(PacketError::TooShort { len: payload.len() });
+        }
+        if payload[0] != FILE_HEADER_VERSION {
+            return Err(PacketError::Malformed("unsupported file header version"));
+        }
+        let size = u64::from_be_bytes(payload[1..9].try_into().unwrap());
+        let sha256: [u8; 32] = payload[9..41].try_into().unwrap();
+        let name_len = u16::from_be_bytes(payload[41..43].try_into().unwrap()) as usize;
+        if payload.len() != FIXED_LEN + name_len {
+            return Err(PacketError::Malformed("file name length does not match"));
+        }
+        let name = std::str::from_utf8(&payload[FIXED_LEN..])
+            .map_err(|_| PacketError::Malformed("file name is not UTF-8"))?
+            .to_string();
+        if !is_plain_file_name(&name) {
+            return Err(PacketError::Malformed("file name must not contain a path"));
+        }
+
+        Ok(FileHeader { name, size, sha256 })
+    }
+}
+
+/// `a.txt` のようなディレクトリを含まない名前か (`../x` や `/etc/x` を弾く)
+pub fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(['/', '\\'])
+        && Path::new(name).file_name().map(|n| n == name).unwrap_or(false)
+}
@@ -0,0 +1,10 @@
+// src/protocol/mod.rs
+pub mod packet;
+pub mod ack;
+pub mod file;
+pub mod secure;
+pub mod announce;
+pub mod echo;
+pub mod task_reply;
+pub mod task_alert;
+pub mod task_ops;
@@ -0,0 +1,372 @@
+// OSAI UDP パケットのコーデック
+//
+// レイアウト (すべてビッグエンディアン):
+// | session_id 16 | chunk 8 | format 2 | data_vec 14 | payload ... |
+//
+// server / server_signal / client はすべてここを通してエンコード・デコードする。
+use std::fmt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+pub const SESSION_ID_LEN: usize = 16;
+pub const CHUNK_ID_LEN: usize = 8;
+pub const FORMAT_LEN: usize = 2;
+pub const EMOTION_VEC_LEN: usize = 14;
+
+/// ヘッダー部分 (session_id + chunk + format + data_vec) の長さ
+pub const HEADER_LEN: usize = SESSION_ID_LEN + CHUNK_ID_LEN + FORMAT_LEN + EMOTION_VEC_LEN;
+/// Ethernet MTU(1500) - IPv4(20) - UDP(8) に収まる最大サイズ
+pub const MAX_PACKET_SIZE: usize = 1472;
+pub const MAX_PAYLOAD_SIZE: usize = MAX_PACKET_SIZE - HEADER_LEN;
+
+/// 1つのメッセージ(送信単位)を識別する16バイトのID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub struct SessionId(pub [u8; SESSION_ID_LEN]);
+
+impl SessionId {
+    pub fn random() -> Self {
+        let mut id = [0u8; SESSION_ID_LEN];
+        rand::rng().fill(&mut id);
+        SessionId(id)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+/// チャンク番号。
+/// `END` は分割メッセージの終端 (payload は空か、総チャンク数の u32 BE)。
+/// `SINGLE` は1パケットで完結するメッセージ (シグナルなど)。
+/// データチャンクは下位32bitが番号で、最上位bitが立っていれば受信側に ACK を要求する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkId(pub u64);
+
+const ACK_REQUEST_BIT: u64 = 1 << 63;
+
+impl ChunkId {
+    pub const END: ChunkId = ChunkId(u64::MAX);
+    pub const SINGLE: ChunkId = ChunkId(u64::MAX - 1);
+
+    pub fn new(index: u32) -> Self {
+        ChunkId(index as u64)
+    }
+
+    /// ACK を要求するデータチャンク (信頼モード)
+    pub fn reliable(index: u32) -> Self {
+        ChunkId(ACK_REQUEST_BIT | index as u64)
+    }
+
+    pub fn wants_ack(&self) -> bool {
+        !self.is_end() && !self.is_single() && self.0 & ACK_REQUEST_BIT != 0
+    }
+
+    pub fn is_end(&self) -> bool {
+        *self == ChunkId::END
+    }
+
+    pub fn is_single(&self) -> bool {
+        *self == ChunkId::SINGLE
+    }
+
+    /// データチャンクの番号。END / SINGLE なら `None`
+    pub fn index(&self) -> Option<u32> {
+        if self.is_end() || self.is_single() {
+            None
+        } else {
+            u32::try_from(self.0 & !ACK_REQUEST_BIT).ok()
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; CHUNK_ID_LEN] {
+        self.0.to_be_bytes()
+    }
+}
+
+/// 2バイトのフォーマットコード。serde では [上位, 下位] の配列になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Format(pub [u8; FORMAT_LEN]);
+
+impl Format {
+    /// UTF-8 テキスト
+    pub const TEXT: Format = Format([0, 0]);
+    /// サーバー発見の応答
+    pub const DISCOVERY: Format = Format([0, 1]);
+    /// 感情ベクトル + 歌詞 (AI 学習)
+    pub const EMOTION: Format = Format([0, 2]);
+    /// タスク登録 `YYYY-MM-DD:HH:MM:name` (日時の書き方は IOT::when。`in 20m 薬` なども受けたノードの今から)
+    pub const TASK: Format = Format([0, 3]);
+    /// 信頼モードの選択的 ACK/NACK (protocol::ack)
+    pub const ACK: Format = Format([0, 4]);
+    /// ファイル転送 (protocol::file)
+    pub const FILE: Format = Format([0, 5]);
+    /// 暗号化された封筒。中に本来のパケットが入っている (protocol::secure)
+    pub const SEALED: Format = Format([0, 6]);
+    /// X25519 鍵交換 (protocol::secure)
+    pub const HANDSHAKE: Format = Format([0, 7]);
+    /// 「誰かいますか」。本文は送り主の生存シグナル。受けたノードはすぐにシグナルを返す
+    pub const PROBE: Format = Format([0, 8]);
+    /// ping (protocol::echo)。受信側は同じ本文の reply を返す
+    pub const ECHO: Format = Format([0, 9]);
+    /// タスク登録の受付・拒否 (protocol::task_reply)
+    pub const TASK_REPLY: Format = Format([0, 10]);
+    /// 通知に応答が無かった (protocol::task_alert)。caregiver に送る
+    pub const TASK_ALERT: Format = Format([0, 11]);
+    /// 通知への ack・snooze (protocol::task_alert)。返事は TASK_REPLY
+    pub const TASK_ACK: Format = Format([0, 12]);
+    /// タスクの変更 (protocol::task_ops)。返事は TASK_REPLY
+    pub const TASK_EDIT: Format = Format([0, 13]);
+    /// タスクの削除 (protocol::task_ops)。返事は TASK_REPLY
+    pub const TASK_DELETE: Format = Format([0, 14]);
+    /// タスクをこの回で終わらせる (protocol::task_ops)。返事は TASK_REPLY
+    pub const TASK_DONE: Format = Format([0, 15]);
+    /// タスクの一覧 (protocol::task_ops)。返事は TASK_REPLY の tasks
+    pub const TASK_LIST: Format = Format([0, 16]);
+    /// サーバー生存シグナル
+    pub const SIGNAL: Format = Format([0xFF, 0xFF]);
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:#04x},{:#04x}]", self.0[0], self.0[1])
+    }
+}
+
+/// 14次元の感情ベクトル (ai/chilk.rs 参照)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct EmotionVec(pub [u8; EMOTION_VEC_LEN]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    /// ヘッダーに満たない長さ
+    TooShort { len: usize },
+    /// MAX_PACKET_SIZE を超えている
+    TooLarge { len: usize },
+    /// 長さは正しいが中身が不正
+    Malformed(&'static str),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::TooShort { len } => {
+                write!(f, "packet too short: {} bytes (header is {})", len, HEADER_LEN)
+            }
+            PacketError::TooLarge { len } => {
+                write!(f, "packet too large: {} bytes (max {})", len, MAX_PACKET_SIZE)
+            }
+            PacketError::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsaiPacket {
+    pub session_id: SessionId,
+    pub chunk: ChunkId,
+    pub format: Format,
+    pub data_vec: EmotionVec,
+    pub payload: Vec<u8>,
+}
+
+impl OsaiPacket {
+    pub fn new(
+        session_id: SessionId,
+        chunk: ChunkId,
+        format: Format,
+        data_vec: EmotionVec,
+        payload: Vec<u8>,
+    ) -> Self {
+        OsaiPacket { session_id, chunk, format, data_vec, payload }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+
+    /// `buf` の先頭に書き込み、書き込んだバイト数を返す
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, PacketError> {
+        let len = self.encoded_len();
+        if len > MAX_PACKET_SIZE {
+            return Err(PacketError::TooLarge { len });
+        }
+        if buf.len() < len {
+            return Err(PacketError::TooShort { len: buf.len() });
+        }
+
+        let mut offset = 0;
+        buf[offset..offset + SESSION_ID_LEN].copy_from_slice(&self.session_id.0);
+        offset += SESSION_ID_LEN;
+
+        buf[offset..offset + CHUNK_ID_LEN].copy_from_slice(&self.chunk.to_bytes());
+        offset += CHUNK_ID_LEN;
+
+        buf[offset..offset + FORMAT_LEN].copy_from_slice(&self.format.0);
+        offset += FORMAT_LEN;
+
+        buf[offset..offset + EMOTION_VEC_LEN].copy_from_slice(&self.data_vec.0);
+        offset += EMOTION_VEC_LEN;
+
+        buf[offset..offset + self.payload.len()].copy_from_slice(&self.payload);
+
+        Ok(len)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        let mut buf = vec![0u8; self.encoded_len()];
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// 分割メッセージの終端パケット。payload に総チャンク数を入れる
+    pub fn end_of(session_id: SessionId, format: Format, data_vec: EmotionVec, total_chunks: u32) -> Self {
+        OsaiPacket::new(session_id, ChunkId::END, format, data_vec, total_chunks.to_be_bytes().to_vec())
+    }
+
+    /// 終端パケットに入っている総チャンク数。古い送信側は空で送ってくるので `None`
+    pub fn total_chunks(&self) -> Option<u32> {
+        if !self.chunk.is_end() {
+            return None;
+        }
+        let bytes: [u8; 4] = self.payload.as_slice().try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(PacketError::TooShort { len: bytes.len() });
+        }
+        if bytes.len() > MAX_PACKET_SIZE {
+            return Err(PacketError::TooLarge { len: bytes.len() });
+        }
+
+        let (session_id, rest) = bytes.split_at(SESSION_ID_LEN);
+        let (chunk, rest) = rest.split_at(CHUNK_ID_LEN);
+        let (format, rest) = rest.split_at(FORMAT_LEN);
+        let (data_vec, payload) = rest.split_at(EMOTION_VEC_LEN);
+
+        // split_at の長さは固定なので try_into は失敗しない
+        let chunk = ChunkId(u64::from_be_bytes(chunk.try_into().unwrap()));
+        if !chunk.is_end() && !chunk.is_single() && chunk.index().is_none() {
+            return Err(PacketError::Malformed("chunk index out of range"));
+        }
+
+        Ok(OsaiPacket {
+            session_id: SessionId(session_id.try_into().unwrap()),
+            chunk,
+            format: Format(format.try_into().unwrap()),
+            data_vec: EmotionVec(data_vec.try_into().unwrap()),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_packet(rng: &mut impl Rng, payload_len: usize) -> OsaiPacket {
+        let chunk = match rng.random_range(0..4) {
+            0 => ChunkId::END,
+            1 => ChunkId::SINGLE,
+            2 => ChunkId::reliable(rng.random()),
+            _ => ChunkId::new(rng.random()),
+        };
+        OsaiPacket::new(
+            SessionId(rng.random()),
+            chunk,
+            Format(rng.random()),
+            EmotionVec(rng.random()),
+            (0..payload_len).map(|_| rng.random()).collect(),
+        )
+    }
+
+    #[test]
+    fn random_packets_round_trip() {
+        let mut rng = rand::rng();
+        for _ in 0..2000 {
+            let len = rng.random_range(0..=MAX_PAYLOAD_SIZE);
+            let packet = random_packet(&mut rng, len);
+            let bytes = packet.encode().unwrap();
+            assert_eq!(bytes.len(), HEADER_LEN + len);
+            assert_eq!(OsaiPacket::decode(&bytes).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn header_only_and_max_size_round_trip() {
+        let mut rng = rand::rng();
+        for len in [0, 1, MAX_PAYLOAD_SIZE - 1, MAX_PAYLOAD_SIZE] {
+            let packet = random_packet(&mut rng, len);
+            let bytes = packet.encode().unwrap();
+            assert_eq!(OsaiPacket::decode(&bytes).unwrap(), packet);
+        }
+        assert_eq!(HEADER_LEN + MAX_PAYLOAD_SIZE, MAX_PACKET_SIZE);
+    }
+
+    #[test]
+    fn oversized_packets_are_rejected() {
+        let packet = random_packet(&mut rand::rng(), MAX_PAYLOAD_SIZE + 1);
+        assert_eq!(packet.encode(), Err(PacketError::TooLarge { len: MAX_PACKET_SIZE + 1 }));
+        let bytes = vec![0u8; MAX_PACKET_SIZE + 1];
+        assert_eq!(OsaiPacket::decode(&bytes), Err(PacketError::TooLarge { len: MAX_PACKET_SIZE + 1 }));
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = random_packet(&mut rand::rng(), 10).encode().unwrap();
+        for len in 0..HEADER_LEN {
+            assert_eq!(OsaiPacket::decode(&bytes[..len]), Err(PacketError::TooShort { len }));
+        }
+        // ヘッダーだけなら payload が空のパケット
+        assert!(OsaiPacket::decode(&bytes[..HEADER_LEN]).unwrap().payload.is_empty());
+    }
+
+    #[test]
+    fn encode_into_needs_room() {
+        let packet = random_packet(&mut rand::rng(), 100);
+        let mut buf = [0u8; HEADER_LEN + 99];
+        assert_eq!(packet.encode_into(&mut buf), Err(PacketError::TooShort { len: HEADER_LEN + 99 }));
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        assert_eq!(packet.encode_into(&mut buf), Ok(HEADER_LEN + 100));
+        assert_eq!(OsaiPacket::decode(&buf[..HEADER_LEN + 100]).unwrap(), packet);
+    }
+
+    #[test]
+    fn chunk_ids() {
+        assert!(ChunkId::END.is_end() && !ChunkId::END.wants_ack() && ChunkId::END.index().is_none());
+        assert!(ChunkId::SINGLE.is_single() && !ChunkId::SINGLE.wants_ack() && ChunkId::SINGLE.index().is_none());
+        for index in [0, 1, u32::MAX - 1, u32::MAX] {
+            let plain = ChunkId::new(index);
+            let reliable = ChunkId::reliable(index);
+            assert!(!plain.wants_ack());
+            assert!(reliable.wants_ack());
+            assert_eq!(plain.index(), Some(index));
+            assert_eq!(reliable.index(), Some(index));
+            assert_ne!(reliable, ChunkId::END);
+            assert_ne!(reliable, ChunkId::SINGLE);
+        }
+    }
+
+    #[test]
+    fn chunk_index_above_u32_is_malformed() {
+        let mut bytes = random_packet(&mut rand::rng(), 0).encode().unwrap();
+        for chunk in [1u64 << 32, ACK_REQUEST_BIT | (1 << 40), u64::MAX - 2] {
+            bytes[SESSION_ID_LEN..SESSION_ID_LEN + CHUNK_ID_LEN].copy_from_slice(&chunk.to_be_bytes());
+            assert_eq!(OsaiPacket::decode(&bytes), Err(PacketError::Malformed("chunk index out of range")));
+        }
+    }
+
+    #[test]
+    fn end_packet_carries_total() {
+        let packet = OsaiPacket::end_of(SessionId([7; SESSION_ID_LEN]), Format::FILE, EmotionVec::default(), 42);
+        let decoded = OsaiPacket::decode(&packet.encode().unwrap()).unwrap();
+        assert_eq!(decoded.total_chunks(), Some(42));
+        let legacy = OsaiPacket::new(decoded.session_id, ChunkId::END, Format::FILE, EmotionVec::default(), Vec::new());
+        assert_eq!(legacy.total_chunks(), None);
+    }
+}
@@ -0,0 +1,403 @@
+// 暗号化した OSAI パケット (Format::SEALED) と X25519 ハンドシェイク (Format::HANDSHAKE) のコーデック
+//
+// SEALED の payload レイアウト:
+// | kind 1 | key_id 8 | timestamp 8 (unix 秒 BE) | nonce 12 | 暗号文 (format 2 | data_vec 14 | payload) | tag 16 |
+//
+// 外側の session_id / chunk は再組み立てに使うので平文のまま残し、kind〜nonce と合わせて AAD にする。
+// 鍵は master 鍵から HKDF(salt = session_id) でセッションごとに作るので、別のセッションには流用できない。
+//
+// HANDSHAKE の payload レイアウト:
+// | version 1 | role 1 (0 = 開始, 1 = 応答) | static 公開鍵 32 | ephemeral 公開鍵 32 |
+use std::fmt;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+
+use crate::protocol::packet::{
+    ChunkId, EmotionVec, Format, OsaiPacket, SessionId, CHUNK_ID_LEN, EMOTION_VEC_LEN,
+    FORMAT_LEN, MAX_PAYLOAD_SIZE, SESSION_ID_LEN,
+};
+
+pub const KEY_LEN: usize = 32;
+pub const KEY_ID_LEN: usize = 8;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+const SEALED_HEADER_LEN: usize = 1 + KEY_ID_LEN + 8 + NONCE_LEN;
+/// 封をすると増えるバイト数
+pub const SEAL_OVERHEAD: usize = SEALED_HEADER_LEN + FORMAT_LEN + EMOTION_VEC_LEN + TAG_LEN;
+/// 封をしても1パケットに収まる payload の最大サイズ
+pub const MAX_SEALABLE_PAYLOAD: usize = MAX_PAYLOAD_SIZE - SEAL_OVERHEAD;
+
+const HANDSHAKE_VERSION: u8 = 1;
+const HANDSHAKE_LEN: usize = 2 + KEY_LEN * 2;
+
+pub type KeyId = [u8; KEY_ID_LEN];
+
+/// どの master 鍵で封をしたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    /// 事前共有鍵
+    Psk,
+    /// X25519 ハンドシェイクで作った鍵。key_id はハンドシェイクの session_id の先頭8バイト
+    Peer,
+}
+
+impl KeyKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            KeyKind::Psk => 0,
+            KeyKind::Peer => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(KeyKind::Psk),
+            1 => Some(KeyKind::Peer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecureError {
+    Malformed(&'static str),
+    /// 対応する鍵を持っていない
+    UnknownKey(KeyKind),
+    /// タイムスタンプが許容範囲の外 (再送攻撃か時計のずれ)
+    Stale { skew_secs: i64 },
+    /// 認証タグが合わない
+    Decrypt,
+    TooLarge { len: usize },
+}
+
+impl fmt::Display for SecureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecureError::Malformed(reason) => write!(f, "malformed sealed packet: {}", reason),
+            SecureError::UnknownKey(kind) => write!(f, "no {:?} key for sealed packet", kind),
+            SecureError::Stale { skew_secs } => write!(f, "sealed packet timestamp off by {}s", skew_secs),
+            SecureError::Decrypt => write!(f, "sealed packet failed authentication"),
+            SecureError::TooLarge { len } => {
+                write!(f, "payload too large to seal: {} bytes (max {})", len, MAX_SEALABLE_PAYLOAD)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SecureError {}
+
+/// SEALED の平文ヘッダー部分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealedHeader {
+    pub kind: KeyKind,
+    pub key_id: KeyId,
+    /// 封をした時刻 (unix 秒)
+    pub timestamp: u64,
+}
+
+impl SealedHeader {
+    /// 復号する前に鍵を選ぶために先頭だけ読む
+    pub fn peek(packet: &OsaiPacket) -> Result<Self, SecureError> {
+        if packet.format != Format::SEALED {
+            return Err(SecureError::Malformed("not a sealed packet"));
+        }
+        if packet.payload.len() < SEALED_HEADER_LEN + FORMAT_LEN + EMOTION_VEC_LEN + TAG_LEN {
+            return Err(SecureError::Malformed("too short"));
+        }
+        let p = &packet.payload;
+        let kind = KeyKind::from_byte(p[0]).ok_or(SecureError::Malformed("unknown key kind"))?;
+        Ok(SealedHeader {
+            kind,
+            key_id: p[1..1 + KEY_ID_LEN].try_into().unwrap(),
+            timestamp: u64::from_be_bytes(p[1 + KEY_ID_LEN..1 + KEY_ID_LEN + 8].try_into().unwrap()),
+        })
+    }
+}
+
+/// master 鍵とセッション ID から、そのセッション専用の鍵を作る
+pub fn derive_session_key(master: &[u8; KEY_LEN], session_id: SessionId) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&session_id.0), master)
+        .expand(b"osai session v1", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+/// パスフレーズから PSK の master 鍵を作る
+pub fn psk_master(passphrase: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(b"osai psk v1"), passphrase)
+        .expand(b"master", &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+/// ハンドシェイクの2つの DH 結果から master 鍵を作る。
+/// `transcript` は開始側 static, 開始側 ephemeral, 応答側 static, 応答側 ephemeral の順
+pub fn handshake_master(
+    session_id: SessionId,
+    ephemeral_dh: &[u8; KEY_LEN],
+    static_dh: &[u8; KEY_LEN],
+    transcript: [&[u8; KEY_LEN]; 4],
+) -> [u8; KEY_LEN] {
+    let mut ikm = [0u8; KEY_LEN * 2];
+    ikm[..KEY_LEN].copy_from_slice(ephemeral_dh);
+    ikm[KEY_LEN..].copy_from_slice(static_dh);
+
+    let mut info = Vec::with_capacity(16 + KEY_LEN * 4);
+    info.extend_from_slice(b"osai x25519 v1");
+    for key in transcript {
+        info.extend_from_slice(key);
+    }
+
+    let mut key = [0u8; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&session_id.0), &ikm)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+/// 外側ヘッダーと SEALED ヘッダー (nonce まで) を AAD にする
+fn aad(session_id: SessionId, chunk: ChunkId, sealed_header: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(SESSION_ID_LEN + CHUNK_ID_LEN + SEALED_HEADER_LEN);
+    aad.extend_from_slice(&session_id.0);
+    aad.extend_from_slice(&chunk.to_bytes());
+    aad.extend_from_slice(sealed_header);
+    aad
+}
+
+/// `packet` の format / data_vec / payload を暗号化して SEALED パケットにする。
+/// `session_key` は `derive_session_key` で作ったもの
+pub fn seal(
+    session_key: &[u8; KEY_LEN],
+    header: &SealedHeader,
+    packet: &OsaiPacket,
+) -> Result<OsaiPacket, SecureError> {
+    if packet.payload.len() > MAX_SEALABLE_PAYLOAD {
+        return Err(SecureError::TooLarge { len: packet.payload.len() });
+    }
+
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::rng().fill(&mut nonce);
+
+    let mut sealed_header = Vec::with_capacity(SEALED_HEADER_LEN);
+    sealed_header.push(header.kind.to_byte());
+    sealed_header.extend_from_slice(&header.key_id);
+    sealed_header.extend_from_slice(&header.timestamp.to_be_bytes());
+    sealed_header.extend_from_slice(&nonce);
+
+    let mut plaintext = Vec::with_capacity(FORMAT_LEN + EMOTION_VEC_LEN + packet.payload.len());
+    plaintext.extend_from_slice(&packet.format.0);
+    plaintext.extend_from_slice(&packet.data_vec.0);
+    plaintext.extend_from_slice(&packet.payload);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(session_key));
+    let aad = aad(packet.session_id, packet.chunk, &sealed_header);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: &aad })
+        .map_err(|_| SecureError::Malformed("encryption failed"))?;
+
+    let mut payload = sealed_header;
+    payload.extend_from_slice(&ciphertext);
+    Ok(OsaiPacket::new(
+        packet.session_id,
+        packet.chunk,
+        Format::SEALED,
+        EmotionVec::default(),
+        payload,
+    ))
+}
+
+/// SEALED パケットを復号して中身のパケットを返す
+pub fn open(session_key: &[u8; KEY_LEN], packet: &OsaiPacket) -> Result<OsaiPacket, SecureError> {
+    SealedHeader::peek(packet)?;
+    let (sealed_header, ciphertext) = packet.payload.split_at(SEALED_HEADER_LEN);
+    let nonce = &sealed_header[SEALED_HEADER_LEN - NONCE_LEN..];
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(session_key));
+    let aad = aad(packet.session_id, packet.chunk, sealed_header);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| SecureError::Decrypt)?;
+
+    let (format, rest) = plaintext.split_at(FORMAT_LEN);
+    let (data_vec, payload) = rest.split_at(EMOTION_VEC_LEN);
+    let format = Format(format.try_into().unwrap());
+    if format == Format::SEALED || format == Format::HANDSHAKE {
+        return Err(SecureError::Malformed("nested envelope"));
+    }
+
+    Ok(OsaiPacket::new(
+        packet.session_id,
+        packet.chunk,
+        format,
+        EmotionVec(data_vec.try_into().unwrap()),
+        payload.to_vec(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    Init,
+    Reply,
+}
+
+/// X25519 ハンドシェイクのメッセージ。開始側と応答側で同じ session_id を使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeMessage {
+    pub role: HandshakeRole,
+    pub static_public: [u8; KEY_LEN],
+    pub ephemeral_public: [u8; KEY_LEN],
+}
+
+impl HandshakeMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HANDSHAKE_LEN);
+        buf.push(HANDSHAKE_VERSION);
+        buf.push(match self.role {
+            HandshakeRole::Init => 0,
+            HandshakeRole::Reply => 1,
+        });
+        buf.extend_from_slice(&self.static_public);
+        buf.extend_from_slice(&self.ephemeral_public);
+        buf
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, SecureError> {
+        if payload.len() != HANDSHAKE_LEN {
+            return Err(SecureError::Malformed("handshake length"));
+        }
+        if payload[0] != HANDSHAKE_VERSION {
+            return Err(SecureError::Malformed("unsupported handshake version"));
+        }
+        let role = match payload[1] {
+            0 => HandshakeRole::Init,
+            1 => HandshakeRole::Reply,
+            _ => return Err(SecureError::Malformed("unknown handshake role")),
+        };
+        Ok(HandshakeMessage {
+            role,
+            static_public: payload[2..2 + KEY_LEN].try_into().unwrap(),
+            ephemeral_public: payload[2 + KEY_LEN..].try_into().unwrap(),
+        })
+    }
+
+    pub fn into_packet(self, session_id: SessionId) -> OsaiPacket {
+        OsaiPacket::new(
+            session_id,
+            ChunkId::SINGLE,
+            Format::HANDSHAKE,
+            EmotionVec::default(),
+            self.encode(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::context::{PeerAuth, SecurityContext};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    fn now_secs() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn packet(session_id: SessionId) -> OsaiPacket {
+        OsaiPacket::new(session_id, ChunkId::reliable(3), Format::TEXT, EmotionVec([7; EMOTION_VEC_LEN]), b"hello".to_vec())
+    }
+
+    fn psk_header(timestamp: u64) -> SealedHeader {
+        SealedHeader { kind: KeyKind::Psk, key_id: [0; KEY_ID_LEN], timestamp }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let session_id = SessionId::random();
+        let key = derive_session_key(&psk_master(b"secret"), session_id);
+        let inner = packet(session_id);
+        let header = psk_header(now_secs());
+        let sealed = seal(&key, &header, &inner).unwrap();
+        assert_eq!(sealed.format, Format::SEALED);
+        assert_eq!((sealed.session_id, sealed.chunk), (inner.session_id, inner.chunk));
+        assert_eq!(sealed.payload.len(), inner.payload.len() + SEAL_OVERHEAD);
+        assert!(!sealed.payload.windows(5).any(|w| w == b"hello"));
+        assert_eq!(SealedHeader::peek(&sealed).unwrap(), header);
+
+        // エンコードしても開ける
+        let decoded = OsaiPacket::decode(&sealed.encode().unwrap()).unwrap();
+        assert_eq!(open(&key, &decoded).unwrap(), inner);
+        // 同じ中身でも nonce が違う
+        assert_ne!(seal(&key, &header, &inner).unwrap().payload, sealed.payload);
+    }
+
+    #[test]
+    fn tampered_or_wrong_key_fails() {
+        let session_id = SessionId::random();
+        let master = psk_master(b"secret");
+        let key = derive_session_key(&master, session_id);
+        let sealed = seal(&key, &psk_header(now_secs()), &packet(session_id)).unwrap();
+
+        assert_eq!(open(&derive_session_key(&psk_master(b"other"), session_id), &sealed), Err(SecureError::Decrypt));
+        // 別のセッションの鍵では開けない
+        assert_eq!(open(&derive_session_key(&master, SessionId::random()), &sealed), Err(SecureError::Decrypt));
+        // 外側のヘッダー・SEALED ヘッダー・暗号文のどれを変えても駄目
+        let mut moved = sealed.clone();
+        moved.chunk = ChunkId::reliable(4);
+        assert_eq!(open(&key, &moved), Err(SecureError::Decrypt));
+        for i in [1, 9, SEALED_HEADER_LEN, sealed.payload.len() - 1] {
+            let mut flipped = sealed.clone();
+            flipped.payload[i] ^= 1;
+            assert_eq!(open(&key, &flipped), Err(SecureError::Decrypt), "byte {}", i);
+        }
+        let mut short = sealed.clone();
+        short.payload.truncate(SEAL_OVERHEAD - 1);
+        assert_eq!(open(&key, &short), Err(SecureError::Malformed("too short")));
+        let too_large = OsaiPacket::new(session_id, ChunkId::SINGLE, Format::TEXT, EmotionVec::default(), vec![0; MAX_SEALABLE_PAYLOAD + 1]);
+        assert_eq!(seal(&key, &psk_header(0), &too_large), Err(SecureError::TooLarge { len: MAX_SEALABLE_PAYLOAD + 1 }));
+    }
+
+    #[test]
+    fn nested_envelopes_are_rejected() {
+        let session_id = SessionId::random();
+        let key = derive_session_key(&psk_master(b"secret"), session_id);
+        let inner = seal(&key, &psk_header(now_secs()), &packet(session_id)).unwrap();
+        let outer = seal(&key, &psk_header(now_secs()), &inner).unwrap();
+        assert_eq!(open(&key, &outer), Err(SecureError::Malformed("nested envelope")));
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut ctx = SecurityContext::new();
+        ctx.set_psk(b"secret");
+        ctx.max_clock_skew = Duration::from_secs(120);
+        let session_id = SessionId::random();
+        let key = derive_session_key(&psk_master(b"secret"), session_id);
+
+        let fresh = seal(&key, &psk_header(now_secs() - 60), &packet(session_id)).unwrap();
+        let (opened, session_key) = ctx.open_incoming(fresh).unwrap();
+        assert_eq!(opened, packet(session_id));
+        assert_eq!(session_key.unwrap().auth(), PeerAuth::Psk);
+
+        // 時計のずれの範囲の外は古い (または未来の) パケット
+        for timestamp in [now_secs() - 600, now_secs() + 600] {
+            let stale = seal(&key, &psk_header(timestamp), &packet(session_id)).unwrap();
+            assert!(matches!(ctx.open_incoming(stale), Err(SecureError::Stale { .. })));
+        }
+
+        // 同じセッションはずれの2倍のあいだ覚えていて、2回目は弾く
+        let start = Instant::now();
+        assert!(ctx.check_replay(session_id, start));
+        assert!(!ctx.check_replay(session_id, start + Duration::from_secs(200)));
+        assert!(ctx.check_replay(SessionId::random(), start + Duration::from_secs(200)));
+        assert!(ctx.check_replay(session_id, start + Duration::from_secs(441)));
+
+        // PSK を持っていない相手には開けない
+        let stranger = SecurityContext::new();
+        let sealed = seal(&key, &psk_header(now_secs()), &packet(session_id)).unwrap();
+        assert_eq!(stranger.open_incoming(sealed).unwrap_err(), SecureError::UnknownKey(KeyKind::Psk));
+    }
+}
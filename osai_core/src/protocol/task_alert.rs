@@ -0,0 +1,71 @@
+// 通知に応答が無いときの知らせ (Format::TASK_ALERT) と、離れたノードからの ack (Format::TASK_ACK)。どちらも JSON。
+//
+// ```json
+// {"node":"living-doll","task":"薬を飲む","datetime":"2025-12-01:08:00 JST","minutes_before":0,"repeats":2,"kind":"unanswered"}
+// {"task":"薬を飲む","action":"ack"}
+// {"task":"","action":{"snooze":10}}
+// ```
+//
+// TASK_ACK の task が空なら、鳴っている通知すべて。返事は TASK_REPLY (受け付けたら accepted)
+use serde::{Deserialize, Serialize};
+
+use crate::IOT::task::AckAction;
+use crate::protocol::packet::{PacketError, MAX_PAYLOAD_SIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskAlertKind {
+    /// ack が無いので鳴らし直した
+    Unanswered,
+    /// 鳴らし直しても応答が無く、諦めた
+    Missed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskAlert {
+    /// 通知したノードの名前
+    pub node: String,
+    pub task: String,
+    /// 相手のタイムゾーンで表示した時刻 (Task::display_time)
+    pub datetime: String,
+    pub minutes_before: u32,
+    /// 鳴らした回数 (最初の通知を含む)
+    pub repeats: u32,
+    pub kind: TaskAlertKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskAck {
+    /// タスク名。空なら鳴っている通知すべて
+    #[serde(default)]
+    pub task: String,
+    pub action: AckAction,
+}
+
+fn encode_json<T: Serialize>(body: &T, what: &'static str) -> Result<Vec<u8>, PacketError> {
+    let body = serde_json::to_vec(body).map_err(|_| PacketError::Malformed(what))?;
+    if body.len() > MAX_PAYLOAD_SIZE {
+        return Err(PacketError::TooLarge { len: body.len() });
+    }
+    Ok(body)
+}
+
+impl TaskAlert {
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        encode_json(self, "task alert could not be serialized")
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        serde_json::from_slice(payload).map_err(|_| PacketError::Malformed("invalid task alert body"))
+    }
+}
+
+impl TaskAck {
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        encode_json(self, "task ack could not be serialized")
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        serde_json::from_slice(payload).map_err(|_| PacketError::Malformed("invalid task ack body"))
+    }
+}
@@ -0,0 +1,40 @@
+// 離れたノードのタスクを操作する。どれも JSON で、返事は TASK_REPLY。
+//
+// ```json
+// TASK_EDIT   {"id":"3f2a9c1e","edit":{"datetime":"2025-12-01:09:00","recurrence":null}}
+// TASK_DELETE {"id":"3f2a9c1e"}
+// TASK_DONE   {"id":"薬を飲む"}
+// TASK_LIST   {"from":"2025-12-01","state":"pending","tag":"care"}
+// ```
+//
+// id は IOT::task_store と同じで、ID の先頭 (4文字以上) か名前
+use serde::{Deserialize, Serialize};
+
+use crate::IOT::task::TaskEdit;
+use crate::protocol::packet::{PacketError, MAX_PAYLOAD_SIZE};
+
+/// TASK_DELETE / TASK_DONE
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskRef {
+    pub id: String,
+}
+
+/// TASK_EDIT
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskEditRequest {
+    pub id: String,
+    pub edit: TaskEdit,
+}
+
+/// 本文を JSON にする (1パケットに収まること)
+pub fn encode_body<T: Serialize>(body: &T) -> Result<Vec<u8>, PacketError> {
+    let body = serde_json::to_vec(body).map_err(|_| PacketError::Malformed("task operation could not be serialized"))?;
+    if body.len() > MAX_PAYLOAD_SIZE {
+        return Err(PacketError::TooLarge { len: body.len() });
+    }
+    Ok(body)
+}
+
+pub fn decode_body<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T, PacketError> {
+    serde_json::from_slice(payload).map_err(|_| PacketError::Malformed("invalid task operation body"))
+}
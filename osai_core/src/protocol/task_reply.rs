@@ -0,0 +1,81 @@
+// タスク登録 (Format::TASK) への返事 (Format::TASK_REPLY)。JSON で送る。
+//
+// ```json
+// {"accepted":true,"task":{"id":"3f2a9c1e-...","datetime":"2025-12-31:08:00","name":"起床","state":"pending"},"error":null}
+// {"accepted":false,"task":null,"error":"Invalid date/time format. ..."}
+// {"accepted":true,"task":null,"error":null,"tasks":[...],"omitted":3}
+// ```
+//
+// TASK_EDIT / TASK_DELETE / TASK_DONE / TASK_ACK にも同じ形で返す (task は変えたあと・消したタスク)。
+// TASK_LIST は tasks に入れ、1パケットに入りきらない分は omitted に数だけ入れる
+//
+// session_id は登録したセッションと同じ。鍵があれば登録と同じ鍵で封をする
+use serde::{Deserialize, Serialize};
+
+use crate::IOT::task::Task;
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, PacketError, SessionId, MAX_PAYLOAD_SIZE};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskReply {
+    pub accepted: bool,
+    /// 受け付けたタスク (受信側で保存したもの)
+    pub task: Option<Task>,
+    /// 断った理由
+    pub error: Option<String>,
+    /// TASK_LIST の結果
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tasks: Vec<Task>,
+    /// 入りきらずに載せなかったタスクの数
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub omitted: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+impl TaskReply {
+    pub fn accepted(task: Task) -> Self {
+        TaskReply { accepted: true, task: Some(task), error: None, tasks: Vec::new(), omitted: 0 }
+    }
+
+    pub fn rejected(error: impl Into<String>) -> Self {
+        TaskReply { accepted: false, task: None, error: Some(error.into()), tasks: Vec::new(), omitted: 0 }
+    }
+
+    /// 1パケットに入るだけ先頭から載せる
+    pub fn listed(mut tasks: Vec<Task>) -> Self {
+        let total = tasks.len();
+        loop {
+            let reply = TaskReply { accepted: true, task: None, error: None, omitted: total - tasks.len(), tasks };
+            if reply.encode().is_ok() {
+                return reply;
+            }
+            tasks = reply.tasks;
+            tasks.pop();
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, PacketError> {
+        let body = serde_json::to_vec(self).map_err(|_| PacketError::Malformed("task reply could not be serialized"))?;
+        if body.len() > MAX_PAYLOAD_SIZE {
+            return Err(PacketError::TooLarge { len: body.len() });
+        }
+        Ok(body)
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<Self, PacketError> {
+        serde_json::from_slice(payload).map_err(|_| PacketError::Malformed("invalid task reply body"))
+    }
+
+    /// 1パケットで完結するので chunk は SINGLE
+    pub fn into_packet(self, session_id: SessionId) -> Result<OsaiPacket, PacketError> {
+        Ok(OsaiPacket::new(
+            session_id,
+            ChunkId::SINGLE,
+            Format::TASK_REPLY,
+            EmotionVec::default(),
+            self.encode()?,
+        ))
+    }
+}
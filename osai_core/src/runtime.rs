@@ -0,0 +1,284 @@
+// OSAI のサービス (UDP サーバー、HTTP、WebSocket、タスクスケジューラー) を起動・停止する。
+// どのサービスも OsaiRuntime の CancellationToken を見ていて、shutdown() でキャンセルして終わるのを待つ。
+// 同じプロセスでノードを何度でも起動し直せる (止まった時点でソケットは閉じている)。
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::server::discovery;
+use crate::server::server::start_server;
+use crate::server::web::http_server::http_server;
+use crate::server::web::websocket::start_websocket_server;
+use crate::IOT::task::{run_task_scheduler, TaskFilter};
+use crate::IOT::task_store::TaskStore;
+use crate::OSAI;
+
+/// shutdown() で全サービスが止まるまで待つ時間。過ぎたら abort する
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Service {
+    /// OSAI UDP サーバー (server.port)
+    Server,
+    /// share ディレクトリの HTTP サーバー (http.port)
+    Http,
+    /// WebSocket サーバー (websocket.port)
+    WebSocket,
+    /// タスクの通知
+    Scheduler,
+}
+
+impl Service {
+    pub const ALL: [Service; 4] = [Service::Server, Service::Http, Service::WebSocket, Service::Scheduler];
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Service::Server => "server",
+            Service::Http => "http_server",
+            Service::WebSocket => "websocket",
+            Service::Scheduler => "scheduler",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// 動いているサービスの一覧。生存シグナルに載せるポートを決めるのに使う
+#[derive(Debug, Clone, Default)]
+pub struct RunningServices(Arc<RwLock<HashSet<Service>>>);
+
+impl RunningServices {
+    pub fn contains(&self, service: Service) -> bool {
+        self.0.read().unwrap().contains(&service)
+    }
+
+    /// OsaiRuntime を使わずに start_server などを直接動かすとき用
+    pub fn insert(&self, service: Service) {
+        self.0.write().unwrap().insert(service);
+    }
+
+    pub fn remove(&self, service: Service) {
+        self.0.write().unwrap().remove(&service);
+    }
+}
+
+struct RunningService {
+    service: Service,
+    handle: JoinHandle<Result<(), String>>,
+}
+
+/// 起動中のサービスのハンドル。drop するとキャンセルだけして終わりは待たない
+pub struct OsaiRuntime {
+    osai: OSAI,
+    token: CancellationToken,
+    services: Vec<RunningService>,
+    running: RunningServices,
+    exited_tx: mpsc::UnboundedSender<Service>,
+    exited_rx: mpsc::UnboundedReceiver<Service>,
+}
+
+impl OsaiRuntime {
+    /// まだ何も起動しない。spawn で追加する
+    pub fn new(osai: OSAI) -> Self {
+        let (exited_tx, exited_rx) = mpsc::unbounded_channel();
+        OsaiRuntime {
+            osai,
+            token: CancellationToken::new(),
+            services: Vec::new(),
+            running: RunningServices::default(),
+            exited_tx,
+            exited_rx,
+        }
+    }
+
+    /// `services` を起動して返す
+    pub fn start(osai: OSAI, services: &[Service]) -> Result<Self, String> {
+        let mut runtime = Self::new(osai);
+        for service in services {
+            runtime.spawn(*service)?;
+        }
+        Ok(runtime)
+    }
+
+    pub fn osai(&self) -> &OSAI {
+        &self.osai
+    }
+
+    /// 全サービスが見ているトークン。キャンセルすると止まり始める
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// 動いているサービス (終わったものは外れる)
+    pub fn running(&self) -> RunningServices {
+        self.running.clone()
+    }
+
+    pub fn is_running(&self, service: Service) -> bool {
+        self.services
+            .iter()
+            .any(|s| s.service == service && !s.handle.is_finished())
+    }
+
+    /// 「誰かいますか」を送る。返ってきたシグナルは UDP サーバーが受けて PEER_REGISTRY に入る
+    pub async fn probe(&self) -> Result<(), String> {
+        if !self.running.contains(Service::Server) {
+            return Err("Server is not running".to_string());
+        }
+        let config = self.osai.config();
+        discovery::probe(config, config.node_id()?, &self.running).await
+    }
+
+    /// サービスを1つ起動する。同じサービスが動いていればエラー。
+    /// bind の失敗などはサービスが終わった時点でログに出し、shutdown() の結果にも含める
+    pub fn spawn(&mut self, service: Service) -> Result<(), String> {
+        if self.token.is_cancelled() {
+            return Err("Runtime is shutting down".to_string());
+        }
+        if self.is_running(service) {
+            return Err(format!("{} is already running", service));
+        }
+
+        let osai = self.osai.clone();
+        let config = Arc::clone(osai.config());
+        let token = self.token.clone();
+        let exited = self.exited_tx.clone();
+        let running = self.running.clone();
+        running.insert(service);
+        let handle = tokio::spawn(async move {
+            let result = match service {
+                Service::Server => start_server(config, running.clone(), token).await.map(|_| ()),
+                Service::Http => match config.share_dir() {
+                    Ok(share_dir) => http_server(&config, share_dir, token)
+                        .await
+                        .map_err(|e| e.to_string()),
+                    Err(e) => Err(e),
+                },
+                Service::WebSocket => start_websocket_server(
+                    config.websocket.bind.to_string(),
+                    config.websocket.port.to_string(),
+                    token,
+                )
+                .await
+                .map(|_| ()),
+                Service::Scheduler => {
+                    // 壊れたファイルでも止めずに報告だけする (直されたら通知を続ける)
+                    match TaskStore::new(&config.paths.task_file).list(&TaskFilter::default()) {
+                        Ok(tasks) => println!("Loaded {} tasks.", tasks.len()),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                    run_task_scheduler(osai, token).await;
+                    Ok(())
+                }
+            };
+            if let Err(e) = &result {
+                eprintln!("{} error: {}", service, e);
+            }
+            running.remove(service);
+            let _ = exited.send(service);
+            result
+        });
+        self.services.push(RunningService { service, handle });
+        Ok(())
+    }
+
+    /// Ctrl-C か、どれかのサービスが自分で終わる (bind の失敗など) まで待ってから全体を止める
+    pub async fn run_until_signal(mut self) -> Result<(), String> {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => println!("Ctrl-C received, shutting down"),
+            Some(service) = self.exited_rx.recv() => println!("{} exited, shutting down", service),
+            _ = self.token.cancelled() => {}
+        }
+        self.shutdown().await
+    }
+
+    /// キャンセルして全サービスの終了を待つ。SHUTDOWN_TIMEOUT を過ぎたものは abort する。
+    /// 途中で失敗したサービスがあればまとめてエラーにする
+    pub async fn shutdown(mut self) -> Result<(), String> {
+        self.token.cancel();
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_TIMEOUT;
+
+        let mut errors = Vec::new();
+        for RunningService { service, mut handle } in mem::take(&mut self.services) {
+            match tokio::time::timeout_at(deadline, &mut handle).await {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => errors.push(format!("{}: {}", service, e)),
+                Ok(Err(e)) => errors.push(format!("{} panicked: {}", service, e)),
+                Err(_) => {
+                    handle.abort();
+                    errors.push(format!("{} did not stop within {:?}", service, SHUTDOWN_TIMEOUT));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+impl Drop for OsaiRuntime {
+    fn drop(&mut self) {
+        self.token.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener, UdpSocket};
+    use crate::config::OsaiConfig;
+
+    const ALL: [Service; 4] = [Service::Server, Service::Http, Service::WebSocket, Service::Scheduler];
+
+    #[tokio::test]
+    async fn services_stop_cleanly_and_free_their_ports() {
+        let config = OsaiConfig::loopback();
+        let http = SocketAddr::new(config.http.bind.unwrap(), config.http.port);
+        let websocket = SocketAddr::new(config.websocket.bind, config.websocket.port);
+        let udp = config.server.bind_addr();
+        let dir = config.paths.share_dir.clone().unwrap().parent().unwrap().to_path_buf();
+
+        // 同じポートで起動と停止を繰り返す
+        for _ in 0..3 {
+            let mut runtime = OsaiRuntime::start(OSAI::with_config(config.clone()), &ALL).unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            for service in ALL {
+                assert!(runtime.is_running(service), "{} is not running", service);
+                assert!(runtime.running().contains(service));
+                let error = runtime.spawn(service).unwrap_err();
+                assert!(error.contains("already running"), "{}", error);
+            }
+
+            assert_eq!(runtime.shutdown().await, Ok(()));
+            UdpSocket::bind(udp).unwrap();
+            TcpListener::bind(http).unwrap();
+            TcpListener::bind(websocket).unwrap();
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn failed_bind_is_reported_by_shutdown() {
+        let config = OsaiConfig::loopback();
+        let _taken = UdpSocket::bind(config.server.bind_addr()).unwrap();
+        let mut runtime = OsaiRuntime::new(OSAI::with_config(config));
+        runtime.spawn(Service::Server).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!runtime.is_running(Service::Server));
+        assert!(!runtime.running().contains(Service::Server));
+        // 終わったサービスはもう一度起動できる
+        runtime.spawn(Service::Server).unwrap();
+
+        let error = runtime.shutdown().await.unwrap_err();
+        assert_eq!(error.matches(&format!("{}:", Service::Server)).count(), 2, "{}", error);
+    }
+}
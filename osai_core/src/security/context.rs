@@ -0,0 +1,483 @@
+// ノードの鍵と認証ポリシー。
+// PSK は security.psk (OSAI_PSK)、X25519 は security.trusted_peers (OSAI_TRUSTED_PEERS) で有効になる。
+// どちらも設定されていなければ open モードになり、平文のパケットもこれまでどおり受け付ける。
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::config::OsaiConfig;
+use crate::protocol::packet::{Format, OsaiPacket, SessionId};
+use crate::protocol::secure::{
+    self, derive_session_key, handshake_master, psk_master, HandshakeMessage, HandshakeRole,
+    KeyId, KeyKind, SealedHeader, SecureError, KEY_ID_LEN, KEY_LEN,
+};
+
+/// ハンドシェイクで作った鍵の寿命。過ぎたら張り直す
+pub const PEER_KEY_LIFETIME: Duration = Duration::from_secs(600);
+/// 受け入れたハンドシェイク鍵の上限。古いものから捨てる
+const MAX_INBOUND_KEYS: usize = 256;
+/// 認証なしでも受け付けるフォーマット (発見用)
+const PUBLIC_FORMATS: [Format; 3] = [Format::SIGNAL, Format::DISCOVERY, Format::PROBE];
+
+/// パケットの送り主をどう確認できたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PeerAuth {
+    /// 平文
+    #[default]
+    None,
+    /// 事前共有鍵を知っている相手
+    Psk,
+    /// ハンドシェイクした相手。static 公開鍵
+    Peer([u8; KEY_LEN]),
+}
+
+impl PeerAuth {
+    pub fn is_authenticated(&self) -> bool {
+        !matches!(self, PeerAuth::None)
+    }
+}
+
+impl fmt::Display for PeerAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAuth::None => write!(f, "none"),
+            PeerAuth::Psk => write!(f, "psk"),
+            PeerAuth::Peer(public) => write!(f, "peer {}", &hex::encode(public)[..16]),
+        }
+    }
+}
+
+/// 相手と共有している master 鍵 (PSK かハンドシェイクの結果)
+#[derive(Clone)]
+pub struct Credential {
+    kind: KeyKind,
+    key_id: KeyId,
+    master: [u8; KEY_LEN],
+    auth: PeerAuth,
+    created: Instant,
+}
+
+impl Credential {
+    pub fn psk(passphrase: &[u8]) -> Self {
+        Credential {
+            kind: KeyKind::Psk,
+            key_id: [0u8; KEY_ID_LEN],
+            master: psk_master(passphrase),
+            auth: PeerAuth::Psk,
+            created: Instant::now(),
+        }
+    }
+
+    fn peer(session_id: SessionId, master: [u8; KEY_LEN], peer_static: [u8; KEY_LEN]) -> Self {
+        Credential {
+            kind: KeyKind::Peer,
+            key_id: session_id.0[..KEY_ID_LEN].try_into().unwrap(),
+            master,
+            auth: PeerAuth::Peer(peer_static),
+            created: Instant::now(),
+        }
+    }
+
+    pub fn auth(&self) -> PeerAuth {
+        self.auth
+    }
+
+    /// `session_id` 専用の鍵を作る
+    pub fn session_key(&self, session_id: SessionId) -> SessionKey {
+        SessionKey {
+            kind: self.kind,
+            key_id: self.key_id,
+            key: derive_session_key(&self.master, session_id),
+            auth: self.auth,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.kind == KeyKind::Peer && now.duration_since(self.created) > PEER_KEY_LIFETIME
+    }
+}
+
+/// 1セッション分の鍵。送信側はチャンクの封に、受信側は ACK の封に使う
+#[derive(Clone)]
+pub struct SessionKey {
+    kind: KeyKind,
+    key_id: KeyId,
+    key: [u8; KEY_LEN],
+    auth: PeerAuth,
+}
+
+/// 鍵そのものはログに出さない
+impl fmt::Debug for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKey").field("kind", &self.kind).field("auth", &self.auth).finish_non_exhaustive()
+    }
+}
+
+impl SessionKey {
+    pub fn auth(&self) -> PeerAuth {
+        self.auth
+    }
+
+    pub fn seal(&self, packet: &OsaiPacket) -> Result<OsaiPacket, SecureError> {
+        let header = SealedHeader { kind: self.kind, key_id: self.key_id, timestamp: unix_now() };
+        secure::seal(&self.key, &header, packet)
+    }
+
+    /// 同じセッションの SEALED パケット (ACK など) を開く
+    pub fn open(&self, packet: &OsaiPacket) -> Result<OsaiPacket, SecureError> {
+        let header = SealedHeader::peek(packet)?;
+        if header.kind != self.kind || header.key_id != self.key_id {
+            return Err(SecureError::UnknownKey(header.kind));
+        }
+        secure::open(&self.key, packet)
+    }
+}
+
+/// 鍵があれば封をしてからエンコードする
+pub fn encode_packet(packet: &OsaiPacket, key: Option<&SessionKey>) -> Result<Vec<u8>, String> {
+    let sealed;
+    let packet = match key {
+        Some(key) => {
+            sealed = key.seal(packet).map_err(|e| format!("Failed to seal packet: {}", e))?;
+            &sealed
+        }
+        None => packet,
+    };
+    packet.encode().map_err(|e| format!("Failed to build packet: {}", e))
+}
+
+/// 応答側で覚えておくハンドシェイク。Init の再送には同じ Reply を返す
+struct InboundKey {
+    credential: Credential,
+    init_ephemeral: [u8; KEY_LEN],
+    reply: HandshakeMessage,
+}
+
+pub struct SecurityContext {
+    open_mode: bool,
+    psk: Option<Credential>,
+    identity: Option<StaticSecret>,
+    /// X25519 の秘密鍵を保存するファイル
+    identity_file: PathBuf,
+    trusted_peers: HashSet<[u8; KEY_LEN]>,
+    /// SEALED のタイムスタンプと自分の時計のずれの許容範囲
+    pub max_clock_skew: Duration,
+    inbound: HashMap<KeyId, InboundKey>,
+    outbound: HashMap<SocketAddr, Credential>,
+    /// 処理済みの認証付きセッション。再送攻撃を弾くため max_clock_skew の2倍だけ覚えておく
+    replay: HashMap<SessionId, Instant>,
+}
+
+impl Default for SecurityContext {
+    fn default() -> Self {
+        SecurityContext {
+            open_mode: true,
+            psk: None,
+            identity: None,
+            identity_file: PathBuf::from("osai_identity.key"),
+            trusted_peers: HashSet::new(),
+            max_clock_skew: Duration::from_secs(120),
+            inbound: HashMap::new(),
+            outbound: HashMap::new(),
+            replay: HashMap::new(),
+        }
+    }
+}
+
+impl SecurityContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 設定の security / paths.identity_file から作る
+    pub fn from_config(config: &OsaiConfig) -> Self {
+        let security = &config.security;
+        let mut ctx = Self::new();
+        ctx.identity_file = config.paths.identity_file.clone();
+        ctx.max_clock_skew = Duration::from_secs(security.max_clock_skew_secs);
+        if let Some(psk) = security.psk.as_deref().filter(|p| !p.is_empty()) {
+            ctx.set_psk(psk.as_bytes());
+        }
+        for peer in &security.trusted_peers {
+            match parse_public_key(peer) {
+                Ok(public) => ctx.trust_peer(public),
+                Err(e) => eprintln!("Ignoring trusted peer {}: {}", peer, e),
+            }
+        }
+        if !ctx.trusted_peers.is_empty() {
+            if let Err(e) = ctx.identity_public() {
+                eprintln!("X25519 handshake disabled: {}", e);
+            }
+        }
+        ctx.open_mode = security.open_mode.unwrap_or(!ctx.has_credentials());
+        ctx
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_mode
+    }
+
+    /// open モードでは平文のパケットも受け付ける
+    pub fn set_open_mode(&mut self, open: bool) {
+        self.open_mode = open;
+    }
+
+    pub fn set_psk(&mut self, passphrase: &[u8]) {
+        self.psk = Some(Credential::psk(passphrase));
+    }
+
+    pub fn trust_peer(&mut self, public: [u8; KEY_LEN]) {
+        self.trusted_peers.insert(public);
+    }
+
+    pub fn set_identity(&mut self, secret: [u8; KEY_LEN]) {
+        self.identity = Some(StaticSecret::from(secret));
+    }
+
+    /// 自分の X25519 公開鍵。まだ読み込んでいなければ鍵ファイルを読む (無ければ作る)
+    pub fn identity_public(&mut self) -> Result<[u8; KEY_LEN], String> {
+        if self.identity.is_none() {
+            self.identity = Some(load_or_create_identity(&self.identity_file)?);
+        }
+        Ok(PublicKey::from(self.identity.as_ref().unwrap()).to_bytes())
+    }
+
+    pub fn has_credentials(&self) -> bool {
+        self.psk.is_some() || !self.trusted_peers.is_empty()
+    }
+
+    /// このフォーマットを `auth` の送り主から受け付けてよいか
+    pub fn allows(&self, format: Format, auth: PeerAuth) -> bool {
+        self.open_mode || auth.is_authenticated() || PUBLIC_FORMATS.contains(&format)
+    }
+
+    /// 受信したパケットを開く。平文ならそのまま返し、鍵は `None`
+    pub fn open_incoming(&self, packet: OsaiPacket) -> Result<(OsaiPacket, Option<SessionKey>), SecureError> {
+        if packet.format != Format::SEALED {
+            return Ok((packet, None));
+        }
+        let header = SealedHeader::peek(&packet)?;
+        let skew = unix_now() as i64 - header.timestamp as i64;
+        if skew.unsigned_abs() > self.max_clock_skew.as_secs() {
+            return Err(SecureError::Stale { skew_secs: skew });
+        }
+
+        let credential = match header.kind {
+            KeyKind::Psk => self.psk.as_ref(),
+            KeyKind::Peer => self
+                .inbound
+                .get(&header.key_id)
+                .map(|inbound| &inbound.credential)
+                .filter(|c| !c.expired(Instant::now())),
+        }
+        .ok_or(SecureError::UnknownKey(header.kind))?;
+
+        let key = credential.session_key(packet.session_id);
+        let inner = key.open(&packet)?;
+        Ok((inner, Some(key)))
+    }
+
+    /// 認証付きセッションを処理する前に呼ぶ。初めて見るセッションなら true
+    pub fn check_replay(&mut self, session_id: SessionId, now: Instant) -> bool {
+        let window = self.max_clock_skew * 2;
+        self.replay.retain(|_, seen| now.duration_since(*seen) <= window);
+        self.replay.insert(session_id, now).is_none()
+    }
+
+    pub fn psk_credential(&self) -> Option<Credential> {
+        self.psk.clone()
+    }
+
+    /// `dst` とハンドシェイク済みで期限内の鍵
+    pub fn peer_credential(&self, dst: SocketAddr) -> Option<Credential> {
+        self.outbound.get(&dst).filter(|c| !c.expired(Instant::now())).cloned()
+    }
+
+    /// `dst` に送るときの鍵。ハンドシェイク済みならその鍵、無ければ PSK
+    pub fn outbound_credential(&self, dst: SocketAddr) -> Option<Credential> {
+        self.peer_credential(dst).or_else(|| self.psk_credential())
+    }
+
+    /// 送る前に X25519 ハンドシェイクをするか (信頼する公開鍵が設定されている)
+    pub fn wants_handshake(&self) -> bool {
+        self.identity.is_some() && !self.trusted_peers.is_empty()
+    }
+
+    /// 開始側: ephemeral 鍵と Init メッセージを作る
+    pub fn begin_handshake(&self) -> Result<(StaticSecret, HandshakeMessage), String> {
+        let identity = self.identity.as_ref().ok_or("no X25519 identity configured")?;
+        let ephemeral = random_secret();
+        let init = HandshakeMessage {
+            role: HandshakeRole::Init,
+            static_public: PublicKey::from(identity).to_bytes(),
+            ephemeral_public: PublicKey::from(&ephemeral).to_bytes(),
+        };
+        Ok((ephemeral, init))
+    }
+
+    /// 開始側: Reply を検証して `dst` 用の鍵を保存する
+    pub fn finish_handshake(
+        &mut self,
+        dst: SocketAddr,
+        session_id: SessionId,
+        ephemeral: &StaticSecret,
+        reply: &HandshakeMessage,
+    ) -> Result<Credential, String> {
+        let identity = self.identity.as_ref().ok_or("no X25519 identity configured")?;
+        if reply.role != HandshakeRole::Reply {
+            return Err("expected a handshake reply".to_string());
+        }
+        if !self.trusted_peers.contains(&reply.static_public) {
+            return Err(format!("{} is not a trusted peer", hex::encode(reply.static_public)));
+        }
+
+        let ephemeral_dh = dh(ephemeral, &reply.ephemeral_public)?;
+        let static_dh = dh(identity, &reply.static_public)?;
+        let master = handshake_master(
+            session_id,
+            &ephemeral_dh,
+            &static_dh,
+            [
+                &PublicKey::from(identity).to_bytes(),
+                &PublicKey::from(ephemeral).to_bytes(),
+                &reply.static_public,
+                &reply.ephemeral_public,
+            ],
+        );
+
+        let credential = Credential::peer(session_id, master, reply.static_public);
+        self.outbound.insert(dst, credential.clone());
+        Ok(credential)
+    }
+
+    /// 応答側: Init を検証して Reply を返し、鍵を保存する
+    pub fn accept_handshake(
+        &mut self,
+        session_id: SessionId,
+        init: &HandshakeMessage,
+    ) -> Result<HandshakeMessage, String> {
+        let identity = self.identity.as_ref().ok_or("no X25519 identity configured")?;
+        if init.role != HandshakeRole::Init {
+            return Err("expected a handshake init".to_string());
+        }
+        if !self.trusted_peers.contains(&init.static_public) {
+            return Err(format!("{} is not a trusted peer", hex::encode(init.static_public)));
+        }
+
+        let key_id: KeyId = session_id.0[..KEY_ID_LEN].try_into().unwrap();
+        if let Some(existing) = self.inbound.get(&key_id) {
+            // Reply が落ちて Init が再送された
+            if existing.init_ephemeral == init.ephemeral_public
+                && existing.credential.auth == PeerAuth::Peer(init.static_public)
+            {
+                return Ok(existing.reply.clone());
+            }
+            return Err("handshake session id already in use".to_string());
+        }
+
+        let ephemeral = random_secret();
+        let ephemeral_dh = dh(&ephemeral, &init.ephemeral_public)?;
+        let static_dh = dh(identity, &init.static_public)?;
+        let reply = HandshakeMessage {
+            role: HandshakeRole::Reply,
+            static_public: PublicKey::from(identity).to_bytes(),
+            ephemeral_public: PublicKey::from(&ephemeral).to_bytes(),
+        };
+        let master = handshake_master(
+            session_id,
+            &ephemeral_dh,
+            &static_dh,
+            [&init.static_public, &init.ephemeral_public, &reply.static_public, &reply.ephemeral_public],
+        );
+
+        let now = Instant::now();
+        self.inbound.retain(|_, inbound| !inbound.credential.expired(now));
+        if self.inbound.len() >= MAX_INBOUND_KEYS {
+            if let Some(oldest) = self
+                .inbound
+                .iter()
+                .min_by_key(|(_, inbound)| inbound.credential.created)
+                .map(|(id, _)| *id)
+            {
+                self.inbound.remove(&oldest);
+            }
+        }
+        self.inbound.insert(
+            key_id,
+            InboundKey {
+                credential: Credential::peer(session_id, master, init.static_public),
+                init_ephemeral: init.ephemeral_public,
+                reply: reply.clone(),
+            },
+        );
+        Ok(reply)
+    }
+}
+
+/// OSAI::with_config が設定から作り直す。それまでは環境変数だけを見る
+pub static SECURITY: Lazy<RwLock<SecurityContext>> = Lazy::new(|| {
+    RwLock::new(SecurityContext::from_config(&OsaiConfig::from_env()))
+});
+
+pub fn parse_public_key(hex_key: &str) -> Result<[u8; KEY_LEN], String> {
+    let bytes = hex::decode(hex_key).map_err(|e| format!("invalid hex: {}", e))?;
+    bytes.try_into().map_err(|_| format!("public key must be {} bytes", KEY_LEN))
+}
+
+/// 秘密鍵ファイル (hex) を読む。無ければ作って所有者だけ読めるようにする
+fn load_or_create_identity(path: &Path) -> Result<StaticSecret, String> {
+    if path.exists() {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let secret = parse_public_key(text.trim())
+            .map_err(|e| format!("Bad identity key in {}: {}", path.display(), e))?;
+        return Ok(StaticSecret::from(secret));
+    }
+
+    let mut secret = [0u8; KEY_LEN];
+    rand::rng().fill(&mut secret);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    std::io::Write::write_all(&mut file, hex::encode(secret).as_bytes())
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    println!("Created X25519 identity: {}", path.display());
+    Ok(StaticSecret::from(secret))
+}
+
+fn random_secret() -> StaticSecret {
+    let mut secret = [0u8; KEY_LEN];
+    rand::rng().fill(&mut secret);
+    StaticSecret::from(secret)
+}
+
+/// 低位数点などで共有鍵が全部0になる相手は拒否する
+fn dh(secret: &StaticSecret, public: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN], String> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err("handshake public key is not usable".to_string());
+    }
+    Ok(shared.to_bytes())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
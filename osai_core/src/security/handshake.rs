@@ -0,0 +1,87 @@
+// X25519 ハンドシェイク (Format::HANDSHAKE) の送受信
+//
+// 開始側: Init (static 公開鍵 + ephemeral 公開鍵) を送り、同じ session_id の Reply を待つ。
+// 応答側: static 公開鍵が信頼済みなら Reply を返す。
+// 両者は ephemeral 同士と static 同士の DH から master 鍵を作るので、
+// 信頼済みの static 秘密鍵を持っている相手としか SEALED パケットをやり取りできない。
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::protocol::packet::{Format, OsaiPacket, SessionId, MAX_PACKET_SIZE};
+use crate::protocol::secure::{HandshakeMessage, HandshakeRole};
+use crate::security::context::{Credential, SECURITY};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+const HANDSHAKE_ATTEMPTS: u32 = 3;
+
+/// `dst` に送るときの鍵を決める。
+/// 信頼する公開鍵が設定されていればハンドシェイクし (済んでいれば使い回す)、失敗したら PSK を使う。
+/// どちらも設定されていなければ平文 (`None`)
+pub async fn credential_for(socket: &UdpSocket, dst: SocketAddr) -> Result<Option<Credential>, String> {
+    let (cached, wants_handshake, psk) = {
+        let ctx = SECURITY.read().unwrap();
+        (ctx.peer_credential(dst), ctx.wants_handshake(), ctx.psk_credential())
+    };
+    if cached.is_some() {
+        return Ok(cached);
+    }
+    if !wants_handshake {
+        return Ok(psk);
+    }
+
+    match handshake(socket, dst).await {
+        Ok(credential) => Ok(Some(credential)),
+        Err(e) if psk.is_some() => {
+            eprintln!("Handshake with {} failed, falling back to PSK: {}", dst, e);
+            Ok(psk)
+        }
+        Err(e) => Err(format!("Handshake with {} failed: {}", dst, e)),
+    }
+}
+
+/// `dst` とハンドシェイクして鍵を保存する
+pub async fn handshake(socket: &UdpSocket, dst: SocketAddr) -> Result<Credential, String> {
+    let session_id = SessionId::random();
+    let (ephemeral, init) = SECURITY.read().unwrap().begin_handshake()?;
+    let bytes = init
+        .into_packet(session_id)
+        .encode()
+        .map_err(|e| format!("Failed to build handshake: {}", e))?;
+
+    let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        socket.send_to(&bytes, dst).await
+            .map_err(|e| format!("Failed to send handshake: {}", e))?;
+
+        let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
+        loop {
+            let (len, from) = match tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+                Ok(Ok(received)) => received,
+                Ok(Err(e)) => return Err(format!("Recv error: {}", e)),
+                Err(_) => break,
+            };
+            if from != dst {
+                continue;
+            }
+            let reply = match OsaiPacket::decode(&buf[..len]) {
+                Ok(packet) if packet.format == Format::HANDSHAKE && packet.session_id == session_id => {
+                    match HandshakeMessage::decode(&packet.payload) {
+                        Ok(reply) if reply.role == HandshakeRole::Reply => reply,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            return SECURITY.write().unwrap().finish_handshake(dst, session_id, &ephemeral, &reply);
+        }
+    }
+    Err(format!("no handshake reply after {} attempts", HANDSHAKE_ATTEMPTS))
+}
+
+/// 応答側: Init に対する Reply パケットを作る
+pub fn respond(packet: &OsaiPacket) -> Result<OsaiPacket, String> {
+    let init = HandshakeMessage::decode(&packet.payload).map_err(|e| e.to_string())?;
+    let reply = SECURITY.write().unwrap().accept_handshake(packet.session_id, &init)?;
+    Ok(reply.into_packet(packet.session_id))
+}
@@ -0,0 +1,3 @@
+// src/security/mod.rs
+pub mod context;
+pub mod handshake;
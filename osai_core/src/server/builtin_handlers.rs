@@ -0,0 +1,214 @@
+// process_format の組み込みハンドラー
+use crate::ai::hebbian_local::ai;
+use std::net::SocketAddr;
+
+use crate::ai::state::{MY_VEC, W1, W2};
+
+use crate::fileIO::create_lyric::create_lyric;
+use crate::IOT::task::{add_new_task, TaskFilter};
+use crate::IOT::task_store::TaskStore;
+use crate::server::discovery::record_announce;
+use crate::protocol::announce::Announce;
+use crate::protocol::packet::OsaiPacket;
+use crate::protocol::task_alert::{TaskAck, TaskAlert};
+use crate::protocol::task_ops::{decode_body, TaskEditRequest, TaskRef};
+use crate::server::file_receiver::ReceivedFile;
+use crate::server::format_handler::{FormatHandler, FormatResponse, PacketContext};
+
+/// [0,0] UTF-8 テキスト
+pub struct TextHandler;
+
+impl FormatHandler for TextHandler {
+    fn handle(&self, packet: &OsaiPacket, _ctx: &PacketContext) -> FormatResponse {
+        FormatResponse::Text(String::from_utf8_lossy(&packet.payload).to_string())
+    }
+}
+
+/// [0,1] サーバー発見の応答
+pub struct DiscoveryHandler;
+
+impl FormatHandler for DiscoveryHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        println!("  Discovered Server IP: {}", ctx.addr);
+        FormatResponse::Discovered { addr: ctx.addr, data: packet.payload.clone() }
+    }
+}
+
+/// [0,2] 感情ベクトルで学習し、歌詞を作る
+pub struct EmotionHandler;
+
+impl FormatHandler for EmotionHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        println!("receive data");
+        //ほんとはSIMDでやりたい
+        let input_vec = packet.data_vec.0;
+        let mut my_vec_guard = MY_VEC.lock().unwrap();
+        let mut w1_guard = W1.lock().unwrap();
+        let mut w2_guard = W2.lock().unwrap();
+
+        let (new_vec, is_trusted) = ai(*my_vec_guard, input_vec, &mut w1_guard, &mut w2_guard);
+        if is_trusted {
+            *my_vec_guard = new_vec;
+            //app_handle.emit("trusted_node", "学習データを更新しました").unwrap();
+        } else {
+            //app_handle.emit("enemy_detected", "敵シグナルを検知").unwrap();
+        }
+
+        //vocaloid logic
+        let text = String::from_utf8_lossy(&packet.payload).to_string();
+        if input_vec[0] > 4 {
+            create_lyric(&ctx.config.paths.lyric_file, &text, input_vec);
+            println!("lyric created");
+        } else {
+            println!("lyric didnot created");
+        }
+        FormatResponse::AiCheck { trusted: is_trusted }
+    }
+}
+
+/// [0,3] タスク登録
+pub struct TaskHandler;
+
+impl FormatHandler for TaskHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        println!("receive data (Task Registration)");
+
+        // TaskStore に足せば保存したときにスケジューラー (TaskStore::subscribe) が起きて通知の予定に入る
+        let payload_str = String::from_utf8_lossy(&packet.payload).to_string();
+        let registered = add_new_task(&payload_str, ctx.config.scheduler.zone())
+            .map_err(|e| e.to_string())
+            .and_then(|task| TaskStore::new(&ctx.config.paths.task_file).add(task));
+        match registered {
+            Ok(task) => FormatResponse::TaskRegistered(task),
+            Err(e) => {
+                eprintln!("Task registration from {} failed: {}", ctx.addr, e);
+                FormatResponse::TaskRejected(e)
+            }
+        }
+    }
+}
+
+/// [0,11] 相手のノードの通知に応答が無かった (自分が caregiver)
+pub struct TaskAlertHandler;
+
+impl FormatHandler for TaskAlertHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        match TaskAlert::decode(&packet.payload) {
+            Ok(alert) => FormatResponse::TaskAlert { from: ctx.addr, alert },
+            Err(e) => FormatResponse::Text(format!("Ignored task alert from {}: {}", ctx.addr, e)),
+        }
+    }
+}
+
+/// [0,12] 鳴っている通知への ack・snooze。結果は TASK_REPLY で返す
+pub struct TaskAckHandler;
+
+impl FormatHandler for TaskAckHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let responded = TaskAck::decode(&packet.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|ack| TaskStore::new(&ctx.config.paths.task_file).respond(&ack.task, ack.action));
+        match responded {
+            Ok(tasks) => FormatResponse::TaskResponded(tasks),
+            Err(e) => FormatResponse::TaskOpRejected(e),
+        }
+    }
+}
+
+/// [0,13] タスクの変更
+pub struct TaskEditHandler;
+
+impl FormatHandler for TaskEditHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let edited = decode_body::<TaskEditRequest>(&packet.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|req| TaskStore::new(&ctx.config.paths.task_file).update(&req.id, &req.edit));
+        match edited {
+            Ok(task) => FormatResponse::TaskChanged { action: "Edited", task },
+            Err(e) => FormatResponse::TaskOpRejected(e),
+        }
+    }
+}
+
+/// [0,14] タスクの削除
+pub struct TaskDeleteHandler;
+
+impl FormatHandler for TaskDeleteHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let deleted = decode_body::<TaskRef>(&packet.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|req| TaskStore::new(&ctx.config.paths.task_file).delete(&req.id));
+        match deleted {
+            Ok(task) => FormatResponse::TaskChanged { action: "Deleted", task },
+            Err(e) => FormatResponse::TaskOpRejected(e),
+        }
+    }
+}
+
+/// [0,15] タスクをこの回で終わらせる
+pub struct TaskDoneHandler;
+
+impl FormatHandler for TaskDoneHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let completed = decode_body::<TaskRef>(&packet.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|req| TaskStore::new(&ctx.config.paths.task_file).complete(&req.id));
+        match completed {
+            Ok(task) => FormatResponse::TaskChanged { action: "Completed", task },
+            Err(e) => FormatResponse::TaskOpRejected(e),
+        }
+    }
+}
+
+/// [0,16] タスクの一覧
+pub struct TaskListHandler;
+
+impl FormatHandler for TaskListHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let listed = decode_body::<TaskFilter>(&packet.payload)
+            .map_err(|e| e.to_string())
+            .and_then(|filter| TaskStore::new(&ctx.config.paths.task_file).list(&filter));
+        match listed {
+            Ok(tasks) => FormatResponse::TaskListed(tasks),
+            Err(e) => FormatResponse::TaskOpRejected(e),
+        }
+    }
+}
+
+/// [0,5] ファイル転送。保存は file_receiver が済ませているので結果を返すだけ。
+/// 1パケットのものは StreamSink を通っていない (送り主が書いた JSON) ので受け付けない
+pub struct FileHandler;
+
+impl FormatHandler for FileHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        if packet.chunk.is_single() {
+            return FormatResponse::Text(format!("Ignored single-packet file from {}", ctx.addr));
+        }
+        match serde_json::from_slice::<ReceivedFile>(&packet.payload) {
+            Ok(file) => FormatResponse::FileReceived(file),
+            Err(e) => FormatResponse::Text(format!("File from {} could not be read back: {}", ctx.addr, e)),
+        }
+    }
+}
+
+/// [0xFF,0xFF] サーバー生存シグナル。ピア一覧に記録する
+pub struct SignalHandler;
+
+impl FormatHandler for SignalHandler {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        let announce = match Announce::decode(&packet.payload) {
+            Ok(announce) => announce,
+            Err(e) => return FormatResponse::Text(format!("Ignored signal from {}: {}", ctx.addr, e)),
+        };
+
+        // ブロードキャストやマルチキャストでは自分のシグナルも届く
+        if ctx.config.node.id == Some(announce.node_id) {
+            return FormatResponse::Text("Ignored own signal".to_string());
+        }
+
+        let joined = record_announce(ctx.addr.ip(), &announce);
+        let addr = SocketAddr::new(ctx.addr.ip(), announce.ports.udp);
+
+        FormatResponse::PeerAnnounced { node_id: announce.node_id, name: announce.name, addr, joined }
+    }
+}
@@ -0,0 +1,295 @@
+// 生存シグナルとプローブの送受信。
+// discovery の設定に従って、ブロードキャスト・IPv4 マルチキャスト・IPv6 リンクローカルマルチキャスト・
+// seeds へのユニキャストに同じパケットを送る。自分のシグナルもループバックで届くので、受信側はノードIDで捨てる。
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Instant;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::config::OsaiConfig;
+use crate::p2p::node::NodeId;
+use crate::p2p::registry::PEER_REGISTRY;
+use crate::protocol::announce::Announce;
+use crate::protocol::packet::OsaiPacket;
+use crate::runtime::RunningServices;
+use crate::server::server_signal;
+
+/// マルチキャストはルーターを越えない
+const MULTICAST_HOPS: u32 = 1;
+
+/// 生存シグナルを discovery の全送り先へ送る
+pub async fn announce(config: &OsaiConfig, node_id: NodeId, running: &RunningServices) -> Result<(), String> {
+    let announce = server_signal::build_announce(config, node_id, running);
+    let bytes = server_signal::build_server_announce_packet(&announce)
+        .and_then(|packet| packet.encode())
+        .map_err(|e| format!("Failed to encode announce: {}", e))?;
+    send_to_targets(config, &bytes, "Signal").await
+}
+
+/// 「誰かいますか」を送る。受けたノードは server.port へすぐにシグナルを返すので、
+/// 返事は動いている UDP サーバーが受けて PEER_REGISTRY に入る
+pub async fn probe(config: &OsaiConfig, node_id: NodeId, running: &RunningServices) -> Result<(), String> {
+    let bytes = server_signal::build_announce(config, node_id, running)
+        .into_probe_packet()
+        .and_then(|packet| packet.encode())
+        .map_err(|e| format!("Failed to encode probe: {}", e))?;
+    send_to_targets(config, &bytes, "Probe").await
+}
+
+/// 送り先ごとに送る。届かない送り先 (ブロードキャストを通さないネットワークなど) があっても残りには送り、
+/// 1つも送れなかったときだけエラー
+async fn send_to_targets(config: &OsaiConfig, bytes: &[u8], what: &str) -> Result<(), String> {
+    let targets = config.discovery.targets(&config.server.announce_ports());
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    let v4 = if targets.iter().any(SocketAddr::is_ipv4) {
+        Some(bind_v4_sender().await?)
+    } else {
+        None
+    };
+    let v6 = if targets.iter().any(SocketAddr::is_ipv6) {
+        match bind_v6_sender(config.discovery.interface_v6).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut sent = 0;
+    let mut last_error = None;
+    for target in &targets {
+        let socket = match (target, &v4, &v6) {
+            (SocketAddr::V4(_), Some(socket), _) | (SocketAddr::V6(_), _, Some(socket)) => socket,
+            _ => continue,
+        };
+        match socket.send_to(bytes, target).await {
+            Ok(_) => {
+                sent += 1;
+                println!("{} sent to {}", what, target);
+            }
+            Err(e) => {
+                eprintln!("Failed to send {} to {}: {}", what.to_lowercase(), target, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if sent == 0 => Err(format!("Failed to send {}: {}", what.to_lowercase(), e)),
+        _ => Ok(()),
+    }
+}
+
+async fn bind_v4_sender() -> Result<UdpSocket, String> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| format!("Failed to bind signal socket: {}", e))?;
+    socket
+        .set_broadcast(true)
+        .map_err(|e| format!("Failed to enable broadcast: {}", e))?;
+    socket
+        .set_multicast_ttl_v4(MULTICAST_HOPS)
+        .map_err(|e| format!("Failed to set multicast TTL: {}", e))?;
+    Ok(socket)
+}
+
+async fn bind_v6_sender(interface: u32) -> Result<UdpSocket, String> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+        .map_err(|e| format!("IPv6 is not available: {}", e))?;
+    socket
+        .set_multicast_if_v6(interface)
+        .and_then(|_| socket.set_multicast_hops_v6(MULTICAST_HOPS))
+        .and_then(|_| socket.set_nonblocking(true))
+        .map_err(|e| format!("Failed to set up IPv6 signal socket: {}", e))?;
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+    socket
+        .bind(&addr.into())
+        .map_err(|e| format!("Failed to bind IPv6 signal socket: {}", e))?;
+    UdpSocket::from_std(socket.into()).map_err(|e| e.to_string())
+}
+
+/// サーバーのソケットを IPv4 マルチキャストグループに参加させる。
+/// 参加できなくてもブロードキャストとユニキャストは受けられるので警告だけにする
+pub fn join_multicast_v4(socket: &UdpSocket, config: &OsaiConfig) {
+    let Some(group) = config.discovery.multicast_v4 else { return };
+    let interface = match config.server.bind {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    match socket.join_multicast_v4(group, interface) {
+        Ok(()) => println!("Joined multicast group {}", group),
+        Err(e) => eprintln!("Failed to join multicast group {}: {}", group, e),
+    }
+}
+
+/// IPv6 マルチキャストを受けるソケット。サーバーの IPv4 ソケットと同じポートを使うので IPV6_V6ONLY にする。
+/// multicast_v6 が無ければ None
+pub fn bind_v6_listener(config: &OsaiConfig) -> Result<Option<UdpSocket>, String> {
+    let Some(group) = config.discovery.multicast_v6 else { return Ok(None) };
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+        .map_err(|e| format!("IPv6 is not available: {}", e))?;
+    socket
+        .set_only_v6(true)
+        .and_then(|_| socket.set_reuse_address(true))
+        .and_then(|_| socket.set_nonblocking(true))
+        .map_err(|e| format!("Failed to set up IPv6 discovery socket: {}", e))?;
+    let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.server.port);
+    socket
+        .bind(&addr.into())
+        .map_err(|e| format!("Failed to bind IPv6 discovery socket to {}: {}", addr, e))?;
+    socket
+        .join_multicast_v6(&group, config.discovery.interface_v6)
+        .map_err(|e| format!("Failed to join multicast group {}: {}", group, e))?;
+    println!("Joined multicast group {} on port {}", group, config.server.port);
+    UdpSocket::from_std(socket.into()).map(Some).map_err(|e| e.to_string())
+}
+
+/// 生存シグナルかプローブの本文を PEER_REGISTRY に記録する。初めてのピアなら true
+pub fn record_announce(ip: IpAddr, announce: &Announce) -> bool {
+    let joined = PEER_REGISTRY.write().unwrap().observe(ip, announce, Instant::now());
+    if joined {
+        println!(
+            "Peer {} [{}] ({}, {}) joined at {}",
+            announce.name,
+            announce.node_id.short(),
+            announce.device,
+            announce.software,
+            SocketAddr::new(ip, announce.ports.udp)
+        );
+    }
+    joined
+}
+
+/// プローブにシグナルで答える。返信先は送信元の IP と本文の ports.udp (リンクローカルならスコープもそのまま)。
+/// 自分のプローブなら何もしない
+pub async fn answer_probe(
+    socket: &UdpSocket,
+    packet: &OsaiPacket,
+    from: SocketAddr,
+    config: &OsaiConfig,
+    node_id: NodeId,
+    running: &RunningServices,
+) -> Result<(), String> {
+    let prober = Announce::decode(&packet.payload).map_err(|e| format!("Invalid probe from {}: {}", from, e))?;
+    if prober.node_id == node_id {
+        return Ok(());
+    }
+    record_announce(from.ip(), &prober);
+
+    let reply = server_signal::build_announce(config, node_id, running)
+        .into_packet()
+        .and_then(|packet| packet.encode())
+        .map_err(|e| format!("Failed to encode announce: {}", e))?;
+    let mut reply_addr = from;
+    reply_addr.set_port(prober.ports.udp);
+    socket
+        .send_to(&reply, reply_addr)
+        .await
+        .map_err(|e| format!("Failed to answer probe from {}: {}", reply_addr, e))?;
+    println!("Answered probe from {} [{}] at {}", prober.name, prober.node_id.short(), reply_addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV6;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use tokio_util::sync::CancellationToken;
+    use crate::config::DiscoveryConfig;
+    use crate::protocol::packet::{Format, MAX_PACKET_SIZE};
+    use crate::runtime::Service;
+    use crate::server::server::start_server;
+
+    async fn recv_announce(socket: &UdpSocket, wait: Duration) -> Option<(Format, Announce)> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        let (len, _) = timeout(wait, socket.recv_from(&mut buf)).await.ok()?.unwrap();
+        let packet = OsaiPacket::decode(&buf[..len]).unwrap();
+        Some((packet.format, Announce::decode(&packet.payload).unwrap()))
+    }
+
+    #[test]
+    fn targets_cover_every_enabled_route() {
+        let discovery = DiscoveryConfig {
+            broadcast: true,
+            multicast_v4: Some(Ipv4Addr::new(239, 255, 79, 83)),
+            multicast_v6: Some("ff02::4f53".parse().unwrap()),
+            interface_v6: 3,
+            seeds: vec!["192.168.2.10:9000".parse().unwrap()],
+            ..DiscoveryConfig::default()
+        };
+        let group_v6 = |port| SocketAddr::V6(SocketAddrV6::new("ff02::4f53".parse().unwrap(), port, 0, 3));
+        let expected: Vec<SocketAddr> = vec![
+            "255.255.255.255:8080".parse().unwrap(),
+            "239.255.79.83:8080".parse().unwrap(),
+            group_v6(8080),
+            "255.255.255.255:8081".parse().unwrap(),
+            "239.255.79.83:8081".parse().unwrap(),
+            group_v6(8081),
+            "192.168.2.10:9000".parse().unwrap(),
+        ];
+        assert_eq!(discovery.targets(&[8080, 8081]), expected);
+
+        let seeds_only = DiscoveryConfig {
+            broadcast: false,
+            multicast_v4: None,
+            multicast_v6: None,
+            ..discovery
+        };
+        assert_eq!(seeds_only.targets(&[8080]), ["192.168.2.10:9000".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn node_ignores_itself_and_answers_probes_right_away() {
+        let mut config = OsaiConfig::loopback();
+        // 自分宛てに送れば、ブロードキャストで自分のシグナルが戻ってくるのと同じになる
+        config.discovery.seeds = vec![config.server.bind_addr()];
+        config.discovery.probe_on_start = true;
+        config.server.announce_interval_secs = 3600;
+        let node_id = config.node.id.unwrap();
+        let config = Arc::new(config);
+        let running = RunningServices::default();
+        running.insert(Service::Server);
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(start_server(Arc::clone(&config), running.clone(), shutdown.clone()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        announce(&config, node_id, &running).await.unwrap();
+
+        // 別のノードのふりをしてプローブを送ると、次のシグナルの時刻を待たずに返事が来る
+        let prober = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut other = OsaiConfig::loopback();
+        other.server.port = prober.local_addr().unwrap().port();
+        let other_id = other.node.id.unwrap();
+        let probe = server_signal::build_announce(&other, other_id, &RunningServices::default())
+            .into_probe_packet()
+            .unwrap()
+            .encode()
+            .unwrap();
+        prober.send_to(&probe, config.server.bind_addr()).await.unwrap();
+
+        let (format, reply) = recv_announce(&prober, Duration::from_secs(1)).await.expect("probe was not answered");
+        assert_eq!(format, Format::SIGNAL);
+        assert_eq!(reply.node_id, node_id);
+        assert_eq!(reply.ports.udp, config.server.port);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        {
+            let registry = PEER_REGISTRY.read().unwrap();
+            assert!(registry.get(&node_id).is_none(), "node recorded its own announce");
+            assert_eq!(registry.get(&other_id).unwrap().udp_addr(), prober.local_addr().unwrap());
+        }
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(config.paths.share_dir.as_ref().unwrap().parent().unwrap());
+    }
+}
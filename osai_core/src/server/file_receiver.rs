@@ -0,0 +1,354 @@
+// Format::FILE のチャンクを share ディレクトリの一時ファイルに書き込み、
+// SHA-256 が一致したら本来の名前にリネームする
+//
+// ハッシュは先頭から続いたところまで書くたびに計算しておき、finish で読み直さない。
+// 順番を飛ばして届いたチャンクだけ、前が埋まったときにファイルから読んで足す。
+// finish (fsync・リネーム) は受信ループの外で呼ばれる (reassembly::StreamFinish)。
+//
+// ヘッダー (チャンク0) のサイズが max_file_size を超える転送は断る。
+// ヘッダーより先に届いた中身のチャンクは少しだけメモリに置き、ヘッダーが来てから書く
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::client::client::CHUNK_SIZE;
+use crate::protocol::file::FileHeader;
+use crate::protocol::packet::SessionId;
+use crate::server::reassembly::{StreamSink, StreamSinkFactory};
+
+/// 受信が終わったファイル。FileHandler に JSON で渡す
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceivedFile {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// ヘッダーより先に届いた中身のチャンクを置いておける数
+const MAX_CHUNKS_BEFORE_HEADER: usize = 16;
+
+pub struct FileSinkFactory {
+    share_dir: PathBuf,
+    max_file_size: u64,
+}
+
+impl FileSinkFactory {
+    /// `max_file_size` (バイト) より大きいファイルは受け取らない
+    pub fn new(share_dir: PathBuf, max_file_size: u64) -> Self {
+        FileSinkFactory { share_dir, max_file_size }
+    }
+}
+
+/// ファイルを受け取れないとき (share ディレクトリが作れないなど) の代わり。どのセッションも断る
+pub struct RefuseFiles(pub String);
+
+impl StreamSinkFactory for RefuseFiles {
+    fn open(&self, _addr: SocketAddr, _session_id: SessionId) -> Result<Box<dyn StreamSink>, String> {
+        Err(format!("file transfer is disabled: {}", self.0))
+    }
+}
+
+impl StreamSinkFactory for FileSinkFactory {
+    fn open(&self, addr: SocketAddr, session_id: SessionId) -> Result<Box<dyn StreamSink>, String> {
+        let temp_path = self.share_dir.join(format!(".osai-{}.part", session_id));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .map_err(|e| format!("Failed to create {}: {}", temp_path.display(), e))?;
+        println!("Receiving file from {} into {}", addr, temp_path.display());
+
+        Ok(Box::new(FileSink {
+            share_dir: self.share_dir.clone(),
+            temp_path,
+            file,
+            max_file_size: self.max_file_size,
+            header: None,
+            early: Vec::new(),
+            hasher: Sha256::new(),
+            hashed: 0,
+            unhashed: BTreeSet::new(),
+        }))
+    }
+}
+
+struct FileSink {
+    share_dir: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+    max_file_size: u64,
+    header: Option<FileHeader>,
+    /// ヘッダーより先に届いた (番号, 中身)
+    early: Vec<(u32, Vec<u8>)>,
+    /// 先頭から hashed バイトまでのハッシュ
+    hasher: Sha256,
+    hashed: u64,
+    /// 書いたがまだハッシュに足していないチャンク (前に抜けがある)
+    unhashed: BTreeSet<u32>,
+}
+
+impl StreamSink for FileSink {
+    fn write_chunk(&mut self, index: u32, data: &[u8]) -> Result<(), String> {
+        if index == 0 {
+            let header = FileHeader::decode(data).map_err(|e| format!("Bad file header: {}", e))?;
+            if header.size > self.max_file_size {
+                return Err(format!(
+                    "{} is {} bytes, larger than the limit of {} bytes",
+                    header.name, header.size, self.max_file_size
+                ));
+            }
+            println!("  File: {} ({} bytes)", header.name, header.size);
+            self.header = Some(header);
+            for (index, data) in std::mem::take(&mut self.early) {
+                self.write_at(index, &data)?;
+            }
+            return Ok(());
+        }
+
+        if self.header.is_none() {
+            // 書く前にサイズの上限だけは確かめておく
+            self.check_offset(index, data, self.max_file_size)?;
+            if self.early.len() >= MAX_CHUNKS_BEFORE_HEADER {
+                return Err(format!("more than {} chunks arrived before the file header", MAX_CHUNKS_BEFORE_HEADER));
+            }
+            self.early.push((index, data.to_vec()));
+            return Ok(());
+        }
+        self.write_at(index, data)
+    }
+
+    fn finish(mut self: Box<Self>, _total_chunks: u32) -> Result<Vec<u8>, String> {
+        let result = self.verify_and_rename();
+        if result.is_err() {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+        result
+    }
+
+    fn abort(self: Box<Self>) {
+        let _ = fs::remove_file(&self.temp_path);
+    }
+}
+
+impl FileSink {
+    /// チャンク番号 `index` (1以上) の書き込み先。`limit` バイトを超えるならエラー
+    fn check_offset(&self, index: u32, data: &[u8], limit: u64) -> Result<u64, String> {
+        if data.len() > CHUNK_SIZE {
+            return Err(format!("chunk {} is larger than {} bytes", index, CHUNK_SIZE));
+        }
+        let offset = (index as u64 - 1) * CHUNK_SIZE as u64;
+        if offset + data.len() as u64 > limit {
+            return Err(format!("chunk {} goes past the size limit {}", index, limit));
+        }
+        Ok(offset)
+    }
+
+    /// ヘッダーが届いてから呼ぶ
+    fn write_at(&mut self, index: u32, data: &[u8]) -> Result<(), String> {
+        let size = self.header.as_ref().map_or(0, |header| header.size);
+        let offset = self.check_offset(index, data, size)?;
+        self.file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+        self.file.write_all(data).map_err(|e| e.to_string())?;
+        if offset != self.hashed {
+            self.unhashed.insert(index);
+            return Ok(());
+        }
+        self.hasher.update(data);
+        self.hashed += data.len() as u64;
+        self.hash_written(size)
+    }
+
+    /// 続きがもう書いてあれば読み直してハッシュに足す
+    fn hash_written(&mut self, size: u64) -> Result<(), String> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        while let Some(&index) = self.unhashed.first() {
+            let offset = (index as u64 - 1) * CHUNK_SIZE as u64;
+            if offset != self.hashed {
+                break;
+            }
+            self.unhashed.remove(&index);
+            let len = (size - offset).min(CHUNK_SIZE as u64) as usize;
+            self.file.seek(SeekFrom::Start(offset)).map_err(|e| e.to_string())?;
+            self.file.read_exact(&mut buf[..len]).map_err(|e| e.to_string())?;
+            self.hasher.update(&buf[..len]);
+            self.hashed += len as u64;
+        }
+        Ok(())
+    }
+
+    fn verify_and_rename(&mut self) -> Result<Vec<u8>, String> {
+        let header = self.header.clone().ok_or("file header (chunk 0) was never received")?;
+        self.file.sync_all().map_err(|e| e.to_string())?;
+
+        let size = self.file.metadata().map_err(|e| e.to_string())?.len();
+        if size != header.size {
+            return Err(format!("size mismatch: expected {} got {}", header.size, size));
+        }
+
+        // 途中のチャンクが短いと後ろがハッシュに足されないまま残る
+        if self.hashed != size {
+            return Err(format!("chunks do not line up: hashed {} of {} bytes", self.hashed, size));
+        }
+        let digest: [u8; 32] = self.hasher.clone().finalize().into();
+        if digest != header.sha256 {
+            return Err(format!(
+                "sha256 mismatch: expected {} got {}",
+                hex::encode(header.sha256),
+                hex::encode(digest)
+            ));
+        }
+
+        let dest = reserve_path(&self.share_dir, &header.name)?;
+        if let Err(e) = fs::rename(&self.temp_path, &dest) {
+            let _ = fs::remove_file(&dest);
+            return Err(format!("Failed to rename to {}: {}", dest.display(), e));
+        }
+        println!("File saved: {}", dest.display());
+
+        let received = ReceivedFile {
+            name: header.name,
+            path: dest.to_string_lossy().to_string(),
+            size,
+            sha256: hex::encode(digest),
+        };
+        serde_json::to_vec(&received).map_err(|e| e.to_string())
+    }
+}
+
+/// 同名のファイルがあれば `name-1.ext` のようにずらす。
+/// 空のファイルを create_new で作って名前を押さえるので、その間に作られたファイルを上書きしない
+fn reserve_path(dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let path = Path::new(name);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    let candidates = std::iter::once(dir.join(name))
+        .chain((1..).map(|i| dir.join(format!("{}-{}{}", stem, i, ext))));
+    for candidate in candidates {
+        match OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Failed to create {}: {}", candidate.display(), e)),
+        }
+    }
+    unreachable!("candidate names never run out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("osai-file-receiver-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 9000))
+    }
+
+    fn header(name: &str, data: &[u8]) -> Vec<u8> {
+        FileHeader { name: name.to_string(), size: data.len() as u64, sha256: Sha256::digest(data).into() }
+            .encode()
+            .unwrap()
+    }
+
+    #[test]
+    fn rejects_files_over_the_limit() {
+        let dir = temp_dir("limit");
+        let factory = FileSinkFactory::new(dir.clone(), CHUNK_SIZE as u64);
+        let data = vec![7u8; CHUNK_SIZE + 1];
+
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        assert!(sink.write_chunk(0, &header("big.bin", &data)).is_err());
+        sink.abort();
+
+        // ヘッダーより先に届いたチャンクも上限より先には置けない
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        assert!(sink.write_chunk(2, &data[CHUNK_SIZE..]).is_err());
+        sink.abort();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn chunks_before_the_header_are_written_once_it_arrives() {
+        let dir = temp_dir("early");
+        let factory = FileSinkFactory::new(dir.clone(), 1 << 20);
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        sink.write_chunk(3, chunks[2]).unwrap();
+        sink.write_chunk(1, chunks[0]).unwrap();
+        sink.write_chunk(0, &header("early.bin", &data)).unwrap();
+        sink.write_chunk(2, chunks[1]).unwrap();
+        let received: ReceivedFile = serde_json::from_slice(&sink.finish(4).unwrap()).unwrap();
+
+        assert_eq!(received.size, data.len() as u64);
+        assert_eq!(fs::read(&received.path).unwrap(), data);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn gaps_are_hashed_once_filled() {
+        let dir = temp_dir("gaps");
+        let factory = FileSinkFactory::new(dir.clone(), 1 << 20);
+        let data: Vec<u8> = (0..CHUNK_SIZE * 4 + 10).map(|i| (i * 7) as u8).collect();
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_SIZE).collect();
+
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        sink.write_chunk(0, &header("gaps.bin", &data)).unwrap();
+        for index in [1, 3, 5, 2, 4] {
+            sink.write_chunk(index, chunks[index as usize - 1]).unwrap();
+        }
+        let received: ReceivedFile = serde_json::from_slice(&sink.finish(6).unwrap()).unwrap();
+        assert_eq!(received.sha256, hex::encode(Sha256::digest(&data)));
+        assert_eq!(fs::read(&received.path).unwrap(), data);
+
+        // 中身が違えば保存せずに一時ファイルも消す
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        sink.write_chunk(0, &header("bad.bin", &data)).unwrap();
+        sink.write_chunk(2, chunks[1]).unwrap();
+        sink.write_chunk(1, &vec![0u8; CHUNK_SIZE]).unwrap();
+        for index in 3..=5 {
+            sink.write_chunk(index, chunks[index as usize - 1]).unwrap();
+        }
+        let error = sink.finish(6).unwrap_err();
+        assert!(error.contains("sha256 mismatch"), "{}", error);
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names, ["gaps.bin"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn too_many_chunks_before_the_header_fail() {
+        let dir = temp_dir("flood");
+        let factory = FileSinkFactory::new(dir.clone(), u64::MAX);
+        let mut sink = factory.open(addr(), SessionId::random()).unwrap();
+        for index in 1..=MAX_CHUNKS_BEFORE_HEADER as u32 {
+            sink.write_chunk(index, b"x").unwrap();
+        }
+        assert!(sink.write_chunk(MAX_CHUNKS_BEFORE_HEADER as u32 + 1, b"x").is_err());
+        sink.abort();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn reserved_names_never_overwrite() {
+        let dir = temp_dir("reserve");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+
+        let first = reserve_path(&dir, "a.txt").unwrap();
+        let second = reserve_path(&dir, "a.txt").unwrap();
+        assert_eq!(first, dir.join("a-1.txt"));
+        assert_eq!(second, dir.join("a-2.txt"));
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "old");
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -3,7 +3,7 @@ use std::error::Error;
 use std::fs;
 
 // "share" ディレクトリの作成または取得
-fn get_or_create_share_dir() -> Result<String, String> {
+pub fn get_or_create_share_dir() -> Result<String, String> {
     let base_dir = std::env::current_exe()
         .map_err(|e| e.to_string())?
         .parent()
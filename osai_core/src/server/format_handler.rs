@@ -1,112 +1,306 @@
 //use tauri::{AppHandle, Emitter};
+use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
-use crate::ai::hebbian_local::ai;
-use crate::ai::state::{MY_VEC, W1, W2, SERVER_LIST, ServerInfo};
+use std::sync::{Arc, RwLock};
+use once_cell::sync::Lazy;
 
-use crate::fileIO::create_lyric::create_lyric;
-use crate::IOT::task::add_new_task;
+use crate::config::OsaiConfig;
+use crate::IOT::task::Task;
+use crate::p2p::node::NodeId;
+use crate::protocol::packet::{Format, OsaiPacket, PacketError, SessionId};
+use crate::protocol::task_alert::{TaskAlert, TaskAlertKind};
+use crate::protocol::task_reply::TaskReply;
+use crate::server::builtin_handlers::{
+    DiscoveryHandler, EmotionHandler, FileHandler, SignalHandler, TaskAckHandler, TaskAlertHandler,
+    TaskDeleteHandler, TaskDoneHandler, TaskEditHandler, TaskHandler, TaskListHandler, TextHandler,
+};
+use crate::security::context::{PeerAuth, SECURITY};
+use crate::server::file_receiver::ReceivedFile;
 
+/// ハンドラーに渡される受信元の情報
+#[derive(Debug, Clone)]
+pub struct PacketContext {
+    /// 送信元アドレス
+    pub addr: SocketAddr,
+    /// 受信したローカルのポート
+    pub port: String,
+    /// 送り主をどう確認できたか (平文なら PeerAuth::None)
+    pub auth: PeerAuth,
+    /// ノードの設定 (書き込み先のパスなど)
+    pub config: Arc<OsaiConfig>,
+}
 
-pub fn process_format(
-    format: [u8; 2],
-    session_id: [u8; 16],
-    _chunk: [u8; 8],
-    data_vec: [u8; 14],
-    data_payload: Vec<u8>,
-    addr: SocketAddr,
-    port: String,
-) -> String {
-    println!("format:{:x?}", format);
-    println!("data_vec:{:x?}", data_vec);
-
-    //ほんとはSIMDでやりたい
-    //こういう記法はむしろIOTで扱えるフォーマットを絞ってやる
-    let received_data_display = match format {
-        [0, 0] => {
-            String::from_utf8_lossy(&data_payload).to_string()
-        }
-        [0, 1] => {
+/// ハンドラーの処理結果
+#[derive(Debug, Clone)]
+pub enum FormatResponse {
+    Text(String),
+    Discovered { addr: SocketAddr, data: Vec<u8> },
+    AiCheck { trusted: bool },
+    TaskRegistered(Task),
+    TaskRejected(String),
+    /// ピアの通知に応答が無い (scheduler.caregiver に自分が指定されている)
+    TaskAlert { from: SocketAddr, alert: TaskAlert },
+    /// ピアからの ack・snooze を受けた
+    TaskResponded(Vec<Task>),
+    /// ピアからの変更・削除・完了 (`action` は "Edited" など)
+    TaskChanged { action: &'static str, task: Task },
+    /// ピアにタスクの一覧を返す
+    TaskListed(Vec<Task>),
+    /// ack・変更などをしなかった (当てはまるタスクが無いなど)
+    TaskOpRejected(String),
+    /// 生存シグナルを受けた。`addr` はピアの UDP サーバー、`joined` は初めて見たとき
+    PeerAnnounced { node_id: NodeId, name: String, addr: SocketAddr, joined: bool },
+    FileReceived(ReceivedFile),
+    /// 外部クレートのハンドラー用
+    Custom(String),
+    Unsupported(Format),
+    /// open モードでないのに認証なしで届いた
+    Unauthenticated(Format),
+}
 
-            println!("  Discovered Server IP: {}", addr);
-            hex::encode(&data_payload)
-        }
-        [0, 2] => {
-            println!("receive data");
-            let input_vec = data_vec;
-            let mut my_vec_guard = MY_VEC.lock().unwrap();
-            let mut w1_guard = W1.lock().unwrap();
-            let mut w2_guard = W2.lock().unwrap(); 
-
-            let (new_vec, is_trusted) = ai(*my_vec_guard, input_vec, &mut *w1_guard, &mut *w2_guard);
-            if is_trusted {
-                *my_vec_guard = new_vec;
-                //app_handle.emit("trusted_node", "学習データを更新しました").unwrap();
-            } else {
-                //app_handle.emit("enemy_detected", "敵シグナルを検知").unwrap();
+impl FormatResponse {
+    /// 送り主に返すパケット。session_id は受けたメッセージと同じにする
+    pub fn reply(&self, session_id: SessionId) -> Option<Result<OsaiPacket, PacketError>> {
+        match self {
+            FormatResponse::TaskRegistered(task) => {
+                Some(TaskReply::accepted(task.clone()).into_packet(session_id))
             }
-
-            //vocaloid logic
-            let text = String::from_utf8_lossy(&data_payload).to_string();
-            if input_vec[0] > 4 {
-                create_lyric(&text, input_vec);
-                println!("lyric created");
-            }else{
-                println!("lyric didnot created");
+            FormatResponse::TaskRejected(e) => Some(TaskReply::rejected(e.clone()).into_packet(session_id)),
+            FormatResponse::TaskResponded(tasks) => {
+                let reply = match tasks.first() {
+                    Some(task) => TaskReply::accepted(task.clone()),
+                    None => TaskReply::listed(Vec::new()),
+                };
+                Some(reply.into_packet(session_id))
             }
-            format!("AI check result: trusted={}", is_trusted)
+            FormatResponse::TaskChanged { task, .. } => Some(TaskReply::accepted(task.clone()).into_packet(session_id)),
+            FormatResponse::TaskListed(tasks) => Some(TaskReply::listed(tasks.clone()).into_packet(session_id)),
+            FormatResponse::TaskOpRejected(e) => Some(TaskReply::rejected(e.clone()).into_packet(session_id)),
+            _ => None,
         }
+    }
+}
 
-        [0, 3] => {
-            println!("receive data (Task Registration)");
-
-            let payload_str = String::from_utf8_lossy(&data_payload).to_string();
-            match add_new_task(&payload_str) {
-                Ok(task) => format!("Task Registered: {}", task.name),
-                Err(e) => {
-                    eprintln!("Task registration failed: {}", e);
-                    format!("Task Registration Error: {}", e)
-                }
+impl fmt::Display for FormatResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatResponse::Text(text) => write!(f, "{}", text),
+            FormatResponse::Discovered { data, .. } => write!(f, "{}", hex::encode(data)),
+            FormatResponse::AiCheck { trusted } => write!(f, "AI check result: trusted={}", trusted),
+            FormatResponse::TaskRegistered(task) => write!(f, "Task Registered: {}", task.name),
+            FormatResponse::TaskRejected(e) => write!(f, "Task Registration Error: {}", e),
+            FormatResponse::TaskAlert { from, alert } => {
+                let status = match alert.kind {
+                    TaskAlertKind::Unanswered => "not acknowledged yet",
+                    TaskAlertKind::Missed => "MISSED",
+                };
+                write!(
+                    f,
+                    "[CAREGIVER ALERT] {} ({}): '{}' at {} {} after {} repeats (remote_ack {} {})",
+                    alert.node, from, alert.task, alert.datetime, status, alert.repeats, alert.node, alert.task
+                )
+            }
+            FormatResponse::TaskResponded(tasks) => {
+                let names: Vec<&str> = tasks.iter().map(|t| t.name.as_str()).collect();
+                write!(f, "Reminder Acknowledged by peer: {}", names.join(", "))
+            }
+            FormatResponse::TaskChanged { action, task } => {
+                write!(f, "Task {} by peer: {} {}", action, task.short_id(), task.name)
+            }
+            FormatResponse::TaskListed(tasks) => write!(f, "Task List sent: {} tasks", tasks.len()),
+            FormatResponse::TaskOpRejected(e) => write!(f, "Task Operation Error: {}", e),
+            FormatResponse::PeerAnnounced { node_id, name, addr, joined: true } => {
+                write!(f, "Peer Joined: {} {} ({})", name, node_id, addr)
+            }
+            FormatResponse::PeerAnnounced { node_id, name, addr, joined: false } => {
+                write!(f, "Peer Alive: {} {} ({})", name, node_id, addr)
+            }
+            FormatResponse::FileReceived(file) => {
+                write!(f, "File Received: {} ({} bytes) -> {}", file.name, file.size, file.path)
+            }
+            FormatResponse::Custom(text) => write!(f, "{}", text),
+            FormatResponse::Unsupported(format) => write!(f, "unsupported format: {}", format),
+            FormatResponse::Unauthenticated(format) => {
+                write!(f, "rejected unauthenticated packet: {}", format)
             }
         }
-        
+    }
+}
 
+/// 2バイトのフォーマットごとの処理。外部クレートは実装して `register_handler` で登録する
+pub trait FormatHandler: Send + Sync {
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse;
+}
 
+impl<F> FormatHandler for F
+where
+    F: Fn(&OsaiPacket, &PacketContext) -> FormatResponse + Send + Sync,
+{
+    fn handle(&self, packet: &OsaiPacket, ctx: &PacketContext) -> FormatResponse {
+        self(packet, ctx)
+    }
+}
 
-        [0xFF, 0xFF] => {
-            println!("--- Signal ---");
-            println!("  IP: {} PORT: {}", addr, port);
-            println!("  Session ID: {:x?}", session_id);
-            println!("  Data Vec: {:x?}", data_vec);
+#[derive(Default)]
+pub struct FormatRegistry {
+    handlers: HashMap<Format, Arc<dyn FormatHandler>>,
+}
 
-            let ip_str = addr.ip().to_string();
-            let port_num = port.parse::<u16>().unwrap_or(0);
+impl FormatRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-            let server_info = ServerInfo {
-                addr: ip_str,
-                port: port_num,
-            };
+    /// text / discovery / emotion / task (登録・ack・変更など) / file / signal の組み込みハンドラーを登録済みのレジストリ
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register(Format::TEXT, TextHandler);
+        registry.register(Format::DISCOVERY, DiscoveryHandler);
+        registry.register(Format::EMOTION, EmotionHandler);
+        registry.register(Format::TASK, TaskHandler);
+        registry.register(Format::TASK_ALERT, TaskAlertHandler);
+        registry.register(Format::TASK_ACK, TaskAckHandler);
+        registry.register(Format::TASK_EDIT, TaskEditHandler);
+        registry.register(Format::TASK_DELETE, TaskDeleteHandler);
+        registry.register(Format::TASK_DONE, TaskDoneHandler);
+        registry.register(Format::TASK_LIST, TaskListHandler);
+        registry.register(Format::FILE, FileHandler);
+        registry.register(Format::SIGNAL, SignalHandler);
+        registry
+    }
 
-            let mut list = SERVER_LIST.lock().unwrap();
-            if !list.contains(&server_info) {
-                list.insert(server_info.clone());
-                println!("not duplicate server:{}", server_info.addr); 
-            }else{
-                println!("dup server:{}:{}", server_info.addr, port);
-            }
+    /// 登録済みのハンドラーがあれば置き換えて、古い方を返す
+    pub fn register<H: FormatHandler + 'static>(
+        &mut self,
+        format: Format,
+        handler: H,
+    ) -> Option<Arc<dyn FormatHandler>> {
+        self.handlers.insert(format, Arc::new(handler))
+    }
 
-            if let Ok(msg) = String::from_utf8(data_payload) {
-                format!("Server Discovered: {} Msg({})", addr, msg)
-            } else {
-                format!("Server Discovered: {}", addr)
-            }
-        }
+    pub fn unregister(&mut self, format: Format) -> Option<Arc<dyn FormatHandler>> {
+        self.handlers.remove(&format)
+    }
 
-        _ => {
-            format!("unsupported format: {:x?}", format)
-        }
+    pub fn get(&self, format: Format) -> Option<Arc<dyn FormatHandler>> {
+        self.handlers.get(&format).cloned()
+    }
+
+    pub fn formats(&self) -> Vec<Format> {
+        let mut formats: Vec<Format> = self.handlers.keys().copied().collect();
+        formats.sort();
+        formats
+    }
+}
+
+pub static FORMAT_REGISTRY: Lazy<RwLock<FormatRegistry>> = Lazy::new(|| {
+    RwLock::new(FormatRegistry::with_builtin())
+});
+
+/// 起動時に独自フォーマットのハンドラーを追加する
+pub fn register_handler<H: FormatHandler + 'static>(
+    format: Format,
+    handler: H,
+) -> Option<Arc<dyn FormatHandler>> {
+    FORMAT_REGISTRY.write().unwrap().register(format, handler)
+}
+
+pub fn process_format(
+    packet: &OsaiPacket,
+    addr: SocketAddr,
+    auth: PeerAuth,
+    config: &Arc<OsaiConfig>,
+) -> FormatResponse {
+    println!("format:{}", packet.format);
+    println!("data_vec:{:x?}", packet.data_vec.0);
+
+    if !SECURITY.read().unwrap().allows(packet.format, auth) {
+        let response = FormatResponse::Unauthenticated(packet.format);
+        eprintln!("{} from {}", response, addr);
+        return response;
+    }
+
+    // ハンドラー実行中にロックを持たないように Arc だけ取り出す
+    let handler = FORMAT_REGISTRY.read().unwrap().get(packet.format);
+    let ctx = PacketContext {
+        addr,
+        port: config.server.port.to_string(),
+        auth,
+        config: Arc::clone(config),
+    };
+    let response = match handler {
+        Some(handler) => handler.handle(packet, &ctx),
+        None => FormatResponse::Unsupported(packet.format),
     };
 
-    println!("data:{}", received_data_display);
-    received_data_display
+    println!("data:{}", response);
+    response
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::packet::{ChunkId, EmotionVec};
+
+    const CUSTOM: Format = Format([0x7F, 0x01]);
+
+    fn packet(format: Format) -> OsaiPacket {
+        OsaiPacket::new(SessionId::random(), ChunkId::SINGLE, format, EmotionVec::default(), b"hi".to_vec())
+    }
+
+    fn ctx() -> PacketContext {
+        PacketContext {
+            addr: SocketAddr::from(([127, 0, 0, 1], 9000)),
+            port: "8080".to_string(),
+            auth: PeerAuth::None,
+            config: Arc::new(OsaiConfig::default()),
+        }
+    }
+
+    fn reply(registry: &FormatRegistry, format: Format) -> Option<String> {
+        registry.get(format).map(|handler| handler.handle(&packet(format), &ctx()).to_string())
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let mut registry = FormatRegistry::new();
+        assert!(registry.formats().is_empty());
+        assert!(registry
+            .register(CUSTOM, |p: &OsaiPacket, _: &PacketContext| {
+                FormatResponse::Custom(format!("first {}", String::from_utf8_lossy(&p.payload)))
+            })
+            .is_none());
+        assert_eq!(registry.formats(), [CUSTOM]);
+        assert_eq!(reply(&registry, CUSTOM).as_deref(), Some("first hi"));
+
+        assert!(registry.unregister(CUSTOM).is_some());
+        assert!(registry.unregister(CUSTOM).is_none());
+        assert!(registry.get(CUSTOM).is_none());
+    }
+
+    #[test]
+    fn register_replaces_and_returns_the_old_handler() {
+        let mut registry = FormatRegistry::with_builtin();
+        assert_eq!(reply(&registry, Format::TEXT).as_deref(), Some("hi"));
+
+        let old = registry
+            .register(Format::TEXT, |_: &OsaiPacket, _: &PacketContext| FormatResponse::Custom("replaced".to_string()))
+            .unwrap();
+        assert_eq!(reply(&registry, Format::TEXT).as_deref(), Some("replaced"));
+        // 返ってきたのは前の組み込みハンドラー
+        assert_eq!(old.handle(&packet(Format::TEXT), &ctx()).to_string(), "hi");
+        assert_eq!(registry.formats().iter().filter(|f| **f == Format::TEXT).count(), 1);
+    }
+
+    #[test]
+    fn unknown_formats_have_no_handler() {
+        let registry = FormatRegistry::with_builtin();
+        assert!(registry.get(CUSTOM).is_none());
+        assert!(!registry.formats().contains(&CUSTOM));
+        // ACK・封・ハンドシェイクは受信ループが扱うのでハンドラーは無い
+        for format in [Format::ACK, Format::SEALED, Format::HANDSHAKE] {
+            assert!(registry.get(format).is_none(), "{}", format);
+        }
+    }
+}
@@ -1,7 +1,13 @@
 // src/server/mod.rs
 pub mod server;
 pub mod server_signal;
+pub mod discovery;
 pub mod file_server;
 pub mod format_handler;
+pub mod builtin_handlers;
+pub mod reassembly;
+pub mod file_receiver;
+pub mod rate_limit;
+pub mod stats;
 pub mod web;
 
@@ -0,0 +1,254 @@
+// 受信ループのトークンバケット。
+// 送信元IPごとにデータグラム数を、送信元IPとフォーマットごとに組み立て終わったメッセージ数を制限する。
+// [0,2] の学習のように重いハンドラーへ1台のピアが延々と送り込んでノード全体が止まるのを防ぐ。
+// フォーマットのバケットもピアごとなので、騒がしいピアが TASK などの枠を使い切っても他のピアは止まらない。
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::protocol::packet::Format;
+
+/// 送信元IPのバケットをこれ以上持たない (送信元を偽装した大量のパケット対策)
+const MAX_TRACKED_PEERS: usize = 4096;
+/// (送信元IP, フォーマット) のバケットをこれ以上持たない
+const MAX_TRACKED_MESSAGE_BUCKETS: usize = MAX_TRACKED_PEERS * 4;
+/// 満タンのままこれだけ経ったバケットは捨てる
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct BucketConfig {
+    /// 1秒あたりに補充するトークン数
+    pub rate: f64,
+    /// バケットの容量 (一度に許すバースト)
+    pub burst: f64,
+}
+
+impl BucketConfig {
+    pub const fn new(rate: f64, burst: f64) -> Self {
+        BucketConfig { rate, burst }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    config: BucketConfig,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(config: BucketConfig, now: Instant) -> Self {
+        TokenBucket { config, tokens: config.burst, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.config.rate).min(self.config.burst);
+        self.last = now;
+    }
+
+    /// トークンが1つあれば消費して true
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_idle(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        elapsed > IDLE_BUCKET_TTL
+            && self.tokens + elapsed.as_secs_f64() * self.config.rate >= self.config.burst
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// 送信元IPごとのデータグラム数
+    pub per_peer: BucketConfig,
+    /// 送信元IPごと・フォーマットごとのメッセージ数
+    pub per_format: HashMap<Format, BucketConfig>,
+    /// per_format に無いフォーマットのメッセージ数
+    pub default_format: BucketConfig,
+    /// 受信ループとハンドラーの間のキューの長さ。溢れたメッセージは捨てる
+    pub queue_capacity: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let mut per_format = HashMap::new();
+        // 学習が走るので重い
+        per_format.insert(Format::EMOTION, BucketConfig::new(5.0, 10.0));
+        per_format.insert(Format::TASK, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_ALERT, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_ACK, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_EDIT, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_DELETE, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_DONE, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::TASK_LIST, BucketConfig::new(2.0, 5.0));
+        per_format.insert(Format::FILE, BucketConfig::new(5.0, 10.0));
+        // 返信でシグナルを送るので、増幅に使われないように絞る
+        per_format.insert(Format::PROBE, BucketConfig::new(5.0, 20.0));
+        per_format.insert(Format::ECHO, BucketConfig::new(20.0, 40.0));
+        // 応答のたびに鍵交換の計算が走る
+        per_format.insert(Format::HANDSHAKE, BucketConfig::new(2.0, 5.0));
+        RateLimits {
+            // 1432 バイトのチャンクで 7MB/s 程度
+            per_peer: BucketConfig::new(5000.0, 10000.0),
+            per_format,
+            default_format: BucketConfig::new(50.0, 100.0),
+            queue_capacity: 64,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    peers: HashMap<IpAddr, TokenBucket>,
+    formats: HashMap<(IpAddr, Format), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter { limits, peers: HashMap::new(), formats: HashMap::new() }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// データグラムを1つ受け付けてよいか
+    pub fn allow_packet(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.peers.contains_key(&ip) && self.peers.len() >= MAX_TRACKED_PEERS {
+            self.prune(now);
+            if self.peers.len() >= MAX_TRACKED_PEERS {
+                return false;
+            }
+        }
+        let config = self.limits.per_peer;
+        self.peers
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(config, now))
+            .try_take(now)
+    }
+
+    /// `ip` から組み立て終わったメッセージをハンドラーに渡してよいか
+    pub fn allow_message(&mut self, ip: IpAddr, format: Format, now: Instant) -> bool {
+        let key = (ip, format);
+        if !self.formats.contains_key(&key) && self.formats.len() >= MAX_TRACKED_MESSAGE_BUCKETS {
+            self.prune(now);
+            if self.formats.len() >= MAX_TRACKED_MESSAGE_BUCKETS {
+                return false;
+            }
+        }
+        let config = self
+            .limits
+            .per_format
+            .get(&format)
+            .copied()
+            .unwrap_or(self.limits.default_format);
+        self.formats
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(config, now))
+            .try_take(now)
+    }
+
+    /// しばらく使われていない満タンのバケットを捨てる
+    pub fn prune(&mut self, now: Instant) {
+        self.peers.retain(|_, bucket| !bucket.is_idle(now));
+        self.formats.retain(|_, bucket| !bucket.is_idle(now));
+    }
+
+    pub fn tracked_peers(&self) -> usize {
+        self.peers.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(BucketConfig::new(2.0, 3.0), start);
+        assert!((0..3).all(|_| bucket.try_take(start)));
+        assert!(!bucket.try_take(start));
+
+        // 0.5秒で1つ
+        assert!(!bucket.try_take(start + Duration::from_millis(400)));
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+
+        // 長く空けても burst までしか溜まらない
+        let later = start + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.try_take(later)));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn idle_peers_are_pruned() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimits::default());
+        assert!(limiter.allow_packet(ip(1), start));
+        assert!(limiter.allow_message(ip(1), Format::TASK, start));
+
+        limiter.prune(start + IDLE_BUCKET_TTL);
+        assert_eq!(limiter.tracked_peers(), 1);
+        limiter.prune(start + IDLE_BUCKET_TTL + Duration::from_secs(1));
+        assert_eq!(limiter.tracked_peers(), 0);
+        assert!(limiter.formats.is_empty());
+    }
+
+    #[test]
+    fn tracked_peers_are_capped() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(RateLimits::default());
+        for n in 0..MAX_TRACKED_PEERS as u32 {
+            assert!(limiter.allow_packet(ip(n), start));
+        }
+        assert!(!limiter.allow_packet(ip(MAX_TRACKED_PEERS as u32), start));
+        // 知っているピアはそのまま
+        assert!(limiter.allow_packet(ip(0), start));
+        assert_eq!(limiter.tracked_peers(), MAX_TRACKED_PEERS);
+
+        // 古いバケットが捨てられれば新しいピアを受け付ける
+        let later = start + IDLE_BUCKET_TTL + Duration::from_secs(1);
+        assert!(limiter.allow_packet(ip(MAX_TRACKED_PEERS as u32), later));
+        assert_eq!(limiter.tracked_peers(), 1);
+    }
+
+    #[test]
+    fn formats_are_limited_per_peer() {
+        let now = Instant::now();
+        let limits = RateLimits::default();
+        let task_burst = limits.per_format[&Format::TASK].burst as usize;
+        let mut limiter = RateLimiter::new(limits);
+
+        assert!((0..task_burst).all(|_| limiter.allow_message(ip(1), Format::TASK, now)));
+        assert!(!limiter.allow_message(ip(1), Format::TASK, now));
+        // 同じピアの別のフォーマットと、別のピアの同じフォーマットは使い切られていない
+        assert!(limiter.allow_message(ip(1), Format::TEXT, now));
+        assert!(limiter.allow_message(ip(2), Format::TASK, now));
+    }
+
+    #[test]
+    fn handshakes_have_their_own_bucket() {
+        let now = Instant::now();
+        let limits = RateLimits::default();
+        let burst = limits.per_format[&Format::HANDSHAKE].burst as usize;
+        let mut limiter = RateLimiter::new(limits);
+
+        assert!((0..burst).all(|_| limiter.allow_message(ip(1), Format::HANDSHAKE, now)));
+        assert!(!limiter.allow_message(ip(1), Format::HANDSHAKE, now));
+        assert!(limiter.allow_message(ip(1), Format::HANDSHAKE, now + Duration::from_secs(1)));
+    }
+}
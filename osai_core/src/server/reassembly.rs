@@ -0,0 +1,882 @@
+// 分割して送られてきたメッセージを session_id ごとに組み立て直す
+//
+// client::send_text は CHUNK_SIZE ごとにチャンク番号をつけて送り、最後に END を送る。
+// ここでチャンクを番号順に並べ、抜けを検出し、END が揃ったら1つの OsaiPacket にして返す。
+// ファイルのように大きいフォーマットは `stream_format` で登録するとメモリに溜めずに
+// StreamSink へ直接書き込む。揃ったときの StreamSink::finish は重い (fsync やリネーム) ので
+// 受信ループでは呼ばず、Finishing で渡して結果を `finished` で受け取る。
+// 1つのセッションは最初のチャンクと同じ認証 (PeerAuth) のチャンクしか受け付けない。
+// 平文のチャンクを認証済みのセッションに混ぜられないようにするため。
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::protocol::ack::AckBody;
+use crate::protocol::packet::{ChunkId, EmotionVec, Format, OsaiPacket, SessionId};
+use crate::security::context::{PeerAuth, SessionKey};
+
+#[derive(Debug, Clone)]
+pub struct ReassemblyLimits {
+    /// 最後のチャンクからこれだけ経っても揃わないセッションは破棄する
+    pub session_timeout: Duration,
+    /// 送信元IPごとにバッファできる合計バイト数
+    pub max_bytes_per_peer: usize,
+    /// 全体でバッファできる合計バイト数
+    pub max_bytes_total: usize,
+    /// 送信元IPごとの同時セッション数
+    pub max_sessions_per_peer: usize,
+    /// 1セッションのチャンク数の上限
+    pub max_chunks_per_session: u32,
+    /// StreamSink に書き込むセッションのチャンク数の上限 (メモリを使わないので大きめ)
+    pub max_chunks_per_stream: u32,
+    /// 再送を見分けるために覚えておく処理済みセッションの数。超えたら古いものから忘れる
+    pub max_completed_sessions: usize,
+}
+
+/// NACK のログ・Incomplete に載せる抜けの番号の数 (小さい順)
+const MAX_REPORTED_MISSING: usize = 64;
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        ReassemblyLimits {
+            session_timeout: Duration::from_secs(10),
+            max_bytes_per_peer: 4 * 1024 * 1024,
+            max_bytes_total: 32 * 1024 * 1024,
+            max_sessions_per_peer: 32,
+            max_chunks_per_session: 4096,
+            max_chunks_per_stream: 1 << 20,
+            max_completed_sessions: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReassemblyError {
+    PeerLimit(IpAddr),
+    TotalLimit,
+    TooManySessions(IpAddr),
+    TooManyChunks(u32),
+    FormatMismatch { expected: Format, got: Format },
+    /// セッションの途中で認証が変わった
+    AuthMismatch { expected: PeerAuth, got: PeerAuth },
+    /// StreamSink の作成・書き込み・完了処理に失敗した
+    Sink(String),
+}
+
+impl fmt::Display for ReassemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReassemblyError::PeerLimit(ip) => write!(f, "per-peer buffer limit reached for {}", ip),
+            ReassemblyError::TotalLimit => write!(f, "total reassembly buffer limit reached"),
+            ReassemblyError::TooManySessions(ip) => write!(f, "too many open sessions from {}", ip),
+            ReassemblyError::TooManyChunks(n) => write!(f, "session has too many chunks: {}", n),
+            ReassemblyError::FormatMismatch { expected, got } => {
+                write!(f, "format changed inside session: expected {} got {}", expected, got)
+            }
+            ReassemblyError::AuthMismatch { expected, got } => {
+                write!(f, "authentication changed inside session: expected {} got {}", expected, got)
+            }
+            ReassemblyError::Sink(e) => write!(f, "stream sink error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReassemblyError {}
+
+/// チャンクをメモリに溜めずに受け取る先 (一時ファイルなど)
+pub trait StreamSink: Send {
+    fn write_chunk(&mut self, index: u32, data: &[u8]) -> Result<(), String>;
+    /// 全チャンクが揃ったときに呼ばれる。戻り値がハンドラーに渡る payload になる
+    fn finish(self: Box<Self>, total_chunks: u32) -> Result<Vec<u8>, String>;
+    /// タイムアウトや失敗で破棄するとき
+    fn abort(self: Box<Self>);
+}
+
+pub trait StreamSinkFactory: Send + Sync {
+    fn open(&self, addr: SocketAddr, session_id: SessionId) -> Result<Box<dyn StreamSink>, String>;
+}
+
+/// `ack` は送信側が ACK を要求している (信頼モード) ときだけ `Some` になる
+#[derive(Debug)]
+pub enum ReassemblyEvent {
+    /// メッセージが揃った。payload は全チャンクを連結したもの (StreamSink なら finish の戻り値)
+    Complete { message: OsaiPacket, auth: PeerAuth, ack: Option<AckBody> },
+    /// バッファした (または重複なので無視した)
+    Pending,
+    /// END は届いたがチャンクが抜けている。抜けを知らせる NACK を必ず返す。
+    /// `missing` は小さい番号から MAX_REPORTED_MISSING 個まで (全部は ack に入っている)
+    Incomplete { session_id: SessionId, missing: Vec<u32>, ack: AckBody },
+    /// 処理済みのセッションの再送。ACK が落ちたときに来る
+    Duplicate { ack: Option<AckBody> },
+    /// 制限に引っかかったので捨てた
+    Dropped(ReassemblyError),
+    /// セッションごと破棄した (StreamSink の失敗など)
+    Failed { session_id: SessionId, error: ReassemblyError, ack: Option<AckBody> },
+    /// StreamSink のセッションの全チャンクが揃った。StreamFinish::run を受信ループの外で呼び、
+    /// 結果を Reassembler::finished に渡すと Complete か Failed になる。それまでの再送には ACK を返さない
+    Finishing(StreamFinish),
+}
+
+/// 揃った StreamSink のセッション。run はブロックするので spawn_blocking などで呼ぶ
+pub struct StreamFinish {
+    pub addr: SocketAddr,
+    pub session_id: SessionId,
+    format: Format,
+    data_vec: EmotionVec,
+    auth: PeerAuth,
+    key: Option<SessionKey>,
+    reliable: bool,
+    total: u32,
+    sink: Box<dyn StreamSink>,
+}
+
+impl fmt::Debug for StreamFinish {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamFinish")
+            .field("addr", &self.addr)
+            .field("session_id", &self.session_id)
+            .field("format", &self.format)
+            .field("total", &self.total)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StreamFinish {
+    pub fn run(self) -> FinishedStream {
+        let result = self.sink.finish(self.total).map(|payload| {
+            OsaiPacket::new(self.session_id, ChunkId::END, self.format, self.data_vec, payload)
+        });
+        FinishedStream {
+            addr: self.addr,
+            session_id: self.session_id,
+            auth: self.auth,
+            key: self.key,
+            reliable: self.reliable,
+            total: self.total,
+            result,
+        }
+    }
+}
+
+/// StreamFinish::run の結果
+pub struct FinishedStream {
+    pub addr: SocketAddr,
+    pub session_id: SessionId,
+    auth: PeerAuth,
+    /// チャンクを開いた鍵。ACK もこれで封をして返す
+    pub key: Option<SessionKey>,
+    reliable: bool,
+    total: u32,
+    result: Result<OsaiPacket, String>,
+}
+
+/// タイムアウトで破棄されたセッション
+#[derive(Debug, Clone)]
+pub struct ExpiredSession {
+    pub addr: SocketAddr,
+    pub session_id: SessionId,
+    pub format: Format,
+    /// 抜けていたチャンクの数
+    pub missing: u32,
+    /// 送信側に返す Failed ACK (信頼モードのときだけ)
+    pub ack: Option<AckBody>,
+    /// チャンクを開いた鍵。封をしたセッションには ACK もこれで封をして返す (平文だと捨てられる)
+    pub key: Option<SessionKey>,
+}
+
+enum Storage {
+    Memory(BTreeMap<u32, Vec<u8>>),
+    Stream { received: ChunkSet, sink: Box<dyn StreamSink> },
+}
+
+/// StreamSink に書いたチャンクの番号。max_chunks_per_stream (100万) 個でも 128KiB のビット列で、
+/// 数と最大番号は入れるたびに数えておく
+#[derive(Default)]
+struct ChunkSet {
+    bits: Vec<u64>,
+    count: u32,
+    last: Option<u32>,
+}
+
+impl ChunkSet {
+    fn contains(&self, index: u32) -> bool {
+        self.bits.get(index as usize / 64).is_some_and(|word| word & (1 << (index % 64)) != 0)
+    }
+
+    fn insert(&mut self, index: u32) {
+        if self.contains(index) {
+            return;
+        }
+        let word = index as usize / 64;
+        if word >= self.bits.len() {
+            self.bits.resize(word + 1, 0);
+        }
+        self.bits[word] |= 1 << (index % 64);
+        self.count += 1;
+        self.last = self.last.max(Some(index));
+    }
+
+    /// 小さい順
+    fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bits.iter().enumerate().flat_map(|(word, &bits)| {
+            (0..64).filter(move |bit| bits & (1 << bit) != 0).map(move |bit| (word * 64) as u32 + bit)
+        })
+    }
+}
+
+struct PartialMessage {
+    format: Format,
+    auth: PeerAuth,
+    /// 最初のチャンクを開いた鍵 (平文なら None)
+    key: Option<SessionKey>,
+    data_vec: EmotionVec,
+    storage: Storage,
+    end_seen: bool,
+    /// END に総チャンク数が入っていればその値
+    total: Option<u32>,
+    /// メモリに溜めているバイト数
+    bytes: usize,
+    last_update: Instant,
+    /// 送信側が ACK を要求している
+    reliable: bool,
+}
+
+impl PartialMessage {
+    fn new(format: Format, key: Option<SessionKey>, data_vec: EmotionVec, storage: Storage, now: Instant) -> Self {
+        PartialMessage {
+            format,
+            auth: key.as_ref().map_or(PeerAuth::None, SessionKey::auth),
+            key,
+            data_vec,
+            storage,
+            end_seen: false,
+            total: None,
+            bytes: 0,
+            last_update: now,
+            reliable: false,
+        }
+    }
+
+    /// 届いた番号を小さい順に
+    fn received(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        match &self.storage {
+            Storage::Memory(chunks) => Box::new(chunks.keys().copied()),
+            Storage::Stream { received, .. } => Box::new(received.iter()),
+        }
+    }
+
+    fn last_received(&self) -> Option<u32> {
+        match &self.storage {
+            Storage::Memory(chunks) => chunks.keys().next_back().copied(),
+            Storage::Stream { received, .. } => received.last,
+        }
+    }
+
+    /// 届いたチャンクの数。どれも expected_chunks() 未満の番号なので、揃ったかはこれと比べるだけでよい
+    fn received_count(&self) -> u32 {
+        match &self.storage {
+            Storage::Memory(chunks) => chunks.len() as u32,
+            Storage::Stream { received, .. } => received.count,
+        }
+    }
+
+    fn has(&self, index: u32) -> bool {
+        match &self.storage {
+            Storage::Memory(chunks) => chunks.contains_key(&index),
+            Storage::Stream { received, .. } => received.contains(index),
+        }
+    }
+
+    /// 総チャンク数。END に入っていなければ届いた最大番号 + 1 とみなす
+    fn expected_chunks(&self) -> u32 {
+        self.total.unwrap_or_else(|| self.last_received().map_or(0, |last| last + 1))
+    }
+
+    fn missing_count(&self) -> u32 {
+        self.expected_chunks() - self.received_count()
+    }
+
+    /// 抜けている番号を小さい順に `limit` 個まで。届いた番号の隙間をたどる
+    fn missing(&self, limit: usize) -> Vec<u32> {
+        let mut missing = Vec::new();
+        let mut next = 0;
+        for index in self.received().chain(std::iter::once(self.expected_chunks())) {
+            missing.extend((next..index).take(limit - missing.len()));
+            if missing.len() >= limit {
+                break;
+            }
+            next = index + 1;
+        }
+        missing
+    }
+
+    fn is_complete(&self) -> bool {
+        self.end_seen && self.missing_count() == 0
+    }
+
+    fn partial_ack(&self) -> AckBody {
+        AckBody::partial(self.total, self.received())
+    }
+
+    fn abort(self) {
+        if let Storage::Stream { sink, .. } = self.storage {
+            sink.abort();
+        }
+    }
+}
+
+pub struct Reassembler {
+    limits: ReassemblyLimits,
+    sessions: HashMap<(SocketAddr, SessionId), PartialMessage>,
+    peer_bytes: HashMap<IpAddr, usize>,
+    peer_sessions: HashMap<IpAddr, usize>,
+    total_bytes: usize,
+    /// 処理済みセッション -> (完了時刻, 再送に返す ACK)。再送を二重処理しないため。
+    /// 信頼モードでないか、StreamSink の finish が終わっていなければ ACK は None
+    completed: HashMap<(SocketAddr, SessionId), (Instant, Option<AckBody>)>,
+    /// completed に入れた順 (= 完了時刻の順)。古いものから忘れるため
+    completed_order: VecDeque<(Instant, (SocketAddr, SessionId))>,
+    streams: HashMap<Format, Arc<dyn StreamSinkFactory>>,
+}
+
+impl Reassembler {
+    pub fn new(limits: ReassemblyLimits) -> Self {
+        Reassembler {
+            limits,
+            sessions: HashMap::new(),
+            peer_bytes: HashMap::new(),
+            peer_sessions: HashMap::new(),
+            total_bytes: 0,
+            completed: HashMap::new(),
+            completed_order: VecDeque::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// `format` のセッションはメモリに溜めずに `factory` が作る StreamSink に書き込む
+    pub fn stream_format<F: StreamSinkFactory + 'static>(&mut self, format: Format, factory: F) {
+        self.streams.insert(format, Arc::new(factory));
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    pub fn open_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// `session_key` は security::context::SecurityContext::open_incoming でパケットを開いた鍵 (平文なら None)
+    pub fn push(
+        &mut self,
+        addr: SocketAddr,
+        packet: OsaiPacket,
+        session_key: Option<&SessionKey>,
+        now: Instant,
+    ) -> ReassemblyEvent {
+        let auth = session_key.map_or(PeerAuth::None, SessionKey::auth);
+        // 1パケットで完結するものはそのまま渡す
+        if packet.chunk.is_single() {
+            return ReassemblyEvent::Complete { message: packet, auth, ack: None };
+        }
+
+        let key = (addr, packet.session_id);
+        if let Some((_, ack)) = self.completed.get(&key) {
+            return ReassemblyEvent::Duplicate { ack: ack.clone() };
+        }
+        if !self.sessions.contains_key(&key) {
+            let open = self.peer_sessions.get(&addr.ip()).copied().unwrap_or(0);
+            if open >= self.limits.max_sessions_per_peer {
+                return ReassemblyEvent::Dropped(ReassemblyError::TooManySessions(addr.ip()));
+            }
+            let storage = match self.streams.get(&packet.format) {
+                Some(factory) => match factory.open(addr, packet.session_id) {
+                    Ok(sink) => Storage::Stream { received: ChunkSet::default(), sink },
+                    Err(e) => return ReassemblyEvent::Dropped(ReassemblyError::Sink(e)),
+                },
+                None => Storage::Memory(BTreeMap::new()),
+            };
+            *self.peer_sessions.entry(addr.ip()).or_insert(0) += 1;
+            self.sessions.insert(
+                key,
+                PartialMessage::new(packet.format, session_key.cloned(), packet.data_vec, storage, now),
+            );
+        }
+
+        let message = self.sessions.get_mut(&key).unwrap();
+        if message.format != packet.format {
+            return ReassemblyEvent::Dropped(ReassemblyError::FormatMismatch {
+                expected: message.format,
+                got: packet.format,
+            });
+        }
+        if message.auth != auth {
+            return ReassemblyEvent::Dropped(ReassemblyError::AuthMismatch {
+                expected: message.auth,
+                got: auth,
+            });
+        }
+        message.last_update = now;
+        message.reliable |= packet.chunk.wants_ack();
+
+        let max_chunks = match message.storage {
+            Storage::Memory(_) => self.limits.max_chunks_per_session,
+            Storage::Stream { .. } => self.limits.max_chunks_per_stream,
+        };
+
+        let is_end = packet.chunk.is_end();
+        if is_end {
+            if let Some(total) = packet.total_chunks() {
+                // 総数より大きい番号がもう届いていたら数が合わない
+                let received_max = message.last_received().map_or(0, |last| last + 1);
+                if total > max_chunks || received_max > total {
+                    return self.fail(&key, ReassemblyError::TooManyChunks(total.max(received_max)));
+                }
+                message.total = Some(total);
+            }
+            message.end_seen = true;
+        } else {
+            // decode 済みなので END / SINGLE 以外は必ず番号がある
+            let index = packet.chunk.index().unwrap_or(u32::MAX);
+            let limit = message.total.unwrap_or(max_chunks);
+            if index >= limit {
+                return ReassemblyEvent::Dropped(ReassemblyError::TooManyChunks(index.saturating_add(1)));
+            }
+            if message.has(index) {
+                return ReassemblyEvent::Pending;
+            }
+
+            match &mut message.storage {
+                Storage::Memory(chunks) => {
+                    let len = packet.payload.len();
+                    let peer_bytes = self.peer_bytes.get(&addr.ip()).copied().unwrap_or(0);
+                    if peer_bytes + len > self.limits.max_bytes_per_peer {
+                        return ReassemblyEvent::Dropped(ReassemblyError::PeerLimit(addr.ip()));
+                    }
+                    if self.total_bytes + len > self.limits.max_bytes_total {
+                        return ReassemblyEvent::Dropped(ReassemblyError::TotalLimit);
+                    }
+
+                    chunks.insert(index, packet.payload);
+                    message.bytes += len;
+                    *self.peer_bytes.entry(addr.ip()).or_insert(0) += len;
+                    self.total_bytes += len;
+                }
+                Storage::Stream { received, sink } => {
+                    if let Err(e) = sink.write_chunk(index, &packet.payload) {
+                        return self.fail(&key, ReassemblyError::Sink(e));
+                    }
+                    received.insert(index);
+                }
+            }
+        }
+
+        if message.is_complete() {
+            let message = self.remove(&key).unwrap();
+            let total = message.expected_chunks();
+            let reliable = message.reliable;
+            let PartialMessage { format, auth, key: session_key, data_vec, storage, .. } = message;
+            match storage {
+                Storage::Memory(chunks) => {
+                    let ack = reliable.then(|| AckBody::complete(total));
+                    self.remember_completed(key, ack.clone(), now);
+                    let payload: Vec<u8> = chunks.into_values().flatten().collect();
+                    return ReassemblyEvent::Complete {
+                        message: OsaiPacket::new(packet.session_id, ChunkId::END, format, data_vec, payload),
+                        auth,
+                        ack,
+                    };
+                }
+                Storage::Stream { sink, .. } => {
+                    self.remember_completed(key, None, now);
+                    return ReassemblyEvent::Finishing(StreamFinish {
+                        addr,
+                        session_id: packet.session_id,
+                        format,
+                        data_vec,
+                        auth,
+                        key: session_key,
+                        reliable,
+                        total,
+                        sink,
+                    });
+                }
+            }
+        }
+
+        // NACK は END が来たときだけ返す。再送チャンクのたびに返すと再送が膨らむ
+        if is_end {
+            ReassemblyEvent::Incomplete {
+                session_id: packet.session_id,
+                missing: message.missing(MAX_REPORTED_MISSING),
+                ack: message.partial_ack(),
+            }
+        } else {
+            ReassemblyEvent::Pending
+        }
+    }
+
+    /// タイムアウトしたセッションを破棄して返す
+    pub fn expire(&mut self, now: Instant) -> Vec<ExpiredSession> {
+        let timeout = self.limits.session_timeout;
+        // 処理済みの記録は再送が収まるまで少し長めに持つ
+        while let Some(&(done, _)) = self.completed_order.front() {
+            if now.duration_since(done) <= timeout * 3 {
+                break;
+            }
+            self.forget_oldest_completed();
+        }
+
+        let expired_keys: Vec<(SocketAddr, SessionId)> = self
+            .sessions
+            .iter()
+            .filter(|(_, m)| now.duration_since(m.last_update) > timeout)
+            .map(|(k, _)| *k)
+            .collect();
+
+        expired_keys
+            .into_iter()
+            .filter_map(|key| {
+                let message = self.remove(&key)?;
+                let expired = ExpiredSession {
+                    addr: key.0,
+                    session_id: key.1,
+                    format: message.format,
+                    missing: message.missing_count(),
+                    ack: message.reliable.then(|| AckBody::failed(message.total)),
+                    key: message.key.clone(),
+                };
+                message.abort();
+                Some(expired)
+            })
+            .collect()
+    }
+
+    /// Finishing で渡したセッションの結果。以降の再送にも同じ ACK を返す
+    pub fn finished(&mut self, finished: FinishedStream, now: Instant) -> ReassemblyEvent {
+        let FinishedStream { addr, session_id, auth, reliable, total, result, .. } = finished;
+        let (event, ack) = match result {
+            Ok(message) => {
+                let ack = reliable.then(|| AckBody::complete(total));
+                (ReassemblyEvent::Complete { message, auth, ack: ack.clone() }, ack)
+            }
+            Err(e) => {
+                let ack = reliable.then(|| AckBody::failed(Some(total)));
+                (ReassemblyEvent::Failed { session_id, error: ReassemblyError::Sink(e), ack: ack.clone() }, ack)
+            }
+        };
+        match self.completed.get_mut(&(addr, session_id)) {
+            Some((_, repeat)) => *repeat = ack,
+            None => self.remember_completed((addr, session_id), ack, now),
+        }
+        event
+    }
+
+    /// 停止時に途中のセッションをすべて捨てる (書きかけのファイルも消す)。捨てた数を返す
+    pub fn abort_all(&mut self) -> usize {
+        let keys: Vec<(SocketAddr, SessionId)> = self.sessions.keys().copied().collect();
+        for key in &keys {
+            if let Some(message) = self.remove(key) {
+                message.abort();
+            }
+        }
+        keys.len()
+    }
+
+    /// 処理済みとして覚える。max_completed_sessions を超えたら古いものから忘れる
+    fn remember_completed(&mut self, key: (SocketAddr, SessionId), ack: Option<AckBody>, now: Instant) {
+        while self.completed.len() >= self.limits.max_completed_sessions.max(1) {
+            self.forget_oldest_completed();
+        }
+        self.completed.insert(key, (now, ack));
+        self.completed_order.push_back((now, key));
+    }
+
+    fn forget_oldest_completed(&mut self) {
+        if let Some((_, key)) = self.completed_order.pop_front() {
+            self.completed.remove(&key);
+        }
+    }
+
+    /// セッションを破棄して Failed を返す
+    fn fail(&mut self, key: &(SocketAddr, SessionId), error: ReassemblyError) -> ReassemblyEvent {
+        let ack = match self.remove(key) {
+            Some(message) => {
+                let ack = message.reliable.then(|| AckBody::failed(message.total));
+                message.abort();
+                ack
+            }
+            None => None,
+        };
+        ReassemblyEvent::Failed { session_id: key.1, error, ack }
+    }
+
+    fn remove(&mut self, key: &(SocketAddr, SessionId)) -> Option<PartialMessage> {
+        let message = self.sessions.remove(key)?;
+        let ip = key.0.ip();
+
+        if let Some(bytes) = self.peer_bytes.get_mut(&ip) {
+            *bytes -= message.bytes;
+            if *bytes == 0 {
+                self.peer_bytes.remove(&ip);
+            }
+        }
+        if let Some(count) = self.peer_sessions.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.peer_sessions.remove(&ip);
+            }
+        }
+        self.total_bytes -= message.bytes;
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ack::AckStatus;
+
+    fn addr() -> SocketAddr {
+        "192.0.2.1:9000".parse().unwrap()
+    }
+
+    fn chunk(session_id: SessionId, index: u32) -> OsaiPacket {
+        OsaiPacket::new(session_id, ChunkId::reliable(index), Format::TEXT, EmotionVec::default(), vec![index as u8; 3])
+    }
+
+    fn end(session_id: SessionId, total: u32) -> OsaiPacket {
+        OsaiPacket::end_of(session_id, Format::TEXT, EmotionVec::default(), total)
+    }
+
+    /// 書いた (番号, データ)
+    type Written = std::sync::Mutex<Vec<(u32, Vec<u8>)>>;
+
+    /// 書いたチャンクを番号ごとに覚えておき、finish で連結して返す
+    #[derive(Clone, Default)]
+    struct VecSink(Arc<Written>);
+
+    impl StreamSink for VecSink {
+        fn write_chunk(&mut self, index: u32, data: &[u8]) -> Result<(), String> {
+            self.0.lock().unwrap().push((index, data.to_vec()));
+            Ok(())
+        }
+
+        fn finish(self: Box<Self>, total_chunks: u32) -> Result<Vec<u8>, String> {
+            let mut chunks = self.0.lock().unwrap().clone();
+            assert_eq!(chunks.len(), total_chunks as usize, "each chunk is written once");
+            chunks.sort();
+            Ok(chunks.into_iter().flat_map(|(_, data)| data).collect())
+        }
+
+        fn abort(self: Box<Self>) {}
+    }
+
+    impl StreamSinkFactory for VecSink {
+        fn open(&self, _: SocketAddr, _: SessionId) -> Result<Box<dyn StreamSink>, String> {
+            Ok(Box::new(self.clone()))
+        }
+    }
+
+    /// 0..100 を逆順・重複ありで送り、END は途中に挟む
+    fn out_of_order(reassembler: &mut Reassembler, format: Format) {
+        let now = Instant::now();
+        let id = SessionId::random();
+        let packet = |index: u32| {
+            OsaiPacket::new(id, ChunkId::reliable(index), format, EmotionVec::default(), vec![index as u8; 3])
+        };
+        let end = OsaiPacket::end_of(id, format, EmotionVec::default(), 100);
+        for index in (50..100).rev() {
+            assert!(matches!(reassembler.push(addr(), packet(index), None, now), ReassemblyEvent::Pending));
+        }
+        let ReassemblyEvent::Incomplete { missing, ack, .. } = reassembler.push(addr(), end.clone(), None, now)
+        else {
+            panic!("expected Incomplete");
+        };
+        assert_eq!(missing, (0..50).collect::<Vec<u32>>());
+        assert_eq!(ack.missing(100), missing);
+        for index in [75, 99, 50] {
+            assert!(matches!(reassembler.push(addr(), packet(index), None, now), ReassemblyEvent::Pending));
+        }
+        for index in (1..50).rev().step_by(2).chain((2..50).step_by(2)) {
+            assert!(matches!(reassembler.push(addr(), packet(index), None, now), ReassemblyEvent::Pending));
+        }
+        // END はもう届いているので、最後の抜けが届いたところで揃う
+        let event = match reassembler.push(addr(), packet(0), None, now) {
+            ReassemblyEvent::Finishing(finish) => {
+                // finish の結果が出るまでの再送には ACK を返さない
+                assert!(matches!(reassembler.push(addr(), packet(3), None, now), ReassemblyEvent::Duplicate { ack: None }));
+                reassembler.finished(finish.run(), now)
+            }
+            event => event,
+        };
+        let ReassemblyEvent::Complete { message, ack, .. } = event else {
+            panic!("expected Complete");
+        };
+        let expected: Vec<u8> = (0..100u8).flat_map(|i| [i; 3]).collect();
+        assert_eq!(message.payload, expected);
+        assert_eq!(message.format, format);
+        assert_eq!(ack, Some(AckBody::complete(100)));
+        assert_eq!(reassembler.buffered_bytes(), 0);
+        assert_eq!(reassembler.open_sessions(), 0);
+
+        // ACK が落ちて再送されたチャンクと END
+        assert!(matches!(reassembler.push(addr(), packet(3), None, now), ReassemblyEvent::Duplicate { ack: Some(_) }));
+        assert!(matches!(reassembler.push(addr(), end, None, now), ReassemblyEvent::Duplicate { ack: Some(_) }));
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_chunks_in_memory() {
+        out_of_order(&mut Reassembler::new(ReassemblyLimits::default()), Format::TEXT);
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_chunks_to_stream_sink() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        reassembler.stream_format(Format::FILE, VecSink::default());
+        out_of_order(&mut reassembler, Format::FILE);
+    }
+
+    /// finish で失敗する (ハッシュが合わないファイルなど)
+    struct RejectingSink;
+
+    impl StreamSink for RejectingSink {
+        fn write_chunk(&mut self, _: u32, _: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn finish(self: Box<Self>, _: u32) -> Result<Vec<u8>, String> {
+            Err("sha256 mismatch".to_string())
+        }
+
+        fn abort(self: Box<Self>) {}
+    }
+
+    impl StreamSinkFactory for RejectingSink {
+        fn open(&self, _: SocketAddr, _: SessionId) -> Result<Box<dyn StreamSink>, String> {
+            Ok(Box::new(RejectingSink))
+        }
+    }
+
+    #[test]
+    fn failed_finish_is_repeated_to_resends() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        reassembler.stream_format(Format::FILE, RejectingSink);
+        let id = SessionId::random();
+        let chunk = OsaiPacket::new(id, ChunkId::reliable(0), Format::FILE, EmotionVec::default(), vec![1]);
+        let end = OsaiPacket::end_of(id, Format::FILE, EmotionVec::default(), 1);
+
+        assert!(matches!(reassembler.push(addr(), chunk, None, now), ReassemblyEvent::Pending));
+        let ReassemblyEvent::Finishing(finish) = reassembler.push(addr(), end.clone(), None, now) else {
+            panic!("expected Finishing");
+        };
+        assert_eq!(reassembler.open_sessions(), 0);
+        let event = reassembler.finished(finish.run(), now);
+        assert!(
+            matches!(&event, ReassemblyEvent::Failed { ack: Some(ack), .. } if ack.status == AckStatus::Failed),
+            "{:?}",
+            event
+        );
+        let ReassemblyEvent::Duplicate { ack: Some(ack) } = reassembler.push(addr(), end, None, now) else {
+            panic!("expected Duplicate");
+        };
+        assert_eq!(ack.status, AckStatus::Failed);
+    }
+
+    #[test]
+    fn chunk_set() {
+        let mut set = ChunkSet::default();
+        for index in [130, 0, 63, 64, 130, 1 << 20] {
+            set.insert(index);
+        }
+        assert_eq!(set.count, 5);
+        assert_eq!(set.last, Some(1 << 20));
+        assert!(set.contains(63) && set.contains(64) && !set.contains(65) && !set.contains(u32::MAX));
+        assert_eq!(set.iter().collect::<Vec<u32>>(), vec![0, 63, 64, 130, 1 << 20]);
+    }
+
+    #[test]
+    fn expired_sealed_session_returns_its_key() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let now = Instant::now();
+        let credential = crate::security::context::Credential::psk(b"secret");
+        let (sealed_id, plain_id) = (SessionId::random(), SessionId::random());
+        let key = credential.session_key(sealed_id);
+        reassembler.push(addr(), chunk(sealed_id, 1), Some(&key), now);
+        reassembler.push(addr(), chunk(plain_id, 1), None, now);
+        // 途中で平文に変わったチャンクは混ぜない
+        let event = reassembler.push(addr(), chunk(sealed_id, 0), None, now);
+        assert!(matches!(event, ReassemblyEvent::Dropped(ReassemblyError::AuthMismatch { .. })), "{:?}", event);
+
+        let mut expired = reassembler.expire(now + reassembler.limits.session_timeout * 2);
+        expired.sort_by_key(|e| e.session_id != sealed_id);
+        let [sealed, plain] = expired.as_slice() else { panic!("{:?}", expired) };
+        assert!(plain.key.is_none());
+        let ack = sealed.ack.clone().unwrap().into_packet(sealed_id);
+        // 送信側は同じセッションの鍵で開ける
+        let sealed_ack = sealed.key.as_ref().unwrap().seal(&ack).unwrap();
+        assert_eq!(sealed_ack.format, Format::SEALED);
+        assert_eq!(key.open(&sealed_ack).unwrap(), ack);
+    }
+
+    #[test]
+    fn completed_sessions_are_bounded() {
+        let limits = ReassemblyLimits { max_completed_sessions: 3, ..ReassemblyLimits::default() };
+        let mut reassembler = Reassembler::new(limits);
+        let now = Instant::now();
+        let sessions: Vec<SessionId> = (0..5).map(|_| SessionId::random()).collect();
+        for &id in &sessions {
+            reassembler.push(addr(), chunk(id, 0), None, now);
+            let event = reassembler.push(addr(), end(id, 1), None, now);
+            assert!(matches!(event, ReassemblyEvent::Complete { .. }), "{:?}", event);
+        }
+        assert_eq!(reassembler.completed.len(), 3);
+        assert_eq!(reassembler.completed_order.len(), 3);
+        // 新しいものは再送として扱い、忘れた古いものはもう一度組み立てる
+        let event = reassembler.push(addr(), end(sessions[4], 1), None, now);
+        assert!(matches!(event, ReassemblyEvent::Duplicate { ack: Some(_) }), "{:?}", event);
+        let event = reassembler.push(addr(), end(sessions[0], 1), None, now);
+        assert!(matches!(event, ReassemblyEvent::Incomplete { .. }), "{:?}", event);
+
+        let later = now + reassembler.limits.session_timeout * 4;
+        reassembler.expire(later);
+        assert!(reassembler.completed.is_empty());
+        assert!(reassembler.completed_order.is_empty());
+    }
+
+    #[test]
+    fn missing_chunks_are_reported_in_order_and_capped() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let now = Instant::now();
+        let id = SessionId::random();
+        for index in [1, 2, 5] {
+            reassembler.push(addr(), chunk(id, index), None, now);
+        }
+        let ReassemblyEvent::Incomplete { missing, ack, .. } = reassembler.push(addr(), end(id, 1000), None, now)
+        else {
+            panic!("expected Incomplete");
+        };
+        assert_eq!(missing.len(), MAX_REPORTED_MISSING);
+        assert_eq!(&missing[..5], &[0, 3, 4, 6, 7]);
+        assert_eq!(ack.missing(1000).len(), 997);
+
+        let expired = reassembler.expire(now + reassembler.limits.session_timeout * 2);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].missing, 997);
+    }
+
+    #[test]
+    fn end_total_below_received_chunks_fails() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let now = Instant::now();
+        let id = SessionId::random();
+        reassembler.push(addr(), chunk(id, 0), None, now);
+        reassembler.push(addr(), chunk(id, 4), None, now);
+        let event = reassembler.push(addr(), end(id, 2), None, now);
+        assert!(matches!(event, ReassemblyEvent::Failed { error: ReassemblyError::TooManyChunks(5), .. }), "{:?}", event);
+        assert_eq!(reassembler.open_sessions(), 0);
+    }
+}
@@ -1,85 +1,465 @@
 use tokio::net::UdpSocket;
 use tokio::task;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use crate::server::server_signal;
+use std::time::Instant;
+use crate::config::OsaiConfig;
+use crate::p2p::node::NodeId;
+use crate::p2p::registry::PEER_REGISTRY;
+use crate::runtime::RunningServices;
+use crate::server::discovery;
 use crate::server::format_handler::process_format;
+use crate::protocol::ack::AckBody;
+use crate::protocol::echo::{EchoBody, EchoKind};
+use crate::protocol::packet::{Format, OsaiPacket, SessionId, MAX_PACKET_SIZE};
+use crate::server::file_receiver::{FileSinkFactory, RefuseFiles};
+use crate::server::reassembly::{FinishedStream, Reassembler, ReassemblyEvent, ReassemblyLimits};
+use crate::security::context::{encode_packet, PeerAuth, SessionKey, SECURITY};
+use crate::security::handshake;
+use crate::server::rate_limit::{RateLimiter, RateLimits};
+use crate::server::stats::{ServerStats, SERVER_STATS};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::sync::CancellationToken;
 
-fn parse_packet(payload: &[u8]) -> Option<([u8; 16], [u8; 8], [u8; 2], [u8; 14], Vec<u8>)> {
-    if payload.len() < 40 {
-        return None;
+/// `key` があればチャンクと同じ鍵で封をして返す
+async fn send_ack(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    session_id: SessionId,
+    ack: AckBody,
+    key: Option<&SessionKey>,
+) {
+    match encode_packet(&ack.into_packet(session_id), key) {
+        Ok(bytes) => {
+            if let Err(e) = socket.send_to(&bytes, addr).await {
+                eprintln!("Failed to send ack to {}: {}", addr, e);
+            }
+        }
+        Err(e) => eprintln!("Failed to encode ack: {}", e),
     }
-    let session_id = payload[0..16].try_into().ok()?;
-    let chunk = payload[16..24].try_into().ok()?;
-    let format = payload[24..26].try_into().ok()?;
-    let data_vec = payload[26..40].try_into().ok()?;
-    let data = payload[40..].to_vec();
-
-    Some((session_id, chunk, format, data_vec, data))
 }
 
-async fn server_launch_signal(port: &str) -> Result<(), String> {
-    let mut send_buf = [0u8; 1024];
-    let local_port = port.parse::<u16>().map_err(|e| format!("Invalid port: {}", e))?;
-
-    let packet_len = server_signal::build_server_announce_packet(
-        &mut send_buf,
-        local_port,
-        local_port,
-    );
+/// ping の request に同じ session_id と seq で答える。request が封をされていれば同じ鍵で封をする
+async fn answer_echo(
+    socket: &UdpSocket,
+    packet: &OsaiPacket,
+    addr: SocketAddr,
+    key: Option<&SessionKey>,
+) -> Result<(), String> {
+    let echo = EchoBody::decode(&packet.payload).map_err(|e| format!("Invalid ping from {}: {}", addr, e))?;
+    if echo.kind != EchoKind::Request {
+        return Ok(());
+    }
+    let reply = encode_packet(&echo.reply().into_packet(packet.session_id), key)?;
+    socket
+        .send_to(&reply, addr)
+        .await
+        .map_err(|e| format!("Failed to answer ping from {}: {}", addr, e))?;
+    Ok(())
+}
 
-    let send_socket = UdpSocket::bind("0.0.0.0:0").await
-        .map_err(|e| format!("Failed to bind signal socket: {}", e))?;
+/// 受信ループからハンドラーに渡すメッセージ
+struct Job {
+    message: OsaiPacket,
+    addr: SocketAddr,
+    auth: PeerAuth,
+    /// 封をされて届いたときの鍵。返事にも同じ鍵で封をする
+    key: Option<SessionKey>,
+}
 
-    let broadcast_addr = format!("255.255.255.255:{}", local_port);
-    send_socket.set_broadcast(true)
-        .map_err(|e| format!("Failed to enable broadcast: {}", e))?;
+/// 揃ったメッセージをハンドラーのキューに入れる。ハンドラーのワーカーが止まっていれば false
+fn submit(
+    message: OsaiPacket,
+    addr: SocketAddr,
+    auth: PeerAuth,
+    key: Option<SessionKey>,
+    rate_limiter: &mut RateLimiter,
+    jobs: &mpsc::Sender<Job>,
+) -> bool {
+    if auth.is_authenticated() && !SECURITY.write().unwrap().check_replay(message.session_id, Instant::now()) {
+        eprintln!("Replayed session {} from {} ignored", message.session_id, addr);
+        return true;
+    }
+    if !rate_limiter.allow_message(addr.ip(), message.format, Instant::now()) {
+        SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_format, addr.ip());
+        eprintln!("Rate limited {} message from {}", message.format, addr);
+        return true;
+    }
+    match jobs.try_send(Job { message, addr, auth, key }) {
+        Ok(()) => true,
+        Err(TrySendError::Full(job)) => {
+            SERVER_STATS.record_drop(&SERVER_STATS.queue_full, addr.ip());
+            eprintln!("Handler queue full, dropped {} message from {}", job.message.format, addr);
+            true
+        }
+        Err(TrySendError::Closed(_)) => {
+            eprintln!("Handler worker stopped");
+            false
+        }
+    }
+}
 
-    send_socket.send_to(&send_buf[..packet_len], &broadcast_addr).await
-        .map_err(|e| format!("Failed to send announce: {}", e))?;
+/// キューからメッセージを取り出して1つずつハンドラーを実行する。
+/// ハンドラーは同期処理でロックも取るので、受信ループを止めないよう blocking スレッドで動かす。
+/// 返事があるもの (タスク登録など) は送り主に返す。
+/// 送信側 (受信ループ) が閉じたら残りを処理してから終わる
+fn spawn_handler_worker(
+    config: Arc<OsaiConfig>,
+    socket: Arc<UdpSocket>,
+    mut jobs: mpsc::Receiver<Job>,
+) -> task::JoinHandle<()> {
+    task::spawn(async move {
+        while let Some(Job { message, addr, auth, key }) = jobs.recv().await {
+            let config = Arc::clone(&config);
+            let session_id = message.session_id;
+            let result = task::spawn_blocking(move || {
+                process_format(&message, addr, auth, &config)
+            })
+            .await;
+            match result {
+                Ok(response) => {
+                    ServerStats::incr(&SERVER_STATS.handled);
+                    let reply = response
+                        .reply(session_id)
+                        .map(|reply| reply.map_err(|e| e.to_string()).and_then(|p| encode_packet(&p, key.as_ref())));
+                    match reply {
+                        Some(Ok(bytes)) => {
+                            if let Err(e) = socket.send_to(&bytes, addr).await {
+                                eprintln!("Failed to send reply to {}: {}", addr, e);
+                            }
+                        }
+                        Some(Err(e)) => eprintln!("Failed to encode reply: {}", e),
+                        None => {}
+                    }
+                }
+                Err(e) => {
+                    ServerStats::incr(&SERVER_STATS.handler_panics);
+                    eprintln!("Handler failed: {}", e);
+                }
+            }
+        }
+    })
+}
 
-    println!("Signal sent to {}", broadcast_addr);
-    Ok(())
+/// IPv6 マルチキャストで届くシグナルとプローブだけを受ける。それ以外のフォーマットは IPv4 のソケットに送ってもらう
+async fn run_v6_listener(
+    socket: UdpSocket,
+    config: Arc<OsaiConfig>,
+    node_id: NodeId,
+    running: RunningServices,
+    jobs: mpsc::Sender<Job>,
+    shutdown: CancellationToken,
+) {
+    let mut rate_limiter = RateLimiter::new(RateLimits::default());
+    let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+    loop {
+        let (len, addr) = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = socket.recv_from(&mut buf) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("IPv6 discovery recv error: {}", e);
+                    break;
+                }
+            },
+        };
+        ServerStats::incr(&SERVER_STATS.received);
+        let now = Instant::now();
+        if !rate_limiter.allow_packet(addr.ip(), now) {
+            SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_peer, addr.ip());
+            continue;
+        }
+        let packet = match OsaiPacket::decode(&buf[..len]) {
+            Ok(packet) if packet.chunk.is_single() => packet,
+            Ok(_) => continue,
+            Err(e) => {
+                SERVER_STATS.record_drop(&SERVER_STATS.parse_errors, addr.ip());
+                eprintln!("Parse error from {}: {}", addr, e);
+                continue;
+            }
+        };
+        if !rate_limiter.allow_message(addr.ip(), packet.format, now) {
+            SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_format, addr.ip());
+            continue;
+        }
+        match packet.format {
+            Format::PROBE => {
+                if let Err(e) = discovery::answer_probe(&socket, &packet, addr, &config, node_id, &running).await {
+                    eprintln!("{}", e);
+                }
+            }
+            Format::SIGNAL => {
+                let job = Job { message: packet, addr, auth: PeerAuth::None, key: None };
+                if jobs.try_send(job).is_err() {
+                    SERVER_STATS.record_drop(&SERVER_STATS.queue_full, addr.ip());
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
-pub async fn start_server(port: String) -> Result<String, String> {
-    let address = format!("0.0.0.0:{}", port);
+/// `shutdown` がキャンセルされるまで受信する。`running` は生存シグナルに載せるサービスの一覧。
+/// 止めるときは書きかけのファイルを消し、キューに残ったメッセージを処理し終えてからソケットを閉じる
+pub async fn start_server(
+    config: Arc<OsaiConfig>,
+    running: RunningServices,
+    shutdown: CancellationToken,
+) -> Result<String, String> {
+    let address = config.server.bind_addr();
     println!("Binding UDP server to: {}", address);
 
     let socket = Arc::new(
-    tokio::net::UdpSocket::bind(&address)
+    tokio::net::UdpSocket::bind(address)
         .await
         .map_err(|e| e.to_string())?
     );
     println!("UDP Server bound to: {}", address);
 
-    let port_for_signal = port.clone();
-    task::spawn(async move {
+    let node_id = config.node_id()?;
+    // ハンドラーが自分のシグナルを見分けられるように、ファイルから読んだIDも設定に入れておく
+    let config = if config.node.id.is_none() {
+        let mut resolved = (*config).clone();
+        resolved.node.id = Some(node_id);
+        Arc::new(resolved)
+    } else {
+        config
+    };
+    PEER_REGISTRY.write().unwrap().set_ttl(config.discovery.peer_ttl());
+    println!("Node ID: {}", node_id);
+    discovery::join_multicast_v4(&socket, &config);
+    let v6_socket = match discovery::bind_v6_listener(&config) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("IPv6 discovery disabled: {}", e);
+            None
+        }
+    };
+
+    let config_for_signal = Arc::clone(&config);
+    // 受信エラーで抜けたときにも止められるように子トークンにしておく
+    let signal_stop = shutdown.child_token();
+    let shutdown_for_signal = signal_stop.clone();
+    let running_for_signal = running.clone();
+    let signal_task = task::spawn(async move {
+        let running = running_for_signal;
+        if config_for_signal.discovery.probe_on_start {
+            if let Err(e) = discovery::probe(&config_for_signal, node_id, &running).await {
+                eprintln!("Probe error: {}", e);
+            }
+        }
         loop {
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+            tokio::select! {
+                _ = tokio::time::sleep(config_for_signal.server.announce_interval()) => {}
+                _ = shutdown_for_signal.cancelled() => break,
+            }
 
-            if let Err(e) = server_launch_signal(&port_for_signal).await {
+            if let Err(e) = discovery::announce(&config_for_signal, node_id, &running).await {
                 eprintln!("Signal error: {}", e);
             }
         }
     });
 
     let socket_for_recv = Arc::clone(&socket);
+    let mut rate_limiter = RateLimiter::new(RateLimits::default());
+    let (job_tx, job_rx) = mpsc::channel(rate_limiter.limits().queue_capacity);
+    let worker = spawn_handler_worker(Arc::clone(&config), Arc::clone(&socket), job_rx);
+    let v6_task = v6_socket.map(|v6_socket| {
+        task::spawn(run_v6_listener(
+            v6_socket,
+            Arc::clone(&config),
+            node_id,
+            running.clone(),
+            job_tx.clone(),
+            signal_stop.clone(),
+        ))
+    });
+    let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+    // StreamSink の finish (ファイルの fsync とリネーム) は blocking スレッドで動かし、結果をここで受ける
+    let (finished_tx, mut finished_rx) = mpsc::unbounded_channel::<FinishedStream>();
+    // ファイルはメモリに溜めずに share ディレクトリへ直接書く
+    match config.share_dir() {
+        Ok(share_dir) => reassembler.stream_format(
+            Format::FILE,
+            FileSinkFactory::new(share_dir, config.server.max_file_size()),
+        ),
+        // メモリに溜めて組み立てると、送り主が書いた JSON がそのまま FileHandler に届くので断る
+        Err(e) => {
+            eprintln!("File transfer disabled: {}", e);
+            reassembler.stream_format(Format::FILE, RefuseFiles(e));
+        }
+    }
+    let mut expire_tick = tokio::time::interval(std::time::Duration::from_secs(1));
     loop {
-        let mut buf = [0u8; 1024];
-        match socket_for_recv.recv_from(&mut buf).await {
-            Ok((len, addr)) => {
-                if let Some((session_id, chunk, format, data_vec, data_payload)) = parse_packet(&buf[..len]) {
-                    let _ = process_format(format, session_id, chunk, data_vec, data_payload, addr, port.clone());
-                } else {
-                    eprintln!("Parse error");
+        // MAX_PACKET_SIZE より大きいデータグラムは切り詰められずに TooLarge として弾きたいので1バイト余分に取る
+        let mut buf = [0u8; MAX_PACKET_SIZE + 1];
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            received = socket_for_recv.recv_from(&mut buf) => match received {
+                Ok((len, addr)) => {
+                    ServerStats::incr(&SERVER_STATS.received);
+                    // デコードより前に、送信元ごとのバケットで弾く
+                    if !rate_limiter.allow_packet(addr.ip(), Instant::now()) {
+                        SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_peer, addr.ip());
+                        continue;
+                    }
+                    let packet = match OsaiPacket::decode(&buf[..len]) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            SERVER_STATS.record_drop(&SERVER_STATS.parse_errors, addr.ip());
+                            eprintln!("Parse error from {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+                    if packet.format == Format::HANDSHAKE {
+                        if !rate_limiter.allow_message(addr.ip(), packet.format, Instant::now()) {
+                            SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_format, addr.ip());
+                            continue;
+                        }
+                        match handshake::respond(&packet).and_then(|reply| {
+                            reply.encode().map_err(|e| format!("Failed to build handshake reply: {}", e))
+                        }) {
+                            Ok(reply) => {
+                                if let Err(e) = socket_for_recv.send_to(&reply, addr).await {
+                                    eprintln!("Failed to send handshake reply to {}: {}", addr, e);
+                                }
+                            }
+                            Err(e) => eprintln!("Handshake from {} rejected: {}", addr, e),
+                        }
+                        continue;
+                    }
+
+                    let opened = SECURITY.read().unwrap().open_incoming(packet);
+                    let (packet, key) = match opened {
+                        Ok(opened) => opened,
+                        Err(e) => {
+                            SERVER_STATS.record_drop(&SERVER_STATS.unauthenticated, addr.ip());
+                            eprintln!("Rejected sealed packet from {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+                    let auth = key.as_ref().map(|k| k.auth()).unwrap_or(PeerAuth::None);
+                    // open モードでなければ平文はバッファする前に捨てる
+                    if !SECURITY.read().unwrap().allows(packet.format, auth) {
+                        SERVER_STATS.record_drop(&SERVER_STATS.unauthenticated, addr.ip());
+                        eprintln!("Dropped unauthenticated {} packet from {}", packet.format, addr);
+                        continue;
+                    }
+                    // プローブと ping は組み立て不要で、返事は受けたソケットから送る
+                    if packet.format == Format::PROBE || packet.format == Format::ECHO {
+                        if !rate_limiter.allow_message(addr.ip(), packet.format, Instant::now()) {
+                            SERVER_STATS.record_drop(&SERVER_STATS.rate_limited_format, addr.ip());
+                            continue;
+                        }
+                        let answered = if packet.format == Format::PROBE {
+                            discovery::answer_probe(&socket_for_recv, &packet, addr, &config, node_id, &running).await
+                        } else {
+                            answer_echo(&socket_for_recv, &packet, addr, key.as_ref()).await
+                        };
+                        if let Err(e) = answered {
+                            eprintln!("{}", e);
+                        }
+                        continue;
+                    }
+
+                    let session_id = packet.session_id;
+                    match reassembler.push(addr, packet, key.as_ref(), Instant::now()) {
+                        ReassemblyEvent::Complete { message, auth, ack } => {
+                            // 先に ACK を返して送信側の再送を止める
+                            if let Some(ack) = ack {
+                                send_ack(&socket_for_recv, addr, session_id, ack, key.as_ref()).await;
+                            }
+                            if !submit(message, addr, auth, key, &mut rate_limiter, &job_tx) {
+                                break;
+                            }
+                        }
+                        ReassemblyEvent::Finishing(finish) => {
+                            let finished_tx = finished_tx.clone();
+                            task::spawn_blocking(move || {
+                                let _ = finished_tx.send(finish.run());
+                            });
+                        }
+                        ReassemblyEvent::Pending => {}
+                        ReassemblyEvent::Incomplete { session_id, missing, ack } => {
+                            eprintln!("Session {} from {} is missing chunks {:?}", session_id, addr, missing);
+                            send_ack(&socket_for_recv, addr, session_id, ack, key.as_ref()).await;
+                        }
+                        ReassemblyEvent::Duplicate { ack } => {
+                            if let Some(ack) = ack {
+                                send_ack(&socket_for_recv, addr, session_id, ack, key.as_ref()).await;
+                            }
+                        }
+                        ReassemblyEvent::Dropped(e) => {
+                            SERVER_STATS.record_drop(&SERVER_STATS.reassembly_dropped, addr.ip());
+                            eprintln!("Dropped chunk from {}: {}", addr, e);
+                        }
+                        ReassemblyEvent::Failed { session_id, error, ack } => {
+                            eprintln!("Session {} from {} failed: {}", session_id, addr, error);
+                            if let Some(ack) = ack {
+                                send_ack(&socket_for_recv, addr, session_id, ack, key.as_ref()).await;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Recv error: {}", e);
+                    break;
+                }
+            },
+            Some(finished) = finished_rx.recv() => {
+                let (addr, key) = (finished.addr, finished.key.clone());
+                match reassembler.finished(finished, Instant::now()) {
+                    // 確かめ終わってから ACK を返す
+                    ReassemblyEvent::Complete { message, auth, ack } => {
+                        if let Some(ack) = ack {
+                            send_ack(&socket_for_recv, addr, message.session_id, ack, key.as_ref()).await;
+                        }
+                        if !submit(message, addr, auth, key, &mut rate_limiter, &job_tx) {
+                            break;
+                        }
+                    }
+                    ReassemblyEvent::Failed { session_id, error, ack } => {
+                        eprintln!("Session {} from {} failed: {}", session_id, addr, error);
+                        if let Some(ack) = ack {
+                            send_ack(&socket_for_recv, addr, session_id, ack, key.as_ref()).await;
+                        }
+                    }
+                    _ => {}
                 }
             }
-            Err(e) => {
-                eprintln!("Recv error: {}", e);
-                break;
+            _ = expire_tick.tick() => {
+                rate_limiter.prune(Instant::now());
+                for peer in PEER_REGISTRY.write().unwrap().expire(Instant::now()) {
+                    println!("Peer {} [{}] ({}) left", peer.info.name, peer.node_id.short(), peer.udp_addr());
+                }
+                for expired in reassembler.expire(Instant::now()) {
+                    eprintln!(
+                        "Session {} from {} timed out ({} chunks missing)",
+                        expired.session_id, expired.addr, expired.missing
+                    );
+                    // 封をしたセッションには、チャンクを開いた鍵で封をして返す
+                    if let Some(ack) = expired.ack {
+                        send_ack(&socket_for_recv, expired.addr, expired.session_id, ack, expired.key.as_ref()).await;
+                    }
+                }
             }
         }
     }
 
-    Ok(format!("Server started on {}", address))
+    let aborted = reassembler.abort_all();
+    if aborted > 0 {
+        println!("Discarded {} unfinished sessions", aborted);
+    }
+    // IPv6 の受信も job_tx を持っているので、ワーカーを待つ前に止める
+    signal_stop.cancel();
+    let _ = signal_task.await;
+    if let Some(v6_task) = v6_task {
+        let _ = v6_task.await;
+    }
+    drop(job_tx);
+    let _ = worker.await;
+
+    println!("UDP Server on {} stopped", address);
+    Ok(format!("Server stopped on {}", address))
 }
@@ -1,38 +1,43 @@
-use rand::Rng; // rand::Rng の機能を使うために必要
-use crate::client::client::build_udp_packet; // client モジュールの build_udp_packet 関数をインポート
+use crate::config::OsaiConfig;
+use crate::p2p::node::NodeId;
+use crate::protocol::announce::{Announce, ServicePorts, ANNOUNCE_VERSION};
+use crate::protocol::packet::{Format, OsaiPacket, PacketError};
+use crate::runtime::{RunningServices, Service};
+use crate::server::format_handler::FORMAT_REGISTRY;
 
-pub fn build_server_announce_packet(
-    buffer: &mut [u8],
-    src_port: u16,
-    dst_port: u16,
-) -> usize { // <--- 戻り値は `usize` のままにします。パケットの長さを返すため。
-    let mut rng = rand::rng();
-    
-    //parser
-    let mut session_id: [u8; 16] = [255; 16]; // 16バイトの配列を初期化
-    rng.fill(&mut session_id); // rand::Rng::fill() を使って配列をランダムなバイトで埋める
-
-    let chunk: [u8; 8] = [255; 8];          // シグナル用なので全て0でOK
-    let format_signal: [u8; 2] = [0xFF, 0xFF]; // サーバー生存シグナル
-    let data_vec: [u8; 14] = [0; 14];     // シグナル用なので全て0でOK
-    let data: &[u8] = b"OSAI Server Online"; // 簡潔なメッセージ (UTF-8)
+/// 今動いているサービスとハンドラーから自分の生存シグナルの本文を作る
+pub fn build_announce(config: &OsaiConfig, node_id: NodeId, running: &RunningServices) -> Announce {
+    let name = config
+        .node
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("osai-{}", node_id.short()));
+    Announce {
+        version: ANNOUNCE_VERSION,
+        node_id,
+        name,
+        software: Announce::software_version(),
+        device: config.node.device,
+        ports: ServicePorts {
+            udp: config.server.port,
+            http: running.contains(Service::Http).then_some(config.http.port),
+            ws: running.contains(Service::WebSocket).then_some(config.websocket.port),
+        },
+        formats: announced_formats(),
+        capabilities: config.node.capabilities.clone(),
+    }
+}
 
-    // build_udp_packet を呼び出し、返された MutableUdpPacket から長さを取得して返す
-    let _packet = build_udp_packet( // build_udp_packet の戻り値を受け取る
-        buffer,
-        src_port,
-        dst_port,
-        &session_id,
-        &chunk,
-        &format_signal,
-        &data_vec,
-        data
-    );
+/// ハンドラーのフォーマットと、受信ループが直接答えるプローブ・ping
+fn announced_formats() -> Vec<Format> {
+    let mut formats = FORMAT_REGISTRY.read().unwrap().formats();
+    formats.extend([Format::PROBE, Format::ECHO]);
+    formats.sort();
+    formats.dedup();
+    formats
+}
 
-    // または、MutableUdpPacket が提供する `get_total_length()` や `packet().len()` など
-    // 正確な方法は、pnetのドキュメントで `MutableUdpPacket` のメソッドを確認してください。
-    // 例えば、`packet.packet().len()` や `packet.packet_size()` のようなもの。
-    // もし単純に `set_length` で設定した値がそのままUDPパケットの全長になるなら、
-    // 以下のように計算で返せます。
-    (8 + session_id.len() + chunk.len() + format_signal.len() + data_vec.len() + data.len()) as usize
+/// サーバー生存シグナル。ノードIDと各サービスのポートを載せる
+pub fn build_server_announce_packet(announce: &Announce) -> Result<OsaiPacket, PacketError> {
+    announce.clone().into_packet()
 }
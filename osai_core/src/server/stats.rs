@@ -0,0 +1,98 @@
+// 受信ループのカウンター。CLI の `stats` で表示する
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use once_cell::sync::Lazy;
+
+/// 送信元ごとの破棄数を覚えておく上限
+const MAX_PEER_ENTRIES: usize = 1024;
+/// report に出す送信元の数
+const TOP_PEERS: usize = 5;
+
+#[derive(Default)]
+pub struct ServerStats {
+    /// 受信したデータグラム
+    pub received: AtomicU64,
+    /// ハンドラーまで届いたメッセージ
+    pub handled: AtomicU64,
+    pub parse_errors: AtomicU64,
+    /// 送信元IPのレート制限で捨てた
+    pub rate_limited_peer: AtomicU64,
+    /// フォーマットのレート制限で捨てた
+    pub rate_limited_format: AtomicU64,
+    /// ハンドラー待ちのキューが一杯で捨てた
+    pub queue_full: AtomicU64,
+    /// 認証できなかった (open モードでない)
+    pub unauthenticated: AtomicU64,
+    /// 再組み立ての制限で捨てた
+    pub reassembly_dropped: AtomicU64,
+    pub handler_panics: AtomicU64,
+    peer_drops: Mutex<HashMap<IpAddr, u64>>,
+}
+
+impl ServerStats {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 捨てたパケットを送信元ごとに数える
+    pub fn record_drop(&self, counter: &AtomicU64, ip: IpAddr) {
+        Self::incr(counter);
+        let mut peers = self.peer_drops.lock().unwrap();
+        if peers.len() >= MAX_PEER_ENTRIES && !peers.contains_key(&ip) {
+            return;
+        }
+        *peers.entry(ip).or_insert(0) += 1;
+    }
+
+    pub fn dropped(&self) -> u64 {
+        [
+            &self.parse_errors,
+            &self.rate_limited_peer,
+            &self.rate_limited_format,
+            &self.queue_full,
+            &self.unauthenticated,
+            &self.reassembly_dropped,
+        ]
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .sum()
+    }
+
+    pub fn report(&self) -> String {
+        let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        let mut out = format!(
+            "received: {}\nhandled: {}\ndropped: {}\n  parse errors: {}\n  rate limited (peer): {}\n  rate limited (format): {}\n  queue full: {}\n  unauthenticated: {}\n  reassembly: {}\nhandler panics: {}",
+            get(&self.received),
+            get(&self.handled),
+            self.dropped(),
+            get(&self.parse_errors),
+            get(&self.rate_limited_peer),
+            get(&self.rate_limited_format),
+            get(&self.queue_full),
+            get(&self.unauthenticated),
+            get(&self.reassembly_dropped),
+            get(&self.handler_panics),
+        );
+
+        let mut peers: Vec<(IpAddr, u64)> = self
+            .peer_drops
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, n)| (*ip, *n))
+            .collect();
+        if !peers.is_empty() {
+            peers.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+            out.push_str("\ntop dropped peers:");
+            for (ip, n) in peers.into_iter().take(TOP_PEERS) {
+                let _ = write!(out, "\n  {}: {}", ip, n);
+            }
+        }
+        out
+    }
+}
+
+pub static SERVER_STATS: Lazy<ServerStats> = Lazy::new(ServerStats::default);
@@ -2,6 +2,8 @@
 use local_ip_address::local_ip;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use tokio_util::sync::CancellationToken;
 use warp::Filter;
 use reqwest;
 use serde::Deserialize;
@@ -10,27 +12,79 @@ use serde::Deserialize;
 use base64::engine::general_purpose;
 use base64::Engine;
 
-pub async fn http_server() -> Result<(), warp::Error>{
-    let dir = "/share";
-    let files = warp::fs::dir(dir);
+use chrono::Utc;
+use warp::http::StatusCode;
+use warp::reply::Reply;
 
-    // ローカルIPアドレスを取得
-    let ip: IpAddr = match local_ip() {
-        Ok(ip) => ip,
-        Err(_) => {
-            eprintln!("Failed to get local IP address. Using 127.0.0.1");
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
-        }
+use crate::config::OsaiConfig;
+use crate::IOT::ical::to_ics;
+use crate::IOT::task::TaskFilter;
+use crate::IOT::task_store::TaskStore;
+
+/// `share_dir` を http://<bind>:<port>/share/ で公開する。
+/// http.task_feed なら /tasks.ics でまだ終わっていないタスクも出す。
+/// `shutdown` がキャンセルされたら処理中のリクエストを返し終えてから止まる
+pub async fn http_server(
+    osai_config: &OsaiConfig,
+    share_dir: PathBuf,
+    shutdown: CancellationToken,
+) -> Result<(), warp::Error>{
+    let config = &osai_config.http;
+    let store = TaskStore::new(&osai_config.paths.task_file);
+    let default_reminders = osai_config.scheduler.default_reminders();
+    let task_feed = config.task_feed;
+    let feed = warp::path("tasks.ics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(move || {
+            let store = store.clone();
+            let default_reminders = default_reminders.clone();
+            async move {
+                if !task_feed {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(task_feed_reply(&store, &default_reminders))
+            }
+        });
+    let files = feed.or(warp::path("share").and(warp::fs::dir(share_dir)));
+
+    // bind が無ければローカルIPアドレスを取得
+    let ip: IpAddr = match config.bind {
+        Some(ip) => ip,
+        None => match local_ip() {
+            Ok(ip) => ip,
+            Err(_) => {
+                eprintln!("Failed to get local IP address. Using 127.0.0.1");
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+            }
+        },
     };
 
-    println!("Starting HTTP file server at http://{}:1234/", ip);
+    let (addr, server) = warp::serve(files)
+        .try_bind_with_graceful_shutdown((ip, config.port), shutdown.cancelled_owned())?;
+    println!("Starting HTTP file server at http://{}/share/", addr);
+    if task_feed {
+        println!("Serving the task calendar at http://{}/tasks.ics", addr);
+    }
 
-    warp::serve(files)
-        .run((ip, 1234))
-        .await;
+    server.await;
+    println!("HTTP file server on {} stopped", addr);
     Ok(())
 }
 
+/// 読むたびにタスクファイルから作る。読めなければ 500 で理由を返す
+fn task_feed_reply(store: &TaskStore, default_reminders: &[u32]) -> warp::reply::Response {
+    let now = Utc::now();
+    match store.list(&TaskFilter::default()) {
+        Ok(tasks) => {
+            let upcoming: Vec<_> = tasks.into_iter().filter(|task| !task.is_done(now)).collect();
+            warp::reply::with_header(to_ics(&upcoming, default_reminders, now), "content-type", "text/calendar; charset=utf-8")
+                .into_response()
+        }
+        Err(e) => warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct FileList {
     pub files: Vec<String>,
@@ -55,8 +109,8 @@ pub async fn fetch_file_list(url: String) -> Result<Vec<String>, String> {
     Ok(file_list.files)
 }
 
-pub async fn request_file(file_name: String, ip: String) -> Result<String, String> {
-    let file_url = format!("http://{}:1234/share/{}", ip, file_name);
+pub async fn request_file(file_name: String, ip: String, port: u16) -> Result<String, String> {
+    let file_url = format!("http://{}:{}/share/{}", ip, port, file_name);
 
     // HTTP GET リクエストを送信
     let response = reqwest::get(&file_url)
@@ -1,54 +1,59 @@
 //use tauri::{AppHandle};
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
 use tokio_tungstenite::accept_async;
+use tokio_util::sync::CancellationToken;
 use futures_util::{StreamExt, SinkExt};
 //use tauri::Emitter;
 
-// WebSocketサーバーをバックグラウンドで開始するTauriコマンド
-pub async fn start_websocket_server(ip: String, port: String) -> Result<String, String> {
+// WebSocketサーバーを開始する。`shutdown` がキャンセルされるまで受け付け、
+// 止めるときは各接続に Close を送って閉じ終わるのを待つ
+pub async fn start_websocket_server(ip: String, port: String, shutdown: CancellationToken) -> Result<String, String> {
     let addr = format!("{}:{}", ip, port);
     println!("Attempting to start WebSocket server at: {}", addr);
 
-    // Clone 'addr' here so that a copy can be moved into the spawned task,
-    // while the original 'addr' remains available for the `Ok` return.
-    let addr_clone_for_spawn = addr.clone(); // <--- Add this line
+    let listener = TcpListener::bind(&addr).await
+        .map_err(|e| format!("Failed to bind WebSocket server to {}: {}", addr, e))?;
+    println!("WebSocket server listening on: {}", addr);
 
-    // WebSocketサーバーをバックグラウンドで実行するためのtokio::spawn
-    tokio::spawn(async move {
-        // Use the cloned version 'addr_clone_for_spawn' inside this block
-        let listener = match TcpListener::bind(&addr_clone_for_spawn).await { // <-- Use addr_clone_for_spawn
-            Ok(l) => l,
-            Err(e) => {
-                let error_msg = format!("Failed to bind WebSocket server to {}: {}", addr_clone_for_spawn, e); // <-- Use addr_clone_for_spawn
-                eprintln!("{}", error_msg);
-                // フロントエンドへのエラー通知
-                return;
-            }
-        };
-        println!("WebSocket server listening on: {}", addr_clone_for_spawn); // <-- Use addr_clone_for_spawn
-
-        loop {
-            match listener.accept().await {
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            accepted = listener.accept() => match accepted {
                 Ok((stream, peer_addr)) => {
                     println!("New WebSocket connection from: {}", peer_addr);
 
                     // 各接続を非同期タスクで処理
-                    tokio::spawn(handle_websocket_connection(stream, peer_addr));
+                    connections.spawn(handle_websocket_connection(stream, peer_addr, shutdown.clone()));
                 }
                 Err(e) => {
                     eprintln!("Error accepting WebSocket connection: {}", e);
                     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                 }
-            }
+            },
+            // 終わった接続を回収する
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
         }
-    });
+    }
 
-    // The original 'addr' is still available here
-    Ok(format!("Attempting to start WebSocket server on {}", addr)) // <-- Use the original 'addr'
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    println!("WebSocket server on {} stopped", addr);
+    Ok(format!("WebSocket server stopped on {}", addr))
 }
 
-async fn handle_websocket_connection(stream: tokio::net::TcpStream, peer_addr: std::net::SocketAddr) {
-    let ws_stream = match accept_async(stream).await {
+async fn handle_websocket_connection(
+    stream: tokio::net::TcpStream,
+    peer_addr: std::net::SocketAddr,
+    shutdown: CancellationToken,
+) {
+    // ハンドシェイクを送ってこない接続で停止が止まらないようにする
+    let accepted = tokio::select! {
+        _ = shutdown.cancelled() => return,
+        accepted = accept_async(stream) => accepted,
+    };
+    let ws_stream = match accepted {
         Ok(ws) => ws,
         Err(e) => {
             eprintln!("Error during WebSocket handshake from {}: {}", peer_addr, e);
@@ -59,7 +64,17 @@ async fn handle_websocket_connection(stream: tokio::net::TcpStream, peer_addr: s
 
     let (mut write, mut read) = ws_stream.split();
 
-    while let Some(message) = read.next().await {
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.cancelled() => {
+                let _ = write.send(tokio_tungstenite::tungstenite::Message::Close(None)).await;
+                break;
+            }
+            message = read.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
         match message {
             Ok(msg) => {
                 match msg {